@@ -0,0 +1,540 @@
+"""Type stubs for the compiled hussh module.
+
+tests/test_stubs.py checks these against the compiled classes, so update both together.
+"""
+
+from collections.abc import Callable, Iterable, Mapping, Sequence
+import os
+from pathlib import PurePath
+import re
+from types import TracebackType
+from typing import Any, Literal, TypedDict, overload
+
+HookEvent = Literal["before_execute", "after_execute", "on_connect", "on_close"]
+AuthMethod = Literal["publickey", "password", "keyboard-interactive", "agent"]
+TransferBackend = Literal["auto", "sftp", "scp"]
+RetryCondition = Literal["status", "timeout"] | re.Pattern[str]
+# a callable allows the commands it returns True for, regexes deny the commands they match
+CommandPolicy = (
+    Callable[[str], bool] | str | re.Pattern[str] | Iterable[str | re.Pattern[str]]
+)
+# local paths can be any os.PathLike, remote paths any PurePath, which are sent with "/" separators
+LocalPath = str | bytes | os.PathLike[str] | os.PathLike[bytes]
+RemotePath = str | PurePath
+
+class TailCheckpoint(TypedDict):
+    path: str
+    pos: int
+    inode: int | None
+
+class TransferResult(TypedDict):
+    backend: Literal["sftp", "scp"]
+    bytes: int
+    duration: float
+
+class Capabilities(TypedDict):
+    sftp: bool
+    setenv: bool
+    shell: bool
+
+class FilesystemUsage(TypedDict):
+    block_size: int
+    blocks: int
+    blocks_free: int
+    blocks_available: int
+    bytes_total: int
+    bytes_free: int
+
+class ConnectionStats(TypedDict):
+    commands: int
+    command_time: float
+    bytes_uploaded: int
+    bytes_downloaded: int
+    reconnects: int
+
+class TransportStats(TypedDict):
+    cipher: str | None
+    mac: str | None
+    compression: str | None
+    bytes_sent: int | None
+    bytes_received: int | None
+    packets_sent: int | None
+    packets_received: int | None
+    retransmits: int | None
+
+class RebootResult(TypedDict):
+    downtime: float
+    rebooted: bool | None
+
+class PublickeyCheck(TypedDict):
+    result: Literal["accepted", "rejected", "unsupported", "unknown"]
+    key_type: str
+    fingerprint: str
+    detail: str | None
+
+class CredentialRequest(TypedDict):
+    kind: Literal["password", "passphrase"]
+    host: str
+    port: int
+    username: str
+    key_path: str | None
+    attempt: int
+
+def shlex_quote(s: str) -> str: ...
+def strip_ansi(text: str) -> str: ...
+def redact_enabled(enabled: bool | None = None) -> bool: ...
+def set_credential_prompt(
+    prompt: Callable[[CredentialRequest], str | None] | None, cache: bool = True
+) -> None: ...
+def wait_for_ssh(
+    host: str, port: int = 22, timeout: float = 300.0, interval: float = 5.0
+) -> float: ...
+
+class AuthenticationError(Exception): ...
+
+class CommandError(Exception):
+    result: SSHResult
+
+class HookError(Exception):
+    result: SSHResult | None
+    errors: list[BaseException]
+
+class ChannelOpenError(OSError):
+    reason: str
+
+class SFTPUnavailableError(OSError): ...
+class ConnectionLostError(ConnectionError): ...
+class ChannelClosedError(OSError): ...
+class FileTooLargeError(OSError): ...
+
+class CommandPolicyError(PermissionError):
+    command: str
+
+class SSHResult:
+    @property
+    def stdout(self) -> str: ...
+    @property
+    def stderr(self) -> str: ...
+    @property
+    def status(self) -> int: ...
+    @property
+    def command(self) -> str | None: ...
+    @property
+    def truncated(self) -> bool: ...
+    @property
+    def stdout_bytes(self) -> int: ...
+    @property
+    def stderr_bytes(self) -> int: ...
+    @property
+    def user(self) -> str | None: ...
+    @property
+    def attempts(self) -> list[float] | None: ...
+    @property
+    def segments(self) -> list[tuple[Literal["stdout", "stderr"], str]] | None: ...
+    @property
+    def combined(self) -> str | None: ...
+    @property
+    def ok(self) -> bool: ...
+
+class Defaults:
+    def __init__(
+        self,
+        command_timeout: int | None = None,
+        connect_timeout: int | None = None,
+        retries: int | None = None,
+        retry_delay: float | None = None,
+        encoding: str | None = None,
+        buffer_size: int | None = None,
+    ) -> None: ...
+    @property
+    def command_timeout(self) -> int | None: ...
+    @property
+    def connect_timeout(self) -> int | None: ...
+    @property
+    def retries(self) -> int | None: ...
+    @property
+    def retry_delay(self) -> float | None: ...
+    @property
+    def encoding(self) -> str | None: ...
+    @property
+    def buffer_size(self) -> int | None: ...
+
+class Connection:
+    def __init__(
+        self,
+        host: str,
+        port: int = 22,
+        username: str = "root",
+        password: str | None = None,
+        private_key: LocalPath | None = None,
+        timeout: int = 0,
+        connect_timeout: int | None = None,
+        debug: bool = False,
+        trace_file: LocalPath | None = None,
+        hooks: Mapping[HookEvent, Callable[..., object] | Iterable[Callable[..., object]]]
+        | None = None,
+        connect: bool = True,
+        allowed_auth: Iterable[AuthMethod] | None = None,
+        max_channels: int | None = None,
+        transfer_backend: TransferBackend = "auto",
+        default_file_mode: int | None = None,
+        defaults: Defaults | None = None,
+        sftp_max_idle: float | None = None,
+        keepalive_interval: int | None = None,
+        dead_peer_timeout: float | None = None,
+        suppress_cleanup_errors: bool = False,
+        command_policy: CommandPolicy | None = None,
+    ) -> None: ...
+    @property
+    def host(self) -> str: ...
+    @property
+    def port(self) -> int: ...
+    @property
+    def username(self) -> str: ...
+    @property
+    def password(self) -> str: ...
+    @property
+    def private_key(self) -> str: ...
+    @property
+    def timeout(self) -> int: ...
+    @property
+    def connect_timeout(self) -> int: ...
+    @property
+    def closed(self) -> bool: ...
+    @property
+    def connected(self) -> bool: ...
+    @property
+    def allowed_auth(self) -> tuple[AuthMethod, ...]: ...
+    @property
+    def max_channels(self) -> int | None: ...
+    @property
+    def transfer_backend(self) -> TransferBackend: ...
+    @property
+    def default_file_mode(self) -> int | None: ...
+    @property
+    def sftp_max_idle(self) -> float | None: ...
+    @property
+    def keepalive_interval(self) -> int | None: ...
+    @property
+    def dead_peer_timeout(self) -> float | None: ...
+    @property
+    def suppress_cleanup_errors(self) -> bool: ...
+    @property
+    def command_policy(self) -> Callable[[str], bool] | tuple[re.Pattern[str], ...] | None: ...
+    @property
+    def defaults(self) -> Defaults: ...
+    @property
+    def auth_attempts(self) -> list[tuple[str, str]]: ...
+    def connect(self) -> None: ...
+    def wait_until_ready(self, timeout: float = 300.0, interval: float = 5.0) -> float: ...
+    def reboot(
+        self, wait: bool = True, timeout: float = 600.0, command: str = "reboot"
+    ) -> RebootResult | None: ...
+    def check_publickey(self, key: LocalPath) -> PublickeyCheck: ...
+    def as_openssh_command(self, include_options: bool = True) -> str: ...
+    def effective_defaults(self) -> dict[str, int | float | str | None]: ...
+    def detect_remote_locale(self) -> str: ...
+    def get_banner_info(
+        self, motd: bool = True, motd_timeout: int = 1000
+    ) -> dict[str, str | None]: ...
+    def is_active(self) -> bool: ...
+    def stats(self) -> ConnectionStats: ...
+    def reset_stats(self) -> None: ...
+    def transport_stats(self) -> TransportStats: ...
+    def lock(self) -> ConnectionLock: ...
+    def batch(self) -> CommandBatch: ...
+    def open_subsystem(self, name: str) -> SubsystemChannel: ...
+    def tempdir(self, prefix: str = "hussh-", dir: RemotePath = "/tmp") -> RemoteTempPath: ...
+    def tempfile(self, prefix: str = "hussh-", dir: RemotePath = "/tmp") -> RemoteTempPath: ...
+    def execute(
+        self,
+        command: str | Sequence[str],
+        timeout: int | None = None,
+        max_output_bytes: int | None = None,
+        stdout_file: LocalPath | None = None,
+        stderr_file: LocalPath | None = None,
+        stdin_file: LocalPath | None = None,
+        strip_ansi: bool = False,
+        encoding: str | tuple[str, str] | Mapping[str, str] | None = None,
+        retries: int | None = None,
+        retry_delay: float | None = None,
+        retry_on: RetryCondition | Iterable[RetryCondition] | None = None,
+        login_shell: bool = False,
+        combine_streams: bool = False,
+    ) -> SSHResult: ...
+    def remote_env(self, login_shell: bool = False) -> dict[str, str]: ...
+    def capabilities(self, refresh: bool = False) -> Capabilities: ...
+    def run(
+        self,
+        command: str | Sequence[str],
+        hide: bool = False,
+        warn: bool = False,
+        timeout: int | None = None,
+    ) -> SSHResult: ...
+    def sudo(
+        self,
+        command: str | Sequence[str],
+        password: str | None = None,
+        user: str | None = None,
+        hide: bool = False,
+        warn: bool = False,
+        timeout: int | None = None,
+    ) -> SSHResult: ...
+    def run_as(
+        self,
+        user: str,
+        command: str | Sequence[str],
+        via: Literal["sudo", "su"] = "sudo",
+        password: str | None = None,
+        hide: bool = False,
+        warn: bool = False,
+        timeout: int | None = None,
+    ) -> SSHResult: ...
+    def put(self, local: LocalPath, remote: RemotePath | None = None) -> None: ...
+    def get(self, remote: RemotePath, local: LocalPath | None = None) -> None: ...
+    def upload(
+        self,
+        local: LocalPath,
+        remote: RemotePath | None = None,
+        buffer_size: int | None = None,
+        mode: int | None = None,
+    ) -> TransferResult: ...
+    def download(
+        self,
+        remote: RemotePath,
+        local: LocalPath | None = None,
+        buffer_size: int | None = None,
+    ) -> TransferResult: ...
+    @overload
+    def scp_read(
+        self,
+        remote_path: RemotePath,
+        local_path: LocalPath | None = None,
+        buffer_size: int | None = None,
+        *,
+        binary: Literal[True],
+        max_bytes: int | None = None,
+    ) -> bytes: ...
+    @overload
+    def scp_read(
+        self,
+        remote_path: RemotePath,
+        local_path: LocalPath | None = None,
+        buffer_size: int | None = None,
+        binary: Literal[False] = False,
+        max_bytes: int | None = None,
+    ) -> str: ...
+    @overload
+    def scp_read(
+        self,
+        remote_path: RemotePath,
+        local_path: LocalPath | None = None,
+        buffer_size: int | None = None,
+        binary: bool = False,
+        max_bytes: int | None = None,
+    ) -> str | bytes: ...
+    def scp_write(
+        self,
+        local_path: LocalPath,
+        remote_path: RemotePath,
+        buffer_size: int | None = None,
+        mode: int | None = None,
+    ) -> None: ...
+    def scp_write_data(
+        self, data: str, remote_path: RemotePath, mode: int | None = None
+    ) -> None: ...
+    @overload
+    def sftp_read(
+        self,
+        remote_path: RemotePath,
+        local_path: LocalPath | None = None,
+        buffer_size: int | None = None,
+        *,
+        binary: Literal[True],
+        max_bytes: int | None = None,
+    ) -> bytes: ...
+    @overload
+    def sftp_read(
+        self,
+        remote_path: RemotePath,
+        local_path: LocalPath | None = None,
+        buffer_size: int | None = None,
+        binary: Literal[False] = False,
+        max_bytes: int | None = None,
+    ) -> str: ...
+    @overload
+    def sftp_read(
+        self,
+        remote_path: RemotePath,
+        local_path: LocalPath | None = None,
+        buffer_size: int | None = None,
+        binary: bool = False,
+        max_bytes: int | None = None,
+    ) -> str | bytes: ...
+    def sftp_write(
+        self,
+        local_path: LocalPath,
+        remote_path: RemotePath | None = None,
+        buffer_size: int | None = None,
+        mode: int | None = None,
+    ) -> None: ...
+    def sftp_write_data(
+        self, data: str, remote_path: RemotePath, mode: int | None = None
+    ) -> None: ...
+    def sftp_du(self, remote_path: RemotePath) -> dict[str, int]: ...
+    def sftp_statvfs(self, remote_path: RemotePath) -> FilesystemUsage: ...
+    def disk_free(self, remote_path: RemotePath) -> int: ...
+    def remote_copy(
+        self, source_path: RemotePath, dest_conn: Connection, dest_path: RemotePath | None = None
+    ) -> None: ...
+    def tail(
+        self,
+        remote_file: RemotePath | None = None,
+        from_pos: int | None = None,
+        checkpoint: TailCheckpoint | None = None,
+        suppress_cleanup_errors: bool | None = None,
+        wait_for_file: float | None = None,
+    ) -> FileTailer: ...
+    def close(self) -> None: ...
+    def children(
+        self,
+    ) -> list[InteractiveShell | CommandBatch | SubsystemChannel | FileTailer]: ...
+    def add_hook(self, event: HookEvent, hook: Callable[..., object]) -> None: ...
+    def shell(
+        self,
+        pty: bool | None = None,
+        set_prompt: bool = False,
+        strip_ansi: bool = False,
+        suppress_cleanup_errors: bool | None = None,
+        suppress_noise: bool = False,
+    ) -> InteractiveShell: ...
+    def __enter__(self) -> Connection: ...
+    def __exit__(
+        self,
+        _exc_type: type[BaseException] | None = None,
+        _exc_value: BaseException | None = None,
+        _traceback: TracebackType | None = None,
+    ) -> None: ...
+
+class ConnectionLock:
+    def __enter__(self) -> ConnectionLock: ...
+    def __exit__(
+        self,
+        _exc_type: type[BaseException] | None = None,
+        _exc_value: BaseException | None = None,
+        _traceback: TracebackType | None = None,
+    ) -> None: ...
+
+class SubsystemChannel:
+    @property
+    def name(self) -> str: ...
+    @property
+    def eof(self) -> bool: ...
+    def send(self, data: bytes | str) -> None: ...
+    def read_nonblocking(self, size: int = 65536) -> bytes: ...
+    def expect(self, pattern: bytes | str, timeout: int | None = None) -> bytes: ...
+    def close(self) -> None: ...
+    def __enter__(self) -> SubsystemChannel: ...
+    def __exit__(
+        self,
+        _exc_type: type[BaseException] | None = None,
+        _exc_value: BaseException | None = None,
+        _traceback: TracebackType | None = None,
+    ) -> None: ...
+
+class RemoteTempPath:
+    @property
+    def path(self) -> str: ...
+    def cleanup(self) -> bool: ...
+    def __enter__(self) -> str: ...
+    def __exit__(
+        self,
+        _exc_type: type[BaseException] | None = None,
+        _exc_value: BaseException | None = None,
+        _traceback: TracebackType | None = None,
+    ) -> None: ...
+
+class CommandBatch:
+    def execute(
+        self,
+        command: str | Sequence[str],
+        timeout: int | None = None,
+        isolated: bool = False,
+    ) -> SSHResult: ...
+    def close(self) -> None: ...
+    def __enter__(self) -> CommandBatch: ...
+    def __exit__(
+        self,
+        _exc_type: type[BaseException] | None = None,
+        _exc_value: BaseException | None = None,
+        _traceback: TracebackType | None = None,
+    ) -> None: ...
+
+class InteractiveShell:
+    @property
+    def result(self) -> SSHResult | None: ...
+    @property
+    def prompt(self) -> str | None: ...
+    @property
+    def preamble(self) -> str | None: ...
+    def read(self) -> SSHResult: ...
+    def send(self, data: str, add_newline: bool | None = None) -> None: ...
+    def set_prompt(self, timeout: int | None = None) -> str: ...
+    def wait_for_prompt(self, timeout: int | None = None) -> str: ...
+    def send_and_read(self, command: str, timeout: int | None = None) -> str: ...
+    def close(self) -> None: ...
+    def __enter__(self) -> InteractiveShell: ...
+    def __exit__(
+        self,
+        _exc_type: type[BaseException] | None = None,
+        _exc_value: BaseException | None = None,
+        _traceback: TracebackType | None = None,
+    ) -> None: ...
+
+class FileTailer:
+    def __init__(
+        self, conn: Connection, remote_file: RemotePath, init_pos: int | None = None
+    ) -> None: ...
+    @property
+    def remote_file(self) -> str: ...
+    @property
+    def last_pos(self) -> int: ...
+    @property
+    def contents(self) -> str | None: ...
+    @property
+    def started(self) -> bool: ...
+    def start(self) -> None: ...
+    def stop(self) -> None: ...
+    def seek_end(self) -> int | None: ...
+    def read(self, from_pos: int | None = None) -> str: ...
+    def checkpoint(self) -> TailCheckpoint: ...
+    def __enter__(self) -> FileTailer: ...
+    def __exit__(
+        self,
+        _exc_type: type[BaseException] | None = None,
+        _exc_value: BaseException | None = None,
+        _traceback: TracebackType | None = None,
+    ) -> None: ...
+
+class ConnectionPool:
+    def __init__(self, max_per_host: int = 4, idle_timeout: float = 60.0) -> None: ...
+    def acquire(self, host: str, **kwargs: Any) -> PooledConnection: ...
+    def stats(self) -> dict[str, int]: ...
+    def close(self) -> None: ...
+    def __enter__(self) -> ConnectionPool: ...
+    def __exit__(
+        self,
+        _exc_type: type[BaseException] | None = None,
+        _exc_value: BaseException | None = None,
+        _traceback: TracebackType | None = None,
+    ) -> None: ...
+
+class PooledConnection:
+    def release(self) -> None: ...
+    def __enter__(self) -> Connection: ...
+    def __exit__(
+        self,
+        _exc_type: type[BaseException] | None = None,
+        _exc_value: BaseException | None = None,
+        _traceback: TracebackType | None = None,
+    ) -> None: ...
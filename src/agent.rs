@@ -0,0 +1,293 @@
+//! # agent.rs
+//!
+//! This module lets Hussh authenticate with security keys (FIDO2/U2F) held in the ssh-agent,
+//! such as `sk-ssh-ed25519@openssh.com` keys on a YubiKey.
+//!
+//! libssh2's agent support drops the flags and counter that follow a security key's signature, and the server needs both.
+//! So for these identities we ask the agent to sign over `SSH_AUTH_SOCK` ourselves, and hand the whole signature
+//! to libssh2's publickey auth through a sign callback. The agent still does the signing, which for a security key
+//! means waiting for the key to be touched. Ordinary keys keep using libssh2's agent support.
+//!
+//! The same callback flavor of publickey auth lets `query_publickey` ask whether a server would accept a key,
+//! by refusing to sign once the server has said it would.
+use std::ffi::{c_char, c_int, c_uchar, c_void, CString};
+use std::io;
+#[cfg(unix)]
+use std::io::{Read, Write};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::ptr;
+
+use ssh2::Session;
+
+const SSH_AGENTC_SIGN_REQUEST: u8 = 13;
+const SSH_AGENT_SIGN_RESPONSE: u8 = 14;
+// The largest reply we accept from the agent, as in OpenSSH
+const MAX_AGENT_REPLY_LEN: usize = 256 * 1024;
+// Any non-zero return from the sign callback fails the auth attempt
+const SIGN_FAILED: c_int = -1;
+
+type SignCallback = extern "C" fn(
+    *mut c_void,
+    *mut *mut c_uchar,
+    *mut usize,
+    *const c_uchar,
+    usize,
+    *mut *mut c_void,
+) -> c_int;
+
+// libssh2-sys doesn't bind the callback flavor of publickey auth, but links the library that provides it
+extern "C" {
+    fn libssh2_userauth_publickey(
+        session: *mut c_void,
+        username: *const c_char,
+        pubkeydata: *const c_uchar,
+        pubkeydata_len: usize,
+        sign_callback: SignCallback,
+        abstract_: *mut *mut c_void,
+    ) -> c_int;
+}
+
+// Split an SSH string (a big-endian u32 length, then that many bytes) from the front of `data`
+fn read_string(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let len = u32::from_be_bytes(data.get(..4)?.try_into().ok()?) as usize;
+    let end = len.checked_add(4)?;
+    Some((data.get(4..end)?, &data[end..]))
+}
+
+fn write_string(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data);
+}
+
+/// The type of a public key, which leads its blob, e.g. `ssh-ed25519` or `sk-ssh-ed25519@openssh.com`.
+pub(crate) fn key_type(blob: &[u8]) -> &str {
+    read_string(blob)
+        .and_then(|(key_type, _)| std::str::from_utf8(key_type).ok())
+        .unwrap_or("unknown")
+}
+
+/// Whether a key type (or an OpenSSH public key line) belongs to a security key, which signs in hardware.
+pub(crate) fn is_security_key(key_type: &str) -> bool {
+    key_type.starts_with("sk-")
+}
+
+// Ask the agent at SSH_AUTH_SOCK to sign `data` with the key in `blob`, returning the signature blob
+#[cfg(unix)]
+fn agent_sign(blob: &[u8], data: &[u8]) -> io::Result<Vec<u8>> {
+    let socket = std::env::var_os("SSH_AUTH_SOCK")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "SSH_AUTH_SOCK is not set"))?;
+    let mut agent = UnixStream::connect(socket)?;
+    let mut request = vec![SSH_AGENTC_SIGN_REQUEST];
+    write_string(&mut request, blob);
+    write_string(&mut request, data);
+    request.extend_from_slice(&0u32.to_be_bytes());
+    let mut message = Vec::with_capacity(request.len() + 4);
+    write_string(&mut message, &request);
+    agent.write_all(&message)?;
+    let mut len = [0; 4];
+    agent.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_AGENT_REPLY_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("ssh-agent reply of {} bytes is too large", len),
+        ));
+    }
+    let mut reply = vec![0; len];
+    agent.read_exact(&mut reply)?;
+    match reply.split_first() {
+        Some((&SSH_AGENT_SIGN_RESPONSE, rest)) => read_string(rest)
+            .map(|(signature, _)| signature.to_vec())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "malformed ssh-agent sign response",
+                )
+            }),
+        _ => Err(io::Error::other("the ssh-agent refused to sign")),
+    }
+}
+
+#[cfg(not(unix))]
+fn agent_sign(_blob: &[u8], _data: &[u8]) -> io::Result<Vec<u8>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "security keys in the ssh-agent are only supported on unix",
+    ))
+}
+
+// What the sign callback needs, passed through libssh2's abstract pointer
+struct SignContext<'a> {
+    blob: &'a [u8],
+    error: Option<io::Error>,
+}
+
+// Sign libssh2's auth request through the agent.
+// libssh2 wants what follows the signature's algorithm name: the bare signature for ordinary keys,
+// or the signature string followed by the flags and counter for security keys.
+extern "C" fn sign_callback(
+    _session: *mut c_void,
+    sig: *mut *mut c_uchar,
+    sig_len: *mut usize,
+    data: *const c_uchar,
+    data_len: usize,
+    abstract_: *mut *mut c_void,
+) -> c_int {
+    // SAFETY: libssh2 hands back the abstract pointer given to libssh2_userauth_publickey,
+    // which points at the SignContext on userauth_security_key's stack, and data_len bytes of data
+    let context = unsafe { &mut *(*abstract_ as *mut SignContext) };
+    let data = unsafe { std::slice::from_raw_parts(data, data_len) };
+    let signature = match agent_sign(context.blob, data) {
+        Ok(signature) => signature,
+        Err(e) => {
+            context.error = Some(e);
+            return SIGN_FAILED;
+        }
+    };
+    let signature = match read_string(&signature) {
+        Some((_, rest)) if is_security_key(key_type(context.blob)) => Some(rest),
+        Some((_, rest)) => read_string(rest).map(|(bare, _)| bare),
+        None => None,
+    };
+    let Some(signature) = signature else {
+        context.error = Some(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "malformed signature from the ssh-agent",
+        ));
+        return SIGN_FAILED;
+    };
+    // libssh2 frees the signature with its allocator, which is malloc for the sessions ssh2 creates
+    let buf = unsafe { libc::malloc(signature.len().max(1)) } as *mut c_uchar;
+    if buf.is_null() {
+        context.error = Some(io::Error::from(io::ErrorKind::OutOfMemory));
+        return SIGN_FAILED;
+    }
+    // SAFETY: buf was just allocated with room for the signature, and sig/sig_len are libssh2's out-params
+    unsafe {
+        ptr::copy_nonoverlapping(signature.as_ptr(), buf, signature.len());
+        *sig = buf;
+        *sig_len = signature.len();
+    }
+    0
+}
+
+/// Authenticate with a security-key identity from the ssh-agent, whose public key is `blob`.
+/// The agent waits for the key to be touched, so a timeout here usually means nobody touched it.
+pub(crate) fn userauth_security_key(
+    session: &Session,
+    username: &str,
+    blob: &[u8],
+) -> Result<(), String> {
+    let username = CString::new(username).map_err(|e| e.to_string())?;
+    let mut context = SignContext { blob, error: None };
+    let mut abstract_ = &mut context as *mut SignContext as *mut c_void;
+    let rc = {
+        let mut raw = session.raw();
+        // SAFETY: the session is locked for the call, and every pointer outlives it
+        unsafe {
+            libssh2_userauth_publickey(
+                ptr::from_mut(&mut *raw).cast(),
+                username.as_ptr(),
+                blob.as_ptr(),
+                blob.len(),
+                sign_callback,
+                &mut abstract_,
+            )
+        }
+    };
+    if rc == 0 {
+        return Ok(());
+    }
+    Err(match context.error.take() {
+        Some(e) => format!(
+            "{} signing with {} (is the security key plugged in? It must be touched when it blinks, before the agent times out)",
+            e,
+            key_type(blob)
+        ),
+        None => ssh2::Error::last_session_error(session)
+            .map_or_else(|| format!("error code {}", rc), |e| e.to_string()),
+    })
+}
+
+// libssh2's code for a server refusing the key, rather than the request going wrong
+const LIBSSH2_ERROR_AUTHENTICATION_FAILED: c_int = -18;
+
+/// How a server answered a query about a public key.
+pub(crate) enum KeyQuery {
+    /// The server would accept a signature from the key.
+    Accepted,
+    /// The server won't accept the key.
+    Rejected,
+    /// The query failed without an answer either way.
+    Unknown(String),
+}
+
+// Called only once the server has answered the query with PK_OK, so the key would be accepted.
+// Refusing to sign ends the attempt there, without authenticating.
+extern "C" fn query_callback(
+    _session: *mut c_void,
+    _sig: *mut *mut c_uchar,
+    _sig_len: *mut usize,
+    _data: *const c_uchar,
+    _data_len: usize,
+    abstract_: *mut *mut c_void,
+) -> c_int {
+    // SAFETY: libssh2 hands back the abstract pointer given to libssh2_userauth_publickey,
+    // which points at the flag on query_publickey's stack
+    unsafe { *(*abstract_ as *mut bool) = true };
+    SIGN_FAILED
+}
+
+/// Ask the server whether `username` may authenticate with the public key `blob`, without proving we hold
+/// its private key. This sends the key with no signature, which servers answer with PK_OK or a failure.
+pub(crate) fn query_publickey(session: &Session, username: &str, blob: &[u8]) -> KeyQuery {
+    let username = match CString::new(username) {
+        Ok(username) => username,
+        Err(e) => return KeyQuery::Unknown(e.to_string()),
+    };
+    let mut accepted = false;
+    let mut abstract_ = &mut accepted as *mut bool as *mut c_void;
+    let rc = {
+        let mut raw = session.raw();
+        // SAFETY: the session is locked for the call, and every pointer outlives it
+        unsafe {
+            libssh2_userauth_publickey(
+                ptr::from_mut(&mut *raw).cast(),
+                username.as_ptr(),
+                blob.as_ptr(),
+                blob.len(),
+                query_callback,
+                &mut abstract_,
+            )
+        }
+    };
+    match rc {
+        _ if accepted => KeyQuery::Accepted,
+        // the server let us in on the unsigned request
+        0 => KeyQuery::Accepted,
+        LIBSSH2_ERROR_AUTHENTICATION_FAILED => KeyQuery::Rejected,
+        _ => KeyQuery::Unknown(
+            ssh2::Error::last_session_error(session)
+                .map_or_else(|| format!("error code {}", rc), |e| e.to_string()),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_type() {
+        let mut blob = Vec::new();
+        write_string(&mut blob, b"sk-ssh-ed25519@openssh.com");
+        write_string(&mut blob, &[0; 32]);
+        write_string(&mut blob, b"ssh:");
+        assert_eq!(key_type(&blob), "sk-ssh-ed25519@openssh.com");
+        assert!(is_security_key(key_type(&blob)));
+        assert!(!is_security_key("ssh-ed25519"));
+        assert_eq!(key_type(&[0, 0, 0, 9, b's']), "unknown");
+        assert_eq!(key_type(&[]), "unknown");
+    }
+}
@@ -0,0 +1,104 @@
+//! # ansi.rs
+//!
+//! This module removes the terminal escape sequences that shells and programs write to a pty,
+//! such as colors, cursor movement, window titles and bracketed-paste toggles.
+//!
+//! It's a small state machine following ECMA-48, rather than a regex, so a sequence split by an
+//! unexpected control character ends cleanly, and text that merely looks like a sequence is left alone.
+use pyo3::prelude::*;
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Text,
+    // after ESC
+    Escape,
+    // after ESC and one or more intermediate bytes, like the `(` in `ESC ( B`
+    EscapeIntermediate,
+    // inside a control sequence, like `ESC [ 1 ; 31 m`
+    Csi,
+    // inside an operating system command or other string, ended by BEL (OSC only) or ST
+    Osc,
+    Str,
+    // after an ESC inside a string, which is ST if a `\` follows
+    StrEscape { osc: bool },
+}
+
+/// Removes ANSI escape sequences (CSI, OSC, and other ESC sequences) from `text`,
+/// and turns `\r\n` line endings into `\n`. Everything else, including lone `\r`s, is kept.
+#[pyfunction]
+pub(crate) fn strip_ansi(text: &str) -> String {
+    let mut clean = String::with_capacity(text.len());
+    let mut state = State::Text;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        state = match (state, c) {
+            (State::Text, '\x1b') => State::Escape,
+            (State::Text, '\u{9b}') => State::Csi,
+            (State::Text, '\u{9d}') => State::Osc,
+            (State::Text, '\r') if chars.peek() == Some(&'\n') => State::Text,
+            (State::Text, c) => {
+                clean.push(c);
+                State::Text
+            }
+            (State::Escape, '[') => State::Csi,
+            (State::Escape, ']') => State::Osc,
+            // DCS, SOS, PM and APC strings
+            (State::Escape, 'P' | 'X' | '^' | '_') => State::Str,
+            (State::Escape | State::EscapeIntermediate, '\x20'..='\x2f') => {
+                State::EscapeIntermediate
+            }
+            (State::Escape | State::EscapeIntermediate, '\x30'..='\x7e') => State::Text,
+            (State::Csi, '\x20'..='\x3f') => State::Csi,
+            (State::Csi, '\x40'..='\x7e') => State::Text,
+            (State::Osc, '\x07') => State::Text,
+            (State::Osc | State::Str, '\x1b') => State::StrEscape {
+                osc: state == State::Osc,
+            },
+            (State::Osc | State::Str, _) => state,
+            (State::StrEscape { .. }, '\\') => State::Text,
+            (State::StrEscape { osc: true }, _) => State::Osc,
+            (State::StrEscape { osc: false }, _) => State::Str,
+            // anything else cuts a sequence short, and is kept as text
+            (_, '\x1b') => State::Escape,
+            (_, c) => {
+                clean.push(c);
+                State::Text
+            }
+        };
+    }
+    clean
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_csi_and_osc() {
+        assert_eq!(strip_ansi("\x1b[1;31mred\x1b[0m plain"), "red plain");
+        assert_eq!(strip_ansi("\x1b[?2004hprompt\x1b[?2004l"), "prompt");
+        assert_eq!(strip_ansi("\x1b]0;root@host: ~\x07$ "), "$ ");
+        assert_eq!(
+            strip_ansi("\x1b]8;;http://x\x1b\\link\x1b]8;;\x1b\\"),
+            "link"
+        );
+        assert_eq!(strip_ansi("\x1b(Bcharset\x1b=keypad"), "charsetkeypad");
+        assert_eq!(strip_ansi("\u{9b}2Jcleared"), "cleared");
+    }
+
+    #[test]
+    fn normalizes_line_endings() {
+        assert_eq!(strip_ansi("one\r\ntwo\r\n"), "one\ntwo\n");
+        // a lone carriage return is content, not a line ending
+        assert_eq!(strip_ansi("50%\r100%\n"), "50%\r100%\n");
+    }
+
+    #[test]
+    fn keeps_ordinary_text() {
+        let text = "[1;31m isn't a sequence without ESC, and neither is ] or \\ or é\n";
+        assert_eq!(strip_ansi(text), text);
+        // an interrupted sequence keeps what interrupted it
+        assert_eq!(strip_ansi("\x1b[12\nnext"), "\nnext");
+        assert_eq!(strip_ansi("trailing\x1b["), "trailing");
+    }
+}
@@ -0,0 +1,26 @@
+//! # compat.rs
+//!
+//! This module holds compatibility layers that let code written against other ssh libraries run on Hussh.
+//!
+//! ## Submodules
+//!
+//! ### paramiko
+//! Shims for the most commonly used parts of paramiko's API, exposed as `hussh.compat.paramiko`.
+use pyo3::prelude::*;
+
+pub mod paramiko;
+
+/// Build the `hussh.compat` module and its submodules, registering each of them in `sys.modules`
+/// so they can be imported directly, e.g. `from hussh.compat import paramiko`.
+pub fn register_compat(parent: &Bound<'_, PyModule>) -> PyResult<()> {
+    let py = parent.py();
+    let compat = PyModule::new(py, "compat")?;
+    let paramiko = PyModule::new(py, "paramiko")?;
+    paramiko::register(&paramiko)?;
+    compat.add_submodule(&paramiko)?;
+    parent.add_submodule(&compat)?;
+    let modules = py.import("sys")?.getattr("modules")?;
+    modules.set_item("hussh.compat", &compat)?;
+    modules.set_item("hussh.compat.paramiko", &paramiko)?;
+    Ok(())
+}
@@ -0,0 +1,776 @@
+//! # paramiko.rs
+//!
+//! This module provides a paramiko-compatible adapter layer on top of Hussh's `Connection`.
+//! It only covers the parts of paramiko's API that most code relies on, so existing call sites can run unmodified.
+//!
+//! ## Classes
+//!
+//! ### SSHClient
+//! Mirrors `paramiko.SSHClient`: `connect`, `exec_command`, `open_sftp`, `invoke_shell`, `get_transport` and `close`.
+//!
+//! ### SFTPClient
+//! Mirrors `paramiko.SFTPClient`: `put`, `get`, `listdir`, `stat`, `remove`, `mkdir` and `close`.
+//!
+//! ### Channel
+//! Mirrors `paramiko.Channel`: `send`, `recv`, `recv_stderr`, `recv_exit_status` and friends.
+//!
+//! ### ChannelFile
+//! The file-like objects returned by `exec_command` for stdin, stdout and stderr.
+//!
+//! ## Usage
+//!
+//! ```python
+//! from hussh.compat import paramiko
+//!
+//! client = paramiko.SSHClient()
+//! client.set_missing_host_key_policy(paramiko.AutoAddPolicy())
+//! client.connect("my.test.server", username="user", password="pass")
+//! stdin, stdout, stderr = client.exec_command("ls")
+//! print(stdout.read().decode())
+//! print(stdout.channel.recv_exit_status())
+//! ```
+//!
+//! Note: Hussh does not currently verify host keys, so only `AutoAddPolicy` is accepted.
+//! `RejectPolicy`, `WarningPolicy` and custom policies raise `NotImplementedError` rather than being ignored.
+//! Unlike paramiko, the username defaults to "root" to match `Connection`.
+use pyo3::exceptions::{PyIOError, PyNotImplementedError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict, PyType};
+use ssh2::{Channel, Session, Sftp, Stream};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::connection::{
+    channel_open_error, sftp_open_error, AuthenticationError, ChannelSlot, Connection, LocalPath,
+    RemotePath, SessionLock, MAX_BUFF_SIZE,
+};
+
+/// Data sent over a channel can be given as either text or bytes, like in paramiko.
+#[derive(FromPyObject)]
+enum Payload {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl Payload {
+    fn as_bytes(&self) -> &[u8] {
+        match self {
+            Payload::Text(text) => text.as_bytes(),
+            Payload::Bytes(bytes) => bytes,
+        }
+    }
+}
+
+/// Accepted by `SSHClient.set_missing_host_key_policy` for compatibility.
+#[pyclass(module = "hussh.compat.paramiko")]
+pub struct AutoAddPolicy {}
+
+#[pymethods]
+impl AutoAddPolicy {
+    #[new]
+    fn new() -> Self {
+        AutoAddPolicy {}
+    }
+}
+
+/// Refused by `SSHClient.set_missing_host_key_policy` with `NotImplementedError`, since Hussh can't yet reject unknown hosts.
+#[pyclass(module = "hussh.compat.paramiko")]
+pub struct RejectPolicy {}
+
+#[pymethods]
+impl RejectPolicy {
+    #[new]
+    fn new() -> Self {
+        RejectPolicy {}
+    }
+}
+
+/// Refused by `SSHClient.set_missing_host_key_policy` with `NotImplementedError`, since Hussh can't yet warn about unknown hosts.
+#[pyclass(module = "hussh.compat.paramiko")]
+pub struct WarningPolicy {}
+
+#[pymethods]
+impl WarningPolicy {
+    #[new]
+    fn new() -> Self {
+        WarningPolicy {}
+    }
+}
+
+/// # SSHClient
+///
+/// A stand-in for `paramiko.SSHClient` backed by a Hussh `Connection`.
+#[pyclass(module = "hussh.compat.paramiko")]
+pub struct SSHClient {
+    conn: Option<Py<Connection>>,
+}
+
+// Non-public methods for the SSHClient class
+impl SSHClient {
+    fn conn<'py>(&self, py: Python<'py>) -> PyResult<PyRef<'py, Connection>> {
+        match &self.conn {
+            Some(conn) => Ok(conn.bind(py).borrow()),
+            None => Err(PyErr::new::<PyIOError, _>("SSH session not active")),
+        }
+    }
+}
+
+#[pymethods]
+impl SSHClient {
+    #[new]
+    fn new() -> Self {
+        SSHClient { conn: None }
+    }
+
+    /// Only `AutoAddPolicy`, as a class or an instance, is accepted, since Hussh does not currently verify host keys.
+    /// Any other policy raises `NotImplementedError`, so code asking for host keys to be checked doesn't
+    /// silently connect to any host.
+    fn set_missing_host_key_policy(&self, policy: &Bound<'_, PyAny>) -> PyResult<()> {
+        // paramiko's own policy classes are recognized by name, like ours
+        let policy_type = match policy.downcast::<PyType>() {
+            Ok(policy_type) => policy_type.clone(),
+            Err(_) => policy.get_type(),
+        };
+        let name = policy_type.name()?;
+        if name == "AutoAddPolicy" {
+            return Ok(());
+        }
+        Err(PyErr::new::<PyNotImplementedError, _>(format!(
+            "Hussh doesn't verify host keys yet, so {} can't be enforced. Use AutoAddPolicy to connect without checking",
+            name
+        )))
+    }
+
+    /// Accepted for compatibility. Hussh does not currently verify host keys.
+    #[pyo3(signature = (_filename=None))]
+    fn load_system_host_keys(&self, _filename: Option<LocalPath>) {}
+
+    /// Connects and authenticates to `hostname`.
+    /// As in paramiko, `timeout` is in seconds and `password` is used to decrypt the key if no `passphrase` is given.
+    #[pyo3(signature = (hostname, port=22, username=None, password=None, key_filename=None, timeout=None, passphrase=None))]
+    #[allow(clippy::too_many_arguments)]
+    fn connect(
+        &mut self,
+        py: Python<'_>,
+        hostname: &str,
+        port: i32,
+        username: Option<&str>,
+        password: Option<&str>,
+        key_filename: Option<LocalPath>,
+        timeout: Option<f64>,
+        passphrase: Option<&str>,
+    ) -> PyResult<()> {
+        let timeout = timeout.map(|t| (t * 1000.0) as u32);
+        let password = if key_filename.is_some() {
+            passphrase.or(password)
+        } else {
+            password
+        };
+        // keyword arguments keep this in step with Connection's parameters as they're added
+        let kwargs = PyDict::new(py);
+        kwargs.set_item("port", port)?;
+        kwargs.set_item("username", username)?;
+        kwargs.set_item("password", password)?;
+        kwargs.set_item("private_key", key_filename.as_deref())?;
+        kwargs.set_item("timeout", timeout)?;
+        kwargs.set_item("connect_timeout", timeout)?;
+        let conn = py
+            .get_type::<Connection>()
+            .call((hostname,), Some(&kwargs))?
+            .downcast_into::<Connection>()?;
+        self.conn = Some(conn.unbind());
+        Ok(())
+    }
+
+    /// Executes a command and returns `(stdin, stdout, stderr)` file-like objects.
+    /// `bufsize` is accepted for compatibility. As in paramiko, `timeout` (seconds) applies to this channel only,
+    /// so it's used while opening the channel and reading or writing it, and the connection's own timeout is kept.
+    #[pyo3(signature = (command, bufsize=-1, timeout=None, get_pty=false))]
+    fn exec_command(
+        &self,
+        py: Python<'_>,
+        command: &str,
+        bufsize: i32,
+        timeout: Option<f64>,
+        get_pty: bool,
+    ) -> PyResult<(ChannelFile, ChannelFile, ChannelFile)> {
+        let _ = bufsize;
+        let conn = self.conn(py)?;
+        let io = ChannelIo::new(&conn, timeout.map(|t| (t * 1000.0) as u32));
+        let (channel, slot) = conn.open_with_slot(py, |session| {
+            io.run(py, || {
+                let mut channel = session.channel_session().map_err(|e| open_error(&e))?;
+                if get_pty {
+                    channel.request_pty("xterm", None, None).map_err(|e| {
+                        PyErr::new::<PyIOError, _>(format!("PTY request error: {}", e))
+                    })?;
+                }
+                channel
+                    .exec(command)
+                    .map_err(|e| PyErr::new::<PyIOError, _>(format!("Exec error: {}", e)))?;
+                Ok(channel)
+            })
+        })?;
+        // all three files share the same Channel object, as in paramiko
+        let channel = Py::new(
+            py,
+            CompatChannel {
+                channel,
+                io,
+                slot: Some(slot),
+            },
+        )?;
+        let stdin = ChannelFile::new(py, &channel, 0, true);
+        let stdout = ChannelFile::new(py, &channel, 0, false);
+        let stderr = ChannelFile::new(py, &channel, ssh2::EXTENDED_DATA_STDERR, false);
+        Ok((stdin, stdout, stderr))
+    }
+
+    /// Opens an SFTP session on the connection.
+    fn open_sftp(&self, py: Python<'_>) -> PyResult<SFTPClient> {
+        let conn = self.conn(py)?;
+        let (sftp, slot) = conn.open_with_slot(py, |session| {
+            session.sftp().map_err(|e| sftp_open_error(&e))
+        })?;
+        Ok(SFTPClient {
+            sftp: Some(sftp),
+            slot: Some(slot),
+            lock: Arc::clone(&conn.lock),
+        })
+    }
+
+    /// Starts an interactive shell on a new channel with a pseudo-terminal.
+    #[pyo3(signature = (term="vt100", width=80, height=24))]
+    fn invoke_shell(
+        &self,
+        py: Python<'_>,
+        term: &str,
+        width: u32,
+        height: u32,
+    ) -> PyResult<CompatChannel> {
+        let conn = self.conn(py)?;
+        let (channel, slot) = conn.open_with_slot(py, |session| {
+            let mut channel = session.channel_session().map_err(|e| open_error(&e))?;
+            channel
+                .request_pty(term, None, Some((width, height, 0, 0)))
+                .map_err(|e| PyErr::new::<PyIOError, _>(format!("PTY request error: {}", e)))?;
+            channel
+                .shell()
+                .map_err(|e| PyErr::new::<PyIOError, _>(format!("Shell request error: {}", e)))?;
+            Ok(channel)
+        })?;
+        Ok(CompatChannel {
+            channel,
+            io: ChannelIo::new(&conn, None),
+            slot: Some(slot),
+        })
+    }
+
+    /// Returns a `Transport` for the connection, or `None` if the client isn't connected.
+    fn get_transport(slf: Py<Self>, py: Python<'_>) -> Option<Transport> {
+        let connected = slf.borrow(py).conn.is_some();
+        connected.then(|| Transport { client: slf })
+    }
+
+    /// Closes the underlying connection.
+    fn close(&mut self, py: Python<'_>) -> PyResult<()> {
+        if let Some(conn) = self.conn.take() {
+            conn.borrow(py).close(py)?;
+        }
+        Ok(())
+    }
+
+    fn __enter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    #[pyo3(signature = (_exc_type=None, _exc_value=None, _traceback=None))]
+    fn __exit__(
+        &mut self,
+        py: Python<'_>,
+        _exc_type: Option<&Bound<'_, PyAny>>,
+        _exc_value: Option<&Bound<'_, PyAny>>,
+        _traceback: Option<&Bound<'_, PyAny>>,
+    ) -> PyResult<()> {
+        self.close(py)
+    }
+}
+
+/// A stand-in for `paramiko.Transport`, only offering what's needed to check on a client.
+#[pyclass(module = "hussh.compat.paramiko")]
+pub struct Transport {
+    client: Py<SSHClient>,
+}
+
+#[pymethods]
+impl Transport {
+    /// Returns `True` while the client is connected and authenticated.
+    fn is_active(&self, py: Python<'_>) -> bool {
+        match &self.client.borrow(py).conn {
+            Some(conn) => conn.borrow(py).session().authenticated(),
+            None => false,
+        }
+    }
+
+    /// Closes the client this transport belongs to.
+    fn close(&self, py: Python<'_>) -> PyResult<()> {
+        self.client.borrow_mut(py).close(py)
+    }
+}
+
+// The server's refusal to open a channel as a ChannelOpenError, or any other failure as an IOError
+fn open_error(e: &ssh2::Error) -> PyErr {
+    channel_open_error(e)
+        .unwrap_or_else(|| PyErr::new::<PyIOError, _>(format!("Channel open error: {}", e)))
+}
+
+// Runs a compat object's I/O with the connection's lock held and the GIL released, so it takes turns
+// with everything else on the session. paramiko sets `exec_command`'s timeout on the channel alone,
+// but libssh2 only has a session timeout, so it's set on the session while the channel blocks
+// and the connection's own timeout put back after, all under the lock.
+#[derive(Clone)]
+struct ChannelIo {
+    session: Session,
+    lock: Arc<SessionLock>,
+    timeout: Option<u32>,
+}
+
+impl ChannelIo {
+    fn new(conn: &Connection, timeout: Option<u32>) -> Self {
+        ChannelIo {
+            session: conn.session(),
+            lock: Arc::clone(&conn.lock),
+            timeout,
+        }
+    }
+
+    fn run<T: Send>(&self, py: Python<'_>, f: impl FnOnce() -> T + Send) -> T {
+        self.lock.hold(py, || {
+            let Some(timeout) = self.timeout else {
+                return f();
+            };
+            let previous = self.session.timeout();
+            self.session.set_timeout(timeout);
+            let result = f();
+            self.session.set_timeout(previous);
+            result
+        })
+    }
+}
+
+/// # Channel
+///
+/// A stand-in for `paramiko.Channel`, wrapping an ssh2 channel.
+/// As in paramiko, `recv` and `recv_stderr` block until data is available or the channel is closed.
+#[pyclass(name = "Channel", module = "hussh.compat.paramiko")]
+pub struct CompatChannel {
+    channel: Channel,
+    io: ChannelIo,
+    // counts the channel under the connection's max_channels until it's closed
+    slot: Option<ChannelSlot>,
+}
+
+#[pymethods]
+impl CompatChannel {
+    /// Sends data to the channel, returning the number of bytes sent.
+    fn send(&mut self, py: Python<'_>, data: Payload) -> PyResult<usize> {
+        let channel = &mut self.channel;
+        self.io
+            .run(py, || channel.write(data.as_bytes()))
+            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Channel write error: {}", e)))
+    }
+
+    /// Sends all of the data to the channel.
+    fn sendall(&mut self, py: Python<'_>, data: Payload) -> PyResult<()> {
+        let channel = &mut self.channel;
+        self.io
+            .run(py, || channel.write_all(data.as_bytes()))
+            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Channel write error: {}", e)))
+    }
+
+    /// Receives up to `nbytes` of stdout data. An empty result means the channel is closed.
+    fn recv<'py>(&mut self, py: Python<'py>, nbytes: usize) -> PyResult<Bound<'py, PyBytes>> {
+        let mut buffer = vec![0; nbytes];
+        let channel = &mut self.channel;
+        let len = self
+            .io
+            .run(py, || channel.read(&mut buffer))
+            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Channel read error: {}", e)))?;
+        Ok(PyBytes::new(py, &buffer[..len]))
+    }
+
+    /// Receives up to `nbytes` of stderr data. An empty result means the channel is closed.
+    fn recv_stderr<'py>(
+        &mut self,
+        py: Python<'py>,
+        nbytes: usize,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        let mut buffer = vec![0; nbytes];
+        let channel = &mut self.channel;
+        let len = self
+            .io
+            .run(py, || channel.stderr().read(&mut buffer))
+            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Channel read error: {}", e)))?;
+        Ok(PyBytes::new(py, &buffer[..len]))
+    }
+
+    /// Returns `True` if data is buffered and can be read without blocking.
+    fn recv_ready(&self, py: Python<'_>) -> bool {
+        let channel = &self.channel;
+        self.io.run(py, || channel.read_window().available > 0)
+    }
+
+    /// Returns `True` once the remote end has finished sending data.
+    fn exit_status_ready(&self, py: Python<'_>) -> bool {
+        let channel = &self.channel;
+        self.io.run(py, || channel.eof())
+    }
+
+    /// Waits for the remote command to finish and returns its exit status.
+    /// Note: Read stdout/stderr first if the command produces a lot of output, as in paramiko.
+    fn recv_exit_status(&mut self, py: Python<'_>) -> PyResult<i32> {
+        let channel = &mut self.channel;
+        self.io
+            .run(py, || channel.wait_eof())
+            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Wait EOF error: {}", e)))?;
+        self.io
+            .run(py, || channel.wait_close())
+            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Wait close error: {}", e)))?;
+        self.channel
+            .exit_status()
+            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Exit status error: {}", e)))
+    }
+
+    /// Resizes the channel's pseudo-terminal.
+    #[pyo3(signature = (width=80, height=24))]
+    fn resize_pty(&mut self, py: Python<'_>, width: u32, height: u32) -> PyResult<()> {
+        let channel = &mut self.channel;
+        self.io
+            .run(py, || channel.request_pty_size(width, height, None, None))
+            .map_err(|e| PyErr::new::<PyIOError, _>(format!("PTY resize error: {}", e)))
+    }
+
+    /// Sends an EOF, signalling that no more data will be written.
+    fn shutdown_write(&mut self, py: Python<'_>) -> PyResult<()> {
+        let channel = &mut self.channel;
+        self.io
+            .run(py, || channel.send_eof())
+            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Send EOF error: {}", e)))
+    }
+
+    /// Closes the channel.
+    fn close(&mut self, py: Python<'_>) -> PyResult<()> {
+        self.slot = None;
+        let channel = &mut self.channel;
+        self.io
+            .run(py, || channel.close())
+            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Channel close error: {}", e)))
+    }
+}
+
+/// # ChannelFile
+///
+/// A file-like object over one stream of a channel, as returned by `SSHClient.exec_command`.
+/// `read` returns bytes, while `readline`, `readlines` and iteration return decoded text, as in paramiko.
+#[pyclass(module = "hussh.compat.paramiko")]
+pub struct ChannelFile {
+    #[pyo3(get)]
+    channel: Py<CompatChannel>,
+    stream: Stream,
+    io: ChannelIo,
+    writable: bool,
+    buffer: Vec<u8>,
+}
+
+// Non-public methods for the ChannelFile class
+impl ChannelFile {
+    fn new(py: Python<'_>, channel: &Py<CompatChannel>, stream_id: i32, writable: bool) -> Self {
+        let compat = channel.borrow(py);
+        ChannelFile {
+            channel: channel.clone_ref(py),
+            stream: compat.channel.stream(stream_id),
+            io: compat.io.clone(),
+            writable,
+            buffer: Vec::new(),
+        }
+    }
+
+    // Pull the next chunk of the stream into the buffer, returning false at EOF
+    fn fill_buffer(&mut self, py: Python<'_>) -> PyResult<bool> {
+        let mut chunk = vec![0; MAX_BUFF_SIZE];
+        let stream = &mut self.stream;
+        let len = self
+            .io
+            .run(py, || stream.read(&mut chunk))
+            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Channel read error: {}", e)))?;
+        self.buffer.extend_from_slice(&chunk[..len]);
+        Ok(len > 0)
+    }
+}
+
+#[pymethods]
+impl ChannelFile {
+    /// Reads up to `size` bytes, or everything until EOF if `size` is negative.
+    #[pyo3(signature = (size=-1))]
+    fn read<'py>(&mut self, py: Python<'py>, size: i64) -> PyResult<Bound<'py, PyBytes>> {
+        if size < 0 {
+            while self.fill_buffer(py)? {}
+            return Ok(PyBytes::new(py, &std::mem::take(&mut self.buffer)));
+        }
+        let size = size as usize;
+        while self.buffer.len() < size && self.fill_buffer(py)? {}
+        let len = std::cmp::min(size, self.buffer.len());
+        let data: Vec<u8> = self.buffer.drain(..len).collect();
+        Ok(PyBytes::new(py, &data))
+    }
+
+    /// Reads the next line, including its newline. An empty string means EOF.
+    fn readline(&mut self, py: Python<'_>) -> PyResult<String> {
+        loop {
+            if let Some(pos) = self.buffer.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.buffer.drain(..=pos).collect();
+                return Ok(String::from_utf8_lossy(&line).into_owned());
+            }
+            if !self.fill_buffer(py)? {
+                let line = std::mem::take(&mut self.buffer);
+                return Ok(String::from_utf8_lossy(&line).into_owned());
+            }
+        }
+    }
+
+    /// Reads all remaining lines.
+    fn readlines(&mut self, py: Python<'_>) -> PyResult<Vec<String>> {
+        let mut lines = Vec::new();
+        loop {
+            let line = self.readline(py)?;
+            if line.is_empty() {
+                return Ok(lines);
+            }
+            lines.push(line);
+        }
+    }
+
+    /// Writes data to the channel. Only valid for the stdin file.
+    fn write(&mut self, py: Python<'_>, data: Payload) -> PyResult<()> {
+        if !self.writable {
+            return Err(PyErr::new::<PyIOError, _>("File not open for writing"));
+        }
+        let stream = &mut self.stream;
+        self.io
+            .run(py, || stream.write_all(data.as_bytes()))
+            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Channel write error: {}", e)))
+    }
+
+    fn flush(&mut self, py: Python<'_>) -> PyResult<()> {
+        let stream = &mut self.stream;
+        self.io
+            .run(py, || stream.flush())
+            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Channel flush error: {}", e)))
+    }
+
+    /// Closes the file. For stdin, this sends an EOF to the remote command.
+    fn close(&mut self, py: Python<'_>) -> PyResult<()> {
+        if self.writable {
+            self.channel.borrow_mut(py).shutdown_write(py)?;
+        }
+        Ok(())
+    }
+
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python<'_>) -> PyResult<Option<String>> {
+        let line = self.readline(py)?;
+        Ok(if line.is_empty() { None } else { Some(line) })
+    }
+}
+
+/// The subset of `paramiko.SFTPAttributes` that Hussh can provide.
+#[pyclass(module = "hussh.compat.paramiko")]
+pub struct SFTPAttributes {
+    #[pyo3(get)]
+    st_size: Option<u64>,
+    #[pyo3(get)]
+    st_uid: Option<u32>,
+    #[pyo3(get)]
+    st_gid: Option<u32>,
+    #[pyo3(get)]
+    st_mode: Option<u32>,
+    #[pyo3(get)]
+    st_atime: Option<u64>,
+    #[pyo3(get)]
+    st_mtime: Option<u64>,
+}
+
+impl From<ssh2::FileStat> for SFTPAttributes {
+    fn from(stat: ssh2::FileStat) -> Self {
+        SFTPAttributes {
+            st_size: stat.size,
+            st_uid: stat.uid,
+            st_gid: stat.gid,
+            st_mode: stat.perm,
+            st_atime: stat.atime,
+            st_mtime: stat.mtime,
+        }
+    }
+}
+
+/// # SFTPClient
+///
+/// A stand-in for `paramiko.SFTPClient`, wrapping an ssh2 SFTP session.
+#[pyclass(module = "hussh.compat.paramiko")]
+pub struct SFTPClient {
+    sftp: Option<Sftp>,
+    // counts the SFTP channel under the connection's max_channels until it's closed
+    slot: Option<ChannelSlot>,
+    // the connection's lock, held for each request
+    lock: Arc<SessionLock>,
+}
+
+// Non-public methods for the SFTPClient class
+impl SFTPClient {
+    fn sftp(&self) -> PyResult<&Sftp> {
+        self.sftp
+            .as_ref()
+            .ok_or_else(|| PyErr::new::<PyIOError, _>("SFTP session is closed"))
+    }
+
+    // Run `op` on the SFTP session with the connection's lock held and the GIL released
+    fn run<T: Send>(
+        &self,
+        py: Python<'_>,
+        op: impl FnOnce(&Sftp) -> PyResult<T> + Send,
+    ) -> PyResult<T> {
+        let sftp = self.sftp()?;
+        self.lock.hold(py, || op(sftp))
+    }
+}
+
+#[pymethods]
+impl SFTPClient {
+    /// Copies a local file to the remote system, returning the attributes of the new remote file.
+    fn put(
+        &self,
+        py: Python<'_>,
+        localpath: LocalPath,
+        remotepath: RemotePath,
+    ) -> PyResult<SFTPAttributes> {
+        let local_file = std::fs::File::open(&*localpath)
+            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Local file open error: {}", e)))?;
+        let mut reader = BufReader::with_capacity(MAX_BUFF_SIZE, local_file);
+        self.run(py, |sftp| {
+            let mut remote_file = sftp.create(Path::new(&*remotepath)).map_err(|e| {
+                PyErr::new::<PyIOError, _>(format!("Remote file creation error: {}", e))
+            })?;
+            std::io::copy(&mut reader, &mut remote_file)
+                .map_err(|e| PyErr::new::<PyIOError, _>(format!("File copy error: {}", e)))?;
+            remote_file
+                .close()
+                .map_err(|e| PyErr::new::<PyIOError, _>(format!("Close error: {}", e)))
+        })?;
+        self.stat(py, remotepath)
+    }
+
+    /// Copies a remote file to the local system.
+    fn get(&self, py: Python<'_>, remotepath: RemotePath, localpath: LocalPath) -> PyResult<()> {
+        self.run(py, |sftp| {
+            let remote_file = sftp
+                .open(Path::new(&*remotepath))
+                .map_err(|e| PyErr::new::<PyIOError, _>(format!("SFTP open error: {}", e)))?;
+            let mut reader = BufReader::with_capacity(MAX_BUFF_SIZE, remote_file);
+            let local_file = std::fs::File::create(&*localpath)
+                .map_err(|e| PyErr::new::<PyIOError, _>(format!("File create error: {}", e)))?;
+            let mut writer = BufWriter::new(local_file);
+            std::io::copy(&mut reader, &mut writer)
+                .map_err(|e| PyErr::new::<PyIOError, _>(format!("File copy error: {}", e)))?;
+            writer
+                .flush()
+                .map_err(|e| PyErr::new::<PyIOError, _>(format!("Flush error: {}", e)))
+        })
+    }
+
+    /// Returns the names of the entries in a remote directory.
+    #[pyo3(signature = (path=RemotePath(".".to_string())))]
+    fn listdir(&self, py: Python<'_>, path: RemotePath) -> PyResult<Vec<String>> {
+        let entries = self.run(py, |sftp| {
+            sftp.readdir(Path::new(&*path))
+                .map_err(|e| PyErr::new::<PyIOError, _>(format!("Read dir error: {}", e)))
+        })?;
+        Ok(entries
+            .iter()
+            .filter_map(|(entry, _)| entry.file_name())
+            .map(|name| name.to_string_lossy().into_owned())
+            .collect())
+    }
+
+    /// Returns the attributes of a remote path.
+    fn stat(&self, py: Python<'_>, path: RemotePath) -> PyResult<SFTPAttributes> {
+        let stat = self.run(py, |sftp| {
+            sftp.stat(Path::new(&*path))
+                .map_err(|e| PyErr::new::<PyIOError, _>(format!("Stat error: {}", e)))
+        })?;
+        Ok(stat.into())
+    }
+
+    /// Removes a remote file.
+    fn remove(&self, py: Python<'_>, path: RemotePath) -> PyResult<()> {
+        self.run(py, |sftp| {
+            sftp.unlink(Path::new(&*path))
+                .map_err(|e| PyErr::new::<PyIOError, _>(format!("Remove error: {}", e)))
+        })
+    }
+
+    /// Creates a remote directory.
+    #[pyo3(signature = (path, mode=0o777))]
+    fn mkdir(&self, py: Python<'_>, path: RemotePath, mode: i32) -> PyResult<()> {
+        self.run(py, |sftp| {
+            sftp.mkdir(Path::new(&*path), mode)
+                .map_err(|e| PyErr::new::<PyIOError, _>(format!("Mkdir error: {}", e)))
+        })
+    }
+
+    /// Closes the SFTP session.
+    fn close(&mut self, py: Python<'_>) {
+        let sftp = self.sftp.take();
+        self.lock.hold(py, || drop(sftp));
+        self.slot = None;
+    }
+
+    fn __enter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    #[pyo3(signature = (_exc_type=None, _exc_value=None, _traceback=None))]
+    fn __exit__(
+        &mut self,
+        py: Python<'_>,
+        _exc_type: Option<&Bound<'_, PyAny>>,
+        _exc_value: Option<&Bound<'_, PyAny>>,
+        _traceback: Option<&Bound<'_, PyAny>>,
+    ) {
+        self.close(py);
+    }
+}
+
+/// Add the paramiko shims to the `hussh.compat.paramiko` module.
+pub fn register(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    let py = m.py();
+    m.add_class::<SSHClient>()?;
+    m.add_class::<SFTPClient>()?;
+    m.add_class::<SFTPAttributes>()?;
+    m.add_class::<CompatChannel>()?;
+    m.add_class::<ChannelFile>()?;
+    m.add_class::<Transport>()?;
+    m.add_class::<AutoAddPolicy>()?;
+    m.add_class::<RejectPolicy>()?;
+    m.add_class::<WarningPolicy>()?;
+    // paramiko's exceptions map onto the ones Hussh already raises
+    m.add(
+        "AuthenticationException",
+        py.get_type::<AuthenticationError>(),
+    )?;
+    m.add("SSHException", py.get_type::<PyIOError>())?;
+    Ok(())
+}
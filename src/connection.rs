@@ -7,6 +7,7 @@
 //!
 //! ### SSHResult
 //! A class that represents the result of an SSH operation. It includes the standard output, standard error, and exit status of the operation.
+//! Results from `execute`, `run` and `sudo` also record the `command` that produced them.
 //!
 //! ### Connection
 //! A class that represents an SSH connection. It includes methods for executing commands, reading and writing files over SCP and SFTP, and creating an interactive shell.
@@ -14,6 +15,9 @@
 //! ### InteractiveShell
 //! A class that represents an interactive shell over an SSH connection. It includes methods for sending commands and reading the output.
 //!
+//! ### CommandBatch
+//! Runs a series of commands through one remote shell, saving the cost of opening a channel for each. Returned by `Connection.batch()`.
+//!
 //! ## Functions
 //!
 //! ### read_from_channel
@@ -30,6 +34,7 @@
 //! ```
 //!
 //! Multiple forms of authentication are supported. You can use a password, a private key, or the default ssh-agent.
+//! They're tried in turn, as far as `allowed_auth` and the server permit, and each attempt is recorded in `auth_attempts`.
 //!
 //! ```python
 //! conn = Connection("my.test.server", username="user", private_key="~/.ssh/id_rsa")
@@ -38,7 +43,7 @@
 //! ````
 //!
 //! If you don't pass a port, the default SSH port (22) is used.
-//! If you don't pass a username, "root" is used.
+//! If you don't pass a username, "root" is used. An empty username raises a `ValueError`.
 //!
 //! To use the interactive shell, it is recommended to use the shell() context manager from the Connection class.
 //! You can send commands to the shell using the `send` method, then get the results from result when you exit the context manager.
@@ -56,573 +61,5983 @@
 //! Note: The `read` method sends an EOF to the shell, so you won't be able to send more commands after calling `read`. If you want to send more commands, you would need to create a new `InteractiveShell` instance.
 use pyo3::create_exception;
 use pyo3::prelude::*;
-use ssh2::{Channel, Session};
-use std::io::{BufReader, BufWriter, Read, Seek, Write};
-use std::net::TcpStream;
-use std::path::Path;
+use ssh2::{
+    Channel, FileStat, KeyboardInteractivePrompt, MethodType, OpenFlags, OpenType, Prompt, Session,
+};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Cursor, Read, Seek, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use pyo3::exceptions::{
+    PyConnectionError, PyFileNotFoundError, PyIOError, PyPermissionError, PyTimeoutError,
+    PyTypeError, PyValueError,
+};
+use pyo3::types::{PyBytes, PyDict, PyString, PyTuple, PyType, PyWeakrefReference};
 
-use pyo3::exceptions::{PyIOError, PyTimeoutError};
+use crate::agent;
+use crate::ansi;
+use crate::defaults::Defaults;
+use crate::known_hosts::fingerprint;
+use crate::logging::{
+    log, redact, redact_enabled, redact_err, Tracer, DEBUG, ERROR, INFO, REDACTED, WARNING,
+};
+use crate::prompt;
 
-const MAX_BUFF_SIZE: usize = 65536;
+pub(crate) const MAX_BUFF_SIZE: usize = 65536;
+// libssh2's error code for a channel the server refused to open
+const LIBSSH2_ERROR_CHANNEL_FAILURE: i32 = -21;
+// libssh2's error codes for a channel the server has closed, or we've sent EOF on
+const LIBSSH2_ERROR_CHANNEL_CLOSED: i32 = -26;
+const LIBSSH2_ERROR_CHANNEL_EOF_SENT: i32 = -27;
+// libssh2's error codes for a session whose socket has failed, or been closed by the server
+const LIBSSH2_ERROR_SOCKET_SEND: i32 = -7;
+const LIBSSH2_ERROR_SOCKET_DISCONNECT: i32 = -13;
+const LIBSSH2_ERROR_SOCKET_RECV: i32 = -43;
+// SFTP status codes for a lost connection to the SFTP server
+const SSH_FX_NO_CONNECTION: i32 = 6;
+const SSH_FX_CONNECTION_LOST: i32 = 7;
+// The SFTP status code for a path that doesn't exist
+const SSH_FX_NO_SUCH_FILE: i32 = 2;
+// SFTP status codes a server may answer creating an existing path with
+const SSH_FX_FAILURE: i32 = 4;
+const SSH_FX_FILE_ALREADY_EXISTS: i32 = 11;
+// The SFTP status code for a request, like an extension, the server doesn't support
+const SSH_FX_OP_UNSUPPORTED: i32 = 8;
+// How often `tail(wait_for_file=...)` checks whether the file has appeared
+const TAIL_WAIT_INTERVAL: Duration = Duration::from_millis(250);
+// How many random names tempdir and tempfile try before giving up
+const TEMP_NAME_ATTEMPTS: usize = 100;
+// How long `is_active` waits for the server, at most
+const HEALTH_CHECK_TIMEOUT_MS: u32 = 5000;
+// The range of buffer sizes the transfer methods accept
+const MIN_TRANSFER_BUFFER: usize = 4 * 1024;
+const MAX_TRANSFER_BUFFER: usize = 16 * 1024 * 1024;
+// The mode new remote files get when none is asked for
+const DEFAULT_FILE_MODE: i32 = 0o644;
+
+// Transfer buffers are reused across calls on each thread, instead of being allocated for every transfer
+thread_local! {
+    static TRANSFER_BUFFER: Cell<Vec<u8>> = const { Cell::new(Vec::new()) };
+}
 create_exception!(
     connection,
     AuthenticationError,
     pyo3::exceptions::PyException
 );
+create_exception!(connection, CommandError, pyo3::exceptions::PyException);
+create_exception!(connection, HookError, pyo3::exceptions::PyException);
+create_exception!(connection, ChannelOpenError, PyIOError);
+create_exception!(connection, SFTPUnavailableError, PyIOError);
+create_exception!(connection, ConnectionLostError, PyConnectionError);
+create_exception!(connection, ChannelClosedError, PyIOError);
+create_exception!(connection, CommandPolicyError, PyPermissionError);
+create_exception!(connection, FileTooLargeError, PyIOError);
 
-fn read_from_channel(channel: &mut Channel) -> Result<SSHResult, PyErr> {
-    let mut stdout = String::new();
-    channel
-        .read_to_string(&mut stdout)
-        .map_err(|e| PyErr::new::<PyTimeoutError, _>(format!("Timeout reading stdout: {}", e)))?;
-    let mut stderr = String::new();
-    channel
-        .stderr()
-        .read_to_string(&mut stderr)
-        .map_err(|e| PyErr::new::<PyTimeoutError, _>(format!("Timeout reading stderr: {}", e)))?;
-    channel.wait_close().map_err(|e| {
-        PyErr::new::<PyTimeoutError, _>(format!("Timeout waiting for channel to close: {}", e))
-    })?;
-    let status = channel.exit_status().map_err(|e| {
-        PyErr::new::<PyTimeoutError, _>(format!("Timeout getting exit status: {}", e))
-    })?;
-    Ok(SSHResult {
-        stdout,
-        stderr,
-        status,
-    })
+// The events hooks can be registered for with Connection.add_hook
+const HOOK_EVENTS: [&str; 4] = ["before_execute", "after_execute", "on_connect", "on_close"];
+
+// Quote a string so a POSIX shell treats it as a single literal word.
+// Like Python's shlex.quote, strings of only safe characters are left as they are.
+fn shell_quote(s: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c);
+    if !s.is_empty() && s.chars().all(safe) {
+        return s.to_string();
+    }
+    format!("'{}'", s.replace('\'', "'\"'\"'"))
 }
 
-#[pyclass]
-#[derive(Clone)]
-pub struct SSHResult {
-    #[pyo3(get)]
-    pub stdout: String,
-    #[pyo3(get)]
-    pub stderr: String,
-    #[pyo3(get)]
-    pub status: i32,
+/// Quotes a string so a POSIX shell treats it as a single word, with no expansion or interpretation.
+/// This follows the same rules as Python's `shlex.quote`.
+#[pyfunction]
+pub(crate) fn shlex_quote(s: &str) -> String {
+    shell_quote(s)
 }
 
-#[pymethods]
-impl SSHResult {
-    // The __repl__ method for the SSHResult class
-    fn __repr__(&self) -> PyResult<String> {
-        Ok(format!(
-            "SSHResult(stdout={}, stderr={}, status={})",
-            self.stdout, self.stderr, self.status
-        ))
-    }
+// Run a command in a bash login shell, which sources the user's profile as an interactive login would
+fn login_shell_command(command: &str) -> String {
+    format!("bash -lc {}", shell_quote(command))
+}
 
-    // The __str__ method for the SSHResult class
-    fn __str__(&self) -> PyResult<String> {
-        Ok(format!(
-            "stdout:\n{}\nstderr:\n{}\nstatus: {}",
-            self.stdout, self.stderr, self.status
-        ))
-    }
+// Split the output of `env -0` into its names and values. Entries are separated by NULs,
+// since values can contain newlines, and split at their first `=`.
+fn parse_env(output: &str) -> Vec<(&str, &str)> {
+    output
+        .split('\0')
+        .filter_map(|entry| entry.split_once('='))
+        .collect()
 }
 
-/// # Connection
-///
-/// `Connection` is a class that represents an SSH connection. It provides methods for executing commands, reading and writing files over SCP and SFTP, and creating an interactive shell.
-///
-/// ## Attributes
-///
-/// * `session`: The underlying SSH session.
-/// * `host`: The host to connect to.
-/// * `port`: The port to connect to.
-/// * `username`: The username to use for authentication.
-/// * `password`: The password to use for authentication.
-/// * `private_key`: The path to the private key to use for authentication.
-/// * `timeout`: The timeout(ms) for the SSH session.
-///
-/// ## Methods
-///
-/// ### `execute`
-///
-/// Executes a command over the SSH connection and returns the result. It takes the following parameter:
-///
-/// * `command`: The command to execute.
-///
-/// ### `scp_read`
-///
-/// Reads a file over SCP and returns the contents. It takes the following parameters:
-///
-/// * `remote_path`: The path to the file on the remote system.
-/// * `local_path`: The path to save the file on the local system. If not provided, the contents of the file are returned.
-///
-/// ### `scp_write`
-///
-/// Writes a file over SCP. It takes the following parameters:
-///
-/// * `local_path`: The path to the file on the local system.
-/// * `remote_path`: The path to save the file on the remote system.
-///
-/// ### `scp_write_data`
-///
-/// Writes data over SCP. It takes the following parameters:
-///
-/// * `data`: The data to write.
-/// * `remote_path`: The path to save the data on the remote system.
-///
-/// ### `sftp_read`
-///
-/// Reads a file over SFTP and returns the contents. It takes the following parameters:
-///
-/// * `remote_path`: The path to the file on the remote system.
-/// * `local_path`: The path to save the file on the local system. If not provided, the contents of the file are returned.
-///
-/// ### `sftp_write`
-///
-/// Writes a file over SFTP. It takes the following parameters:
-///
-/// * `local_path`: The path to the file on the local system.
-/// * `remote_path`: The path to save the file on the remote system.
-///
-/// ### `shell`
-///
-/// Creates an `InteractiveShell` instance. It takes the following parameter:
-///
-/// ### `remote_copy`
-///
-/// Copies a file from this connection to another connection. It takes the following parameters:
-///
-/// * `source_path`: The path to the file on the remote system.
-/// * `dest_conn`: The destination connection to copy the file to.
-/// * `dest_path`: The path to save the file on the destination system. If not provided, the source path is used.
-#[pyclass]
-pub struct Connection {
-    session: Session,
-    #[pyo3(get)]
-    host: String,
-    #[pyo3(get)]
-    port: i32,
-    #[pyo3(get)]
-    username: String,
-    #[pyo3(get)]
-    password: String,
-    #[pyo3(get)]
-    private_key: String,
-    #[pyo3(get)]
-    timeout: u32,
-    sftp_conn: Option<ssh2::Sftp>,
+// The space available to the user in KiB, from the last line of `df -Pk`, which is the one before
+// the capacity percentage, since device names and mount points can contain spaces
+fn parse_df_available(output: &str) -> Option<u64> {
+    let fields: Vec<&str> = output.lines().last()?.split_whitespace().collect();
+    let capacity = fields.iter().rposition(|field| field.ends_with('%'))?;
+    fields.get(capacity.checked_sub(1)?)?.parse().ok()
 }
 
-// Non-public methods for the Connection class
-impl Connection {
-    // Emulate a python-like sftp property
-    fn sftp(&mut self) -> &ssh2::Sftp {
-        if self.sftp_conn.is_none() {
-            self.sftp_conn = Some(self.session.sftp().unwrap());
+// A command given as a shell string, which runs as is, or as a list of arguments,
+// which are quoted and joined so the shell runs them without interpreting any of them
+#[derive(FromPyObject)]
+enum CommandArg {
+    Shell(String),
+    Argv(Vec<String>),
+}
+
+impl CommandArg {
+    fn into_command(self) -> PyResult<String> {
+        match self {
+            CommandArg::Shell(command) => Ok(command),
+            CommandArg::Argv(args) if args.is_empty() => Err(PyErr::new::<PyValueError, _>(
+                "The command's argument list is empty",
+            )),
+            CommandArg::Argv(args) => Ok(args
+                .iter()
+                .map(|arg| shell_quote(arg))
+                .collect::<Vec<_>>()
+                .join(" ")),
         }
-        self.sftp_conn.as_ref().unwrap()
     }
 }
 
-#[pymethods]
-impl Connection {
-    #[new]
-    #[pyo3(signature = (host, port=22, username="root", password=None, private_key=None, timeout=0))]
-    fn new(
-        host: &str,
-        port: Option<i32>,
-        username: Option<&str>,
-        password: Option<&str>,
-        private_key: Option<&str>,
-        timeout: Option<u32>,
-    ) -> PyResult<Connection> {
-        // if port isn't set, use the default ssh port 22
-        let port = port.unwrap_or(22);
-        // combine the host and port into a single string
-        let conn_str = format!("{}:{}", host, port);
-        let tcp_conn = TcpStream::connect(conn_str)
-            .map_err(|e| PyErr::new::<PyTimeoutError, _>(format!("{}", e)))?;
-        let mut session = Session::new().unwrap();
-        // if a timeout is set, use it
-        let timeout = timeout.unwrap_or(0);
-        session.set_timeout(timeout);
-        session.set_tcp_stream(tcp_conn);
-        session
-            .handshake()
-            .map_err(|e| PyErr::new::<PyTimeoutError, _>(format!("{}", e)))?;
-        // if username isn't set, try using root
-        let username = username.unwrap_or("root");
-        let password = password.unwrap_or("");
-        let private_key = private_key.unwrap_or("");
-        // if private_key is set, use it to authenticate
-        if !private_key.is_empty() {
-            // If a user uses a tilde to represent the home directory,
-            // replace it with the actual home directory
-            let private_key = shellexpand::tilde(private_key).into_owned();
-            // if a password is set, use it to decrypt the private key
-            if !password.is_empty() {
-                session
-                    .userauth_pubkey_file(username, None, Path::new(&private_key), Some(password))
-                    .map_err(|e| PyErr::new::<AuthenticationError, _>(format!("{}", e)))?;
-            } else {
-                // otherwise, try using the private key without a passphrase
-                session
-                    .userauth_pubkey_file(username, None, Path::new(&private_key), None)
-                    .map_err(|e| PyErr::new::<AuthenticationError, _>(format!("{}", e)))?;
-            }
-        } else if !password.is_empty() {
-            session
-                .userauth_password(username, password)
-                .map_err(|e| PyErr::new::<AuthenticationError, _>(format!("{}", e)))?;
-        } else {
-            // if password isn't set, try using the default ssh-agent
-            if session.userauth_agent(username).is_err() {
-                return Err(PyErr::new::<AuthenticationError, _>(
-                    "Failed to authenticate with ssh-agent",
-                ));
-            }
+// A local path, given as a string, or as bytes or any os.PathLike, like a pathlib.Path
+pub(crate) struct LocalPath(pub(crate) String);
+
+impl<'py> FromPyObject<'py> for LocalPath {
+    fn extract_bound(ob: &Bound<'py, PyAny>) -> PyResult<Self> {
+        if let Ok(path) = ob.downcast::<PyString>() {
+            return Ok(LocalPath(path.to_str()?.to_owned()));
         }
-        Ok(Connection {
-            session,
-            port,
-            host: host.to_string(),
-            username: username.to_string(),
-            password: password.to_string(),
-            private_key: private_key.to_string(),
-            timeout,
-            sftp_conn: None,
-        })
+        let path = ob.py().import("os")?.call_method1("fsdecode", (ob,))?;
+        Ok(LocalPath(path.extract()?))
     }
+}
 
-    /// Executes a command over the SSH connection and returns the result.
-    /// If `timeout` is provided, it temporarily updates the session timeout for the duration of the command execution.
-    #[pyo3(signature = (command, timeout=None))]
-    fn execute(&self, command: String, timeout: Option<u32>) -> PyResult<SSHResult> {
-        let original_timeout = self.session.timeout();
-        if let Some(t) = timeout {
-            self.session.set_timeout(t);
-        }
+impl std::ops::Deref for LocalPath {
+    type Target = str;
 
-        let mut channel = self.session.channel_session().map_err(|e| {
-            PyErr::new::<PyTimeoutError, _>(format!(
-                "Timed out establishing channel session.\n{}",
-                e
-            ))
-        })?;
-        // exec is non-blocking, so we don't check for a timeout here, but in read_from_channel
-        channel.exec(&command).unwrap();
-        let result = match read_from_channel(&mut channel) {
-            Ok(res) => res,
-            Err(e) => {
-                self.session.set_timeout(original_timeout);
-                return Err(e);
-            }
-        };
-        self.session.set_timeout(original_timeout);
-        Ok(result)
+    fn deref(&self) -> &str {
+        &self.0
     }
+}
 
-    /// Reads a file over SCP and returns the contents.
-    /// If `local_path` is provided, the file is saved to the local system.
-    /// Otherwise, the contents of the file are returned as a string.
-    #[pyo3(signature = (remote_path, local_path=None))]
-    fn scp_read(&self, remote_path: String, local_path: Option<String>) -> PyResult<String> {
-        let (mut remote_file, stat) = self
-            .session
-            .scp_recv(Path::new(&remote_path))
-            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Failed scp_recv: {}", e)))?;
-        match local_path {
-            Some(local_path) => {
-                let mut local_file = std::fs::File::create(&local_path)
-                    .map_err(|e| PyErr::new::<PyIOError, _>(format!("File create error: {}", e)))?;
-                let mut buffer = vec![0; std::cmp::min(stat.size() as usize, MAX_BUFF_SIZE)];
-                loop {
-                    let len = remote_file
-                        .read(&mut buffer)
-                        .map_err(|e| PyErr::new::<PyIOError, _>(format!("Read error: {}", e)))?;
-                    if len == 0 {
-                        break;
-                    }
-                    local_file
-                        .write_all(&buffer[..len])
-                        .map_err(|e| PyErr::new::<PyIOError, _>(format!("Write error: {}", e)))?;
-                }
-                Ok("Ok".to_string())
-            }
-            None => {
-                let mut contents = String::new();
-                remote_file.read_to_string(&mut contents).map_err(|e| {
-                    PyErr::new::<PyIOError, _>(format!("Read to string failed: {}", e))
-                })?;
-                Ok(contents)
-            }
-        }
+impl AsRef<Path> for LocalPath {
+    fn as_ref(&self) -> &Path {
+        Path::new(&self.0)
     }
+}
 
-    /// Writes a file over SCP.
-    fn scp_write(&self, local_path: String, remote_path: String) -> PyResult<()> {
-        // if remote_path is a directory, append the local file name to the remote path
-        let remote_path = if remote_path.ends_with('/') {
-            format!(
-                "{}/{}",
-                remote_path,
-                Path::new(&local_path)
-                    .file_name()
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-            )
-        } else {
-            remote_path
-        };
-        let mut local_file = std::fs::File::open(&local_path)
-            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Local file open error: {}", e)))?;
-        let metadata = local_file.metadata().unwrap();
-        // TODO: better handle permissions. Perhaps from metadata.permissions()?
-        let mut remote_file = self
-            .session
-            .scp_send(Path::new(&remote_path), 0o644, metadata.len(), None)
-            .map_err(|e| PyErr::new::<PyIOError, _>(format!("scp_send error: {}", e)))?;
-        // create a variable-sized buffer to read the file and loop until EOF
-        let mut read_buffer = vec![0; std::cmp::min(metadata.len() as usize, MAX_BUFF_SIZE)];
-        loop {
-            let bytes_read = local_file
-                .read(&mut read_buffer)
-                .map_err(|e| PyErr::new::<PyIOError, _>(format!("File read error: {}", e)))?;
-            if bytes_read == 0 {
-                break;
-            }
-            remote_file
-                .write_all(&read_buffer[..bytes_read])
-                .map_err(|e| {
-                    PyErr::new::<PyIOError, _>(format!("Remote file write error: {}", e))
-                })?;
+// A remote path, given as a string or as a pure path, like a pathlib.PurePosixPath.
+// Paths are always sent with `/` separators, whatever flavour of path they came from.
+pub(crate) struct RemotePath(pub(crate) String);
+
+impl<'py> FromPyObject<'py> for RemotePath {
+    fn extract_bound(ob: &Bound<'py, PyAny>) -> PyResult<Self> {
+        if let Ok(path) = ob.downcast::<PyString>() {
+            return Ok(RemotePath(path.to_str()?.to_owned()));
         }
-        remote_file.flush().unwrap();
-        remote_file.send_eof().unwrap();
-        remote_file.wait_eof().unwrap();
-        remote_file.close().unwrap();
-        remote_file.wait_close().unwrap();
-        Ok(())
+        let pure_path = ob.py().import("pathlib")?.getattr("PurePath")?;
+        if !ob.is_instance(&pure_path)? {
+            return Err(PyErr::new::<PyTypeError, _>(format!(
+                "expected str or pathlib.PurePath, not {}",
+                ob.get_type().name()?
+            )));
+        }
+        Ok(RemotePath(ob.call_method0("as_posix")?.extract()?))
     }
+}
 
-    /// Writes data over SCP.
-    fn scp_write_data(&self, data: String, remote_path: String) -> PyResult<()> {
-        let mut remote_file = self
-            .session
-            .scp_send(Path::new(&remote_path), 0o644, data.len() as u64, None)
-            .map_err(|e| PyErr::new::<PyIOError, _>(format!("scp_send error: {}", e)))?;
-        remote_file
-            .write_all(data.as_bytes())
-            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Data write error: {}", e)))?;
-        remote_file.send_eof().unwrap();
-        remote_file.wait_eof().unwrap();
-        remote_file.close().unwrap();
-        remote_file.wait_close().unwrap();
-        Ok(())
+impl std::ops::Deref for RemotePath {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
     }
+}
 
-    /// Reads a file over SFTP and returns the contents.
-    /// If `local_path` is provided, the file is saved to the local system.
-    /// Otherwise, the contents of the file are returned as a string.
-    #[pyo3(signature = (remote_path, local_path=None))]
-    fn sftp_read(&mut self, remote_path: String, local_path: Option<String>) -> PyResult<String> {
-        let mut remote_file = BufReader::new(
-            self.sftp()
-                .open(Path::new(&remote_path))
-                .map_err(|e| PyErr::new::<PyIOError, _>(format!("SFTP open error: {}", e)))?,
-        );
-        match local_path {
-            Some(local_path) => {
-                let local_file = std::fs::File::create(&local_path)
-                    .map_err(|e| PyErr::new::<PyIOError, _>(format!("File create error: {}", e)))?;
-                let mut writer = BufWriter::new(local_file);
-                let mut buffer = vec![0; MAX_BUFF_SIZE];
-                loop {
-                    let len = remote_file.read(&mut buffer).map_err(|e| {
-                        PyErr::new::<PyIOError, _>(format!("File read error: {}", e))
-                    })?;
-                    if len == 0 {
-                        break;
-                    }
-                    writer.write_all(&buffer[..len]).map_err(|e| {
-                        PyErr::new::<PyIOError, _>(format!("File write error: {}", e))
-                    })?;
+// Expand a leading tilde and any `$VAR` or `${VAR}` references in a local path, as a shell would
+pub(crate) fn expand_local_path(path: &str) -> PyResult<String> {
+    shellexpand::full(path)
+        .map(|path| path.into_owned())
+        .map_err(|e| {
+            let reason = match e.cause {
+                std::env::VarError::NotPresent => "is not set".to_string(),
+                std::env::VarError::NotUnicode(_) => "is not valid unicode".to_string(),
+            };
+            PyErr::new::<PyValueError, _>(format!(
+                "Couldn't expand {}: environment variable {} {}",
+                path, e.var_name, reason
+            ))
+        })
+}
+
+// The blob of a public key, given as an OpenSSH public key line like `ssh-ed25519 AAAA... comment`,
+// or the path to a public key file, or to a private key with its public key beside it as `<path>.pub`
+fn public_key_blob(key: &str) -> PyResult<Vec<u8>> {
+    let parse_line = |line: &str| {
+        let mut fields = line.split_whitespace();
+        let key_type = fields.next()?;
+        let blob = openssl::base64::decode_block(fields.next()?).ok()?;
+        (agent::key_type(&blob) == key_type).then_some(blob)
+    };
+    if let Some(blob) = parse_line(key) {
+        return Ok(blob);
+    }
+    let path = expand_local_path(key)?;
+    let public_path = format!("{}.pub", path);
+    for candidate in [&path, &public_path] {
+        match std::fs::read_to_string(candidate) {
+            Ok(contents) => {
+                if let Some(blob) = contents.lines().find_map(parse_line) {
+                    return Ok(blob);
                 }
-                writer
-                    .flush()
-                    .map_err(|e| PyErr::new::<PyIOError, _>(format!("Flush error: {}", e)))?;
-                Ok("Ok".to_string())
             }
-            None => {
-                let mut contents = String::new();
-                remote_file.read_to_string(&mut contents).map_err(|e| {
-                    PyErr::new::<PyIOError, _>(format!("Read to string failed: {}", e))
-                })?;
-                Ok(contents)
+            // missing, or binary, so not the public key we're looking for
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::NotFound | std::io::ErrorKind::InvalidData
+                ) => {}
+            Err(e) => {
+                return Err(PyErr::new::<PyIOError, _>(format!(
+                    "Error reading {}: {}",
+                    candidate, e
+                )))
             }
         }
     }
+    Err(PyErr::new::<PyValueError, _>(format!(
+        "No public key found in {} or {}",
+        path, public_path
+    )))
+}
 
-    /// Writes a file over SFTP. If `remote_path` is not provided, the local file is written to the same path on the remote system.
-    #[pyo3(signature = (local_path, remote_path=None))]
-    fn sftp_write(&mut self, local_path: String, remote_path: Option<String>) -> PyResult<()> {
-        let mut local_file = std::fs::File::open(&local_path)
-            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Local file open error: {}", e)))?;
-        let remote_path = remote_path.unwrap_or_else(|| local_path.clone());
-        let metadata = local_file.metadata().unwrap();
-        let mut remote_file = self.sftp().create(Path::new(&remote_path)).map_err(|e| {
-            PyErr::new::<PyIOError, _>(format!("Remote file creation error: {}", e))
-        })?;
-        // create a variable-sized buffer to read the file and loop until EOF
-        let mut read_buffer = vec![0; std::cmp::min(metadata.len() as usize, MAX_BUFF_SIZE)];
-        loop {
-            let bytes_read = local_file
-                .read(&mut read_buffer)
-                .map_err(|e| PyErr::new::<PyIOError, _>(format!("File read error: {}", e)))?;
-            if bytes_read == 0 {
-                break;
-            }
-            remote_file
-                .write_all(&read_buffer[..bytes_read])
-                .map_err(|e| {
-                    PyErr::new::<PyIOError, _>(format!("Remote file write error: {}", e))
-                })?;
-        }
-        remote_file.close().unwrap();
-        Ok(())
-    }
+// The file name at the end of a local path, split on both `/` and `\\` so Windows paths work everywhere
+fn local_file_name(local_path: &str) -> Option<&str> {
+    local_path
+        .rsplit(['/', '\\'])
+        .next()
+        .filter(|name| !name.is_empty() && *name != "." && *name != "..")
+}
 
-    /// Writes data over SFTP.
-    fn sftp_write_data(&mut self, data: String, remote_path: String) -> PyResult<()> {
-        let mut remote_file = self.sftp().create(Path::new(&remote_path)).map_err(|e| {
-            PyErr::new::<PyIOError, _>(format!("Remote file creation error: {}", e))
+// The remote path to use when only a local path is given.
+// POSIX paths are reused as-is, but Windows paths (with a drive letter or backslashes)
+// mean nothing remotely, so only their file name is kept.
+fn default_remote_path(local_path: &str) -> String {
+    let bytes = local_path.as_bytes();
+    let has_drive = bytes.len() >= 2 && bytes[1] == b':' && bytes[0].is_ascii_alphabetic();
+    if has_drive || local_path.contains('\\') {
+        local_file_name(local_path)
+            .unwrap_or(local_path)
+            .to_string()
+    } else {
+        local_path.to_string()
+    }
+}
+
+// Write text to python's sys.stdout or sys.stderr, so output shows up wherever python's streams point
+fn echo_to(py: Python<'_>, stream: &str, data: &[u8]) -> PyResult<()> {
+    let stream = py.import("sys")?.getattr(stream)?;
+    stream.call_method1("write", (String::from_utf8_lossy(data),))?;
+    stream.call_method0("flush")?;
+    Ok(())
+}
+
+// Check a transfer's buffer_size, defaulting to MAX_BUFF_SIZE
+pub(crate) fn transfer_buffer_size(buffer_size: Option<usize>) -> PyResult<usize> {
+    match buffer_size {
+        None => Ok(MAX_BUFF_SIZE),
+        Some(size) if (MIN_TRANSFER_BUFFER..=MAX_TRANSFER_BUFFER).contains(&size) => Ok(size),
+        Some(size) => Err(PyErr::new::<PyValueError, _>(format!(
+            "buffer_size must be between {} and {} bytes, not {}",
+            MIN_TRANSFER_BUFFER, MAX_TRANSFER_BUFFER, size
+        ))),
+    }
+}
+
+// A file transfer that was made: the backend it went over and how many bytes it copied
+struct Transfer {
+    backend: &'static str,
+    bytes: u64,
+}
+
+// The dict returned by `upload` and `download`
+fn transfer_result<'py>(
+    py: Python<'py>,
+    transfer: Transfer,
+    start: Instant,
+) -> PyResult<Bound<'py, PyDict>> {
+    let result = PyDict::new(py);
+    result.set_item("backend", transfer.backend)?;
+    result.set_item("bytes", transfer.bytes)?;
+    result.set_item("duration", start.elapsed().as_secs_f64())?;
+    Ok(result)
+}
+
+// Run `f` with a buffer of `size` bytes, reusing this thread's transfer buffer.
+// A nested transfer finds the buffer taken, and just gets a new one.
+fn with_transfer_buffer<T>(size: usize, f: impl FnOnce(&mut [u8]) -> T) -> T {
+    let mut buffer = TRANSFER_BUFFER.take();
+    buffer.resize(size, 0);
+    let result = f(&mut buffer);
+    TRANSFER_BUFFER.set(buffer);
+    result
+}
+
+// Read a remote file to the end, preallocated when its size is known, as bytes if `binary` is set
+// and otherwise as a string. A file over `max_bytes` is refused before it's read, if its size is known,
+// or once it passes the limit if not, so a huge file can't use up all the memory.
+// Returns the contents and how many bytes were read.
+fn read_contents<'py, R: Read + Send>(
+    py: Python<'py>,
+    remote_file: &mut R,
+    path: &str,
+    size: Option<u64>,
+    binary: bool,
+    max_bytes: Option<u64>,
+) -> PyResult<(Bound<'py, PyAny>, u64)> {
+    let too_big = |size: String| {
+        PyErr::new::<FileTooLargeError, _>(format!(
+            "{} is {} bytes, more than max_bytes ({})",
+            path,
+            size,
+            max_bytes.unwrap_or_default()
+        ))
+    };
+    if let (Some(size), Some(max_bytes)) = (size, max_bytes) {
+        if size > max_bytes {
+            return Err(too_big(size.to_string()));
+        }
+    }
+    let mut contents = Vec::with_capacity(size.unwrap_or(0) as usize);
+    py.allow_threads(|| {
+        remote_file
+            .take(max_bytes.map_or(u64::MAX, |max_bytes| max_bytes.saturating_add(1)))
+            .read_to_end(&mut contents)
+    })
+    .map_err(|e| PyErr::new::<PyIOError, _>(format!("Read error: {}", e)))?;
+    let len = contents.len() as u64;
+    if max_bytes.is_some_and(|max_bytes| len > max_bytes) {
+        return Err(too_big(format!("at least {}", len)));
+    }
+    if binary {
+        return Ok((PyBytes::new(py, &contents).into_any(), len));
+    }
+    let contents = String::from_utf8(contents).map_err(|e| {
+        PyErr::new::<PyIOError, _>(format!(
+            "Read to string failed: {}. Pass binary=True to read it as bytes.",
+            e
+        ))
+    })?;
+    Ok((PyString::new(py, &contents).into_any(), len))
+}
+
+// Read a channel stream until EOF, or until `limit` bytes have been read.
+// Output is written to `file` if one is given, and otherwise collected and returned.
+// If `echo` is set, each chunk is also written to the matching python stream, taking the GIL to do so.
+// The output is decoded as UTF-8, unless another `encoding` is given.
+// Returns the output, the number of bytes read, and whether the limit cut it short.
+fn read_stream<R: Read>(
+    stream: &mut R,
+    name: &str,
+    echo: bool,
+    mut file: Option<&mut File>,
+    limit: Option<u64>,
+    encoding: Option<&str>,
+) -> PyResult<(String, u64, bool)> {
+    let mut output = Vec::new();
+    let mut buffer = vec![0; MAX_BUFF_SIZE];
+    let mut total: u64 = 0;
+    let mut truncated = false;
+    // the start of a multi-byte character split across chunks, held back from the echo
+    let mut held = Vec::new();
+    loop {
+        // once the limit is reached, read a single byte to see if there's any more
+        let want = limit.map_or(MAX_BUFF_SIZE, |limit| {
+            (limit - total).clamp(1, MAX_BUFF_SIZE as u64) as usize
+        });
+        let len = stream
+            .read(&mut buffer[..want])
+            .map_err(|e| read_error(name, e))?;
+        if len == 0 {
+            break;
+        }
+        if limit.is_some_and(|limit| total >= limit) {
+            truncated = true;
+            break;
+        }
+        let chunk = &buffer[..len];
+        total += len as u64;
+        match file.as_mut() {
+            Some(file) => file.write_all(chunk).map_err(|e| {
+                PyErr::new::<PyIOError, _>(format!("Error writing {} to file: {}", name, e))
+            })?,
+            None => output.extend_from_slice(chunk),
+        }
+        if echo {
+            Python::with_gil(|py| echo_chunk(py, name, &mut held, chunk))?;
+        }
+    }
+    if echo && !held.is_empty() {
+        Python::with_gil(|py| echo_to(py, name, &held))?;
+    }
+    let output = decode_output(output, name, encoding, truncated)?;
+    Ok((output, total, truncated))
+}
+
+// The error for a failed read of a command's output
+fn read_error(name: &str, e: std::io::Error) -> PyErr {
+    if e.kind() == std::io::ErrorKind::ConnectionAborted {
+        PyErr::new::<ConnectionLostError, _>(format!("Connection lost reading {}: {}", name, e))
+    } else {
+        PyErr::new::<PyTimeoutError, _>(format!("Timeout reading {}: {}", name, e))
+    }
+}
+
+// Echo a chunk of output, holding back the start of a multi-byte character split across chunks
+// in `held`, until the rest of it arrives
+fn echo_chunk(py: Python<'_>, name: &str, held: &mut Vec<u8>, chunk: &[u8]) -> PyResult<()> {
+    held.extend_from_slice(chunk);
+    let upto = match std::str::from_utf8(held) {
+        Ok(_) => held.len(),
+        Err(e) => e.valid_up_to() + e.error_len().unwrap_or(0),
+    };
+    echo_to(py, name, &held[..upto])?;
+    held.drain(..upto);
+    Ok(())
+}
+
+// Decode a stream's output as UTF-8, unless another `encoding` is given.
+// If the output was `truncated`, a character split by the cut is dropped.
+fn decode_output(
+    mut output: Vec<u8>,
+    name: &str,
+    encoding: Option<&str>,
+    truncated: bool,
+) -> PyResult<String> {
+    if let Some(encoding) = encoding.filter(|e| !is_utf8(e)) {
+        // other encodings are decoded by python, which drops a character split by the cut
+        let errors = if truncated { "ignore" } else { "strict" };
+        return Python::with_gil(|py| {
+            PyBytes::new(py, &output)
+                .call_method1("decode", (encoding, errors))?
+                .extract::<String>()
+        });
+    }
+    // don't let the cut split the last character
+    if truncated {
+        if let Err(e) = std::str::from_utf8(&output) {
+            if e.error_len().is_none() {
+                output.truncate(e.valid_up_to());
+            }
+        }
+    }
+    String::from_utf8(output)
+        .map_err(|e| PyErr::new::<PyTimeoutError, _>(format!("Timeout reading {}: {}", name, e)))
+}
+
+// Whether an encoding name means UTF-8, which is decoded natively
+fn is_utf8(encoding: &str) -> bool {
+    matches!(
+        encoding.to_ascii_lowercase().replace('_', "-").as_str(),
+        "utf-8" | "utf8"
+    )
+}
+
+// The encodings for a command's stdout and stderr: one for both, a (stdout, stderr) pair,
+// or a dict with `stdout` and/or `stderr` keys
+#[derive(FromPyObject)]
+enum OutputEncoding {
+    Both(String),
+    Pair(String, String),
+    Streams(HashMap<String, String>),
+}
+
+impl OutputEncoding {
+    fn into_streams(self) -> PyResult<[Option<String>; 2]> {
+        match self {
+            OutputEncoding::Both(encoding) => Ok([Some(encoding.clone()), Some(encoding)]),
+            OutputEncoding::Pair(stdout, stderr) => Ok([Some(stdout), Some(stderr)]),
+            OutputEncoding::Streams(mut streams) => {
+                let stdout = streams.remove("stdout");
+                let stderr = streams.remove("stderr");
+                if let Some(key) = streams.keys().next() {
+                    return Err(PyErr::new::<PyValueError, _>(format!(
+                        "Unknown stream {:?} in encoding, expected stdout or stderr",
+                        key
+                    )));
+                }
+                Ok([stdout, stderr])
+            }
+        }
+    }
+}
+
+// What makes execute retry a command: a non-zero exit status, a timeout, or stderr matching a regex
+enum RetryOn {
+    Status,
+    Timeout,
+    Stderr(Py<PyAny>),
+}
+
+impl RetryOn {
+    // Parse `retry_on`, which is "status", "timeout", a compiled regex, or a list of them.
+    // Without it, commands are retried after a non-zero exit status or a timeout.
+    fn parse(retry_on: Option<&Bound<'_, PyAny>>) -> PyResult<Vec<RetryOn>> {
+        let Some(retry_on) = retry_on else {
+            return Ok(vec![RetryOn::Status, RetryOn::Timeout]);
+        };
+        let condition = |condition: &Bound<'_, PyAny>| match condition.extract::<String>() {
+            Ok(name) if name == "status" => Ok(RetryOn::Status),
+            Ok(name) if name == "timeout" => Ok(RetryOn::Timeout),
+            Err(_) if condition.hasattr("search")? => {
+                Ok(RetryOn::Stderr(condition.clone().unbind()))
+            }
+            _ => Err(PyErr::new::<PyValueError, _>(format!(
+                "Unknown retry condition {}, expected \"status\", \"timeout\" or a compiled regex",
+                condition.repr()?
+            ))),
+        };
+        if retry_on.is_instance_of::<PyString>() || retry_on.hasattr("search")? {
+            return Ok(vec![condition(retry_on)?]);
+        }
+        retry_on.try_iter()?.map(|item| condition(&item?)).collect()
+    }
+
+    // Whether a command that finished with `result` should be run again
+    fn matches(&self, py: Python<'_>, result: &SSHResult) -> PyResult<bool> {
+        match self {
+            RetryOn::Status => Ok(result.status != 0),
+            RetryOn::Timeout => Ok(false),
+            RetryOn::Stderr(pattern) => pattern
+                .call_method1(py, "search", (&result.stderr,))?
+                .is_truthy(py),
+        }
+    }
+}
+
+// Which commands a connection may run, from its `command_policy`
+enum CommandPolicy {
+    // called with each command, which runs only if it returns true
+    Allow(Py<PyAny>),
+    // compiled regexes, and a command any of them matches is denied
+    Deny(Vec<Py<PyAny>>),
+}
+
+impl CommandPolicy {
+    // Parse `command_policy`, which is a callable, or a regex or list of them as strings or compiled
+    fn parse(policy: &Bound<'_, PyAny>) -> PyResult<Self> {
+        if policy.is_callable() {
+            return Ok(CommandPolicy::Allow(policy.clone().unbind()));
+        }
+        let compile = policy.py().import("re")?.getattr("compile")?;
+        let pattern = |pattern: &Bound<'_, PyAny>| {
+            if pattern.is_instance_of::<PyString>() {
+                Ok(compile.call1((pattern,))?.unbind())
+            } else if pattern.hasattr("search")? {
+                Ok(pattern.clone().unbind())
+            } else {
+                Err(PyErr::new::<PyValueError, _>(format!(
+                    "Invalid command_policy entry {}, expected a regex",
+                    pattern.repr()?
+                )))
+            }
+        };
+        if policy.is_instance_of::<PyString>() || policy.hasattr("search")? {
+            return Ok(CommandPolicy::Deny(vec![pattern(policy)?]));
+        }
+        let patterns = policy
+            .try_iter()
+            .map_err(|_| {
+                PyErr::new::<PyValueError, _>(
+                    "command_policy must be a callable, or a regex or list of them",
+                )
+            })?
+            .map(|item| pattern(&item?))
+            .collect::<PyResult<_>>()?;
+        Ok(CommandPolicy::Deny(patterns))
+    }
+
+    // Raise a CommandPolicyError if the policy doesn't allow `command`
+    fn check(&self, py: Python<'_>, command: &str) -> PyResult<()> {
+        let reason = match self {
+            CommandPolicy::Allow(allow) => (!allow.call1(py, (command,))?.is_truthy(py)?)
+                .then(|| "the command policy".to_string()),
+            CommandPolicy::Deny(patterns) => {
+                let mut reason = None;
+                for pattern in patterns {
+                    if pattern
+                        .call_method1(py, "search", (command,))?
+                        .is_truthy(py)?
+                    {
+                        let source = pattern.getattr(py, "pattern")?;
+                        reason = Some(format!("the pattern {}", source.bind(py).repr()?));
+                        break;
+                    }
+                }
+                reason
+            }
+        };
+        let Some(reason) = reason else {
+            return Ok(());
+        };
+        let err = PyErr::new::<CommandPolicyError, _>(format!(
+            "Command {:?} is denied by {}",
+            command, reason
+        ));
+        err.value(py).setattr("command", command)?;
+        Err(err)
+    }
+
+    // The policy as it was given, or its compiled patterns
+    fn object<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        match self {
+            CommandPolicy::Allow(allow) => Ok(allow.bind(py).clone()),
+            CommandPolicy::Deny(patterns) => Ok(PyTuple::new(py, patterns)?.into_any()),
+        }
+    }
+}
+
+// Where a command's output goes, and how much of it to read
+#[derive(Default)]
+struct OutputOptions {
+    max_bytes: Option<u64>,
+    stdout_file: Option<File>,
+    stderr_file: Option<File>,
+    // output already read from the channel, while streaming stdin
+    stdout_read: Vec<u8>,
+    stderr_read: Vec<u8>,
+    // remove terminal escape sequences from the output kept in the result
+    strip_ansi: bool,
+    // how to decode stdout and stderr, where not UTF-8
+    encodings: [Option<String>; 2],
+    // give up on a server that's sent nothing for this long, while reading concurrently
+    dead_peer_timeout: Option<Duration>,
+    // record the order stdout and stderr arrived in, which only a concurrent read can
+    combine_streams: bool,
+}
+
+// What a command reads on stdin
+enum Stdin<'a> {
+    Empty,
+    Text(&'a str),
+    File(File),
+}
+
+// A command's output, as read from its channel
+struct ChannelOutput {
+    stdout: String,
+    stderr: String,
+    stdout_bytes: u64,
+    stderr_bytes: u64,
+    truncated: bool,
+    segments: Option<Segments>,
+}
+
+// Read a command's output with blocking reads, releasing the GIL while waiting on the server
+fn read_from_channel(channel: &mut Channel) -> Result<SSHResult, PyErr> {
+    Python::with_gil(|py| {
+        py.allow_threads(|| read_output(channel, None, false, OutputOptions::default()))
+    })
+}
+
+// Read a command's output as `options` directs. If the output limit is hit, the channel is closed
+// without waiting for the command to finish, and the result is marked truncated with a status of -1.
+// Given the session, which the caller must hold the lock for, stdout and stderr are read concurrently;
+// otherwise stdout is read to the end before stderr. This needs no GIL, so callers release it while the command runs.
+fn read_output(
+    channel: &mut Channel,
+    session: Option<&Session>,
+    echo: bool,
+    mut options: OutputOptions,
+) -> PyResult<SSHResult> {
+    let output = match session {
+        Some(session) => {
+            let watch = PeerWatch {
+                session: session.clone(),
+                dead_peer_timeout: options.dead_peer_timeout,
+                started: Instant::now(),
+            };
+            session.set_blocking(false);
+            let output = read_concurrently(channel, &watch, echo, &mut options);
+            session.set_blocking(true);
+            output
+        }
+        None => read_sequentially(channel, echo, &mut options),
+    }?;
+    let truncated = output.truncated;
+    if truncated {
+        channel.close().map_err(|e| {
+            PyErr::new::<PyTimeoutError, _>(format!("Timeout closing truncated channel: {}", e))
+        })?;
+    }
+    channel.wait_close().map_err(|e| {
+        PyErr::new::<PyTimeoutError, _>(format!("Timeout waiting for channel to close: {}", e))
+    })?;
+    let status = if truncated {
+        -1
+    } else {
+        channel.exit_status().map_err(|e| {
+            PyErr::new::<PyTimeoutError, _>(format!("Timeout getting exit status: {}", e))
+        })?
+    };
+    let clean = |text: String| match options.strip_ansi {
+        true => ansi::strip_ansi(&text),
+        false => text,
+    };
+    Ok(SSHResult {
+        stdout: clean(output.stdout),
+        stderr: clean(output.stderr),
+        status,
+        command: None,
+        truncated,
+        stdout_bytes: output.stdout_bytes,
+        stderr_bytes: output.stderr_bytes,
+        user: None,
+        attempts: None,
+        segments: output.segments.map(|segments| {
+            segments
+                .into_iter()
+                .map(|(stream, text)| (stream, clean(text)))
+                .collect()
+        }),
+    })
+}
+
+// Read stdout to the end, then stderr, with blocking reads
+fn read_sequentially(
+    channel: &mut Channel,
+    echo: bool,
+    options: &mut OutputOptions,
+) -> PyResult<ChannelOutput> {
+    let (stdout, stdout_bytes, truncated) = read_stream(
+        &mut Cursor::new(std::mem::take(&mut options.stdout_read)).chain(&mut *channel),
+        "stdout",
+        echo,
+        options.stdout_file.as_mut(),
+        options.max_bytes,
+        options.encodings[0].as_deref(),
+    )?;
+    let (stderr, stderr_bytes, truncated) = if truncated {
+        (String::new(), 0, truncated)
+    } else {
+        read_stream(
+            &mut Cursor::new(std::mem::take(&mut options.stderr_read)).chain(channel.stderr()),
+            "stderr",
+            echo,
+            options.stderr_file.as_mut(),
+            options.max_bytes.map(|max| max - stdout_bytes),
+            options.encodings[1].as_deref(),
+        )?
+    };
+    Ok(ChannelOutput {
+        stdout,
+        stderr,
+        stdout_bytes,
+        stderr_bytes,
+        truncated,
+        segments: None,
+    })
+}
+
+const STREAM_NAMES: [&str; 2] = ["stdout", "stderr"];
+
+// Runs of output as (stream name, text), in the order they arrived
+type Segments = Vec<(String, String)>;
+
+// Where the chunks of a concurrent read go: to a file, or kept, up to the output limit for both streams
+struct OutputCollector<'a> {
+    files: [Option<&'a mut File>; 2],
+    limit: Option<u64>,
+    echo: bool,
+    outputs: [Vec<u8>; 2],
+    totals: [u64; 2],
+    // the start of a multi-byte character split across chunks, held back from the echo
+    held: [Vec<u8>; 2],
+    // each run of output from one stream as (stream, start, end), if they're being recorded
+    runs: Option<Vec<(usize, usize, usize)>>,
+}
+
+impl OutputCollector<'_> {
+    // Take a chunk of one stream's output, returning false if the output limit cut it short
+    fn collect(&mut self, id: usize, chunk: &[u8]) -> PyResult<bool> {
+        let name = STREAM_NAMES[id];
+        let room = self.limit.map_or(chunk.len() as u64, |limit| {
+            limit.saturating_sub(self.totals[0] + self.totals[1])
+        });
+        let cut = chunk.len() as u64 > room;
+        let chunk = &chunk[..chunk.len().min(room as usize)];
+        let start = self.outputs[id].len();
+        match self.files[id].as_mut() {
+            Some(file) => file.write_all(chunk).map_err(|e| {
+                PyErr::new::<PyIOError, _>(format!("Error writing {} to file: {}", name, e))
+            })?,
+            None => self.outputs[id].extend_from_slice(chunk),
+        }
+        self.totals[id] += chunk.len() as u64;
+        if let Some(runs) = self.runs.as_mut().filter(|_| !chunk.is_empty()) {
+            match runs.last_mut() {
+                Some((stream, _, end)) if *stream == id => *end += chunk.len(),
+                _ => runs.push((id, start, start + chunk.len())),
+            }
+        }
+        if self.echo {
+            Python::with_gil(|py| echo_chunk(py, name, &mut self.held[id], chunk))?;
+        }
+        Ok(!cut)
+    }
+}
+
+// Read stdout and stderr together as their data arrives, with the session non-blocking, so a command
+// that fills the channel's window with one stream isn't stalled while we wait for the other to end.
+// `watch` sends keepalives while the command is quiet. Output read while streaming stdin comes first.
+fn read_concurrently(
+    channel: &mut Channel,
+    watch: &PeerWatch,
+    echo: bool,
+    options: &mut OutputOptions,
+) -> PyResult<ChannelOutput> {
+    let pending = [
+        std::mem::take(&mut options.stdout_read),
+        std::mem::take(&mut options.stderr_read),
+    ];
+    let mut collector = OutputCollector {
+        files: [options.stdout_file.as_mut(), options.stderr_file.as_mut()],
+        limit: options.max_bytes,
+        echo,
+        outputs: [Vec::new(), Vec::new()],
+        totals: [0, 0],
+        held: [Vec::new(), Vec::new()],
+        runs: options.combine_streams.then(Vec::new),
+    };
+    let mut truncated = false;
+    for (id, chunk) in pending.iter().enumerate() {
+        if !chunk.is_empty() && !truncated {
+            truncated = !collector.collect(id, chunk)?;
+        }
+    }
+    let mut eof = [false, false];
+    let mut buffer = vec![0; MAX_BUFF_SIZE];
+    let mut quiet_since = Instant::now();
+    'read: while !(truncated || eof[0] && eof[1]) {
+        let mut progressed = false;
+        for id in 0..2 {
+            if eof[id] {
+                continue;
+            }
+            let len = match channel.stream(id as i32).read(&mut buffer) {
+                Ok(len) => len,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(read_error(STREAM_NAMES[id], e)),
+            };
+            if len == 0 {
+                eof[id] = true;
+                continue;
+            }
+            progressed = true;
+            if !collector.collect(id, &buffer[..len])? {
+                truncated = true;
+                break 'read;
+            }
+        }
+        if progressed {
+            quiet_since = Instant::now();
+        } else if !(eof[0] && eof[1]) {
+            watch
+                .check(quiet_since)
+                .map_err(|e| read_error("output", e))?;
+            watch.wait();
+        }
+    }
+    if echo {
+        for id in (0..2).filter(|&id| !collector.held[id].is_empty()) {
+            Python::with_gil(|py| echo_to(py, STREAM_NAMES[id], &collector.held[id]))?;
+        }
+    }
+    let segments = match &collector.runs {
+        Some(runs) => Some(decode_runs(&collector.outputs, runs, &options.encodings)?),
+        None => None,
+    };
+    let [stdout, stderr] = collector.outputs;
+    let [stdout_encoding, stderr_encoding] = &options.encodings;
+    Ok(ChannelOutput {
+        stdout: decode_output(stdout, "stdout", stdout_encoding.as_deref(), truncated)?,
+        stderr: decode_output(stderr, "stderr", stderr_encoding.as_deref(), truncated)?,
+        stdout_bytes: collector.totals[0],
+        stderr_bytes: collector.totals[1],
+        truncated,
+        segments,
+    })
+}
+
+// Decode each run of interleaved output. In UTF-8, a character split between two runs goes to the later one;
+// other encodings are decoded by python, carrying a split character over to the stream's next run.
+fn decode_runs(
+    outputs: &[Vec<u8>; 2],
+    runs: &[(usize, usize, usize)],
+    encodings: &[Option<String>; 2],
+) -> PyResult<Segments> {
+    Python::with_gil(|py| {
+        let codecs = py.import("codecs")?;
+        let mut decoders = Vec::with_capacity(2);
+        for encoding in encodings {
+            decoders.push(match encoding.as_deref().filter(|e| !is_utf8(e)) {
+                Some(encoding) => Some(
+                    codecs
+                        .call_method1("getincrementaldecoder", (encoding,))?
+                        .call1(("replace",))?,
+                ),
+                None => None,
+            });
+        }
+        runs.iter()
+            .map(|&(id, start, end)| {
+                let output = &outputs[id];
+                let text = match &decoders[id] {
+                    Some(decoder) => {
+                        // the stream's last run ends with its output
+                        let last = end == output.len();
+                        decoder
+                            .call_method1("decode", (PyBytes::new(py, &output[start..end]), last))?
+                            .extract()?
+                    }
+                    None => {
+                        // back up to the start of a character split by the boundary
+                        let boundary = |mut at: usize| {
+                            while at > 0 && at < output.len() && output[at] & 0xC0 == 0x80 {
+                                at -= 1;
+                            }
+                            at
+                        };
+                        String::from_utf8_lossy(&output[boundary(start)..boundary(end)])
+                            .into_owned()
+                    }
+                };
+                Ok((STREAM_NAMES[id].to_string(), text))
+            })
+            // a run that was only part of a character
+            .filter(|segment| !matches!(segment, Ok((_, text)) if text.is_empty()))
+            .collect()
+    })
+}
+
+// Stream `input` into a command's stdin and send EOF, returning the stdout and stderr read meanwhile.
+// Output is drained as the input goes, so a command that writes while it reads doesn't stall once
+// the channel's window fills. The session is non-blocking until the input is sent, and a session
+// timeout applies to any stretch without progress.
+fn stream_stdin(
+    session: &Session,
+    channel: &mut Channel,
+    input: &mut File,
+) -> PyResult<(Vec<u8>, Vec<u8>)> {
+    let timeout = session.timeout();
+    let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+    session.set_blocking(false);
+    let streamed = with_transfer_buffer(MAX_BUFF_SIZE, |buffer| {
+        let (mut start, mut end, mut eof) = (0, 0, false);
+        let mut last_progress = Instant::now();
+        loop {
+            let mut progressed = false;
+            if start == end && !eof {
+                end = input.read(buffer).map_err(|e| {
+                    PyErr::new::<PyIOError, _>(format!("Stdin file read error: {}", e))
+                })?;
+                start = 0;
+                eof = end == 0;
+            }
+            if start < end {
+                match channel.write(&buffer[start..end]) {
+                    Ok(len) => {
+                        start += len;
+                        progressed = len > 0;
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                    Err(e) => {
+                        return Err(PyErr::new::<PyIOError, _>(format!(
+                            "Stdin write error: {}",
+                            e
+                        )))
+                    }
+                }
+            } else if eof {
+                match channel.send_eof().map_err(std::io::Error::from) {
+                    Ok(()) => return Ok(()),
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                    Err(e) => {
+                        return Err(PyErr::new::<PyIOError, _>(format!("Send EOF error: {}", e)))
+                    }
+                }
+            }
+            for (stream_id, name, output) in
+                [(0, "stdout", &mut stdout), (1, "stderr", &mut stderr)]
+            {
+                let mut stream = channel.stream(stream_id);
+                let mut chunk = [0; 8192];
+                loop {
+                    match stream.read(&mut chunk) {
+                        Ok(0) => break,
+                        Ok(len) => {
+                            output.extend_from_slice(&chunk[..len]);
+                            progressed = true;
+                        }
+                        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                        Err(e) => {
+                            return Err(PyErr::new::<PyIOError, _>(format!(
+                                "Error reading {}: {}",
+                                name, e
+                            )))
+                        }
+                    }
+                }
+            }
+            // keepalives are only sent once due, if the connection has them
+            if let Err(e) = session.keepalive_send().map_err(std::io::Error::from) {
+                if e.kind() != std::io::ErrorKind::WouldBlock {
+                    return Err(PyErr::new::<PyIOError, _>(format!(
+                        "Keepalive error: {}",
+                        e
+                    )));
+                }
+            }
+            if progressed {
+                last_progress = Instant::now();
+            } else if timeout > 0
+                && last_progress.elapsed() >= Duration::from_millis(timeout.into())
+            {
+                return Err(PyErr::new::<PyTimeoutError, _>(
+                    "Timeout streaming stdin to the command",
+                ));
+            } else {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+    });
+    session.set_blocking(true);
+    streamed.map(|_| (stdout, stderr))
+}
+
+// The longest a concurrent read waits on the socket before checking on a quiet command
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Looks after a command whose output is read with the session non-blocking: waiting on the socket,
+// sending libssh2's keepalives while the command is quiet, and applying the timeouts
+struct PeerWatch {
+    session: Session,
+    // give up once the server has sent nothing, not even a keepalive reply, for this long
+    dead_peer_timeout: Option<Duration>,
+    started: Instant,
+}
+
+impl PeerWatch {
+    // Send a keepalive if one is due, then fail if there's been no output since `quiet_since`
+    // for the session timeout, or nothing at all from the server for the dead peer timeout
+    fn check(&self, quiet_since: Instant) -> std::io::Result<()> {
+        if let Err(e) = self.session.keepalive_send().map_err(std::io::Error::from) {
+            if e.kind() != std::io::ErrorKind::WouldBlock {
+                return Err(e);
+            }
+        }
+        let timeout = self.session.timeout();
+        if timeout > 0 && quiet_since.elapsed() >= Duration::from_millis(timeout.into()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "timed out waiting for output",
+            ));
+        }
+        if let (Some(limit), Some(silence)) = (self.dead_peer_timeout, self.server_silence()) {
+            if silence >= limit {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionAborted,
+                    format!(
+                        "the server hasn't answered a keepalive in {:.0}s",
+                        silence.as_secs_f64()
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // How long since the server last sent anything, as far as the kernel knows,
+    // counting from no earlier than the start of the watch
+    #[cfg(target_os = "linux")]
+    fn server_silence(&self) -> Option<Duration> {
+        tcp_info(&self.session).map(|info| {
+            Duration::from_millis(info.tcpi_last_data_recv.into()).min(self.started.elapsed())
+        })
+    }
+
+    // Wait until the socket is ready for libssh2 to make progress, or the poll interval passes
+    #[cfg(unix)]
+    fn wait(&self) {
+        use std::os::unix::io::AsRawFd;
+        let mut events = libc::POLLIN;
+        if matches!(
+            self.session.block_directions(),
+            ssh2::BlockDirections::Outbound | ssh2::BlockDirections::Both
+        ) {
+            events |= libc::POLLOUT;
+        }
+        let mut fd = libc::pollfd {
+            fd: self.session.as_raw_fd(),
+            events,
+            revents: 0,
+        };
+        // SAFETY: fd is a single pollfd, valid for the call
+        unsafe { libc::poll(&mut fd, 1, WATCH_POLL_INTERVAL.as_millis() as libc::c_int) };
+    }
+
+    #[cfg(not(unix))]
+    fn wait(&self) {
+        thread::sleep(WATCH_POLL_INTERVAL);
+    }
+
+    // Only Linux reports when data last arrived, so elsewhere a dead server goes unnoticed
+    #[cfg(not(target_os = "linux"))]
+    fn server_silence(&self) -> Option<Duration> {
+        None
+    }
+}
+
+// The server's refusal to open a channel, as a ChannelOpenError carrying the reason it gave,
+// or None for any other error
+pub(crate) fn channel_open_error(e: &ssh2::Error) -> Option<PyErr> {
+    if e.code() != ssh2::ErrorCode::Session(LIBSSH2_ERROR_CHANNEL_FAILURE) {
+        return None;
+    }
+    // libssh2 puts the reason in brackets, e.g. "Channel open failure (resource shortage)"
+    let message = e.message();
+    let reason = message
+        .split_once('(')
+        .and_then(|(_, rest)| rest.strip_suffix(')'))
+        .unwrap_or("no reason given");
+    let err = PyErr::new::<ChannelOpenError, _>(format!(
+        "The server refused to open a channel: {}",
+        reason
+    ));
+    Python::with_gil(|py| err.value(py).setattr("reason", reason).ok())?;
+    Some(err)
+}
+
+// Whether an SFTP operation failed because its channel is gone, as when a server reaps idle channels
+fn sftp_channel_lost(e: &ssh2::Error) -> bool {
+    matches!(
+        e.code(),
+        ssh2::ErrorCode::Session(LIBSSH2_ERROR_CHANNEL_CLOSED | LIBSSH2_ERROR_CHANNEL_EOF_SENT)
+            | ssh2::ErrorCode::SFTP(SSH_FX_NO_CONNECTION | SSH_FX_CONNECTION_LOST)
+    )
+}
+
+// Whether an operation on `session` failed because the server dropped the connection:
+// it raised a ConnectionLostError, or left the session with a socket error
+fn connection_dropped(py: Python<'_>, err: &PyErr, session: &Session) -> bool {
+    err.is_instance_of::<ConnectionLostError>(py)
+        || ssh2::Error::last_session_error(session).is_some_and(|e| {
+            matches!(
+                e.code(),
+                ssh2::ErrorCode::Session(
+                    LIBSSH2_ERROR_SOCKET_SEND
+                        | LIBSSH2_ERROR_SOCKET_DISCONNECT
+                        | LIBSSH2_ERROR_SOCKET_RECV
+                )
+            )
+        })
+}
+
+// An error opening an SFTP session. Servers without the SFTP subsystem get a SFTPUnavailableError.
+pub(crate) fn sftp_open_error(e: &ssh2::Error) -> PyErr {
+    // libssh2 reports a refused subsystem request with the same code as a refused channel
+    if e.message().contains("SFTP subsystem") {
+        return PyErr::new::<SFTPUnavailableError, _>(format!(
+            "The server doesn't offer SFTP: {}",
+            e
+        ));
+    }
+    channel_open_error(e)
+        .unwrap_or_else(|| PyErr::new::<PyIOError, _>(format!("SFTP session error: {}", e)))
+}
+
+// Check that a mode from Python is a set of permission bits, like 0o600
+fn check_file_mode(mode: u32) -> PyResult<i32> {
+    if mode > 0o7777 {
+        return Err(PyErr::new::<PyValueError, _>(format!(
+            "File mode {:#o} isn't a permission mode like 0o600",
+            mode
+        )));
+    }
+    Ok(mode as i32)
+}
+
+// How sftp_* and put/get transfer files
+#[derive(Clone, Copy, PartialEq)]
+enum TransferBackend {
+    // SFTP, or SCP on servers without SFTP
+    Auto,
+    Sftp,
+    Scp,
+}
+
+// What `capabilities` found the server and account to support
+#[derive(Clone, Copy)]
+struct Capabilities {
+    sftp: bool,
+    setenv: bool,
+    shell: bool,
+}
+
+impl TransferBackend {
+    const NAMES: [&'static str; 3] = ["auto", "sftp", "scp"];
+
+    fn parse(name: &str) -> PyResult<Self> {
+        match name {
+            "auto" => Ok(TransferBackend::Auto),
+            "sftp" => Ok(TransferBackend::Sftp),
+            "scp" => Ok(TransferBackend::Scp),
+            _ => Err(PyErr::new::<PyValueError, _>(format!(
+                "Unknown transfer backend {:?}, expected one of: {}",
+                name,
+                Self::NAMES.join(", ")
+            ))),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            TransferBackend::Auto => "auto",
+            TransferBackend::Sftp => "sftp",
+            TransferBackend::Scp => "scp",
+        }
+    }
+}
+
+// A connection's cached SFTP session, with the channel slot it holds and when it was last used
+type CachedSftp = (ssh2::Sftp, ChannelSlot, Instant);
+
+// The cached SFTP session, locked for as long as this is held
+struct SftpGuard<'a>(MutexGuard<'a, Option<CachedSftp>>);
+
+impl std::ops::Deref for SftpGuard<'_> {
+    type Target = ssh2::Sftp;
+
+    fn deref(&self) -> &ssh2::Sftp {
+        let (sftp, _, _) = self.0.as_ref().expect("the SFTP session is cached");
+        sftp
+    }
+}
+
+// Counts the channels a connection keeps open between calls: the cached SFTP channel, shells, batches and tailers.
+// With `max_channels` set, opening another channel waits until there's room for it.
+struct ChannelSlots {
+    max: Option<usize>,
+    open: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl ChannelSlots {
+    fn new(max: Option<usize>) -> Self {
+        ChannelSlots {
+            max,
+            open: Mutex::new(0),
+            freed: Condvar::new(),
+        }
+    }
+
+    fn full(&self) -> bool {
+        let open = self.open.lock().unwrap_or_else(PoisonError::into_inner);
+        self.max.is_some_and(|max| *open >= max)
+    }
+
+    // Count a channel that stays open until the returned slot is dropped
+    fn take(self: &Arc<Self>) -> ChannelSlot {
+        *self.open.lock().unwrap_or_else(PoisonError::into_inner) += 1;
+        ChannelSlot(Arc::clone(self))
+    }
+
+    // Wait, without the GIL, until there's room for a channel or the deadline passes.
+    // Returns whether there's room.
+    fn wait(&self, py: Python<'_>, deadline: Option<Instant>) -> bool {
+        py.allow_threads(|| {
+            let mut open = self.open.lock().unwrap_or_else(PoisonError::into_inner);
+            while self.max.is_some_and(|max| *open >= max) {
+                open = match deadline {
+                    None => self
+                        .freed
+                        .wait(open)
+                        .unwrap_or_else(PoisonError::into_inner),
+                    Some(deadline) => {
+                        let Some(left) = deadline.checked_duration_since(Instant::now()) else {
+                            return false;
+                        };
+                        self.freed
+                            .wait_timeout(open, left)
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .0
+                    }
+                };
+            }
+            true
+        })
+    }
+}
+
+// A channel counted in its connection's ChannelSlots, until this is dropped
+pub(crate) struct ChannelSlot(Arc<ChannelSlots>);
+
+impl Drop for ChannelSlot {
+    fn drop(&mut self) {
+        let mut open = self.0.open.lock().unwrap_or_else(PoisonError::into_inner);
+        *open = open.saturating_sub(1);
+        drop(open);
+        self.0.freed.notify_all();
+    }
+}
+
+// Open a TCP connection to the first reachable address for host, giving each address `timeout_ms`.
+// Addresses alternate between IPv6 and IPv4, starting with whichever family resolved first.
+pub(crate) fn connect_tcp(host: &str, port: i32, timeout_ms: u32) -> std::io::Result<TcpStream> {
+    let port = u16::try_from(port)
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid port"))?;
+    let (first, second): (Vec<SocketAddr>, Vec<SocketAddr>) = {
+        let addrs: Vec<SocketAddr> = (host, port).to_socket_addrs()?.collect();
+        let prefer_v6 = addrs.first().is_some_and(SocketAddr::is_ipv6);
+        addrs.into_iter().partition(|a| a.is_ipv6() == prefer_v6)
+    };
+    let mut ordered = Vec::with_capacity(first.len() + second.len());
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => break,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+    let timeout = Duration::from_millis(timeout_ms.into());
+    let mut last_err = std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("No addresses found for {}", host),
+    );
+    for addr in ordered {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = e,
+        }
+    }
+    Err(last_err)
+}
+
+// Connect to an SSH server and read its identification line, which starts with "SSH-",
+// skipping any other lines it sends first
+fn probe_ssh(host: &str, port: i32, timeout: Duration) -> std::io::Result<String> {
+    let timeout = timeout.max(Duration::from_millis(1));
+    let stream = connect_tcp(host, port, timeout.as_millis().min(u32::MAX as u128) as u32)?;
+    stream.set_read_timeout(Some(timeout))?;
+    let mut line = String::new();
+    let mut reader = BufReader::new(stream.take(8192));
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Connection closed before the SSH banner",
+            ));
+        }
+        if line.starts_with("SSH-") {
+            return Ok(line.trim_end().to_string());
+        }
+    }
+}
+
+/// Waits for an SSH server to accept connections and send its banner, without authenticating.
+/// It's tried every `interval` seconds until `timeout` seconds have passed, then `TimeoutError` is raised.
+/// Returns how many seconds it took.
+#[pyfunction]
+#[pyo3(signature = (host, port=22, timeout=300.0, interval=5.0))]
+pub(crate) fn wait_for_ssh(
+    py: Python<'_>,
+    host: &str,
+    port: i32,
+    timeout: f64,
+    interval: f64,
+) -> PyResult<f64> {
+    let seconds = |name: &str, value: f64| {
+        Duration::try_from_secs_f64(value)
+            .map_err(|_| PyErr::new::<PyValueError, _>(format!("Invalid {}: {}", name, value)))
+    };
+    let (limit, interval) = (seconds("timeout", timeout)?, seconds("interval", interval)?);
+    let start = Instant::now();
+    loop {
+        let remaining = limit.saturating_sub(start.elapsed());
+        let err = match py.allow_threads(|| probe_ssh(host, port, remaining)) {
+            Ok(banner) => {
+                let elapsed = start.elapsed().as_secs_f64();
+                log(py, "connection", INFO, || {
+                    format!(
+                        "SSH on {}:{} is ready ({}) after {:.3}s",
+                        host, port, banner, elapsed
+                    )
+                });
+                return Ok(elapsed);
+            }
+            Err(e) => e,
+        };
+        log(py, "connection", DEBUG, || {
+            format!("SSH on {}:{} isn't ready yet: {}", host, port, err)
+        });
+        let remaining = limit.saturating_sub(start.elapsed());
+        if remaining.is_zero() {
+            return Err(PyErr::new::<PyTimeoutError, _>(format!(
+                "SSH on {}:{} wasn't ready after {}s: {}",
+                host, port, timeout, err
+            )));
+        }
+        py.allow_threads(|| thread::sleep(interval.min(remaining)));
+        py.check_signals()?;
+    }
+}
+
+// Finish an SCP upload: send EOF, then wait for the remote side to acknowledge it and close
+fn finish_scp_send(channel: &mut Channel, remote_path: &str) -> PyResult<()> {
+    let scp_err = |op: &str, e: &dyn std::fmt::Display| {
+        PyErr::new::<PyIOError, _>(format!("SCP {} error for {}: {}", op, remote_path, e))
+    };
+    channel.flush().map_err(|e| scp_err("flush", &e))?;
+    channel.send_eof().map_err(|e| scp_err("send EOF", &e))?;
+    channel.wait_eof().map_err(|e| scp_err("wait EOF", &e))?;
+    channel.close().map_err(|e| scp_err("close", &e))?;
+    channel.wait_close().map_err(|e| scp_err("wait close", &e))
+}
+
+// Copy a local file to a remote one until the local file ends, returning how many bytes were copied
+fn copy_to_remote<R: Read, W: Write>(
+    local_file: &mut R,
+    remote_file: &mut W,
+    buffer_size: usize,
+) -> PyResult<u64> {
+    with_transfer_buffer(buffer_size, |read_buffer| {
+        let mut total: u64 = 0;
+        loop {
+            let bytes_read = local_file
+                .read(read_buffer)
+                .map_err(|e| PyErr::new::<PyIOError, _>(format!("File read error: {}", e)))?;
+            if bytes_read == 0 {
+                return Ok(total);
+            }
+            remote_file
+                .write_all(&read_buffer[..bytes_read])
+                .map_err(|e| {
+                    PyErr::new::<PyIOError, _>(format!("Remote file write error: {}", e))
+                })?;
+            total += bytes_read as u64;
+        }
+    })
+}
+
+// The auth methods Hussh can attempt, in the order its fallback chain tries them
+const AUTH_METHODS: [&str; 4] = ["publickey", "password", "keyboard-interactive", "agent"];
+
+// Answers every keyboard-interactive prompt with the password, keeping any instructions the server sends
+struct PasswordPrompt<'a> {
+    password: &'a str,
+    instructions: &'a mut String,
+}
+
+impl KeyboardInteractivePrompt for PasswordPrompt<'_> {
+    fn prompt<'b>(
+        &mut self,
+        _username: &str,
+        instructions: &str,
+        prompts: &[Prompt<'b>],
+    ) -> Vec<String> {
+        if !instructions.is_empty() {
+            if !self.instructions.is_empty() {
+                self.instructions.push('\n');
+            }
+            self.instructions.push_str(instructions);
+        }
+        prompts.iter().map(|_| self.password.to_string()).collect()
+    }
+}
+
+// Try each of the ssh-agent's identities in turn, where Session::userauth_agent only tries the first.
+// Security keys are signed through our own agent client, since libssh2 mangles their signatures.
+fn agent_auth(py: Python<'_>, session: &Session, username: &str) -> Result<(), String> {
+    let mut agent = session.agent().map_err(|e| e.to_string())?;
+    agent.connect().map_err(|e| e.to_string())?;
+    agent.list_identities().map_err(|e| e.to_string())?;
+    let identities = agent.identities().map_err(|e| e.to_string())?;
+    if identities.is_empty() {
+        return Err("no identities found in the ssh agent".to_string());
+    }
+    let mut failures = Vec::new();
+    for identity in &identities {
+        let key_type = agent::key_type(identity.blob());
+        log(py, "connection", DEBUG, || {
+            format!(
+                "Offering agent identity {} ({})",
+                identity.comment(),
+                key_type
+            )
+        });
+        let result = if agent::is_security_key(key_type) {
+            agent::userauth_security_key(session, username, identity.blob())
+        } else {
+            agent
+                .userauth(username, identity)
+                .map_err(|e| e.to_string())
+        };
+        match result {
+            Ok(()) => return Ok(()),
+            Err(e) => failures.push(format!("{} {}: {}", key_type, identity.comment(), e)),
+        }
+    }
+    Err(failures.join("; "))
+}
+
+// Whether we have the credentials an auth method needs
+fn can_attempt_auth(method: &str, password: &str, private_key: &str) -> bool {
+    match method {
+        "publickey" => !private_key.is_empty(),
+        "password" | "keyboard-interactive" => !password.is_empty(),
+        "agent" => true,
+        _ => false,
+    }
+}
+
+// Attempt a single auth method, which can_attempt_auth has said we have the credentials for
+fn attempt_auth(
+    py: Python<'_>,
+    session: &Session,
+    method: &str,
+    username: &str,
+    password: &str,
+    private_key: &str,
+    instructions: &mut String,
+) -> Result<(), String> {
+    match method {
+        "publickey" => {
+            // if a password is set, use it to decrypt the private key
+            let passphrase = (!password.is_empty()).then_some(password);
+            session
+                .userauth_pubkey_file(username, None, Path::new(&private_key), passphrase)
+                .map_err(|e| {
+                    // libssh2 can't load security keys from files, so point the user at the agent
+                    match std::fs::read_to_string(format!("{}.pub", private_key)) {
+                        Ok(public_key) if agent::is_security_key(&public_key) => format!(
+                            "{}; {} is a security key, add it to the ssh-agent with ssh-add instead",
+                            e, private_key
+                        ),
+                        _ => e.to_string(),
+                    }
+                })
+        }
+        "password" => session
+            .userauth_password(username, password)
+            .map_err(|e| e.to_string()),
+        "keyboard-interactive" => session
+            .userauth_keyboard_interactive(
+                username,
+                &mut PasswordPrompt {
+                    password,
+                    instructions,
+                },
+            )
+            .map_err(|e| e.to_string()),
+        "agent" => agent_auth(py, session, username),
+        _ => Err(format!("unsupported auth method {}", method)),
+    }
+}
+
+// The kernel's statistics for a session's TCP connection.
+// Fields newer than the running kernel are left at zero.
+#[cfg(target_os = "linux")]
+fn tcp_info(session: &Session) -> Option<libc::tcp_info> {
+    use std::os::unix::io::AsRawFd;
+    // SAFETY: tcp_info is plain data, which getsockopt fills in up to len
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    let rc = unsafe {
+        libc::getsockopt(
+            session.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut libc::tcp_info as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    (rc == 0).then_some(info)
+}
+
+// The names of the counts tcp_counts returns, as transport_stats reports them
+const TCP_COUNTS: [&str; 5] = [
+    "bytes_sent",
+    "bytes_received",
+    "packets_sent",
+    "packets_received",
+    "retransmits",
+];
+
+// The kernel's counts for a session's TCP connection, in the order of TCP_COUNTS
+#[cfg(target_os = "linux")]
+fn tcp_counts(session: &Session) -> Option<[u64; 5]> {
+    tcp_info(session).map(|info| {
+        [
+            info.tcpi_bytes_acked,
+            info.tcpi_bytes_received,
+            info.tcpi_segs_out.into(),
+            info.tcpi_segs_in.into(),
+            info.tcpi_total_retrans.into(),
+        ]
+    })
+}
+
+// Only Linux reports them
+#[cfg(not(target_os = "linux"))]
+fn tcp_counts(_session: &Session) -> Option<[u64; 5]> {
+    None
+}
+
+// The algorithm types traced during the handshake, with labels for the trace
+const TRACED_METHODS: [(MethodType, &str); 8] = [
+    (MethodType::Kex, "kex"),
+    (MethodType::HostKey, "host key"),
+    (MethodType::CryptCs, "cipher client->server"),
+    (MethodType::CryptSc, "cipher server->client"),
+    (MethodType::MacCs, "mac client->server"),
+    (MethodType::MacSc, "mac server->client"),
+    (MethodType::CompCs, "compression client->server"),
+    (MethodType::CompSc, "compression server->client"),
+];
+
+// Trace the algorithms we propose before the handshake, or those negotiated after it
+fn trace_methods(
+    py: Python<'_>,
+    tracer: &Tracer,
+    conn_str: &str,
+    session: &Session,
+    negotiated: bool,
+) {
+    for (method_type, label) in TRACED_METHODS {
+        let message = if negotiated {
+            format!(
+                "Negotiated {}: {}",
+                label,
+                session.methods(method_type).unwrap_or("none")
+            )
+        } else {
+            match session.supported_algs(method_type) {
+                Ok(algs) => format!("Proposing {}: {}", label, algs.join(",")),
+                Err(e) => format!("Unable to list {} proposals: {}", label, e),
+            }
+        };
+        tracer.trace(py, conn_str, message);
+    }
+}
+
+// Trace what the server told us during the handshake, and the auth methods it offers
+fn trace_handshake(
+    py: Python<'_>,
+    tracer: &Tracer,
+    conn_str: &str,
+    session: &Session,
+    username: &str,
+    offered: &Result<String, ssh2::Error>,
+) {
+    let banner = session.banner().unwrap_or("unknown");
+    tracer.trace(py, conn_str, format!("Server banner: {}", banner));
+    trace_methods(py, tracer, conn_str, session, true);
+    let message = match offered {
+        Ok(methods) => format!("Server offers auth methods for {}: {}", username, methods),
+        Err(e) => format!("Unable to query auth methods for {}: {}", username, e),
+    };
+    tracer.trace(py, conn_str, message);
+}
+
+// Finish leaving a context, given the result of its cleanup and the exception raised in the block, if any.
+// A cleanup error is raised only if the block succeeded and cleanup errors aren't suppressed.
+// Otherwise it's logged as a warning, so it never hides the exception the block raised.
+fn exit_context(
+    py: Python<'_>,
+    what: &str,
+    cleanup: PyResult<()>,
+    raised: Option<&Bound<'_, PyAny>>,
+    suppress: bool,
+) -> PyResult<()> {
+    let Err(e) = cleanup else {
+        return Ok(());
+    };
+    match raised {
+        None if !suppress => Err(e),
+        None => {
+            log(py, "connection", WARNING, || {
+                format!("Error closing {}: {}", what, e)
+            });
+            Ok(())
+        }
+        Some(raised) => {
+            let kind = raised
+                .get_type()
+                .name()
+                .map_or_else(|_| "an exception".to_string(), |name| name.to_string());
+            log(py, "connection", WARNING, || {
+                format!("Error closing {} while handling {}: {}", what, kind, e)
+            });
+            Ok(())
+        }
+    }
+}
+
+// Raise a CommandError for a failed result, unless the caller asked to be warned instead
+fn check_result(py: Python<'_>, result: SSHResult, warn: bool) -> PyResult<SSHResult> {
+    if warn || result.status == 0 {
+        return Ok(result);
+    }
+    let err = PyErr::new::<CommandError, _>(format!(
+        "Command {:?} exited with status {}\n{}",
+        result.command.as_deref().unwrap_or_default(),
+        result.status,
+        result.stderr
+    ));
+    err.value(py).setattr("result", result)?;
+    Err(err)
+}
+
+// libssh2 sessions aren't thread-safe, so every operation on a Connection holds its SessionLock.
+// The lock is reentrant, so a thread holding it through Connection.lock() can keep using the connection,
+// and waiting for it releases the GIL, so other threads can use other connections meanwhile.
+#[derive(Default)]
+pub(crate) struct SessionLock {
+    // the thread holding the lock, and how many times it has acquired it
+    owner: Mutex<(Option<ThreadId>, usize)>,
+    released: Condvar,
+}
+
+impl SessionLock {
+    pub(crate) fn acquire(self: &Arc<Self>, py: Python<'_>) -> SessionGuard {
+        let me = thread::current().id();
+        py.allow_threads(|| {
+            let mut owner = self.owner.lock().unwrap_or_else(PoisonError::into_inner);
+            while owner.0.is_some_and(|id| id != me) {
+                owner = self
+                    .released
+                    .wait(owner)
+                    .unwrap_or_else(PoisonError::into_inner);
+            }
+            owner.0 = Some(me);
+            owner.1 += 1;
+        });
+        SessionGuard(Arc::clone(self))
+    }
+
+    // Hold the lock while `op` runs with the GIL released, for I/O on a channel that outlives the call
+    // that opened it, like a shell's, so it takes turns with everything else on the session
+    pub(crate) fn hold<T: Send>(
+        self: &Arc<Self>,
+        py: Python<'_>,
+        op: impl FnOnce() -> T + Send,
+    ) -> T {
+        let _guard = self.acquire(py);
+        py.allow_threads(op)
+    }
+
+    fn release(&self) {
+        let mut owner = self.owner.lock().unwrap_or_else(PoisonError::into_inner);
+        owner.1 = owner.1.saturating_sub(1);
+        if owner.1 == 0 {
+            owner.0 = None;
+            self.released.notify_one();
+        }
+    }
+}
+
+// Releases a SessionLock when dropped
+pub(crate) struct SessionGuard(Arc<SessionLock>);
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+// Counters behind Connection.stats(). Relaxed atomics keep updates cheap, even from &self methods.
+#[derive(Default)]
+struct Stats {
+    commands: AtomicU64,
+    command_time_us: AtomicU64,
+    bytes_uploaded: AtomicU64,
+    bytes_downloaded: AtomicU64,
+    reconnects: AtomicU64,
+    // not a counter, so reset leaves it alone
+    connected_before: AtomicBool,
+}
+
+impl Stats {
+    fn reset(&self) {
+        self.commands.store(0, Ordering::Relaxed);
+        self.command_time_us.store(0, Ordering::Relaxed);
+        self.bytes_uploaded.store(0, Ordering::Relaxed);
+        self.bytes_downloaded.store(0, Ordering::Relaxed);
+        self.reconnects.store(0, Ordering::Relaxed);
+    }
+}
+
+// The objects a connection opens channels for, in the order closing the connection closes them
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum ChildKind {
+    Shell,
+    Batch,
+    Subsystem,
+    Tailer,
+}
+
+impl ChildKind {
+    fn name(self) -> &'static str {
+        match self {
+            ChildKind::Shell => "shell",
+            ChildKind::Batch => "command batch",
+            ChildKind::Subsystem => "subsystem channel",
+            ChildKind::Tailer => "tailer",
+        }
+    }
+}
+
+// Lets a connection close a child directly, since the child's own close would borrow the connection
+trait Release {
+    // Close the child's channel or SFTP session, ignoring errors
+    fn release(&mut self);
+}
+
+// Release a child, unless it's borrowed elsewhere
+fn release<T>(object: &Bound<'_, PyAny>) -> bool
+where
+    T: Release + pyo3::PyClass<Frozen = pyo3::pyclass::boolean_struct::False>,
+{
+    match object.downcast::<T>().map(|child| child.try_borrow_mut()) {
+        Ok(Ok(mut child)) => {
+            child.release();
+            true
+        }
+        _ => false,
+    }
+}
+
+// An object opened from a connection, which closing the connection closes first.
+// The connection only holds a weak reference to it, and shares its closed flag.
+struct Child {
+    kind: ChildKind,
+    object: Py<PyWeakrefReference>,
+    closed: Arc<AtomicBool>,
+}
+
+impl Child {
+    // The object, if it's still alive and open
+    fn live<'py>(&self, py: Python<'py>) -> Option<Bound<'py, PyAny>> {
+        if self.closed.load(Ordering::Relaxed) {
+            return None;
+        }
+        self.object.bind(py).upgrade()
+    }
+}
+
+#[pyclass]
+#[derive(Clone)]
+pub struct SSHResult {
+    #[pyo3(get)]
+    pub stdout: String,
+    #[pyo3(get)]
+    pub stderr: String,
+    #[pyo3(get)]
+    pub status: i32,
+    #[pyo3(get)]
+    pub command: Option<String>,
+    #[pyo3(get)]
+    pub truncated: bool,
+    #[pyo3(get)]
+    pub stdout_bytes: u64,
+    #[pyo3(get)]
+    pub stderr_bytes: u64,
+    // who the command ran as, for sudo and run_as
+    #[pyo3(get)]
+    pub user: Option<String>,
+    // how long each run of the command took, in seconds, for execute
+    #[pyo3(get)]
+    pub attempts: Option<Vec<f64>>,
+    // runs of output as (stream, text) in the order they arrived, for execute with combine_streams
+    #[pyo3(get)]
+    pub segments: Option<Segments>,
+}
+
+#[pymethods]
+impl SSHResult {
+    /// `True` if the command exited with a status of 0
+    #[getter]
+    fn ok(&self) -> bool {
+        self.status == 0
+    }
+
+    /// stdout and stderr together, in the order they arrived, for `execute` with `combine_streams`
+    #[getter]
+    fn combined(&self) -> Option<String> {
+        self.segments
+            .as_ref()
+            .map(|segments| segments.iter().map(|(_, text)| text.as_str()).collect())
+    }
+
+    // The __repl__ method for the SSHResult class
+    fn __repr__(&self) -> PyResult<String> {
+        Ok(format!(
+            "SSHResult(stdout={}, stderr={}, status={})",
+            self.stdout, self.stderr, self.status
+        ))
+    }
+
+    // The __str__ method for the SSHResult class
+    fn __str__(&self) -> PyResult<String> {
+        Ok(format!(
+            "stdout:\n{}\nstderr:\n{}\nstatus: {}",
+            self.stdout, self.stderr, self.status
+        ))
+    }
+}
+
+/// # Connection
+///
+/// `Connection` is a class that represents an SSH connection. It provides methods for executing commands, reading and writing files over SCP and SFTP, and creating an interactive shell.
+///
+/// ## Attributes
+///
+/// * `session`: The underlying SSH session.
+/// * `host`: The host to connect to.
+/// * `port`: The port to connect to.
+/// * `username`: The username to use for authentication.
+/// * `password`: The password to use for authentication. Reads as `***` while `hussh.redact_enabled()`.
+/// * `private_key`: The path to the private key to use for authentication. `~` and environment variables are expanded.
+/// * `timeout`: The timeout(ms) for the SSH session.
+/// * `connect_timeout`: The timeout(ms) for opening the TCP connection to each of the host's addresses.
+/// * `allowed_auth`: The auth methods that may be attempted, out of publickey, password, keyboard-interactive and agent.
+/// * `auth_attempts`: The (method, outcome) of each auth attempt made while connecting.
+/// * `default_file_mode`: The mode new remote files are created with when a write doesn't give one.
+/// * `transfer_backend`: How files are transferred: `auto` (SFTP, or SCP without it), `sftp` or `scp`.
+/// * `sftp_max_idle`: How many seconds the cached SFTP session may sit idle before it's replaced with a new one.
+/// * `keepalive_interval`: How many seconds a command may go quiet before a keepalive is sent, so idle connections aren't dropped.
+/// * `dead_peer_timeout`: How many seconds a command waits on a server that has stopped answering keepalives,
+///   before raising a `ConnectionLostError`. Needs `keepalive_interval`, and is only noticed on Linux.
+/// * `suppress_cleanup_errors`: Whether an error closing the connection, or a shell, tailer, batch or subsystem
+///   channel opened from it, on leaving its context is logged as a warning instead of raised.
+/// * `command_policy`: Limits which commands can be run, checked before each one is sent, as a callable that's
+///   given the command and returns whether it may run, or a regex or list of them that deny any command they match.
+///   A command it denies raises a `CommandPolicyError`, without a channel being opened.
+/// * `defaults`: A `hussh.Defaults` of timeouts, retries, encoding and buffer size, used when a call doesn't give them.
+/// * `closed`: Whether the connection has been closed.
+///
+/// Passing `debug=True` or a `trace_file` path also enables an annotated protocol trace,
+/// written to the file or the `hussh.trace` logger.
+///
+/// ## Methods
+///
+/// ### `connect`
+///
+/// Connects and authenticates. Only needed for connections created with `connect=False`, which otherwise connect on first use.
+/// Pickled and deep-copied connections are created this way too.
+///
+/// ### `wait_until_ready`
+///
+/// Waits for the host to accept SSH connections, as in `hussh.wait_for_ssh`.
+///
+/// ### `reboot`
+///
+/// Reboots the host, waits for it to come back, and reconnects, returning the downtime and whether it really rebooted.
+///
+/// ### `effective_defaults`
+///
+/// Returns a dict of the settings used when a call doesn't give them, after applying `defaults`.
+///
+/// ### `stats`
+///
+/// Returns a dict of counters: commands executed, cumulative command time, and bytes uploaded and downloaded.
+///
+/// ### `reset_stats`
+///
+/// Resets the counters returned by `stats`.
+///
+/// ### `lock`
+///
+/// Returns a context manager holding the connection's lock, so a group of operations isn't interleaved with other threads.
+///
+/// ### `add_hook`
+///
+/// Registers a callable for the `before_execute`, `after_execute`, `on_connect` or `on_close` event.
+///
+/// ### `execute`
+///
+/// Executes a command over the SSH connection and returns the result. It takes the following parameters:
+///
+/// * `command`: The command to execute, as a shell string or a list of arguments.
+///   Arguments in a list are quoted, so spaces, quotes and globs in them reach the command literally.
+/// * `max_output_bytes`: If set, output beyond this many bytes isn't read, and the result is marked `truncated`.
+/// * `stdout_file` / `stderr_file`: Local files to stream the output to, instead of keeping it in the result.
+///
+/// ### `run`
+///
+/// Runs a command in the style of Fabric, echoing its output as it arrives. It takes the following parameters:
+///
+/// * `command`: The command to run.
+/// * `hide`: If true, output isn't echoed to the local stdout/stderr.
+/// * `warn`: If true, a non-zero exit status doesn't raise a `CommandError`.
+///
+/// ### `remote_env`
+///
+/// Returns the remote environment commands run with as a dict, or with `login_shell`, a login shell's environment.
+///
+/// ### `sudo`
+///
+/// Runs a command with sudo. It takes the same parameters as `run`, plus:
+///
+/// * `password`: The sudo password. If not provided, the connection's password is used.
+/// * `user`: The user to run the command as. If not provided, root is used.
+///
+/// ### `run_as`
+///
+/// Runs a command as another user, through `sudo -u` or `su -`. It takes the same parameters as `run`, plus:
+///
+/// * `user`: The user to run the command as.
+/// * `via`: `sudo` (the default) or `su`.
+/// * `password`: The sudo password, as in `sudo`, or the password to give su's prompt, entered over a pty.
+///
+/// ### `put` / `get`
+///
+/// Copies a file to or from the remote system, using SFTP when available and SCP otherwise.
+///
+/// ### `scp_read`
+///
+/// Reads a file over SCP and returns the contents. It takes the following parameters:
+///
+/// * `remote_path`: The path to the file on the remote system.
+/// * `local_path`: The path to save the file on the local system. If not provided, the contents of the file are returned.
+///
+/// ### `scp_write`
+///
+/// Writes a file over SCP. It takes the following parameters:
+///
+/// * `local_path`: The path to the file on the local system.
+/// * `remote_path`: The path to save the file on the remote system.
+///
+/// ### `scp_write_data`
+///
+/// Writes data over SCP. It takes the following parameters:
+///
+/// * `data`: The data to write.
+/// * `remote_path`: The path to save the data on the remote system.
+///
+/// ### `sftp_read`
+///
+/// Reads a file over SFTP and returns the contents. It takes the following parameters:
+///
+/// * `remote_path`: The path to the file on the remote system.
+/// * `local_path`: The path to save the file on the local system. If not provided, the contents of the file are returned.
+///
+/// ### `sftp_write`
+///
+/// Writes a file over SFTP. It takes the following parameters:
+///
+/// * `local_path`: The path to the file on the local system.
+/// * `remote_path`: The path to save the file on the remote system.
+///
+/// ### `sftp_du`
+///
+/// Counts the files, directories and bytes in a remote directory tree over SFTP, without downloading anything.
+///
+/// ### `shell`
+///
+/// Creates an `InteractiveShell` instance. It takes the following parameter:
+///
+/// ### `open_subsystem`
+///
+/// Opens a channel to an SSH subsystem, like `netconf`, and returns a `SubsystemChannel` for exchanging raw bytes with it.
+///
+/// ### `remote_copy`
+///
+/// Copies a file from this connection to another connection. It takes the following parameters:
+///
+/// * `source_path`: The path to the file on the remote system.
+/// * `dest_conn`: The destination connection to copy the file to.
+/// * `dest_path`: The path to save the file on the destination system. If not provided, the source path is used.
+#[pyclass(module = "hussh")]
+pub struct Connection {
+    // replaced by reset_session, so methods only ever need a shared borrow of the connection
+    session: RwLock<Session>,
+    #[pyo3(get)]
+    host: String,
+    #[pyo3(get)]
+    port: i32,
+    #[pyo3(get)]
+    username: String,
+    password: String,
+    #[pyo3(get)]
+    private_key: String,
+    #[pyo3(get)]
+    timeout: u32,
+    #[pyo3(get)]
+    connect_timeout: u32,
+    // the cached SFTP session, with when it was last used
+    sftp_conn: Mutex<Option<CachedSftp>>,
+    sftp_max_idle: Option<Duration>,
+    #[pyo3(get)]
+    keepalive_interval: Option<u32>,
+    dead_peer_timeout: Option<Duration>,
+    #[pyo3(get)]
+    suppress_cleanup_errors: bool,
+    channels: Arc<ChannelSlots>,
+    debug: bool,
+    trace_file: Option<String>,
+    tracer: Option<Tracer>,
+    stats: Stats,
+    hooks: Mutex<HashMap<&'static str, Vec<PyObject>>>,
+    // checked before each command runs, and shared with the shells opened from the connection
+    command_policy: Option<Arc<CommandPolicy>>,
+    connected: AtomicBool,
+    pub(crate) lock: Arc<SessionLock>,
+    remote_home: Mutex<Option<String>>,
+    allowed_auth: Vec<String>,
+    auth_attempts: Mutex<Vec<(String, String)>>,
+    auth_instructions: Mutex<Option<String>>,
+    transfer_backend: TransferBackend,
+    default_file_mode: Option<u32>,
+    // the remote locale's charset, once detect_remote_locale has looked it up
+    remote_encoding: Mutex<Option<String>>,
+    // set once the server turns down an SFTP session, so auto transfers go straight to SCP
+    sftp_unavailable: AtomicBool,
+    // what the server supports, once capabilities() has probed it
+    capabilities: Mutex<Option<Capabilities>>,
+    // shells, batches, subsystem channels and tailers opened from the connection
+    children: Mutex<Vec<Child>>,
+    #[pyo3(get)]
+    defaults: Defaults,
+    pub(crate) closed: AtomicBool,
+}
+
+// Non-public methods for the Connection class
+impl Connection {
+    // Log the completion of a file transfer with its size and duration, and count it in the stats
+    fn record_transfer(
+        &self,
+        py: Python<'_>,
+        logger: &str,
+        upload: bool,
+        bytes: u64,
+        path: &str,
+        start: Instant,
+    ) {
+        let (action, counter) = if upload {
+            ("Wrote", &self.stats.bytes_uploaded)
+        } else {
+            ("Read", &self.stats.bytes_downloaded)
+        };
+        counter.fetch_add(bytes, Ordering::Relaxed);
+        log(py, logger, INFO, || {
+            format!(
+                "{} {} bytes of {} in {:.3}s",
+                action,
+                bytes,
+                path,
+                start.elapsed().as_secs_f64()
+            )
+        });
+    }
+
+    // Save a remote file locally, under its remote name in the current directory if `local` isn't given
+    fn get_file(
+        &self,
+        py: Python<'_>,
+        remote: RemotePath,
+        local: Option<LocalPath>,
+        buffer_size: Option<usize>,
+    ) -> PyResult<Transfer> {
+        let _guard = self.channel_guard(py, true)?;
+        let remote = self.expand_remote_path(remote.0)?;
+        let local = match local {
+            Some(local) => local,
+            None => LocalPath(
+                Path::new(&remote)
+                    .file_name()
+                    .ok_or_else(|| {
+                        PyErr::new::<PyIOError, _>(format!(
+                            "No file name in remote path: {}",
+                            remote
+                        ))
+                    })?
+                    .to_string_lossy()
+                    .into_owned(),
+            ),
+        };
+        let (_, transfer) = self.sftp_read_file(
+            py,
+            RemotePath(remote),
+            Some(local),
+            buffer_size,
+            false,
+            None,
+        )?;
+        Ok(transfer)
+    }
+
+    // Read a file over SFTP, or SCP as `transfer_backend` directs, returning what sftp_read does
+    // and the transfer that was made
+    fn sftp_read_file<'py>(
+        &self,
+        py: Python<'py>,
+        remote_path: RemotePath,
+        local_path: Option<LocalPath>,
+        buffer_size: Option<usize>,
+        binary: bool,
+        max_bytes: Option<u64>,
+    ) -> PyResult<(Bound<'py, PyAny>, Transfer)> {
+        if self.use_scp(py)? {
+            let (contents, bytes) =
+                self.scp_read_file(py, remote_path, local_path, buffer_size, binary, max_bytes)?;
+            return Ok((
+                contents,
+                Transfer {
+                    backend: "scp",
+                    bytes,
+                },
+            ));
+        }
+        let buffer_size = self.buffer_size(buffer_size)?;
+        let local_path = local_path.as_deref().map(expand_local_path).transpose()?;
+        let _guard = self.channel_guard(py, true)?;
+        let remote_path = self.expand_remote_path(remote_path.0)?;
+        log(py, "sftp", DEBUG, || {
+            format!("Reading {} from {}", remote_path, self.host)
+        });
+        let start = Instant::now();
+        let mut remote_file = self
+            .with_sftp(|sftp| sftp.open(Path::new(&remote_path)))?
+            .map_err(|e| PyErr::new::<PyIOError, _>(format!("SFTP open error: {}", e)))?;
+        match local_path {
+            Some(local_path) => {
+                let mut local_file = std::fs::File::create(&local_path)
+                    .map_err(|e| PyErr::new::<PyIOError, _>(format!("File create error: {}", e)))?;
+                let total = py.allow_threads(|| {
+                    with_transfer_buffer(buffer_size, |buffer| {
+                        let mut total: u64 = 0;
+                        loop {
+                            let len = remote_file.read(buffer).map_err(|e| {
+                                PyErr::new::<PyIOError, _>(format!("File read error: {}", e))
+                            })?;
+                            if len == 0 {
+                                return Ok::<_, PyErr>(total);
+                            }
+                            local_file.write_all(&buffer[..len]).map_err(|e| {
+                                PyErr::new::<PyIOError, _>(format!("File write error: {}", e))
+                            })?;
+                            total += len as u64;
+                        }
+                    })
+                })?;
+                self.record_transfer(py, "sftp", false, total, &remote_path, start);
+                let transfer = Transfer {
+                    backend: "sftp",
+                    bytes: total,
+                };
+                Ok((PyString::new(py, "Ok").into_any(), transfer))
+            }
+            None => {
+                let size = remote_file.stat().ok().and_then(|stat| stat.size);
+                let (contents, len) =
+                    read_contents(py, &mut remote_file, &remote_path, size, binary, max_bytes)?;
+                self.record_transfer(py, "sftp", false, len, &remote_path, start);
+                let transfer = Transfer {
+                    backend: "sftp",
+                    bytes: len,
+                };
+                Ok((contents, transfer))
+            }
+        }
+    }
+
+    // Write a file over SFTP, or SCP as `transfer_backend` directs, returning the transfer that was made
+    fn sftp_write_file(
+        &self,
+        py: Python<'_>,
+        local_path: LocalPath,
+        remote_path: Option<RemotePath>,
+        buffer_size: Option<usize>,
+        mode: Option<u32>,
+    ) -> PyResult<Transfer> {
+        if self.use_scp(py)? {
+            let remote_path = match remote_path {
+                Some(remote_path) => remote_path,
+                None => RemotePath(default_remote_path(&expand_local_path(&local_path)?)),
+            };
+            let bytes = self.scp_write_file(py, local_path, remote_path, buffer_size, mode)?;
+            return Ok(Transfer {
+                backend: "scp",
+                bytes,
+            });
+        }
+        let mode = self.file_mode(mode)?;
+        let buffer_size = self.buffer_size(buffer_size)?;
+        let local_path = expand_local_path(&local_path)?;
+        let _guard = self.channel_guard(py, true)?;
+        let mut local_file = std::fs::File::open(&local_path)
+            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Local file open error: {}", e)))?;
+        let remote_path = self.expand_remote_path(
+            remote_path.map_or_else(|| default_remote_path(&local_path), |p| p.0),
+        )?;
+        log(py, "sftp", DEBUG, || {
+            format!("Writing {} to {} on {}", local_path, remote_path, self.host)
+        });
+        let start = Instant::now();
+        let mut remote_file = self.sftp_create(&remote_path, mode)?;
+        // the local file may be a pipe or still growing, so don't trust its size and just copy until EOF
+        let total = py.allow_threads(|| {
+            let total = copy_to_remote(&mut local_file, &mut remote_file, buffer_size)?;
+            remote_file.close().map_err(|e| {
+                PyErr::new::<PyIOError, _>(format!("Close error for {}: {}", remote_path, e))
+            })?;
+            Ok::<_, PyErr>(total)
+        })?;
+        self.record_transfer(py, "sftp", true, total, &remote_path, start);
+        Ok(Transfer {
+            backend: "sftp",
+            bytes: total,
+        })
+    }
+
+    // Read a file over SCP, returning what scp_read does and how many bytes were read
+    fn scp_read_file<'py>(
+        &self,
+        py: Python<'py>,
+        remote_path: RemotePath,
+        local_path: Option<LocalPath>,
+        buffer_size: Option<usize>,
+        binary: bool,
+        max_bytes: Option<u64>,
+    ) -> PyResult<(Bound<'py, PyAny>, u64)> {
+        let buffer_size = self.buffer_size(buffer_size)?;
+        let local_path = local_path.as_deref().map(expand_local_path).transpose()?;
+        let _guard = self.channel_guard(py, false)?;
+        let remote_path = self.expand_remote_path(remote_path.0)?;
+        log(py, "scp", DEBUG, || {
+            format!("Reading {} from {}", remote_path, self.host)
+        });
+        let start = Instant::now();
+        let (mut remote_file, stat) = self
+            .open_session()?
+            .scp_recv(Path::new(&remote_path))
+            .map_err(|e| {
+                channel_open_error(&e).unwrap_or_else(|| {
+                    PyErr::new::<PyIOError, _>(format!("Failed scp_recv: {}", e))
+                })
+            })?;
+        match local_path {
+            Some(local_path) => {
+                let mut local_file = std::fs::File::create(&local_path)
+                    .map_err(|e| PyErr::new::<PyIOError, _>(format!("File create error: {}", e)))?;
+                // a fixed-size buffer, so empty files don't get a zero-length one
+                let total = py.allow_threads(|| {
+                    with_transfer_buffer(buffer_size, |buffer| {
+                        let mut total: u64 = 0;
+                        loop {
+                            let len = remote_file.read(buffer).map_err(|e| {
+                                PyErr::new::<PyIOError, _>(format!("Read error: {}", e))
+                            })?;
+                            if len == 0 {
+                                return Ok::<_, PyErr>(total);
+                            }
+                            local_file.write_all(&buffer[..len]).map_err(|e| {
+                                PyErr::new::<PyIOError, _>(format!("Write error: {}", e))
+                            })?;
+                            total += len as u64;
+                        }
+                    })
+                })?;
+                if total != stat.size() {
+                    return Err(PyErr::new::<PyIOError, _>(format!(
+                        "Read {} of {} bytes from {}",
+                        total,
+                        stat.size(),
+                        remote_path
+                    )));
+                }
+                self.record_transfer(py, "scp", false, total, &remote_path, start);
+                Ok((PyString::new(py, "Ok").into_any(), total))
+            }
+            None => {
+                let (contents, len) = read_contents(
+                    py,
+                    &mut remote_file,
+                    &remote_path,
+                    Some(stat.size()),
+                    binary,
+                    max_bytes,
+                )?;
+                self.record_transfer(py, "scp", false, len, &remote_path, start);
+                Ok((contents, len))
+            }
+        }
+    }
+
+    // Write a file over SCP, returning how many bytes were written
+    fn scp_write_file(
+        &self,
+        py: Python<'_>,
+        local_path: LocalPath,
+        remote_path: RemotePath,
+        buffer_size: Option<usize>,
+        mode: Option<u32>,
+    ) -> PyResult<u64> {
+        let buffer_size = self.buffer_size(buffer_size)?;
+        let mode = self.file_mode(mode)?.unwrap_or(DEFAULT_FILE_MODE);
+        let local_path = expand_local_path(&local_path)?;
+        let _guard = self.channel_guard(py, false)?;
+        let remote_path = self.expand_remote_path(remote_path.0)?;
+        // if remote_path is a directory, append the local file name to the remote path
+        let remote_path = if remote_path.ends_with('/') || self.remote_is_dir(py, &remote_path)? {
+            let name = local_file_name(&local_path).ok_or_else(|| {
+                PyErr::new::<PyIOError, _>(format!("Local path {} has no file name", local_path))
+            })?;
+            format!("{}/{}", remote_path.trim_end_matches('/'), name)
+        } else {
+            remote_path
+        };
+        log(py, "scp", DEBUG, || {
+            format!("Writing {} to {} on {}", local_path, remote_path, self.host)
+        });
+        let start = Instant::now();
+        let local_file = std::fs::File::open(&local_path)
+            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Local file open error: {}", e)))?;
+        let metadata = local_file.metadata().map_err(|e| {
+            PyErr::new::<PyIOError, _>(format!(
+                "Local file metadata error for {}: {}",
+                local_path, e
+            ))
+        })?;
+        if !metadata.is_file() {
+            return Err(PyErr::new::<PyIOError, _>(format!(
+                "scp_write needs the size of {} up front, which pipes and devices don't have. Use sftp_write instead.",
+                local_path
+            )));
+        }
+        // only send the size we announced, even if the file grows while we're copying it
+        let mut local_file = local_file.take(metadata.len());
+        let mut remote_file = self
+            .open_session()?
+            .scp_send(Path::new(&remote_path), mode, metadata.len(), None)
+            .map_err(|e| {
+                channel_open_error(&e)
+                    .unwrap_or_else(|| PyErr::new::<PyIOError, _>(format!("scp_send error: {}", e)))
+            })?;
+        let total =
+            py.allow_threads(|| copy_to_remote(&mut local_file, &mut remote_file, buffer_size))?;
+        // the remote side waits for every byte we announced, so a file that shrank can't be finished
+        if total != metadata.len() {
+            return Err(PyErr::new::<PyIOError, _>(format!(
+                "{} shrank from {} to {} bytes while being copied",
+                local_path,
+                metadata.len(),
+                total
+            )));
+        }
+        py.allow_threads(|| finish_scp_send(&mut remote_file, &remote_path))?;
+        self.record_transfer(py, "scp", true, total, &remote_path, start);
+        Ok(total)
+    }
+
+    // Track an object opened from the connection, so it's closed along with the connection
+    fn add_child(
+        &self,
+        kind: ChildKind,
+        object: &Bound<'_, PyAny>,
+        closed: &Arc<AtomicBool>,
+    ) -> PyResult<()> {
+        let py = object.py();
+        let object = PyWeakrefReference::new(object)?.unbind();
+        let mut children = self.children.lock().unwrap_or_else(PoisonError::into_inner);
+        children.retain(|child| child.live(py).is_some());
+        children.push(Child {
+            kind,
+            object,
+            closed: Arc::clone(closed),
+        });
+        Ok(())
+    }
+
+    // Close every child still open, shells first and tailers last, marking each one closed.
+    // A child that's in use on another thread can't be released here, but is still marked closed.
+    fn close_children(&self, py: Python<'_>) {
+        let mut children =
+            std::mem::take(&mut *self.children.lock().unwrap_or_else(PoisonError::into_inner));
+        children.sort_by_key(|child| child.kind);
+        for child in children {
+            let Some(object) = child.live(py) else {
+                continue;
+            };
+            child.closed.store(true, Ordering::Relaxed);
+            let released = match child.kind {
+                ChildKind::Shell => release::<InteractiveShell>(&object),
+                ChildKind::Batch => release::<CommandBatch>(&object),
+                ChildKind::Subsystem => release::<SubsystemChannel>(&object),
+                ChildKind::Tailer => release::<FileTailer>(&object),
+            };
+            let action = if released { "Closed" } else { "Marked closed" };
+            self.trace(|| {
+                format!(
+                    "{} a {} along with the connection",
+                    action,
+                    child.kind.name()
+                )
+            });
+        }
+    }
+
+    // Track a tailer returned by tail, which may override the connection's suppress_cleanup_errors
+    fn add_tailer<'py>(
+        &self,
+        py: Python<'py>,
+        mut tailer: FileTailer,
+        suppress_cleanup_errors: Option<bool>,
+    ) -> PyResult<Bound<'py, FileTailer>> {
+        if let Some(suppress) = suppress_cleanup_errors {
+            tailer.suppress_cleanup_errors = suppress;
+        }
+        let closed = Arc::clone(&tailer.closed);
+        let tailer = Bound::new(py, tailer)?;
+        self.add_child(ChildKind::Tailer, tailer.as_any(), &closed)?;
+        Ok(tailer)
+    }
+
+    // Call every hook registered for `event`, stopping at the first one that raises
+    fn call_hooks(&self, py: Python<'_>, event: &str, args: Bound<'_, PyTuple>) -> PyResult<()> {
+        for hook in self.hooks_for(py, event) {
+            hook.bind(py).call1(args.clone())?;
+        }
+        Ok(())
+    }
+
+    // The hooks registered for `event`, copied out so a hook can register more while they run
+    fn hooks_for(&self, py: Python<'_>, event: &str) -> Vec<PyObject> {
+        self.hooks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(event)
+            .into_iter()
+            .flatten()
+            .map(|hook| hook.clone_ref(py))
+            .collect()
+    }
+
+    // Call every hook registered for `event`, then raise a HookError if any of them failed.
+    // `result` is attached to the error, so a failing hook never hides what the operation returned.
+    fn call_after_hooks(
+        &self,
+        py: Python<'_>,
+        event: &str,
+        args: Bound<'_, PyTuple>,
+        result: Option<&SSHResult>,
+    ) -> PyResult<()> {
+        let errors: Vec<PyErr> = self
+            .hooks_for(py, event)
+            .iter()
+            .filter_map(|hook| hook.bind(py).call1(args.clone()).err())
+            .collect();
+        if errors.is_empty() {
+            return Ok(());
+        }
+        let err = PyErr::new::<HookError, _>(format!(
+            "{} {} hook(s) raised: {}",
+            errors.len(),
+            event,
+            errors
+                .iter()
+                .map(|e| e.to_string())
+                .collect::<Vec<_>>()
+                .join("; ")
+        ));
+        err.value(py).setattr("result", result.cloned())?;
+        let errors: Vec<Bound<'_, PyAny>> = errors
+            .into_iter()
+            .map(|e| e.into_value(py).into_bound(py).into_any())
+            .collect();
+        err.value(py).setattr("errors", errors)?;
+        Err(err)
+    }
+
+    // Write a line to the protocol trace, if tracing is enabled
+    fn trace<F: FnOnce() -> String>(&self, message: F) {
+        if let Some(tracer) = &self.tracer {
+            let conn_str = format!("{}:{}", self.host, self.port);
+            Python::with_gil(|py| tracer.trace(py, &conn_str, message()));
+        }
+    }
+
+    // The session, connecting first if needed, or a clear error once the connection has been closed
+    fn open_session(&self) -> PyResult<Session> {
+        if self.closed.load(Ordering::Relaxed) {
+            return Err(PyErr::new::<PyIOError, _>(format!(
+                "Connection to {}:{} is closed",
+                self.host, self.port
+            )));
+        }
+        if !self.connected.load(Ordering::Relaxed) {
+            Python::with_gil(|py| self.establish(py))?;
+        }
+        Ok(self.session())
+    }
+
+    // Expand a leading `~` in a remote path to the remote user's home directory.
+    // Other paths, absolute or relative, are passed through untouched.
+    fn expand_remote_path(&self, path: String) -> PyResult<String> {
+        let rest = match path.strip_prefix('~') {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => rest,
+            Some(_) => {
+                return Err(PyErr::new::<PyValueError, _>(format!(
+                    "Expanding another user's home directory isn't supported: {}",
+                    path
+                )))
+            }
+            None => return Ok(path),
+        };
+        let home = self.remote_home()?;
+        if rest.is_empty() {
+            return Ok(home);
+        }
+        Ok(format!("{}{}", home.trim_end_matches('/'), rest))
+    }
+
+    // Whether a remote path is an existing directory, checked over SFTP or with `test -d` without it.
+    // A path that doesn't exist isn't a directory, but any other error is raised.
+    // The caller holds a channel guard, and still has room for a channel afterwards.
+    fn remote_is_dir(&self, py: Python<'_>, path: &str) -> PyResult<bool> {
+        if !self.sftp_unavailable.load(Ordering::Relaxed) {
+            let stat = self.with_sftp(|sftp| sftp.stat(Path::new(path)));
+            // the SFTP session may have taken the room the caller's channel needs
+            self.make_room();
+            match stat {
+                Ok(Ok(stat)) => return Ok(stat.is_dir()),
+                Ok(Err(e)) if e.code() == ssh2::ErrorCode::SFTP(SSH_FX_NO_SUCH_FILE) => {
+                    return Ok(false)
+                }
+                Ok(Err(e)) => {
+                    return Err(PyErr::new::<PyIOError, _>(format!(
+                        "SFTP stat error for {}: {}",
+                        path, e
+                    )))
+                }
+                Err(e) if !e.is_instance_of::<SFTPUnavailableError>(py) => return Err(e),
+                Err(_) => {}
+            }
+        }
+        let mut channel = self.open_session()?.channel_session().map_err(|e| {
+            channel_open_error(&e).unwrap_or_else(|| {
+                PyErr::new::<PyIOError, _>(format!("Channel session error: {}", e))
+            })
+        })?;
+        channel
+            .exec(&format!("test -d {}", shell_quote(path)))
+            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Exec error: {}", e)))?;
+        Ok(read_from_channel(&mut channel)?.status == 0)
+    }
+
+    // The remote user's home directory, learned once from where SFTP sessions start,
+    // or from $HOME on servers without SFTP
+    fn remote_home(&self) -> PyResult<String> {
+        if let Some(home) = self
+            .remote_home
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()
+        {
+            return Ok(home.clone());
+        }
+        let home = self.with_sftp(|sftp| sftp.realpath(Path::new(".")));
+        // the SFTP session may have taken the room the caller's channel, or the one below, needs
+        self.make_room();
+        let home = match home {
+            Ok(Ok(home)) => home.to_string_lossy().into_owned(),
+            _ => {
+                let mut channel = self.open_session()?.channel_session().map_err(|e| {
+                    PyErr::new::<PyIOError, _>(format!("Channel session error: {}", e))
+                })?;
+                channel
+                    .exec("printf %s \"$HOME\"")
+                    .map_err(|e| PyErr::new::<PyIOError, _>(format!("Exec error: {}", e)))?;
+                read_from_channel(&mut channel)?.stdout
+            }
+        };
+        if home.is_empty() {
+            return Err(PyErr::new::<PyIOError, _>(
+                "Unable to find the remote home directory",
+            ));
+        }
+        *self
+            .remote_home
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(home.clone());
+        Ok(home)
+    }
+
+    // Work through the allowed auth methods that the server offers, and that we have credentials for,
+    // until one succeeds. Each attempt is recorded in auth_attempts as (method, outcome).
+    fn authenticate(
+        &self,
+        py: Python<'_>,
+        session: &Session,
+        conn_str: &str,
+        offered: Option<String>,
+    ) -> PyResult<()> {
+        let username = self.username.as_str();
+        // the attempts are gathered here, so auth_attempts isn't locked while a credential prompt waits
+        let mut attempts = Vec::new();
+        let succeeded = self.try_auth_methods(py, session, offered.as_deref(), &mut attempts);
+        *self
+            .auth_attempts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = attempts.clone();
+        if succeeded? {
+            return Ok(());
+        }
+        let message = if attempts.is_empty() {
+            format!(
+                "No allowed auth method could be attempted (allowed: {}; offered by server: {})",
+                self.allowed_auth.join(", "),
+                offered.as_deref().unwrap_or("unknown")
+            )
+        } else {
+            let tried: Vec<String> = attempts
+                .iter()
+                .map(|(method, outcome)| format!("{} ({})", method, outcome))
+                .collect();
+            format!("Authentication failed; tried {}", tried.join(", "))
+        };
+        log(py, "connection", WARNING, || {
+            format!(
+                "Authentication as {} to {} failed: {}",
+                username, conn_str, message
+            )
+        });
+        Err(PyErr::new::<AuthenticationError, _>(message))
+    }
+
+    // Try each allowed and offered auth method in turn, recording each attempt as (method, outcome).
+    // Returns whether one succeeded.
+    fn try_auth_methods(
+        &self,
+        py: Python<'_>,
+        session: &Session,
+        offered: Option<&str>,
+        attempts: &mut Vec<(String, String)>,
+    ) -> PyResult<bool> {
+        let username = self.username.as_str();
+        let mut instructions = String::new();
+        if session.authenticated() {
+            attempts.push(("none".to_string(), "success".to_string()));
+            return Ok(true);
+        }
+        for method in AUTH_METHODS {
+            if !self.allowed_auth.iter().any(|m| m == method) {
+                continue;
+            }
+            // the agent authenticates with public keys, so it's offered as "publickey"
+            let offered_as = if method == "agent" {
+                "publickey"
+            } else {
+                method
+            };
+            if let Some(offered) = offered {
+                if !offered.split(',').any(|m| m == offered_as) {
+                    continue;
+                }
+            }
+            // a password or passphrase we weren't given can be asked for with set_credential_prompt
+            let request = prompt::needed(method, &self.password, &self.private_key).map(|kind| {
+                prompt::CredentialRequest {
+                    kind,
+                    host: &self.host,
+                    port: self.port,
+                    username,
+                    private_key: &self.private_key,
+                }
+            });
+            for attempt in 1..=prompt::MAX_PROMPT_ATTEMPTS {
+                let secret = match &request {
+                    Some(request) => match prompt::ask(py, request, attempt)? {
+                        Some(secret) => Some(secret),
+                        None => break,
+                    },
+                    None => None,
+                };
+                let password = secret.as_deref().unwrap_or(&self.password);
+                if !can_attempt_auth(method, password, &self.private_key) {
+                    break;
+                }
+                log(py, "connection", DEBUG, || {
+                    format!("Authenticating as {} using {}", username, method)
+                });
+                self.trace(|| format!("Attempting {} auth", method));
+                let result = attempt_auth(
+                    py,
+                    session,
+                    method,
+                    username,
+                    password,
+                    &self.private_key,
+                    &mut instructions,
+                );
+                if !instructions.is_empty() {
+                    *self
+                        .auth_instructions
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner) = Some(instructions.clone());
+                }
+                match result {
+                    Ok(()) => {
+                        if let Some(request) = &request {
+                            prompt::remember(request, password);
+                        }
+                        self.trace(|| format!("{} auth succeeded", method));
+                        attempts.push((method.to_string(), "success".to_string()));
+                        return Ok(true);
+                    }
+                    Err(e) => {
+                        // keyboard-interactive errors can quote the responses we gave
+                        let e = redact(&e, &[password]);
+                        self.trace(|| format!("{} auth failed: {}", method, e));
+                        attempts.push((method.to_string(), format!("failed: {}", e)));
+                        match &request {
+                            Some(request) => prompt::forget(request),
+                            None => break,
+                        }
+                    }
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    // Open the TCP connection, then handshake and authenticate the session
+    fn establish(&self, py: Python<'_>) -> PyResult<()> {
+        // a new session may not share the old one's locale
+        *self
+            .remote_encoding
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = None;
+        // combine the host and port into a single string
+        let conn_str = format!("{}:{}", self.host, self.port);
+        let tracer = self.tracer.as_ref();
+        log(py, "connection", INFO, || {
+            format!("Connecting to {}", conn_str)
+        });
+        let tcp_conn = py
+            .allow_threads(|| connect_tcp(&self.host, self.port, self.connect_timeout))
+            .map_err(|e| {
+                log(py, "connection", WARNING, || {
+                    format!("Failed to connect to {}: {}", conn_str, e)
+                });
+                PyErr::new::<PyTimeoutError, _>(format!("{}", e))
+            })?;
+        // ssh2 sessions share their state between clones, so this connects self.session
+        let mut session = self.session();
+        session.set_tcp_stream(tcp_conn);
+        if let Some(tracer) = tracer {
+            tracer.trace(py, &conn_str, "TCP connection established".to_string());
+            trace_methods(py, tracer, &conn_str, &session, false);
+        }
+        py.allow_threads(|| session.handshake()).map_err(|e| {
+            log(py, "connection", WARNING, || {
+                format!("Handshake with {} failed: {}", conn_str, e)
+            });
+            if let Some(tracer) = tracer {
+                tracer.trace(py, &conn_str, format!("Handshake failed: {}", e));
+            }
+            PyErr::new::<PyTimeoutError, _>(format!("{}", e))
+        })?;
+        let username = self.username.as_str();
+        // querying the offered methods sends a "none" auth request, which a server may accept outright
+        let offered = session.auth_methods(username).map(String::from);
+        if let Some(tracer) = tracer {
+            trace_handshake(py, tracer, &conn_str, &session, username, &offered);
+        }
+        self.authenticate(py, &session, &conn_str, offered.ok())?;
+        log(py, "connection", INFO, || {
+            format!("Connected to {} as {}", conn_str, username)
+        });
+        self.connected.store(true, Ordering::Relaxed);
+        if self.stats.connected_before.swap(true, Ordering::Relaxed) {
+            self.stats.reconnects.fetch_add(1, Ordering::Relaxed);
+        }
+        self.call_hooks(py, "on_connect", (&self.host,).into_pyobject(py)?)
+    }
+
+    // Emulate a python-like sftp property. The SFTP session stays locked until the returned guard is dropped.
+    fn sftp(&self) -> PyResult<SftpGuard<'_>> {
+        let session = self.open_session()?;
+        let mut cache = self
+            .sftp_conn
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let cached = cache.take();
+        // a session idle for longer than sftp_max_idle may have been reaped by the server, so replace it
+        let cached = cached.filter(|(_, _, used)| {
+            let idle = used.elapsed();
+            let fresh = self.sftp_max_idle.is_none_or(|max| idle <= max);
+            if !fresh {
+                self.trace(|| {
+                    format!(
+                        "Closed sftp subsystem channel idle for {:.1}s",
+                        idle.as_secs_f64()
+                    )
+                });
+            }
+            fresh
+        });
+        let (sftp, slot) = match cached {
+            Some((sftp, slot, _)) => (sftp, slot),
+            None => {
+                let slot = self.channels.take();
+                let sftp = session.sftp().map_err(|e| {
+                    self.trace(|| format!("Failed to open sftp subsystem channel: {}", e));
+                    let err = sftp_open_error(&e);
+                    Python::with_gil(|py| {
+                        if err.is_instance_of::<SFTPUnavailableError>(py) {
+                            self.sftp_unavailable.store(true, Ordering::Relaxed);
+                        }
+                    });
+                    err
+                })?;
+                self.trace(|| "Opened sftp subsystem channel".to_string());
+                (sftp, slot)
+            }
+        };
+        *cache = Some((sftp, slot, Instant::now()));
+        Ok(SftpGuard(cache))
+    }
+
+    // Run `op` with the SFTP session, releasing the GIL while it waits on the server. If a cached session
+    // turns out to have lost its channel, it's reopened and `op` is tried once more, before any error
+    // is returned for the caller to report.
+    fn with_sftp<T: Send>(
+        &self,
+        mut op: impl FnMut(&ssh2::Sftp) -> Result<T, ssh2::Error> + Send,
+    ) -> PyResult<Result<T, ssh2::Error>> {
+        let cached = self
+            .sftp_conn
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_some();
+        let mut op = |sftp: &ssh2::Sftp| Python::with_gil(|py| py.allow_threads(|| op(sftp)));
+        let result = op(&*self.sftp()?);
+        match result {
+            Err(e) if cached && sftp_channel_lost(&e) => {
+                self.trace(|| format!("Cached sftp subsystem channel was lost: {}", e));
+                Python::with_gil(|py| {
+                    log(py, "sftp", DEBUG, || {
+                        format!("Reopening the lost SFTP session on {}: {}", self.host, e)
+                    })
+                });
+                *self
+                    .sftp_conn
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner) = None;
+                Ok(op(&*self.sftp()?))
+            }
+            result => Ok(result),
+        }
+    }
+
+    // The mode to create a remote file with, if one was asked for by the call or the connection
+    fn file_mode(&self, mode: Option<u32>) -> PyResult<Option<i32>> {
+        mode.or(self.default_file_mode)
+            .map(check_file_mode)
+            .transpose()
+    }
+
+    // Create (or truncate) a remote file over SFTP. With a `mode`, the file is created with it,
+    // and an existing file is set to it, before anything is written.
+    fn sftp_create(&self, remote_path: &str, mode: Option<i32>) -> PyResult<ssh2::File> {
+        let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE;
+        let mut remote_file = self
+            .with_sftp(|sftp| {
+                sftp.open_mode(
+                    Path::new(remote_path),
+                    flags,
+                    mode.unwrap_or(DEFAULT_FILE_MODE),
+                    OpenType::File,
+                )
+            })?
+            .map_err(|e| {
+                PyErr::new::<PyIOError, _>(format!("Remote file creation error: {}", e))
+            })?;
+        if let Some(mode) = mode {
+            remote_file
+                .setstat(FileStat {
+                    size: None,
+                    uid: None,
+                    gid: None,
+                    perm: Some(mode as u32),
+                    atime: None,
+                    mtime: None,
+                })
+                .map_err(|e| {
+                    PyErr::new::<PyIOError, _>(format!(
+                        "Error setting the mode of {}: {}",
+                        remote_path, e
+                    ))
+                })?;
+        }
+        Ok(remote_file)
+    }
+
+    // How to decode command output: as `encoding` says, or else as the detected remote locale,
+    // the connection's default encoding, or UTF-8
+    fn output_encodings(&self, encoding: Option<OutputEncoding>) -> PyResult<[Option<String>; 2]> {
+        let default = self
+            .remote_encoding
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+            .or_else(|| self.defaults.encoding.clone());
+        let [stdout, stderr] = match encoding {
+            Some(encoding) => encoding.into_streams()?,
+            None => [None, None],
+        };
+        Ok([stdout.or_else(|| default.clone()), stderr.or(default)])
+    }
+
+    // Whether sftp_* and put/get should transfer over SCP, which for `auto` means SFTP isn't available
+    fn use_scp(&self, py: Python<'_>) -> PyResult<bool> {
+        match self.transfer_backend {
+            TransferBackend::Scp => Ok(true),
+            TransferBackend::Sftp => Ok(false),
+            TransferBackend::Auto if self.sftp_unavailable.load(Ordering::Relaxed) => Ok(true),
+            TransferBackend::Auto => {
+                let _guard = self.channel_guard(py, true)?;
+                match self.sftp() {
+                    Ok(_) => Ok(false),
+                    Err(e) if e.is_instance_of::<SFTPUnavailableError>(py) => {
+                        log(py, "sftp", INFO, || {
+                            format!("SFTP isn't available on {}, using SCP instead", self.host)
+                        });
+                        Ok(true)
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+        }
+    }
+
+    // Probe what the server supports: an SFTP session, setting environment variables on a channel,
+    // and running commands, which accounts with a shell like nologin can't
+    fn probe_capabilities(&self, py: Python<'_>) -> PyResult<Capabilities> {
+        self.sftp_unavailable.store(false, Ordering::Relaxed);
+        let sftp = {
+            let _guard = self.channel_guard(py, true)?;
+            match self.sftp() {
+                Ok(_) => true,
+                Err(e) if e.is_instance_of::<SFTPUnavailableError>(py) => false,
+                Err(e) => return Err(e),
+            }
+        };
+        let _guard = self.channel_guard(py, false)?;
+        let mut channel = self.open_session()?.channel_session().map_err(|e| {
+            channel_open_error(&e).unwrap_or_else(|| {
+                PyErr::new::<PyIOError, _>(format!("Channel session error: {}", e))
+            })
+        })?;
+        let setenv = channel.setenv("HUSSH_PROBE", "1").is_ok();
+        let shell = channel.exec("true").is_ok()
+            && read_from_channel(&mut channel).is_ok_and(|result| result.status == 0);
+        log(py, "connection", DEBUG, || {
+            format!(
+                "Capabilities of {}: sftp={} setenv={} shell={}",
+                self.host, sftp, setenv, shell
+            )
+        });
+        Ok(Capabilities {
+            sftp,
+            setenv,
+            shell,
+        })
+    }
+
+    // Whether there's room for another channel under `max_channels`,
+    // closing the cached SFTP channel to make some if need be, since it's reopened when next needed
+    fn make_room(&self) -> bool {
+        if !self.channels.full() {
+            return true;
+        }
+        if let Ok(mut cached) = self.sftp_conn.try_lock() {
+            if cached.take().is_some() {
+                self.trace(|| "Closed sftp subsystem channel to make room".to_string());
+            }
+        }
+        !self.channels.full()
+    }
+
+    // Hold the session lock with room to open a channel, or with the SFTP channel cached if `sftp` is set.
+    // If `max_channels` are open, wait for one to close, up to the session timeout if there is one.
+    fn channel_guard(&self, py: Python<'_>, sftp: bool) -> PyResult<SessionGuard> {
+        let timeout = self.session().timeout();
+        let deadline =
+            (timeout > 0).then(|| Instant::now() + Duration::from_millis(timeout.into()));
+        loop {
+            let guard = self.lock.acquire(py);
+            let cached = || {
+                self.sftp_conn
+                    .try_lock()
+                    .is_ok_and(|cached| cached.is_some())
+            };
+            if (sftp && cached()) || self.make_room() {
+                return Ok(guard);
+            }
+            drop(guard);
+            log(py, "connection", DEBUG, || {
+                format!("Waiting for a free channel on {}", self.host)
+            });
+            if !self.channels.wait(py, deadline) {
+                return Err(PyErr::new::<ChannelOpenError, _>(format!(
+                    "No channel to {}:{} was free within {}ms, all {} were in use",
+                    self.host,
+                    self.port,
+                    timeout,
+                    self.channels.max.unwrap_or_default()
+                )));
+            }
+        }
+    }
+
+    // Run a command on a new channel, restoring the session timeout afterwards.
+    // If `stdin` is set, it's written to the command before reading any output.
+    // If `echo` is set, output is also written to python's stdout/stderr as it arrives.
+    fn run_command(
+        &self,
+        py: Python<'_>,
+        command: &str,
+        timeout: Option<u32>,
+        stdin: Stdin<'_>,
+        echo: bool,
+        output: OutputOptions,
+    ) -> PyResult<SSHResult> {
+        self.check_command(py, command)?;
+        let _guard = self.channel_guard(py, false)?;
+        self.run_hooked(py, command, timeout, || {
+            self.run_on_channel(py, command, stdin, echo, output)
+        })
+    }
+
+    // Raise a CommandPolicyError if the connection's command policy denies `command`,
+    // which is checked as it will be sent, e.g. with the sudo wrapper around it
+    fn check_command(&self, py: Python<'_>, command: &str) -> PyResult<()> {
+        match &self.command_policy {
+            Some(policy) => policy.check(py, command),
+            None => Ok(()),
+        }
+    }
+
+    // Run a command with `run` while holding the lock and the command's timeout,
+    // calling the execute hooks and recording logs and stats around it
+    fn run_hooked(
+        &self,
+        py: Python<'_>,
+        command: &str,
+        timeout: Option<u32>,
+        run: impl FnOnce() -> PyResult<SSHResult>,
+    ) -> PyResult<SSHResult> {
+        let _guard = self.lock.acquire(py);
+        let original_timeout = self.open_session()?.timeout();
+        if let Some(t) = timeout.or(self.defaults.command_timeout) {
+            self.session().set_timeout(t);
+        }
+        self.call_hooks(py, "before_execute", (command,).into_pyobject(py)?)?;
+        log(py, "connection", DEBUG, || {
+            format!("Executing command on {}: {}", self.host, command)
+        });
+        let start = Instant::now();
+        let result = run();
+        self.session().set_timeout(original_timeout);
+        self.stats.commands.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .command_time_us
+            .fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+        match &result {
+            Ok(res) => log(py, "connection", INFO, || {
+                format!(
+                    "Command {:?} on {} exited with status {} after {:.3}s",
+                    command,
+                    self.host,
+                    res.status,
+                    start.elapsed().as_secs_f64()
+                )
+            }),
+            Err(e) => log(py, "connection", WARNING, || {
+                format!(
+                    "Command {:?} on {} failed after {:.3}s: {}",
+                    command,
+                    self.host,
+                    start.elapsed().as_secs_f64(),
+                    e
+                )
+            }),
+        }
+        let result = result?;
+        let args = (command, result.clone()).into_pyobject(py)?;
+        self.call_after_hooks(py, "after_execute", args, Some(&result))?;
+        Ok(result)
+    }
+
+    // Run `command` through sudo, as `user` or root. Without a password, from the arguments or the
+    // connection, sudo runs non-interactively so it fails instead of waiting on a prompt.
+    fn run_sudo(
+        &self,
+        py: Python<'_>,
+        command: &str,
+        password: Option<String>,
+        user: Option<&str>,
+        hide: bool,
+        timeout: Option<u32>,
+    ) -> PyResult<SSHResult> {
+        let password = password.or_else(|| {
+            (self.private_key.is_empty() && !self.password.is_empty())
+                .then(|| self.password.clone())
+        });
+        let user_arg = user
+            .map(|user| format!("-u {} ", shell_quote(user)))
+            .unwrap_or_default();
+        let (sudo_cmd, stdin) = match &password {
+            Some(password) => (
+                format!(
+                    "sudo -S -p '' {}-- sh -c {}",
+                    user_arg,
+                    shell_quote(command)
+                ),
+                Some(format!("{}\n", password)),
+            ),
+            None => (
+                format!("sudo -n {}-- sh -c {}", user_arg, shell_quote(command)),
+                None,
+            ),
+        };
+        let mut result = self.run_command(
+            py,
+            &sudo_cmd,
+            timeout,
+            stdin.as_deref().map_or(Stdin::Empty, Stdin::Text),
+            !hide,
+            OutputOptions {
+                encodings: self.output_encodings(None)?,
+                ..Default::default()
+            },
+        )?;
+        result.user = Some(user.unwrap_or("root").to_string());
+        Ok(result)
+    }
+
+    // Run `command` on a pty, entering `password` if it prompts for one before printing anything
+    // else. A prompt is output ending in a colon before any newline, like su's `Password: `.
+    // The pty merges stderr into stdout, so its line endings and escapes are cleaned up.
+    fn run_on_pty(
+        &self,
+        py: Python<'_>,
+        command: &str,
+        password: &str,
+        echo: bool,
+        mut output: OutputOptions,
+    ) -> PyResult<SSHResult> {
+        let mut channel = self.open_channel(py)?;
+        self.trace(|| format!("Opened session channel to exec {:?} on a pty", command));
+        let session = self.session();
+        output.strip_ansi = true;
+        output.dead_peer_timeout = self.dead_peer_timeout;
+        let (prompted, mut result) = py.allow_threads(|| {
+            channel
+                .request_pty("dumb", None, None)
+                .map_err(|e| PyErr::new::<PyIOError, _>(format!("PTY request error: {}", e)))?;
+            channel
+                .exec(command)
+                .map_err(|e| PyErr::new::<PyIOError, _>(format!("Exec error: {}", e)))?;
+            let mut before = Vec::new();
+            let mut buffer = [0; 256];
+            let prompted = loop {
+                let len = channel.read(&mut buffer).map_err(|e| {
+                    PyErr::new::<PyTimeoutError, _>(format!("Timeout waiting for a prompt: {}", e))
+                })?;
+                if len == 0 {
+                    break false;
+                }
+                before.extend_from_slice(&buffer[..len]);
+                if before.contains(&b'\n') {
+                    break false;
+                }
+                if before.trim_ascii_end().ends_with(b":") {
+                    break true;
+                }
+            };
+            if prompted {
+                channel
+                    .write_all(format!("{}\n", password).as_bytes())
+                    .map_err(|e| {
+                        PyErr::new::<PyIOError, _>(format!("Password write error: {}", e))
+                    })?;
+            } else {
+                output.stdout_read = before;
+            }
+            let result = read_output(&mut channel, Some(&session), echo, output)?;
+            Ok::<_, PyErr>((prompted, result))
+        })?;
+        // the newline after the password, which the pty didn't echo
+        if prompted && result.stdout.starts_with('\n') {
+            result.stdout.remove(0);
+        }
+        result.command = Some(command.to_string());
+        Ok(result)
+    }
+
+    // Open a session channel for a command, with the errors and trace that go with it
+    fn open_channel(&self, py: Python<'_>) -> PyResult<Channel> {
+        let session = self.session();
+        py.allow_threads(|| session.channel_session()).map_err(|e| {
+            log(py, "connection", ERROR, || {
+                format!("Failed to open a channel on {}: {}", self.host, e)
+            });
+            self.trace(|| format!("Failed to open session channel: {}", e));
+            channel_open_error(&e).unwrap_or_else(|| {
+                PyErr::new::<PyTimeoutError, _>(format!(
+                    "Timed out establishing channel session.\n{}",
+                    e
+                ))
+            })
+        })
+    }
+
+    fn run_on_channel(
+        &self,
+        py: Python<'_>,
+        command: &str,
+        stdin: Stdin<'_>,
+        echo: bool,
+        mut output: OutputOptions,
+    ) -> PyResult<SSHResult> {
+        let mut channel = self.open_channel(py)?;
+        self.trace(|| format!("Opened session channel to exec {:?}", command));
+        let session = self.session();
+        output.dead_peer_timeout = self.dead_peer_timeout;
+        // nothing from here on needs the GIL, until output is echoed
+        let mut result = py.allow_threads(|| {
+            // exec is non-blocking, so we don't check for a timeout here, but in read_output
+            channel
+                .exec(command)
+                .map_err(|e| PyErr::new::<PyIOError, _>(format!("Exec error: {}", e)))?;
+            match stdin {
+                Stdin::Empty => {}
+                Stdin::Text(stdin) => channel
+                    .write_all(stdin.as_bytes())
+                    .map_err(|e| PyErr::new::<PyIOError, _>(format!("Stdin write error: {}", e)))?,
+                Stdin::File(mut file) => {
+                    (output.stdout_read, output.stderr_read) =
+                        stream_stdin(&session, &mut channel, &mut file)?;
+                }
+            }
+            read_output(&mut channel, Some(&session), echo, output)
+        })?;
+        result.command = Some(command.to_string());
+        Ok(result)
+    }
+
+    // Briefly open a pty shell and read what the server prints before the first prompt, then close it.
+    // The shell is taken to be at its prompt once it has printed nothing for `quiet_ms`.
+    fn read_motd(&self, py: Python<'_>, quiet_ms: u32) -> PyResult<String> {
+        let _guard = self.channel_guard(py, false)?;
+        let mut channel = self.open_session()?.channel_session().map_err(|e| {
+            channel_open_error(&e).unwrap_or_else(|| {
+                PyErr::new::<PyIOError, _>(format!("Channel session error: {}", e))
+            })
+        })?;
+        self.trace(|| "Opened session channel to read the MOTD".to_string());
+        channel
+            .request_pty("xterm", None, None)
+            .map_err(|e| PyErr::new::<PyIOError, _>(format!("PTY request error: {}", e)))?;
+        channel
+            .shell()
+            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Shell request error: {}", e)))?;
+        let original_timeout = self.session().timeout();
+        self.session().set_timeout(quiet_ms);
+        let mut motd = Vec::new();
+        let mut buffer = [0; 4096];
+        let read = loop {
+            match channel.read(&mut buffer) {
+                Ok(0) => break Ok(()),
+                Ok(len) => {
+                    motd.extend_from_slice(&buffer[..len]);
+                    // a server that never stops talking isn't showing a MOTD
+                    if motd.len() >= MAX_BUFF_SIZE {
+                        break Ok(());
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::TimedOut => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        self.session().set_timeout(original_timeout);
+        read.and_then(|_| channel.close().map_err(Into::into))
+            .and_then(|_| channel.wait_close().map_err(Into::into))
+            .map_err(|e: std::io::Error| {
+                PyErr::new::<PyIOError, _>(format!("Error reading the MOTD: {}", e))
+            })?;
+        let motd = String::from_utf8_lossy(&motd).replace("\r\n", "\n");
+        // drop the prompt, which is what's left after the last newline
+        Ok(match motd.rfind('\n') {
+            Some(end) => motd[..=end].to_string(),
+            None => String::new(),
+        })
+    }
+
+    // A transfer's buffer_size, falling back to the connection's default
+    fn buffer_size(&self, buffer_size: Option<usize>) -> PyResult<usize> {
+        transfer_buffer_size(buffer_size.or(self.defaults.buffer_size))
+    }
+
+    // The remote kernel's boot ID, which changes on every boot, if it has one
+    fn boot_id(&self, py: Python<'_>) -> PyResult<Option<String>> {
+        let command = "cat /proc/sys/kernel/random/boot_id";
+        let result =
+            self.run_command(py, command, None, Stdin::Empty, false, Default::default())?;
+        Ok((result.status == 0).then(|| result.stdout.trim().to_string()))
+    }
+
+    // Replace a session the server has dropped, e.g. by rebooting, with a new one that's
+    // established on next use. Channels opened on the old session stop working, and everything cached
+    // about it, like the remote locale and auth details, is forgotten.
+    fn reset_session(&self, py: Python<'_>) -> PyResult<()> {
+        let _guard = self.lock.acquire(py);
+        let session = Session::new()
+            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Session error: {}", e)))?;
+        session.set_timeout(self.timeout);
+        if let Some(interval) = self.keepalive_interval {
+            session.set_keepalive(true, interval);
+        }
+        *self
+            .sftp_conn
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = None;
+        *self.session.write().unwrap_or_else(PoisonError::into_inner) = session;
+        *self
+            .remote_home
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = None;
+        // what we learned about the old session may not hold for the new one
+        *self
+            .capabilities
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = None;
+        *self
+            .remote_encoding
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = None;
+        *self
+            .auth_instructions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = None;
+        self.auth_attempts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+        self.sftp_unavailable.store(false, Ordering::Relaxed);
+        self.connected.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // Create a uniquely named directory or empty file in `dir`, readable only by the remote user.
+    // Names are random, and creation fails if the path exists, so concurrent callers never share one.
+    fn make_temp(&self, prefix: &str, dir: &str, is_dir: bool) -> PyResult<String> {
+        let dir = self.expand_remote_path(dir.to_string())?;
+        for _ in 0..TEMP_NAME_ATTEMPTS {
+            let mut random = [0u8; 8];
+            openssl::rand::rand_bytes(&mut random)
+                .map_err(|e| PyErr::new::<PyIOError, _>(format!("Random name error: {}", e)))?;
+            let name: String = random.iter().map(|b| format!("{:02x}", b)).collect();
+            let path = format!("{}/{}{}", dir.trim_end_matches('/'), prefix, name);
+            let created = self.with_sftp(|sftp| {
+                if is_dir {
+                    sftp.mkdir(Path::new(&path), 0o700)
+                } else {
+                    let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::EXCLUSIVE;
+                    sftp.open_mode(Path::new(&path), flags, 0o600, OpenType::File)
+                        .map(|_| ())
+                }
+            })?;
+            // OpenSSH answers a name that's taken with a bare failure, which it also gives for a full disk
+            // or a read-only filesystem, so the name is only retried if something's really there
+            let taken = |e: &ssh2::Error| match e.code() {
+                ssh2::ErrorCode::SFTP(SSH_FX_FILE_ALREADY_EXISTS) => Ok(true),
+                ssh2::ErrorCode::SFTP(SSH_FX_FAILURE) => self
+                    .with_sftp(|sftp| sftp.lstat(Path::new(&path)))
+                    .map(|stat| stat.is_ok()),
+                _ => Ok(false),
+            };
+            match created {
+                Ok(()) => return Ok(path),
+                Err(e) if taken(&e)? => continue,
+                Err(e) => {
+                    return Err(PyErr::new::<PyIOError, _>(format!(
+                        "Error creating a temporary path in {}: {}",
+                        dir, e
+                    )))
+                }
+            }
+        }
+        Err(PyErr::new::<PyIOError, _>(format!(
+            "No unused temporary name in {} after {} attempts",
+            dir, TEMP_NAME_ATTEMPTS
+        )))
+    }
+
+    // Remove a remote file, or a directory and everything in it, without following symlinks
+    fn remove_tree(&self, path: &str, is_dir: bool) -> PyResult<()> {
+        let sftp = self.sftp()?;
+        let remove_err = |e: ssh2::Error| {
+            PyErr::new::<PyIOError, _>(format!("SFTP remove error for {}: {}", path, e))
+        };
+        if !is_dir {
+            return sftp.unlink(Path::new(path)).map_err(remove_err);
+        }
+        // directories are removed after their contents, deepest first
+        let mut dirs = vec![PathBuf::from(path)];
+        let mut pending = vec![PathBuf::from(path)];
+        while let Some(dir) = pending.pop() {
+            for (entry, stat) in sftp.readdir(&dir).map_err(remove_err)? {
+                if stat.is_dir() {
+                    dirs.push(entry.clone());
+                    pending.push(entry);
+                } else {
+                    sftp.unlink(&entry).map_err(remove_err)?;
+                }
+            }
+        }
+        for dir in dirs.iter().rev() {
+            sftp.rmdir(dir).map_err(remove_err)?;
+        }
+        Ok(())
+    }
+
+    // Give other hussh modules access to the underlying session.
+    // Sessions share their state between clones, so this is the connection's live session until it's reset.
+    pub(crate) fn session(&self) -> Session {
+        self.session
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    // Open a channel for another hussh module, like the paramiko adapter, with `open`. The channel is
+    // opened under the session lock, and the slot returned holds its place under `max_channels` until dropped.
+    pub(crate) fn open_with_slot<T>(
+        &self,
+        py: Python<'_>,
+        open: impl FnOnce(&Session) -> PyResult<T>,
+    ) -> PyResult<(T, ChannelSlot)> {
+        let _guard = self.channel_guard(py, false)?;
+        let opened = open(&self.open_session()?)?;
+        Ok((opened, self.channels.take()))
+    }
+}
+
+#[pymethods]
+impl Connection {
+    #[new]
+    #[pyo3(signature = (host, port=22, username="root", password=None, private_key=None, timeout=0, connect_timeout=None, debug=false, trace_file=None, hooks=None, connect=true, allowed_auth=None, max_channels=None, transfer_backend="auto", default_file_mode=None, defaults=None, sftp_max_idle=None, keepalive_interval=None, dead_peer_timeout=None, suppress_cleanup_errors=false, command_policy=None))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        py: Python<'_>,
+        host: &str,
+        port: Option<i32>,
+        username: Option<&str>,
+        password: Option<&str>,
+        private_key: Option<LocalPath>,
+        timeout: Option<u32>,
+        connect_timeout: Option<u32>,
+        debug: bool,
+        trace_file: Option<LocalPath>,
+        hooks: Option<&Bound<'_, PyDict>>,
+        connect: bool,
+        allowed_auth: Option<Vec<String>>,
+        max_channels: Option<usize>,
+        transfer_backend: &str,
+        default_file_mode: Option<u32>,
+        defaults: Option<Defaults>,
+        sftp_max_idle: Option<f64>,
+        keepalive_interval: Option<u32>,
+        dead_peer_timeout: Option<f64>,
+        suppress_cleanup_errors: bool,
+        command_policy: Option<&Bound<'_, PyAny>>,
+    ) -> PyResult<Connection> {
+        let (private_key, trace_file) = (private_key.as_deref(), trace_file.as_deref());
+        let command_policy = command_policy
+            .map(CommandPolicy::parse)
+            .transpose()?
+            .map(Arc::new);
+        let defaults = defaults.unwrap_or_default();
+        if username.is_some_and(str::is_empty) {
+            return Err(PyErr::new::<PyValueError, _>(
+                "username can't be empty, leave it out to connect as root",
+            ));
+        }
+        if max_channels == Some(0) {
+            return Err(PyErr::new::<PyValueError, _>(
+                "max_channels must be at least 1",
+            ));
+        }
+        let transfer_backend = TransferBackend::parse(transfer_backend)?;
+        let sftp_max_idle = sftp_max_idle
+            .map(|idle| {
+                Duration::try_from_secs_f64(idle).map_err(|_| {
+                    PyErr::new::<PyValueError, _>(format!("Invalid sftp_max_idle: {}", idle))
+                })
+            })
+            .transpose()?;
+        if keepalive_interval == Some(0) {
+            return Err(PyErr::new::<PyValueError, _>(
+                "keepalive_interval must be at least 1",
+            ));
+        }
+        let dead_peer_timeout = dead_peer_timeout
+            .map(|limit| match keepalive_interval {
+                None => Err(PyErr::new::<PyValueError, _>(
+                    "dead_peer_timeout needs a keepalive_interval",
+                )),
+                Some(interval) if limit.is_finite() && limit > interval as f64 => {
+                    Ok(Duration::from_secs_f64(limit))
+                }
+                Some(_) => Err(PyErr::new::<PyValueError, _>(format!(
+                    "Invalid dead_peer_timeout: {}, it must be longer than the keepalive_interval",
+                    limit
+                ))),
+            })
+            .transpose()?;
+        if let Some(mode) = default_file_mode {
+            check_file_mode(mode)?;
+        }
+        let allowed_auth = match allowed_auth {
+            Some(methods) => {
+                if let Some(unknown) = methods.iter().find(|m| !AUTH_METHODS.contains(&m.as_str()))
+                {
+                    return Err(PyErr::new::<PyValueError, _>(format!(
+                        "Unknown auth method {:?}, expected one of: {}",
+                        unknown,
+                        AUTH_METHODS.join(", ")
+                    )));
+                }
+                methods
+            }
+            None => AUTH_METHODS.iter().map(|m| m.to_string()).collect(),
+        };
+        let tracer = if debug || trace_file.is_some() {
+            Some(Tracer::new(trace_file, &[password.unwrap_or("")])?)
+        } else {
+            None
+        };
+        let session = Session::new()
+            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Session error: {}", e)))?;
+        // if a timeout is set, use it
+        let timeout = timeout.unwrap_or(0);
+        session.set_timeout(timeout);
+        if let Some(interval) = keepalive_interval {
+            session.set_keepalive(true, interval);
+        }
+        let conn = Connection {
+            session: RwLock::new(session),
+            // if port isn't set, use the default ssh port 22
+            port: port.unwrap_or(22),
+            host: host.to_string(),
+            // if username isn't set, try using root
+            username: username.unwrap_or("root").to_string(),
+            password: password.unwrap_or("").to_string(),
+            // expanded up front, so an unset variable fails here rather than as an auth failure
+            private_key: private_key
+                .map(expand_local_path)
+                .transpose()?
+                .unwrap_or_default(),
+            timeout,
+            // default to the session timeout, if set, otherwise 30 seconds
+            connect_timeout: connect_timeout
+                .or(defaults.connect_timeout)
+                .filter(|t| *t > 0)
+                .unwrap_or(if timeout > 0 { timeout } else { 30_000 }),
+            sftp_conn: Mutex::default(),
+            sftp_max_idle,
+            keepalive_interval,
+            dead_peer_timeout,
+            suppress_cleanup_errors,
+            channels: Arc::new(ChannelSlots::new(max_channels)),
+            debug,
+            trace_file: trace_file.map(String::from),
+            tracer,
+            stats: Stats::default(),
+            hooks: Mutex::default(),
+            command_policy,
+            connected: AtomicBool::new(false),
+            lock: Arc::default(),
+            remote_home: Mutex::default(),
+            allowed_auth,
+            auth_attempts: Mutex::default(),
+            auth_instructions: Mutex::default(),
+            transfer_backend,
+            default_file_mode,
+            remote_encoding: Mutex::default(),
+            sftp_unavailable: AtomicBool::new(false),
+            capabilities: Mutex::default(),
+            children: Mutex::default(),
+            defaults,
+            closed: AtomicBool::new(false),
+        };
+        // each value is a single hook or an iterable of them
+        for (event, value) in hooks.into_iter().flatten() {
+            let event: String = event.extract()?;
+            if value.is_callable() {
+                conn.add_hook(&event, &value)?;
+            } else {
+                for hook in value.try_iter()? {
+                    conn.add_hook(&event, &hook?)?;
+                }
+            }
+        }
+        if connect {
+            conn.establish(py)?;
+        }
+        Ok(conn)
+    }
+
+    /// Connects and authenticates, if the connection hasn't already.
+    /// Only needed for connections created with `connect=False`, which otherwise connect on first use.
+    fn connect(&self, py: Python<'_>) -> PyResult<()> {
+        let _guard = self.lock.acquire(py);
+        self.open_session().map(|_| ())
+    }
+
+    /// Waits for this connection's host to accept SSH connections, as in `hussh.wait_for_ssh`,
+    /// and returns how many seconds it took. The connection itself isn't re-established.
+    #[pyo3(signature = (timeout=300.0, interval=5.0))]
+    fn wait_until_ready(&self, py: Python<'_>, timeout: f64, interval: f64) -> PyResult<f64> {
+        wait_for_ssh(py, &self.host, self.port, timeout, interval)
+    }
+
+    /// Asks the server whether it would accept the public key `key` for this connection's user, without authenticating.
+    /// `key` is an OpenSSH public key line, or the path to a public key, or to a private key with a `.pub` file beside it.
+    /// The check runs on a new connection to the host, sending the key unsigned, so the private key isn't needed
+    /// and no other auth method is tried. Returns a dict of the `key_type`, its SHA256 `fingerprint`, and the `result`:
+    /// `"accepted"`, `"rejected"`, `"unsupported"` if the server doesn't offer publickey auth for the user,
+    /// or `"unknown"` if the server gave no answer either way, with the reason as `detail`.
+    /// Raises a `TimeoutError` if the host can't be reached.
+    fn check_publickey<'py>(
+        &self,
+        py: Python<'py>,
+        key: LocalPath,
+    ) -> PyResult<Bound<'py, PyDict>> {
+        let blob = public_key_blob(&key)?;
+        let conn_str = format!("{}:{}", self.host, self.port);
+        let (host, port, username, timeout) = (
+            self.host.as_str(),
+            self.port,
+            self.username.as_str(),
+            self.connect_timeout,
+        );
+        let (result, detail) = py
+            .allow_threads(|| {
+                let tcp = connect_tcp(host, port, timeout)?;
+                let mut session = Session::new()?;
+                session.set_timeout(timeout);
+                session.set_tcp_stream(tcp);
+                session.handshake()?;
+                let answer = match session.auth_methods(username) {
+                    Ok(offered) if !offered.split(',').any(|m| m == "publickey") => (
+                        "unsupported",
+                        Some(format!("the server only offers {}", offered)),
+                    ),
+                    _ if session.authenticated() => (
+                        "unknown",
+                        Some("the server let the user in without authenticating".to_string()),
+                    ),
+                    _ => match agent::query_publickey(&session, username, &blob) {
+                        agent::KeyQuery::Accepted => ("accepted", None),
+                        agent::KeyQuery::Rejected => ("rejected", None),
+                        agent::KeyQuery::Unknown(reason) => ("unknown", Some(reason)),
+                    },
+                };
+                let _ = session.disconnect(None, "", None);
+                Ok::<_, std::io::Error>(answer)
+            })
+            .map_err(|e| {
+                PyErr::new::<PyTimeoutError, _>(format!(
+                    "Couldn't check the key on {}: {}",
+                    conn_str, e
+                ))
+            })?;
+        log(py, "connection", DEBUG, || {
+            format!(
+                "Public key {} for {} on {}: {}",
+                fingerprint(&blob),
+                username,
+                conn_str,
+                result
+            )
+        });
+        let check = PyDict::new(py);
+        check.set_item("result", result)?;
+        check.set_item("key_type", agent::key_type(&blob))?;
+        check.set_item("fingerprint", fingerprint(&blob))?;
+        check.set_item("detail", detail)?;
+        Ok(check)
+    }
+
+    /// Reboots the remote host by running `command`, tolerating the connection dropping as it goes down.
+    /// With `wait`, this then waits up to `timeout` seconds for the host to go down, and for SSH to come back,
+    /// reconnects, and returns a dict of the `downtime` in seconds, from running `command` until SSH answered again,
+    /// and whether the host `rebooted`, judged by its boot ID. `rebooted` is `False` if the boot ID was unchanged
+    /// when `timeout` ran out, and `None` if the host has no boot ID.
+    /// Without `wait`, it returns `None`, and the connection is re-established when it's next used.
+    /// A `command` that fails, rather than dropping the connection, raises a `CommandError`.
+    #[pyo3(signature = (wait=true, timeout=600.0, command="reboot"))]
+    fn reboot<'py>(
+        slf: &Bound<'py, Self>,
+        wait: bool,
+        timeout: f64,
+        command: &str,
+    ) -> PyResult<Option<Bound<'py, PyDict>>> {
+        let py = slf.py();
+        let limit = Duration::try_from_secs_f64(timeout)
+            .map_err(|_| PyErr::new::<PyValueError, _>(format!("Invalid timeout: {}", timeout)))?;
+        let (host, port) = {
+            let conn = slf.borrow();
+            (conn.host.clone(), conn.port)
+        };
+        let boot_id = slf.borrow().boot_id(py)?;
+        log(py, "connection", INFO, || {
+            format!("Rebooting {} with {:?}", host, command)
+        });
+        let start = Instant::now();
+        let output = Default::default();
+        let ran = slf
+            .borrow()
+            .run_command(py, command, Some(10_000), Stdin::Empty, false, output);
+        match ran {
+            Ok(result) => {
+                check_result(py, result, false)?;
+            }
+            // the server going down mid-command is what we're after, but a command that
+            // was refused or never ran is an error like any other
+            Err(e) if connection_dropped(py, &e, &slf.borrow().session()) => {
+                log(py, "connection", DEBUG, || {
+                    format!("{} dropped the connection while rebooting: {}", host, e)
+                })
+            }
+            Err(e) => return Err(e),
+        }
+        slf.borrow().reset_session(py)?;
+        if !wait {
+            return Ok(None);
+        }
+        // without a boot ID to tell the old and new sshd apart, wait for the old one to stop first
+        while boot_id.is_none()
+            && start.elapsed() < limit
+            && py
+                .allow_threads(|| probe_ssh(&host, port, Duration::from_secs(1)))
+                .is_ok()
+        {
+            py.allow_threads(|| thread::sleep(Duration::from_secs(1)));
+            py.check_signals()?;
+        }
+        let (downtime, rebooted) = loop {
+            let remaining = limit.saturating_sub(start.elapsed()).as_secs_f64();
+            wait_for_ssh(py, &host, port, remaining, 2.0)?;
+            let downtime = start.elapsed().as_secs_f64();
+            let after = {
+                let conn = slf.borrow();
+                conn.connect(py).and_then(|_| conn.boot_id(py))
+            };
+            let timed_out = start.elapsed() >= limit;
+            match (&boot_id, after) {
+                (_, Err(e)) if timed_out => return Err(e),
+                // the server may still be going down, or not quite up
+                (_, Err(e)) => log(py, "connection", DEBUG, || {
+                    format!("{} isn't back yet: {}", host, e)
+                }),
+                (None, Ok(_)) => break (downtime, None),
+                (Some(before), Ok(after)) if after.as_ref() != Some(before) => {
+                    break (downtime, Some(true))
+                }
+                (Some(_), Ok(_)) if timed_out => {
+                    log(py, "connection", WARNING, || {
+                        format!("{} didn't reboot; its boot ID is unchanged", host)
+                    });
+                    break (downtime, Some(false));
+                }
+                // still the old boot, which hasn't gone down yet
+                (Some(_), Ok(_)) => {}
+            }
+            slf.borrow().reset_session(py)?;
+            py.allow_threads(|| thread::sleep(Duration::from_secs(2)));
+            py.check_signals()?;
+        };
+        let result = PyDict::new(py);
+        result.set_item("downtime", downtime)?;
+        result.set_item("rebooted", rebooted)?;
+        Ok(Some(result))
+    }
+
+    /// Whether the connection is established, not closed, and the server still answers.
+    /// The check opens and closes a channel, giving the server at most 5 seconds to respond.
+    /// It's skipped if all of `max_channels` are in use.
+    pub(crate) fn is_active(&self, py: Python<'_>) -> bool {
+        let _guard = self.lock.acquire(py);
+        if self.closed.load(Ordering::Relaxed) || !self.connected() {
+            return false;
+        }
+        // with every channel in use, the connection is evidently working
+        if !self.make_room() {
+            return true;
+        }
+        let session = self.session();
+        let original_timeout = session.timeout();
+        session.set_timeout(match original_timeout {
+            0 => HEALTH_CHECK_TIMEOUT_MS,
+            t => t.min(HEALTH_CHECK_TIMEOUT_MS),
+        });
+        let active = py
+            .allow_threads(|| {
+                session
+                    .channel_session()
+                    .and_then(|mut channel| channel.close())
+            })
+            .is_ok();
+        session.set_timeout(original_timeout);
+        active
+    }
+
+    /// Whether the connection has been closed.
+    #[getter]
+    fn closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    /// Whether the connection has been established, which may be deferred with `connect=False`.
+    #[getter]
+    fn connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    /// How many seconds a cached SFTP session may sit idle before it's replaced, if there's a limit.
+    #[getter]
+    fn sftp_max_idle(&self) -> Option<f64> {
+        self.sftp_max_idle.map(|idle| idle.as_secs_f64())
+    }
+
+    /// How many seconds a command waits on a server that has stopped answering keepalives, if there's a limit.
+    #[getter]
+    fn dead_peer_timeout(&self) -> Option<f64> {
+        self.dead_peer_timeout.map(|limit| limit.as_secs_f64())
+    }
+
+    /// The auth methods this connection may attempt. They are always tried in the order of the fallback chain.
+    #[getter]
+    fn allowed_auth<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyTuple>> {
+        PyTuple::new(py, &self.allowed_auth)
+    }
+
+    /// The most channels this connection opens at once, or `None` for no limit.
+    #[getter]
+    fn max_channels(&self) -> Option<usize> {
+        self.channels.max
+    }
+
+    /// How `sftp_*`, `put`, `get` and `tail` move files: `auto`, `sftp` or `scp`.
+    #[getter]
+    fn transfer_backend(&self) -> &'static str {
+        self.transfer_backend.name()
+    }
+
+    /// The mode remote files are created with when a write isn't given one, or `None` for 0o644.
+    #[getter]
+    fn default_file_mode(&self) -> Option<u32> {
+        self.default_file_mode
+    }
+
+    /// The (method, outcome) of each auth attempt made by the last connect, in order.
+    #[getter]
+    fn auth_attempts(&self) -> Vec<(String, String)> {
+        self.auth_attempts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Returns what the server tells users as they log in, without running any commands, as a dict:
+    /// - `server_banner`: the server's SSH identification string, e.g. `SSH-2.0-OpenSSH_9.6`
+    /// - `auth_banner`: the banner sent during auth (sshd's `Banner`), or `None`
+    /// - `auth_instructions`: instructions sent with keyboard-interactive prompts, or `None`
+    ///   if keyboard-interactive auth wasn't used
+    /// - `motd`: the message of the day, read by briefly opening a pty shell, or `None` if `motd` is `false`
+    ///
+    /// The shell is taken to be at its prompt once it's printed nothing for `motd_timeout` milliseconds.
+    #[pyo3(signature = (motd=true, motd_timeout=1000))]
+    fn get_banner_info<'py>(
+        &self,
+        py: Python<'py>,
+        motd: bool,
+        motd_timeout: u32,
+    ) -> PyResult<Bound<'py, PyDict>> {
+        let info = PyDict::new(py);
+        {
+            let _guard = self.lock.acquire(py);
+            let session = self.open_session()?;
+            info.set_item("server_banner", session.banner())?;
+            info.set_item("auth_banner", session.userauth_banner().ok().flatten())?;
+        }
+        info.set_item(
+            "auth_instructions",
+            self.auth_instructions
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clone(),
+        )?;
+        let motd = if motd {
+            Some(self.read_motd(py, motd_timeout.max(1))?)
+        } else {
+            None
+        };
+        info.set_item("motd", motd)?;
+        Ok(info)
+    }
+
+    /// Pickles the connection parameters, never the live session, channels or hooks.
+    /// Unpickled and deep-copied connections are independent, and connect on first use.
+    fn __reduce__<'py>(
+        slf: &Bound<'py, Self>,
+    ) -> PyResult<(Bound<'py, PyType>, Bound<'py, PyTuple>)> {
+        let py = slf.py();
+        let conn = slf.borrow();
+        // more arguments than a tuple converts in one go
+        let head = (
+            &conn.host,
+            conn.port,
+            &conn.username,
+            (!conn.password.is_empty()).then_some(&conn.password),
+            (!conn.private_key.is_empty()).then_some(&conn.private_key),
+            conn.timeout,
+            conn.connect_timeout,
+            conn.debug,
+            &conn.trace_file,
+            None::<PyObject>,
+            false,
+            &conn.allowed_auth,
+        )
+            .into_pyobject(py)?;
+        let mut args: Vec<Bound<'py, PyAny>> = head.iter().collect();
+        args.push(conn.channels.max.into_pyobject(py)?);
+        args.push(conn.transfer_backend.name().into_pyobject(py)?.into_any());
+        args.push(conn.default_file_mode.into_pyobject(py)?);
+        args.push(conn.defaults.clone().into_pyobject(py)?.into_any());
+        args.push(conn.sftp_max_idle().into_pyobject(py)?.into_any());
+        args.push(conn.keepalive_interval.into_pyobject(py)?.into_any());
+        args.push(conn.dead_peer_timeout().into_pyobject(py)?.into_any());
+        args.push(
+            conn.suppress_cleanup_errors
+                .into_pyobject(py)?
+                .to_owned()
+                .into_any(),
+        );
+        args.push(conn.command_policy(py)?.into_pyobject(py)?.into_any());
+        let args = PyTuple::new(py, args)?;
+        Ok((slf.get_type(), args))
+    }
+
+    /// Returns an OpenSSH `ssh` command line that connects the way this connection does, to reproduce a problem
+    /// outside Hussh. `-p` is given for a port other than 22, and `-i` for the private key, if there is one.
+    /// With `include_options`, `-o` options are added for the connect timeout, keepalives and allowed auth methods,
+    /// along with `StrictHostKeyChecking=no` and `UserKnownHostsFile=/dev/null`, since Hussh doesn't check host keys
+    /// and ssh would otherwise refuse, or ask about, a host Hussh connects to. Leave them out before reusing the command.
+    /// A password can't be passed to `ssh`, so it ends with a comment saying ssh will ask for it.
+    #[pyo3(signature = (include_options=true))]
+    fn as_openssh_command(&self, include_options: bool) -> String {
+        let mut args = vec!["ssh".to_string()];
+        if self.port != 22 {
+            args.extend(["-p".to_string(), self.port.to_string()]);
+        }
+        if !self.private_key.is_empty() {
+            args.extend(["-i".to_string(), shell_quote(&self.private_key)]);
+        }
+        if include_options {
+            let mut options = vec![
+                format!("ConnectTimeout={}", self.connect_timeout.div_ceil(1000)),
+                // Hussh accepts any host key, so ssh is told to as well
+                "StrictHostKeyChecking=no".to_string(),
+                "UserKnownHostsFile=/dev/null".to_string(),
+            ];
+            if let Some(interval) = self.keepalive_interval {
+                options.push(format!("ServerAliveInterval={}", interval));
+                if let Some(limit) = self.dead_peer_timeout {
+                    let count = (limit.as_secs_f64() / f64::from(interval)).ceil();
+                    options.push(format!("ServerAliveCountMax={}", count));
+                }
+            }
+            if self.allowed_auth.len() < AUTH_METHODS.len() {
+                // ssh offers the agent's keys as publickey auth
+                let mut methods: Vec<&str> = Vec::new();
+                for method in &self.allowed_auth {
+                    let method = if method == "agent" {
+                        "publickey"
+                    } else {
+                        method
+                    };
+                    if !methods.contains(&method) {
+                        methods.push(method);
+                    }
+                }
+                options.push(format!("PreferredAuthentications={}", methods.join(",")));
+            }
+            for option in options {
+                args.extend(["-o".to_string(), option]);
+            }
+        }
+        args.push(shell_quote(&format!("{}@{}", self.username, self.host)));
+        let mut command = args.join(" ");
+        if !self.password.is_empty() {
+            let secret = if self.private_key.is_empty() {
+                "password"
+            } else {
+                "password or key passphrase"
+            };
+            command.push_str(&format!(
+                "  # the {} isn't included, ssh will ask for it",
+                secret
+            ));
+        }
+        command
+    }
+
+    /// Looks up the remote locale's charset with `locale charmap`, and returns it.
+    /// From then on it's the default encoding for command output on this connection, instead of UTF-8.
+    /// The charset is looked up once, and again after the connection is re-established.
+    fn detect_remote_locale(&self, py: Python<'_>) -> PyResult<String> {
+        if let Some(encoding) = self
+            .remote_encoding
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+        {
+            return Ok(encoding);
+        }
+        let _guard = self.channel_guard(py, false)?;
+        let result = {
+            let _lock = self.lock.acquire(py);
+            self.open_session()?;
+            self.run_on_channel(
+                py,
+                "locale charmap",
+                Stdin::Empty,
+                false,
+                OutputOptions::default(),
+            )?
+        };
+        let charmap = result.stdout.trim().to_string();
+        if result.status != 0 || charmap.is_empty() {
+            return Err(PyErr::new::<PyIOError, _>(format!(
+                "Couldn't look up the locale on {}: {}",
+                self.host,
+                result.stderr.trim()
+            )));
+        }
+        // make sure python can decode it, before every command's output depends on it
+        py.import("codecs")?.call_method1("lookup", (&charmap,))?;
+        *self
+            .remote_encoding
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(charmap.clone());
+        Ok(charmap)
+    }
+
+    /// Returns the settings this connection's methods use when a call doesn't give them,
+    /// after applying its `defaults` and falling back to each method's own default.
+    /// A `command_timeout` of 0 means commands never time out.
+    fn effective_defaults<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let [encoding, _] = self.output_encodings(None)?;
+        let dict = self.defaults.as_dict(py)?;
+        dict.set_item(
+            "command_timeout",
+            self.defaults.command_timeout.unwrap_or(self.timeout),
+        )?;
+        dict.set_item("connect_timeout", self.connect_timeout)?;
+        dict.set_item("retries", self.defaults.retries.unwrap_or(0))?;
+        dict.set_item("retry_delay", self.defaults.retry_delay.unwrap_or(1.0))?;
+        dict.set_item("encoding", encoding.as_deref().unwrap_or("utf-8"))?;
+        dict.set_item("buffer_size", self.buffer_size(None)?)?;
+        Ok(dict)
+    }
+
+    /// Returns the remote environment that commands run with, as a dict, read with `env -0`.
+    /// With `login_shell`, it's the environment of a bash login shell, as `execute(login_shell=True)` sees it.
+    /// Comparing the two often explains a command that works interactively but not through Hussh.
+    #[pyo3(signature = (login_shell=false))]
+    fn remote_env<'py>(&self, py: Python<'py>, login_shell: bool) -> PyResult<Bound<'py, PyDict>> {
+        let command = if login_shell {
+            login_shell_command("env -0")
+        } else {
+            "env -0".to_string()
+        };
+        let output = OutputOptions {
+            encodings: self.output_encodings(None)?,
+            ..Default::default()
+        };
+        let result = self.run_command(py, &command, None, Stdin::Empty, false, output)?;
+        let result = check_result(py, result, false)?;
+        let env = PyDict::new(py);
+        for (name, value) in parse_env(&result.stdout) {
+            env.set_item(name, value)?;
+        }
+        Ok(env)
+    }
+
+    /// Returns what the server supports as a dict, probed on first call and cached after that:
+    /// `sftp`, whether it offers an SFTP session, `setenv`, whether it accepts environment variables
+    /// sent on a channel (most only accept those listed in sshd's `AcceptEnv`), and `shell`,
+    /// whether the account can run commands, which it can't with a shell like `nologin`.
+    /// Pass `refresh=True` to probe again, e.g. after the server's configuration has changed.
+    /// With a `transfer_backend` of `auto`, the `sftp` result also picks between SFTP and SCP.
+    #[pyo3(signature = (refresh=false))]
+    fn capabilities<'py>(&self, py: Python<'py>, refresh: bool) -> PyResult<Bound<'py, PyDict>> {
+        let cached = *self
+            .capabilities
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let capabilities = match cached {
+            Some(capabilities) if !refresh => capabilities,
+            _ => {
+                let capabilities = self.probe_capabilities(py)?;
+                *self
+                    .capabilities
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner) = Some(capabilities);
+                capabilities
+            }
+        };
+        let dict = PyDict::new(py);
+        dict.set_item("sftp", capabilities.sftp)?;
+        dict.set_item("setenv", capabilities.setenv)?;
+        dict.set_item("shell", capabilities.shell)?;
+        Ok(dict)
+    }
+
+    /// Returns this connection's counters as a dict: `commands` executed, `command_time` (seconds),
+    /// `bytes_uploaded`/`bytes_downloaded` over SCP and SFTP, and `reconnects`, how many times the session
+    /// was established again after the first time, e.g. after a reboot.
+    fn stats<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let stats = PyDict::new(py);
+        stats.set_item("commands", self.stats.commands.load(Ordering::Relaxed))?;
+        let command_time_us = self.stats.command_time_us.load(Ordering::Relaxed);
+        stats.set_item("command_time", command_time_us as f64 / 1_000_000.0)?;
+        stats.set_item(
+            "bytes_uploaded",
+            self.stats.bytes_uploaded.load(Ordering::Relaxed),
+        )?;
+        stats.set_item(
+            "bytes_downloaded",
+            self.stats.bytes_downloaded.load(Ordering::Relaxed),
+        )?;
+        stats.set_item("reconnects", self.stats.reconnects.load(Ordering::Relaxed))?;
+        Ok(stats)
+    }
+
+    /// Returns what's known about the connection's transport as a dict: the negotiated `cipher`, `mac` and
+    /// `compression` (from the client to the server), and the kernel's counts for the TCP connection of
+    /// `bytes_sent` (acknowledged by the server), `bytes_received`, `packets_sent`, `packets_received`
+    /// and `retransmits`, since it was established. The counts are `None` where the kernel doesn't report them,
+    /// which is anywhere but Linux. Comparing bytes on the wire with `stats()` shows what compression saves.
+    fn transport_stats<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let session = self.open_session()?;
+        let stats = PyDict::new(py);
+        stats.set_item("cipher", session.methods(MethodType::CryptCs))?;
+        stats.set_item("mac", session.methods(MethodType::MacCs))?;
+        stats.set_item("compression", session.methods(MethodType::CompCs))?;
+        let counts = tcp_counts(&session);
+        for (i, name) in TCP_COUNTS.into_iter().enumerate() {
+            stats.set_item(name, counts.map(|counts| counts[i]))?;
+        }
+        Ok(stats)
+    }
+
+    /// Resets all of the counters returned by `stats`, so long-lived connections can sample deltas.
+    fn reset_stats(&self) {
+        self.stats.reset();
+    }
+
+    /// Returns a context manager that holds this connection's lock, so a group of operations
+    /// runs without other threads using the connection in between.
+    /// ```python
+    /// with conn.lock():
+    ///     conn.sftp_write_data("#!/bin/sh", "/root/script.sh")
+    ///     conn.execute("chmod +x /root/script.sh")
+    /// ```
+    fn lock(&self) -> ConnectionLock {
+        ConnectionLock {
+            lock: Arc::clone(&self.lock),
+            guards: Vec::new(),
+        }
+    }
+
+    /// Returns a `CommandBatch` that runs commands through a single remote shell.
+    /// Each command then costs a round trip, rather than the few it takes to open a channel,
+    /// but the commands share the shell's working directory and environment.
+    ///
+    /// ```python
+    /// with conn.batch() as batch:
+    ///     batch.execute("cd /tmp")
+    ///     print(batch.execute("pwd").stdout)  # /tmp
+    /// ```
+    fn batch<'py>(slf: &Bound<'py, Self>) -> PyResult<Bound<'py, CommandBatch>> {
+        let py = slf.py();
+        let conn = slf.borrow();
+        let _guard = conn.channel_guard(py, false)?;
+        let slot = conn.channels.take();
+        let mut channel = conn.open_session()?.channel_session().map_err(|e| {
+            conn.trace(|| format!("Failed to open session channel: {}", e));
+            channel_open_error(&e).unwrap_or_else(|| {
+                PyErr::new::<PyTimeoutError, _>(format!(
+                    "Timed out establishing channel session.\n{}",
+                    e
+                ))
+            })
+        })?;
+        conn.trace(|| "Opened session channel for a command batch".to_string());
+        channel
+            .exec("sh")
+            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Exec error: {}", e)))?;
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos());
+        let closed = Arc::default();
+        let batch = Bound::new(
+            py,
+            CommandBatch {
+                conn: slf.clone().unbind(),
+                channel: Some((channel, slot)),
+                token: format!("HUSSH_BATCH_{:x}_{:x}", std::process::id(), nanos),
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+                closed: Arc::clone(&closed),
+            },
+        )?;
+        conn.add_child(ChildKind::Batch, batch.as_any(), &closed)?;
+        Ok(batch)
+    }
+
+    /// Opens a channel to the SSH subsystem `name`, like `netconf`, and returns a `SubsystemChannel`
+    /// for exchanging raw bytes with it.
+    ///
+    /// ```python
+    /// with conn.open_subsystem("netconf") as netconf:
+    ///     hello = netconf.expect(b"]]>]]>")
+    /// ```
+    fn open_subsystem<'py>(
+        slf: &Bound<'py, Self>,
+        name: String,
+    ) -> PyResult<Bound<'py, SubsystemChannel>> {
+        let py = slf.py();
+        let conn = slf.borrow();
+        let _guard = conn.channel_guard(py, false)?;
+        let slot = conn.channels.take();
+        conn.open_session()?;
+        let mut channel = conn.open_channel(py)?;
+        conn.trace(|| format!("Opened session channel for the {} subsystem", name));
+        channel.subsystem(&name).map_err(|e| {
+            PyErr::new::<PyIOError, _>(format!("Subsystem {} request error: {}", name, e))
+        })?;
+        let closed = Arc::default();
+        let subsystem = Bound::new(
+            py,
+            SubsystemChannel {
+                conn: slf.clone().unbind(),
+                channel: Some((channel, slot)),
+                name,
+                pending: Vec::new(),
+                closed: Arc::clone(&closed),
+            },
+        )?;
+        conn.add_child(ChildKind::Subsystem, subsystem.as_any(), &closed)?;
+        Ok(subsystem)
+    }
+
+    /// Creates a uniquely named directory in `dir`, over SFTP, and returns a `RemoteTempPath` for it.
+    /// Used as a context manager, it gives the directory's path, and removes the directory and everything in it
+    /// on exit, even if the block raised. Failing to remove it is logged as a warning rather than raised.
+    ///
+    /// ```python
+    /// with conn.tempdir() as scratch:
+    ///     conn.sftp_write_data("data", f"{scratch}/input.txt")
+    /// ```
+    #[pyo3(
+        signature = (prefix="hussh-", dir=RemotePath("/tmp".to_string())),
+        text_signature = "($self, prefix=\"hussh-\", dir=\"/tmp\")"
+    )]
+    fn tempdir(slf: &Bound<'_, Self>, prefix: &str, dir: RemotePath) -> PyResult<RemoteTempPath> {
+        RemoteTempPath::create(slf, prefix, &dir, true)
+    }
+
+    /// Creates a uniquely named, empty file in `dir`, over SFTP, and returns a `RemoteTempPath` for it,
+    /// which removes the file on exit, as `tempdir` does.
+    #[pyo3(
+        signature = (prefix="hussh-", dir=RemotePath("/tmp".to_string())),
+        text_signature = "($self, prefix=\"hussh-\", dir=\"/tmp\")"
+    )]
+    fn tempfile(slf: &Bound<'_, Self>, prefix: &str, dir: RemotePath) -> PyResult<RemoteTempPath> {
+        RemoteTempPath::create(slf, prefix, &dir, false)
+    }
+
+    /// Executes a command over the SSH connection and returns the result.
+    /// `command` is a shell string, or a list of arguments that are quoted so the shell doesn't interpret them.
+    /// If `timeout` is provided, it temporarily updates the session timeout for the duration of the command execution.
+    /// If more than `max_output_bytes` of output arrives, reading stops, the channel is closed,
+    /// and the result is marked `truncated`, with a status of -1.
+    /// `stdout_file` and `stderr_file` stream that output to local files instead of the result,
+    /// which still records how many bytes there were.
+    /// `stdin_file` streams a local file to the command's stdin, followed by EOF, without reading it all into memory.
+    /// Output that arrives while it's streaming is held in memory until the input is sent.
+    /// `strip_ansi` removes terminal escape sequences, like colors, from the result's stdout and stderr,
+    /// and turns `\r\n` line endings into `\n`.
+    /// `encoding` decodes the output with any Python codec, instead of the remote locale found by
+    /// `detect_remote_locale` or UTF-8. Give a (stdout, stderr) pair, or a dict, to decode the two differently.
+    /// `retries` runs the command again, up to that many more times, `retry_delay` seconds apart (1 by default),
+    /// while it fails in a way `retry_on` lists: `"status"` (a non-zero exit status), `"timeout"`,
+    /// or a compiled regex searched for in stderr. By default, both a status and a timeout are retried.
+    /// The last result is returned, or its `TimeoutError` raised, and `attempts` lists how long each run took.
+    /// `login_shell` runs the command with `bash -lc`, so it sees the PATH and environment set by the user's profile,
+    /// which commands otherwise don't.
+    /// `combine_streams` also keeps the order stdout and stderr output arrived in, as the result's
+    /// `segments`, a list of (stream, text), and `combined`, the two as one string. It can't be used with
+    /// `max_output_bytes` or output files.
+    #[pyo3(signature = (command, timeout=None, max_output_bytes=None, stdout_file=None, stderr_file=None, stdin_file=None, strip_ansi=false, encoding=None, retries=None, retry_delay=None, retry_on=None, login_shell=false, combine_streams=false))]
+    #[allow(clippy::too_many_arguments)]
+    fn execute(
+        &self,
+        py: Python<'_>,
+        command: CommandArg,
+        timeout: Option<u32>,
+        max_output_bytes: Option<u64>,
+        stdout_file: Option<LocalPath>,
+        stderr_file: Option<LocalPath>,
+        stdin_file: Option<LocalPath>,
+        strip_ansi: bool,
+        encoding: Option<OutputEncoding>,
+        retries: Option<u32>,
+        retry_delay: Option<f64>,
+        retry_on: Option<&Bound<'_, PyAny>>,
+        login_shell: bool,
+        combine_streams: bool,
+    ) -> PyResult<SSHResult> {
+        if combine_streams
+            && (max_output_bytes.is_some() || stdout_file.is_some() || stderr_file.is_some())
+        {
+            return Err(PyErr::new::<PyValueError, _>(
+                "combine_streams can't be used with max_output_bytes, stdout_file or stderr_file",
+            ));
+        }
+        let command = command.into_command()?;
+        let exec = if login_shell {
+            login_shell_command(&command)
+        } else {
+            command.clone()
+        };
+        let retries = retries.or(self.defaults.retries).unwrap_or(0);
+        let retry_delay = retry_delay.or(self.defaults.retry_delay).unwrap_or(1.0);
+        let retry_delay = Duration::try_from_secs_f64(retry_delay).map_err(|_| {
+            PyErr::new::<PyValueError, _>(format!("Invalid retry_delay: {}", retry_delay))
+        })?;
+        let retry_on = RetryOn::parse(retry_on)?;
+        let encodings = self.output_encodings(encoding)?;
+        let create = |path: &Option<LocalPath>| {
+            path.as_ref()
+                .map(|path| {
+                    File::create(path).map_err(|e| {
+                        PyErr::new::<PyIOError, _>(format!("File create error: {}", e))
+                    })
+                })
+                .transpose()
+        };
+        let mut attempts = Vec::new();
+        loop {
+            // output files are started afresh, and stdin read from the start, for every attempt
+            let output = OutputOptions {
+                max_bytes: max_output_bytes,
+                stdout_file: create(&stdout_file)?,
+                stderr_file: create(&stderr_file)?,
+                strip_ansi,
+                encodings: encodings.clone(),
+                combine_streams,
+                ..Default::default()
+            };
+            let stdin = match &stdin_file {
+                Some(path) => Stdin::File(File::open(path).map_err(|e| {
+                    PyErr::new::<PyIOError, _>(format!("Local file open error: {}", e))
+                })?),
+                None => Stdin::Empty,
+            };
+            let start = Instant::now();
+            let result = self.run_command(py, &exec, timeout, stdin, false, output);
+            attempts.push(start.elapsed().as_secs_f64());
+            let retry = attempts.len() <= retries as usize
+                && match &result {
+                    Ok(result) => retry_on.iter().try_fold(false, |retry, condition| {
+                        Ok::<_, PyErr>(retry || condition.matches(py, result)?)
+                    })?,
+                    Err(e) => {
+                        e.is_instance_of::<PyTimeoutError>(py)
+                            && retry_on.iter().any(|c| matches!(c, RetryOn::Timeout))
+                    }
+                };
+            if !retry {
+                return result.map(|mut result| {
+                    result.command = Some(command);
+                    result.attempts = Some(attempts);
+                    result
+                });
+            }
+            log(py, "connection", INFO, || {
+                format!(
+                    "Retrying {:?} on {} in {:.1}s (attempt {} of {})",
+                    command,
+                    self.host,
+                    retry_delay.as_secs_f64(),
+                    attempts.len() + 1,
+                    retries + 1
+                )
+            });
+            py.allow_threads(|| thread::sleep(retry_delay));
+            py.check_signals()?;
+        }
+    }
+
+    /// Runs a command in the style of Fabric's `Connection.run`.
+    /// Output is echoed to the local stdout/stderr as it arrives, unless `hide` is `true`.
+    /// A non-zero exit status raises `CommandError` (with the result attached), unless `warn` is `true`.
+    #[pyo3(signature = (command, hide=false, warn=false, timeout=None))]
+    fn run(
+        &self,
+        py: Python<'_>,
+        command: CommandArg,
+        hide: bool,
+        warn: bool,
+        timeout: Option<u32>,
+    ) -> PyResult<SSHResult> {
+        let result = self.run_command(
+            py,
+            &command.into_command()?,
+            timeout,
+            Stdin::Empty,
+            !hide,
+            OutputOptions {
+                encodings: self.output_encodings(None)?,
+                ..Default::default()
+            },
+        )?;
+        check_result(py, result, warn)
+    }
+
+    /// Runs a command with sudo, in the style of Fabric's `Connection.sudo`.
+    /// If `password` isn't provided, the connection's password is used when it isn't a key passphrase.
+    /// Without any password, sudo is run non-interactively so it fails instead of waiting on a prompt.
+    /// `user` runs the command as someone other than root. `hide` and `warn` behave as in `run`.
+    #[pyo3(signature = (command, password=None, user=None, hide=false, warn=false, timeout=None))]
+    #[allow(clippy::too_many_arguments)]
+    fn sudo(
+        &self,
+        py: Python<'_>,
+        command: CommandArg,
+        password: Option<String>,
+        user: Option<String>,
+        hide: bool,
+        warn: bool,
+        timeout: Option<u32>,
+    ) -> PyResult<SSHResult> {
+        let command = command.into_command()?;
+        let secrets = [password.clone().unwrap_or_default(), self.password.clone()];
+        self.run_sudo(py, &command, password, user.as_deref(), hide, timeout)
+            .and_then(|mut result| {
+                result.command = Some(command);
+                check_result(py, result, warn)
+            })
+            .map_err(|e| redact_err(py, e, &[&secrets[0], &secrets[1]]))
+    }
+
+    /// Runs a command as another user, with `sudo -u` or `su -`.
+    /// With `via="sudo"`, this is the same as `sudo(command, user=user)`.
+    /// With `via="su"`, the command runs in the user's login shell, and a `password` is entered at
+    /// su's prompt over a pty, which merges stderr into stdout. Without one, su must not prompt,
+    /// as when the connection is root.
+    /// The result's `user` is set to `user`. `hide`, `warn` and `timeout` behave as in `run`.
+    #[pyo3(signature = (user, command, via="sudo", password=None, hide=false, warn=false, timeout=None))]
+    #[allow(clippy::too_many_arguments)]
+    fn run_as(
+        &self,
+        py: Python<'_>,
+        user: String,
+        command: CommandArg,
+        via: &str,
+        password: Option<String>,
+        hide: bool,
+        warn: bool,
+        timeout: Option<u32>,
+    ) -> PyResult<SSHResult> {
+        let command = command.into_command()?;
+        let secrets = [password.clone().unwrap_or_default(), self.password.clone()];
+        let result = match via {
+            "sudo" => self.run_sudo(py, &command, password, Some(&user), hide, timeout),
+            "su" => {
+                let su_cmd = format!("su - {} -c {}", shell_quote(&user), shell_quote(&command));
+                let output = OutputOptions {
+                    encodings: self.output_encodings(None)?,
+                    ..Default::default()
+                };
+                match password {
+                    Some(password) => {
+                        self.check_command(py, &su_cmd)?;
+                        let _guard = self.channel_guard(py, false)?;
+                        self.run_hooked(py, &su_cmd, timeout, || {
+                            self.run_on_pty(py, &su_cmd, &password, !hide, output)
+                        })
+                    }
+                    None => self.run_command(py, &su_cmd, timeout, Stdin::Empty, !hide, output),
+                }
+            }
+            _ => {
+                return Err(PyErr::new::<PyValueError, _>(format!(
+                    "Unknown run_as method {:?}, expected \"sudo\" or \"su\"",
+                    via
+                )))
+            }
+        };
+        result
+            .and_then(|mut result| {
+                result.command = Some(command);
+                result.user = Some(user);
+                check_result(py, result, warn)
+            })
+            .map_err(|e| redact_err(py, e, &[&secrets[0], &secrets[1]]))
+    }
+
+    /// Copies a local file to the remote system, in the style of Fabric's `Connection.put`.
+    /// This uses the connection's `transfer_backend`, so by default SFTP, or SCP on servers without it.
+    /// If `remote` is not provided, the local file is written to the same path on the remote system.
+    #[pyo3(signature = (local, remote=None))]
+    fn put(&self, py: Python<'_>, local: LocalPath, remote: Option<RemotePath>) -> PyResult<()> {
+        self.sftp_write(py, local, remote, None, None)
+    }
+
+    /// Copies a remote file to the local system, in the style of Fabric's `Connection.get`.
+    /// This uses the connection's `transfer_backend`, so by default SFTP, or SCP on servers without it.
+    /// If `local` is not provided, the file is saved to the current directory under its remote name.
+    #[pyo3(signature = (remote, local=None))]
+    fn get(&self, py: Python<'_>, remote: RemotePath, local: Option<LocalPath>) -> PyResult<()> {
+        self.get_file(py, remote, local, None)?;
+        Ok(())
+    }
+
+    /// Copies a local file to the remote system, over SFTP if the server has it or SCP if not,
+    /// and returns a dict of the `backend` used, the `bytes` copied and the `duration` in seconds.
+    /// If `remote` is not provided, the local file is written to the same path on the remote system.
+    /// `buffer_size` and `mode` work as they do for `sftp_write`.
+    #[pyo3(signature = (local, remote=None, buffer_size=None, mode=None))]
+    fn upload<'py>(
+        &self,
+        py: Python<'py>,
+        local: LocalPath,
+        remote: Option<RemotePath>,
+        buffer_size: Option<usize>,
+        mode: Option<u32>,
+    ) -> PyResult<Bound<'py, PyDict>> {
+        if Path::new(&expand_local_path(&local)?).is_dir() {
+            return Err(PyErr::new::<PyIOError, _>(format!(
+                "upload copies single files, and {} is a directory",
+                &*local
+            )));
+        }
+        let start = Instant::now();
+        let transfer = self.sftp_write_file(py, local, remote, buffer_size, mode)?;
+        transfer_result(py, transfer, start)
+    }
+
+    /// Copies a remote file to the local system, over SFTP if the server has it or SCP if not,
+    /// and returns a dict of the `backend` used, the `bytes` copied and the `duration` in seconds.
+    /// If `local` is not provided, the file is saved to the current directory under its remote name.
+    /// `buffer_size` works as it does for `sftp_read`.
+    #[pyo3(signature = (remote, local=None, buffer_size=None))]
+    fn download<'py>(
+        &self,
+        py: Python<'py>,
+        remote: RemotePath,
+        local: Option<LocalPath>,
+        buffer_size: Option<usize>,
+    ) -> PyResult<Bound<'py, PyDict>> {
+        let start = Instant::now();
+        let transfer = self.get_file(py, remote, local, buffer_size)?;
+        transfer_result(py, transfer, start)
+    }
+
+    /// Reads a file over SCP and returns the contents.
+    /// If `local_path` is provided, the file is saved to the local system.
+    /// Otherwise, the contents of the file are returned as a string, or as bytes if `binary` is set.
+    /// `max_bytes` limits how large a file can be returned, raising a `FileTooLargeError` for a larger one.
+    /// `buffer_size` sets how much is read at a time, between 4 KiB and 16 MiB (64 KiB by default).
+    #[pyo3(signature = (remote_path, local_path=None, buffer_size=None, binary=false, max_bytes=None))]
+    fn scp_read<'py>(
+        &self,
+        py: Python<'py>,
+        remote_path: RemotePath,
+        local_path: Option<LocalPath>,
+        buffer_size: Option<usize>,
+        binary: bool,
+        max_bytes: Option<u64>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        self.scp_read_file(py, remote_path, local_path, buffer_size, binary, max_bytes)
+            .map(|(contents, _)| contents)
+    }
+
+    /// Writes a file over SCP.
+    /// `buffer_size` sets how much is read at a time, between 4 KiB and 16 MiB (64 KiB by default).
+    /// `mode` sets the permissions a new file is created with, like 0o600, in place of `default_file_mode`.
+    /// Over SCP, a file that already exists keeps its permissions.
+    #[pyo3(signature = (local_path, remote_path, buffer_size=None, mode=None))]
+    fn scp_write(
+        &self,
+        py: Python<'_>,
+        local_path: LocalPath,
+        remote_path: RemotePath,
+        buffer_size: Option<usize>,
+        mode: Option<u32>,
+    ) -> PyResult<()> {
+        self.scp_write_file(py, local_path, remote_path, buffer_size, mode)?;
+        Ok(())
+    }
+
+    /// Writes data over SCP. `mode` works as it does for `scp_write`.
+    #[pyo3(signature = (data, remote_path, mode=None))]
+    fn scp_write_data(
+        &self,
+        py: Python<'_>,
+        data: String,
+        remote_path: RemotePath,
+        mode: Option<u32>,
+    ) -> PyResult<()> {
+        let mode = self.file_mode(mode)?.unwrap_or(DEFAULT_FILE_MODE);
+        let _guard = self.channel_guard(py, false)?;
+        let remote_path = self.expand_remote_path(remote_path.0)?;
+        let start = Instant::now();
+        let mut remote_file = self
+            .open_session()?
+            .scp_send(Path::new(&remote_path), mode, data.len() as u64, None)
+            .map_err(|e| {
+                channel_open_error(&e)
+                    .unwrap_or_else(|| PyErr::new::<PyIOError, _>(format!("scp_send error: {}", e)))
+            })?;
+        py.allow_threads(|| {
+            remote_file
+                .write_all(data.as_bytes())
+                .map_err(|e| PyErr::new::<PyIOError, _>(format!("Data write error: {}", e)))?;
+            finish_scp_send(&mut remote_file, &remote_path)
         })?;
-        remote_file
-            .write_all(data.as_bytes())
-            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Data write error: {}", e)))?;
-        remote_file
-            .close()
-            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Close error: {}", e)))?;
+        self.record_transfer(py, "scp", true, data.len() as u64, &remote_path, start);
+        Ok(())
+    }
+
+    /// Reads a file over SFTP and returns the contents.
+    /// If `local_path` is provided, the file is saved to the local system.
+    /// Otherwise, the contents of the file are returned as a string, or as bytes if `binary` is set.
+    /// `max_bytes` limits how large a file can be returned, raising a `FileTooLargeError` for a larger one.
+    /// `buffer_size` sets how much is read at a time, between 4 KiB and 16 MiB (64 KiB by default).
+    /// With a `transfer_backend` of `scp`, or `auto` on a server without SFTP, this reads over SCP instead.
+    #[pyo3(signature = (remote_path, local_path=None, buffer_size=None, binary=false, max_bytes=None))]
+    fn sftp_read<'py>(
+        &self,
+        py: Python<'py>,
+        remote_path: RemotePath,
+        local_path: Option<LocalPath>,
+        buffer_size: Option<usize>,
+        binary: bool,
+        max_bytes: Option<u64>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        self.sftp_read_file(py, remote_path, local_path, buffer_size, binary, max_bytes)
+            .map(|(contents, _)| contents)
+    }
+
+    /// Writes a file over SFTP. If `remote_path` is not provided, the local file is written to the same path on the remote system.
+    /// `buffer_size` sets how much is read at a time, between 4 KiB and 16 MiB (64 KiB by default).
+    /// With a `transfer_backend` of `scp`, or `auto` on a server without SFTP, this writes over SCP instead.
+    /// `mode` sets the file's permissions, like 0o600, in place of `default_file_mode`.
+    /// It's applied as the file is opened, before anything is written, even if the file already exists.
+    #[pyo3(signature = (local_path, remote_path=None, buffer_size=None, mode=None))]
+    fn sftp_write(
+        &self,
+        py: Python<'_>,
+        local_path: LocalPath,
+        remote_path: Option<RemotePath>,
+        buffer_size: Option<usize>,
+        mode: Option<u32>,
+    ) -> PyResult<()> {
+        self.sftp_write_file(py, local_path, remote_path, buffer_size, mode)?;
+        Ok(())
+    }
+
+    /// Writes data over SFTP, or SCP as `transfer_backend` directs. `mode` works as it does for `sftp_write`.
+    #[pyo3(signature = (data, remote_path, mode=None))]
+    fn sftp_write_data(
+        &self,
+        py: Python<'_>,
+        data: String,
+        remote_path: RemotePath,
+        mode: Option<u32>,
+    ) -> PyResult<()> {
+        if self.use_scp(py)? {
+            return self.scp_write_data(py, data, remote_path, mode);
+        }
+        let mode = self.file_mode(mode)?;
+        let _guard = self.channel_guard(py, true)?;
+        let remote_path = self.expand_remote_path(remote_path.0)?;
+        let start = Instant::now();
+        let mut remote_file = self.sftp_create(&remote_path, mode)?;
+        py.allow_threads(|| {
+            remote_file
+                .write_all(data.as_bytes())
+                .map_err(|e| PyErr::new::<PyIOError, _>(format!("Data write error: {}", e)))?;
+            remote_file
+                .close()
+                .map_err(|e| PyErr::new::<PyIOError, _>(format!("Close error: {}", e)))
+        })?;
+        self.record_transfer(py, "sftp", true, data.len() as u64, &remote_path, start);
+        Ok(())
+    }
+
+    /// Returns the size and free space of the filesystem holding `remote_path` as a dict of
+    /// `block_size`, `blocks`, `blocks_free`, `blocks_available` (free to non-root users), `bytes_total`
+    /// and `bytes_free`, which is the space available to non-root users, like `df` reports.
+    /// This uses the statvfs@openssh.com SFTP extension, so servers without it raise `SFTPUnavailableError`.
+    /// `disk_free` falls back to running `df` instead.
+    fn sftp_statvfs<'py>(
+        &self,
+        py: Python<'py>,
+        remote_path: RemotePath,
+    ) -> PyResult<Bound<'py, PyDict>> {
+        let _guard = self.channel_guard(py, true)?;
+        let remote_path = self.expand_remote_path(remote_path.0)?;
+        let stat = self
+            .with_sftp(|sftp| {
+                let path = Path::new(&remote_path);
+                // directories can't be opened as files, so take a directory handle for them
+                let mut handle = sftp.opendir(path).or_else(|_| sftp.open(path))?;
+                handle.statvfs()
+            })?
+            .map_err(|e| {
+                if e.code() == ssh2::ErrorCode::SFTP(SSH_FX_OP_UNSUPPORTED) {
+                    PyErr::new::<SFTPUnavailableError, _>(format!(
+                        "The server doesn't support the statvfs SFTP extension: {}",
+                        e
+                    ))
+                } else {
+                    PyErr::new::<PyIOError, _>(format!(
+                        "SFTP statvfs error for {}: {}",
+                        remote_path, e
+                    ))
+                }
+            })?;
+        // f_frsize is the unit the block counts are in, though some servers only fill in f_bsize
+        let block_size = if stat.f_frsize > 0 {
+            stat.f_frsize
+        } else {
+            stat.f_bsize
+        };
+        let usage = PyDict::new(py);
+        usage.set_item("block_size", block_size)?;
+        usage.set_item("blocks", stat.f_blocks)?;
+        usage.set_item("blocks_free", stat.f_bfree)?;
+        usage.set_item("blocks_available", stat.f_bavail)?;
+        usage.set_item("bytes_total", stat.f_blocks.saturating_mul(block_size))?;
+        usage.set_item("bytes_free", stat.f_bavail.saturating_mul(block_size))?;
+        Ok(usage)
+    }
+
+    /// Returns how many bytes non-root users can write to the filesystem holding `remote_path`,
+    /// e.g. to check there's room before an upload. This is `sftp_statvfs(remote_path)["bytes_free"]`,
+    /// or on servers without SFTP or its statvfs extension, the available space `df` reports.
+    fn disk_free(&self, py: Python<'_>, remote_path: RemotePath) -> PyResult<u64> {
+        if !self.use_scp(py)? {
+            match self.sftp_statvfs(py, RemotePath(remote_path.0.clone())) {
+                Ok(usage) => return usage.as_any().get_item("bytes_free")?.extract(),
+                Err(e) if e.is_instance_of::<SFTPUnavailableError>(py) => {
+                    log(py, "sftp", DEBUG, || {
+                        format!("Falling back to df on {}: {}", self.host, e)
+                    });
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        let remote_path = {
+            let _guard = self.channel_guard(py, false)?;
+            self.expand_remote_path(remote_path.0)?
+        };
+        let command = format!("df -Pk -- {}", shell_quote(&remote_path));
+        let output = OutputOptions {
+            encodings: self.output_encodings(None)?,
+            ..Default::default()
+        };
+        let result = self.run_command(py, &command, None, Stdin::Empty, false, output)?;
+        let result = check_result(py, result, false)?;
+        parse_df_available(&result.stdout)
+            .map(|kib| kib.saturating_mul(1024))
+            .ok_or_else(|| {
+                PyErr::new::<PyIOError, _>(format!(
+                    "Couldn't read the free space for {} from df: {}",
+                    remote_path, result.stdout
+                ))
+            })
+    }
+
+    /// Walks the remote directory tree at `remote_path` over SFTP, without downloading anything.
+    /// Returns a dict of `files`, `dirs` (below `remote_path`), `bytes` (the total size of the files)
+    /// and `skipped`, the directories that couldn't be read, like ones without permission.
+    /// Symlinks are counted as files and not followed.
+    fn sftp_du<'py>(
+        &self,
+        py: Python<'py>,
+        remote_path: RemotePath,
+    ) -> PyResult<Bound<'py, PyDict>> {
+        let _guard = self.channel_guard(py, true)?;
+        let remote_path = self.expand_remote_path(remote_path.0)?;
+        let root = self
+            .with_sftp(|sftp| sftp.readdir(Path::new(&remote_path)))?
+            .map_err(|e| {
+                PyErr::new::<PyIOError, _>(format!("SFTP readdir error for {}: {}", remote_path, e))
+            })?;
+        let sftp = self.sftp()?;
+        let (mut files, mut dirs, mut bytes, mut skipped) = (0u64, 0u64, 0u64, 0u64);
+        let mut pending = vec![root];
+        while let Some(entries) = pending.pop() {
+            for (path, stat) in entries {
+                if stat.is_dir() {
+                    dirs += 1;
+                    match sftp.readdir(&path) {
+                        Ok(entries) => pending.push(entries),
+                        Err(_) => skipped += 1,
+                    }
+                } else {
+                    files += 1;
+                    bytes += stat.size.unwrap_or(0);
+                }
+            }
+        }
+        let usage = PyDict::new(py);
+        usage.set_item("files", files)?;
+        usage.set_item("dirs", dirs)?;
+        usage.set_item("bytes", bytes)?;
+        usage.set_item("skipped", skipped)?;
+        Ok(usage)
+    }
+
+    // Copy a file from this connection to another connection
+    #[pyo3(signature = (source_path, dest_conn, dest_path=None))]
+    fn remote_copy(
+        &self,
+        py: Python<'_>,
+        source_path: RemotePath,
+        dest_conn: &Connection,
+        dest_path: Option<RemotePath>,
+    ) -> PyResult<()> {
+        // take both locks in a consistent order, so opposing copies can't deadlock
+        let (_first, _second) = if Arc::as_ptr(&self.lock) < Arc::as_ptr(&dest_conn.lock) {
+            (self.lock.acquire(py), dest_conn.lock.acquire(py))
+        } else {
+            (dest_conn.lock.acquire(py), self.lock.acquire(py))
+        };
+        // a defaulted dest_path is expanded against the destination's own home
+        let dest_path = dest_conn
+            .expand_remote_path(dest_path.map_or_else(|| source_path.0.clone(), |p| p.0))?;
+        let source_path = self.expand_remote_path(source_path.0)?;
+        let mut remote_file = BufReader::new(
+            self.with_sftp(|sftp| sftp.open(Path::new(&source_path)))?
+                .map_err(|e| PyErr::new::<PyIOError, _>(format!("Remote open error: {}", e)))?,
+        );
+        let mut other_file = dest_conn.sftp_create(&dest_path, dest_conn.file_mode(None)?)?;
+        log(py, "sftp", DEBUG, || {
+            format!(
+                "Copying {} on {} to {} on {}",
+                source_path, self.host, dest_path, dest_conn.host
+            )
+        });
+        let start = Instant::now();
+        let total = py.allow_threads(|| {
+            let mut buffer = vec![0; MAX_BUFF_SIZE];
+            let mut total: u64 = 0;
+            loop {
+                let len = remote_file
+                    .read(&mut buffer)
+                    .map_err(|e| PyErr::new::<PyIOError, _>(format!("File read error: {}", e)))?;
+                if len == 0 {
+                    return Ok::<_, PyErr>(total);
+                }
+                other_file
+                    .write_all(&buffer[..len])
+                    .map_err(|e| PyErr::new::<PyIOError, _>(format!("File write error: {}", e)))?;
+                total += len as u64;
+            }
+        })?;
+        self.record_transfer(py, "sftp", false, total, &source_path, start);
+        dest_conn.record_transfer(py, "sftp", true, total, &dest_path, start);
+        Ok(())
+    }
+
+    /// Return a FileTailer instance given a remote file path
+    /// This is best used as a context manager, but can be used directly
+    /// ```python
+    /// with conn.tail("remote_file.log") as tailer:
+    ///     time.sleep(5)  # wait or perform other operations
+    ///     print(tailer.read())
+    ///     time.sleep(5)  # wait or perform other operations
+    /// print(tailer.contents)
+    /// ```
+    /// `from_pos` starts reading at a byte offset instead of the current end of the file.
+    /// `checkpoint` resumes from a dict returned by `FileTailer.checkpoint()`, starting over at
+    /// the beginning if the file has since been rotated or truncated.
+    /// `suppress_cleanup_errors` overrides the connection's setting for errors reading the file on exit.
+    /// `wait_for_file` waits up to that many seconds for a file that doesn't exist yet, like the log of
+    /// a service still starting, raising a `TimeoutError` if it never appears.
+    /// Without it, tailing a missing file raises a `FileNotFoundError` when the tailer starts.
+    #[pyo3(signature = (
+        remote_file=None, from_pos=None, checkpoint=None, suppress_cleanup_errors=None,
+        wait_for_file=None
+    ))]
+    fn tail<'py>(
+        &self,
+        py: Python<'py>,
+        remote_file: Option<RemotePath>,
+        from_pos: Option<u64>,
+        checkpoint: Option<TailCheckpoint>,
+        suppress_cleanup_errors: Option<bool>,
+        wait_for_file: Option<f64>,
+    ) -> PyResult<Bound<'py, FileTailer>> {
+        let Some(checkpoint) = checkpoint else {
+            let remote_file = remote_file.ok_or_else(|| {
+                PyErr::new::<PyValueError, _>("tail needs a remote_file or a checkpoint")
+            })?;
+            let tailer = FileTailer::new(py, self, remote_file, from_pos)?;
+            if let Some(timeout) = wait_for_file {
+                tailer.wait_for_file(py, timeout)?;
+            }
+            return self.add_tailer(py, tailer, suppress_cleanup_errors);
+        };
+        if from_pos.is_some() {
+            return Err(PyErr::new::<PyValueError, _>(
+                "Pass from_pos or checkpoint to tail, not both",
+            ));
+        }
+        let mut tailer = FileTailer::new(
+            py,
+            self,
+            remote_file.unwrap_or_else(|| RemotePath(checkpoint.path.clone())),
+            None,
+        )?;
+        if tailer.remote_file != checkpoint.path {
+            return Err(PyErr::new::<PyValueError, _>(format!(
+                "The checkpoint is for {}, not {}",
+                checkpoint.path, tailer.remote_file
+            )));
+        }
+        if let Some(timeout) = wait_for_file {
+            tailer.wait_for_file(py, timeout)?;
+        }
+        tailer.init_pos = Some(tailer.lock.hold(py, || tailer.resume_pos(&checkpoint))?);
+        self.add_tailer(py, tailer, suppress_cleanup_errors)
+    }
+
+    /// Close the connection's session.
+    /// Shells, command batches, subsystem channels and tailers opened from the connection are closed first,
+    /// in that order, and raise `ChannelClosedError` if they're used afterwards.
+    /// Closing is idempotent, and a session the peer has already dropped is closed without error.
+    pub(crate) fn close(&self, py: Python<'_>) -> PyResult<()> {
+        let _guard = self.lock.acquire(py);
+        if self.closed.swap(true, Ordering::Relaxed) {
+            return Ok(());
+        }
+        self.trace(|| "Disconnecting".to_string());
+        self.close_children(py);
+        *self
+            .sftp_conn
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = None;
+        if self.connected() {
+            let session = self.session();
+            let _ = py.allow_threads(|| session.disconnect(None, "Bye from Hussh", None));
+        }
+        self.call_after_hooks(py, "on_close", (&self.host,).into_pyobject(py)?, None)
+    }
+
+    /// Returns the shells, command batches, subsystem channels and tailers opened from the connection
+    /// that are still open, in the order they were opened.
+    fn children<'py>(&self, py: Python<'py>) -> Vec<Bound<'py, PyAny>> {
+        self.children
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .filter_map(|child| child.live(py))
+            .collect()
+    }
+
+    /// Registers `hook` to be called on `event`:
+    ///
+    /// * `before_execute(command)`: before a command runs. Raising vetoes the command.
+    /// * `after_execute(command, result)`: after a command completes with an `SSHResult`.
+    /// * `on_connect(host)`: once the connection is authenticated. Raising aborts the connection.
+    /// * `on_close(host)`: after the connection is closed.
+    ///
+    /// Every `after_execute` and `on_close` hook runs even if another raises.
+    /// Their exceptions are then raised together as a `HookError`, with the command's result as its `result`.
+    /// Since connecting happens on construction, pass `on_connect` hooks with the `hooks` argument of `Connection`.
+    fn add_hook(&self, event: &str, hook: &Bound<'_, PyAny>) -> PyResult<()> {
+        let event = HOOK_EVENTS
+            .into_iter()
+            .find(|e| *e == event)
+            .ok_or_else(|| {
+                PyErr::new::<PyValueError, _>(format!(
+                    "Unknown hook event '{}', expected one of: {}",
+                    event,
+                    HOOK_EVENTS.join(", ")
+                ))
+            })?;
+        if !hook.is_callable() {
+            return Err(PyErr::new::<PyTypeError, _>(format!(
+                "The {} hook must be callable",
+                event
+            )));
+        }
+        self.hooks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(event)
+            .or_default()
+            .push(hook.clone().unbind());
         Ok(())
     }
 
-    // Copy a file from this connection to another connection
-    #[pyo3(signature = (source_path, dest_conn, dest_path=None))]
-    fn remote_copy(
-        &self,
-        source_path: String,
-        dest_conn: &mut Connection,
-        dest_path: Option<String>,
-    ) -> PyResult<()> {
-        let mut remote_file = BufReader::new(
-            self.session
-                .sftp()
-                .map_err(|e| PyErr::new::<PyIOError, _>(format!("SFTP error: {}", e)))?
-                .open(Path::new(&source_path))
-                .map_err(|e| PyErr::new::<PyIOError, _>(format!("Remote open error: {}", e)))?,
-        );
-        let dest_path = dest_path.unwrap_or_else(|| source_path.clone());
-        let mut other_file = dest_conn
-            .sftp()
-            .create(Path::new(&dest_path))
-            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Dest file creation error: {}", e)))?;
-        let mut buffer = vec![0; MAX_BUFF_SIZE];
-        loop {
-            let len = remote_file
-                .read(&mut buffer)
-                .map_err(|e| PyErr::new::<PyIOError, _>(format!("File read error: {}", e)))?;
-            if len == 0 {
-                break;
-            }
-            other_file
-                .write_all(&buffer[..len])
-                .map_err(|e| PyErr::new::<PyIOError, _>(format!("File write error: {}", e)))?;
+    /// Provide an enter for the context manager
+    fn __enter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    /// Provide an exit for the context manager
+    /// This will close the session. An error closing it is raised only if the block didn't raise,
+    /// and `suppress_cleanup_errors` isn't set. Otherwise it's logged as a warning.
+    #[pyo3(signature = (_exc_type=None, _exc_value=None, _traceback=None))]
+    fn __exit__(
+        &self,
+        py: Python<'_>,
+        _exc_type: Option<&Bound<'_, PyAny>>,
+        _exc_value: Option<&Bound<'_, PyAny>>,
+        _traceback: Option<&Bound<'_, PyAny>>,
+    ) -> PyResult<()> {
+        let closed = self.close(py);
+        exit_context(
+            py,
+            "the connection",
+            closed,
+            _exc_value,
+            self.suppress_cleanup_errors,
+        )
+    }
+
+    /// The command policy, a callable or a tuple of compiled regexes, or `None` if there isn't one.
+    #[getter]
+    fn command_policy<'py>(&self, py: Python<'py>) -> PyResult<Option<Bound<'py, PyAny>>> {
+        self.command_policy
+            .as_ref()
+            .map(|policy| policy.object(py))
+            .transpose()
+    }
+
+    /// The password, which reads as `***` unless redaction is turned off with `hussh.redact_enabled(False)`.
+    #[getter]
+    fn password(&self) -> &str {
+        if redact_enabled(None) && !self.password.is_empty() {
+            REDACTED
+        } else {
+            &self.password
+        }
+    }
+
+    fn __repr__(&self) -> PyResult<String> {
+        Ok(format!(
+            "Connection(host={}, port={}, username={}, password=*****)",
+            self.host, self.port, self.username
+        ))
+    }
+
+    /// Creates an `InteractiveShell` instance.
+    /// If `pty` is `true`, a pseudo-terminal is requested for the shell.
+    /// `set_prompt` gives the shell a unique prompt, for `send_and_read`, and needs `pty`.
+    /// `strip_ansi` removes terminal escape sequences, like colors, from the output the shell returns.
+    /// `suppress_cleanup_errors` overrides the connection's setting for errors reading the shell's result on exit.
+    /// `suppress_noise` waits for the shell to be ready and discards whatever it printed first, like a MOTD
+    /// or output from the user's rc files, so it doesn't end up in the result. It's kept in the shell's `preamble`.
+    /// Note: This is best used as a context manager
+    /// ```python
+    /// with conn.shell() as shell:
+    ///     shell.send("ls")
+    ///     shell.send("pwd")
+    /// print(shell.result.stdout)
+    /// ```
+    #[pyo3(signature = (pty=None, set_prompt=false, strip_ansi=false, suppress_cleanup_errors=None, suppress_noise=false))]
+    fn shell<'py>(
+        &self,
+        py: Python<'py>,
+        pty: Option<bool>,
+        set_prompt: bool,
+        strip_ansi: bool,
+        suppress_cleanup_errors: Option<bool>,
+        suppress_noise: bool,
+    ) -> PyResult<Bound<'py, InteractiveShell>> {
+        if set_prompt && pty != Some(true) {
+            return Err(PyErr::new::<PyValueError, _>(
+                "Setting the prompt needs a shell with pty=True",
+            ));
+        }
+        let _guard = self.channel_guard(py, false)?;
+        let slot = self.channels.take();
+        let mut channel = self.open_session()?.channel_session().map_err(|e| {
+            channel_open_error(&e).unwrap_or_else(|| {
+                PyErr::new::<PyTimeoutError, _>(format!(
+                    "Timed out establishing channel session.\n{}",
+                    e
+                ))
+            })
+        })?;
+        self.trace(|| {
+            format!(
+                "Opened session channel for a shell (pty: {})",
+                pty.unwrap_or(false)
+            )
+        });
+        if let Some(pty) = pty {
+            if pty {
+                channel
+                    .request_pty("xterm", None, None)
+                    .map_err(|e| PyErr::new::<PyIOError, _>(format!("PTY request error: {}", e)))?;
+            }
+        }
+        channel
+            .shell()
+            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Shell request error: {}", e)))?;
+        let mut shell = InteractiveShell {
+            channel: ChannelWrapper { channel },
+            pty: pty.unwrap_or(false),
+            result: None,
+            slot: Some(Arc::new(slot)),
+            session: Some(self.session()),
+            prompt: None,
+            preamble: None,
+            strip_ansi,
+            pending: Vec::new(),
+            command_policy: self.command_policy.clone(),
+            closed: Arc::default(),
+            suppress_cleanup_errors: suppress_cleanup_errors
+                .unwrap_or(self.suppress_cleanup_errors),
+            lock: Arc::clone(&self.lock),
+        };
+        if suppress_noise {
+            shell.skip_noise(py)?;
+        }
+        if set_prompt {
+            shell.set_prompt(py, None)?;
+        }
+        let closed = Arc::clone(&shell.closed);
+        let shell = Bound::new(py, shell)?;
+        self.add_child(ChildKind::Shell, shell.as_any(), &closed)?;
+        Ok(shell)
+    }
+}
+
+// Best-effort cleanup for connections that are garbage collected without being closed.
+// on_close hooks need the GIL, so they only run on an explicit close.
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if !*self.closed.get_mut() && self.connected() {
+            *self
+                .sftp_conn
+                .get_mut()
+                .unwrap_or_else(PoisonError::into_inner) = None;
+            let _ = self.session().disconnect(None, "Bye from Hussh", None);
+        }
+    }
+}
+
+/// Holds a connection's lock while its context is entered. Returned by `Connection.lock()`.
+#[pyclass]
+pub struct ConnectionLock {
+    lock: Arc<SessionLock>,
+    guards: Vec<SessionGuard>,
+}
+
+#[pymethods]
+impl ConnectionLock {
+    fn __enter__(mut slf: PyRefMut<Self>) -> PyRefMut<Self> {
+        let guard = slf.lock.acquire(slf.py());
+        slf.guards.push(guard);
+        slf
+    }
+
+    #[pyo3(signature = (_exc_type=None, _exc_value=None, _traceback=None))]
+    fn __exit__(
+        &mut self,
+        _exc_type: Option<&Bound<'_, PyAny>>,
+        _exc_value: Option<&Bound<'_, PyAny>>,
+        _traceback: Option<&Bound<'_, PyAny>>,
+    ) {
+        self.guards.pop();
+    }
+}
+
+// Read from `stream` into `pending` until `marker` arrives, returning what came before it.
+// Anything read past the marker is left in `pending`.
+fn read_until<R: Read>(
+    stream: &mut R,
+    pending: &mut Vec<u8>,
+    marker: &[u8],
+    name: &str,
+) -> PyResult<Vec<u8>> {
+    let mut searched = 0;
+    loop {
+        if let Some(pos) = pending[searched..]
+            .windows(marker.len())
+            .position(|w| w == marker)
+        {
+            let pos = searched + pos;
+            let before = pending[..pos].to_vec();
+            pending.drain(..pos + marker.len());
+            return Ok(before);
+        }
+        searched = pending.len().saturating_sub(marker.len() - 1);
+        let mut buffer = [0; 8192];
+        let len = stream.read(&mut buffer).map_err(|e| {
+            PyErr::new::<PyTimeoutError, _>(format!("Timeout reading {}: {}", name, e))
+        })?;
+        if len == 0 {
+            return Err(PyErr::new::<PyIOError, _>(
+                "The shell exited before the command finished",
+            ));
+        }
+        pending.extend_from_slice(&buffer[..len]);
+    }
+}
+
+/// Runs commands one after another through a single remote shell. Returned by `Connection.batch()`.
+///
+/// Each command's end is marked on stdout and stderr with a token unique to the batch,
+/// which is how the output and exit status of each command are told apart.
+/// Commands share the shell's working directory and environment, and read stdin from `/dev/null`.
+/// A command that exits the shell, or has a syntax error, ends the batch.
+#[pyclass(weakref)]
+pub struct CommandBatch {
+    conn: Py<Connection>,
+    channel: Option<(Channel, ChannelSlot)>,
+    token: String,
+    // output read past the end of the last command
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    // shared with the connection, which sets it when it closes the batch
+    closed: Arc<AtomicBool>,
+}
+
+impl Release for CommandBatch {
+    fn release(&mut self) {
+        if let Some((mut channel, _slot)) = self.channel.take() {
+            let _ = channel.close();
+        }
+    }
+}
+
+// Take a batched command's output on one stream from `pending`, once its end `marker` has arrived,
+// along with the exit status line after it on stdout. Anything after them is left in `pending`.
+// `searched` is how far the marker's known not to start, so each new chunk is only searched once.
+fn take_marked(
+    pending: &mut Vec<u8>,
+    searched: &mut usize,
+    marker: &[u8],
+    with_status: bool,
+) -> Option<(Vec<u8>, Vec<u8>)> {
+    let Some(pos) = pending[*searched..]
+        .windows(marker.len())
+        .position(|w| w == marker)
+    else {
+        *searched = pending.len().saturating_sub(marker.len() - 1);
+        return None;
+    };
+    let pos = *searched + pos;
+    *searched = pos;
+    let rest = pos + marker.len();
+    let status_len = if with_status {
+        pending[rest..].iter().position(|&b| b == b'\n')? + 1
+    } else {
+        0
+    };
+    let status = pending[rest..rest + status_len].to_vec();
+    let before = pending[..pos].to_vec();
+    pending.drain(..rest + status_len);
+    *searched = 0;
+    Some((before, status))
+}
+
+impl CommandBatch {
+    // Run one command through the shell, or return an error if the batch can't be used any more.
+    // Both streams are read together until both end markers arrive, with the session non-blocking,
+    // so a command that fills the channel's window with stderr can't stall its stdout.
+    // The caller holds the connection's lock.
+    fn run(
+        &mut self,
+        command: &str,
+        watch: &PeerWatch,
+        encodings: &[Option<String>; 2],
+    ) -> PyResult<SSHResult> {
+        let (channel, _) = self
+            .channel
+            .as_mut()
+            .ok_or_else(|| PyErr::new::<ChannelClosedError, _>("The command batch is closed"))?;
+        let script = format!(
+            "{{ :\n{}\n}} </dev/null\nprintf '\\n%s %s\\n' {token} \"$?\"\nprintf '\\n%s\\n' {token} >&2\n",
+            command,
+            token = self.token
+        );
+        channel
+            .write_all(script.as_bytes())
+            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Shell write error: {}", e)))?;
+        let markers = [
+            format!("\n{} ", self.token).into_bytes(),
+            format!("\n{}\n", self.token).into_bytes(),
+        ];
+        let mut pending = [&mut self.stdout, &mut self.stderr];
+        let mut searched = [0, 0];
+        let mut outputs: [Option<(Vec<u8>, Vec<u8>)>; 2] = [None, None];
+        let mut buffer = vec![0; MAX_BUFF_SIZE];
+        let mut quiet_since = Instant::now();
+        watch.session.set_blocking(false);
+        let read = (|| {
+            while outputs.iter().any(Option::is_none) {
+                let mut progressed = false;
+                for id in 0..2 {
+                    if outputs[id].is_some() {
+                        continue;
+                    }
+                    outputs[id] =
+                        take_marked(pending[id], &mut searched[id], &markers[id], id == 0);
+                    if outputs[id].is_some() {
+                        continue;
+                    }
+                    let len = match channel.stream(id as i32).read(&mut buffer) {
+                        Ok(len) => len,
+                        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                        Err(e) => return Err(read_error(STREAM_NAMES[id], e)),
+                    };
+                    if len == 0 {
+                        return Err(PyErr::new::<PyIOError, _>(
+                            "The shell exited before the command finished",
+                        ));
+                    }
+                    progressed = true;
+                    pending[id].extend_from_slice(&buffer[..len]);
+                }
+                if progressed {
+                    quiet_since = Instant::now();
+                } else if outputs.iter().any(Option::is_none) {
+                    watch
+                        .check(quiet_since)
+                        .map_err(|e| read_error("output", e))?;
+                    watch.wait();
+                }
+            }
+            Ok(())
+        })();
+        watch.session.set_blocking(true);
+        read?;
+        let [Some((stdout, status)), Some((stderr, _))] = outputs else {
+            unreachable!("both markers arrived");
+        };
+        let status = String::from_utf8_lossy(&status)
+            .trim()
+            .parse()
+            .map_err(|_| {
+                PyErr::new::<PyIOError, _>("Malformed exit status from the batch shell")
+            })?;
+        let decode = |output: Vec<u8>, id: usize| match encodings[id]
+            .as_deref()
+            .filter(|e| !is_utf8(e))
+        {
+            Some(encoding) => decode_output(output, STREAM_NAMES[id], Some(encoding), false),
+            None => String::from_utf8(output).map_err(|e| {
+                PyErr::new::<PyIOError, _>(format!("Error decoding {}: {}", STREAM_NAMES[id], e))
+            }),
+        };
+        Ok(SSHResult {
+            stdout_bytes: stdout.len() as u64,
+            stderr_bytes: stderr.len() as u64,
+            stdout: decode(stdout, 0)?,
+            stderr: decode(stderr, 1)?,
+            status,
+            command: Some(command.to_string()),
+            truncated: false,
+            user: None,
+            attempts: None,
+            segments: None,
+        })
+    }
+}
+
+#[pymethods]
+impl CommandBatch {
+    /// Executes a command through the batch's shell and returns the result.
+    /// `command` is a shell string, or a list of arguments that are quoted so the shell doesn't interpret them.
+    /// If `isolated` is `true`, the command runs on its own channel, as with `Connection.execute`,
+    /// so it doesn't see or change the batch's working directory and environment.
+    /// If reading a command's output fails or times out, the batch can't be used any more.
+    #[pyo3(signature = (command, timeout=None, isolated=false))]
+    fn execute(
+        &mut self,
+        py: Python<'_>,
+        command: CommandArg,
+        timeout: Option<u32>,
+        isolated: bool,
+    ) -> PyResult<SSHResult> {
+        let command = command.into_command()?;
+        let conn = self.conn.clone_ref(py);
+        let conn = conn.borrow(py);
+        let encodings = conn.output_encodings(None)?;
+        if isolated {
+            let output = OutputOptions {
+                encodings,
+                ..Default::default()
+            };
+            return conn.run_command(py, &command, timeout, Stdin::Empty, false, output);
+        }
+        conn.check_command(py, &command)?;
+        conn.run_hooked(py, &command, timeout, || {
+            let watch = PeerWatch {
+                session: conn.session(),
+                dead_peer_timeout: conn.dead_peer_timeout,
+                started: Instant::now(),
+            };
+            let result = py.allow_threads(|| self.run(&command, &watch, &encodings));
+            if result.is_err() {
+                self.channel = None;
+            }
+            result
+        })
+    }
+
+    /// Ends the batch's shell. Closing is idempotent.
+    fn close(&mut self, py: Python<'_>) -> PyResult<()> {
+        self.closed.store(true, Ordering::Relaxed);
+        let Some((mut channel, _slot)) = self.channel.take() else {
+            return Ok(());
+        };
+        let lock = Arc::clone(&self.conn.borrow(py).lock);
+        lock.hold(py, || {
+            channel
+                .write_all(b"exit\n")
+                .and_then(|_| channel.send_eof().map_err(Into::into))
+                .and_then(|_| channel.wait_close().map_err(Into::into))
+        })
+        .map_err(|e: std::io::Error| {
+            PyErr::new::<PyIOError, _>(format!("Shell close error: {}", e))
+        })
+    }
+
+    fn __enter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    #[pyo3(signature = (_exc_type=None, _exc_value=None, _traceback=None))]
+    fn __exit__(
+        &mut self,
+        py: Python<'_>,
+        _exc_type: Option<&Bound<'_, PyAny>>,
+        _exc_value: Option<&Bound<'_, PyAny>>,
+        _traceback: Option<&Bound<'_, PyAny>>,
+    ) -> PyResult<()> {
+        let suppress = self.conn.borrow(py).suppress_cleanup_errors;
+        let closed = self.close(py);
+        exit_context(py, "the command batch", closed, _exc_value, suppress)
+    }
+}
+
+/// A channel to an SSH subsystem, such as `netconf`, returned by `Connection.open_subsystem()`.
+///
+/// Data goes back and forth as bytes, exactly as the subsystem frames it.
+#[pyclass(weakref)]
+pub struct SubsystemChannel {
+    conn: Py<Connection>,
+    channel: Option<(Channel, ChannelSlot)>,
+    #[pyo3(get)]
+    name: String,
+    // output read past the last expected pattern
+    pending: Vec<u8>,
+    // shared with the connection, which sets it when it closes the channel
+    closed: Arc<AtomicBool>,
+}
+
+impl Release for SubsystemChannel {
+    fn release(&mut self) {
+        if let Some((mut channel, _slot)) = self.channel.take() {
+            let _ = channel.close();
+        }
+    }
+}
+
+impl SubsystemChannel {
+    fn channel(&mut self) -> PyResult<&mut Channel> {
+        match self.channel.as_mut() {
+            Some((channel, _)) => Ok(channel),
+            None => Err(PyErr::new::<ChannelClosedError, _>(format!(
+                "The {} subsystem channel is closed",
+                self.name
+            ))),
+        }
+    }
+}
+
+#[pymethods]
+impl SubsystemChannel {
+    /// Sends `data`, as bytes or a string encoded as UTF-8, with nothing added.
+    fn send(&mut self, py: Python<'_>, data: &Bound<'_, PyAny>) -> PyResult<()> {
+        let data = match data.downcast::<PyBytes>() {
+            Ok(bytes) => bytes.as_bytes().to_vec(),
+            Err(_) => data.extract::<String>()?.into_bytes(),
+        };
+        let lock = Arc::clone(&self.conn.borrow(py).lock);
+        let channel = self.channel()?;
+        lock.hold(py, || {
+            channel.write_all(&data).and_then(|_| channel.flush())
+        })
+        .map_err(|e| PyErr::new::<PyIOError, _>(format!("Subsystem write error: {}", e)))
+    }
+
+    /// Returns up to `size` bytes that have already arrived, without waiting for more.
+    /// An empty result means nothing has arrived yet, or the subsystem has closed, as `eof` tells.
+    #[pyo3(signature = (size=65536))]
+    fn read_nonblocking<'py>(
+        &mut self,
+        py: Python<'py>,
+        size: usize,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        if !self.pending.is_empty() {
+            let len = size.min(self.pending.len());
+            let data: Vec<u8> = self.pending.drain(..len).collect();
+            return Ok(PyBytes::new(py, &data));
+        }
+        let conn = self.conn.clone_ref(py);
+        let conn = conn.borrow(py);
+        let session = conn.session();
+        let mut buffer = vec![0; size];
+        let channel = self.channel()?;
+        let read = conn.lock.hold(py, || {
+            session.set_blocking(false);
+            let read = channel.read(&mut buffer);
+            session.set_blocking(true);
+            read
+        });
+        let len = match read {
+            Ok(len) => len,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => 0,
+            Err(e) => {
+                return Err(PyErr::new::<PyIOError, _>(format!(
+                    "Subsystem read error: {}",
+                    e
+                )))
+            }
+        };
+        Ok(PyBytes::new(py, &buffer[..len]))
+    }
+
+    /// Reads until `pattern` (bytes or a string) arrives, and returns what came before it.
+    /// Anything after the pattern is kept for the next read.
+    /// If `timeout` (ms) is given, it replaces the session timeout while waiting, raising `TimeoutError` if it passes.
+    #[pyo3(signature = (pattern, timeout=None))]
+    fn expect<'py>(
+        &mut self,
+        py: Python<'py>,
+        pattern: &Bound<'_, PyAny>,
+        timeout: Option<u32>,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        let pattern = match pattern.downcast::<PyBytes>() {
+            Ok(bytes) => bytes.as_bytes().to_vec(),
+            Err(_) => pattern.extract::<String>()?.into_bytes(),
+        };
+        if pattern.is_empty() {
+            return Err(PyErr::new::<PyValueError, _>(
+                "The pattern to expect is empty",
+            ));
         }
-        Ok(())
+        let conn = self.conn.clone_ref(py);
+        let conn = conn.borrow(py);
+        let session = conn.session();
+        let Some((channel, _)) = self.channel.as_mut() else {
+            return Err(PyErr::new::<ChannelClosedError, _>(format!(
+                "The {} subsystem channel is closed",
+                self.name
+            )));
+        };
+        let (pending, name) = (&mut self.pending, &self.name);
+        let output = conn.lock.hold(py, || {
+            let original_timeout = session.timeout();
+            if let Some(t) = timeout {
+                session.set_timeout(t);
+            }
+            let output = read_until(channel, pending, &pattern, name);
+            session.set_timeout(original_timeout);
+            output
+        });
+        let output = output.map_err(|e| {
+            if channel.eof() {
+                PyErr::new::<PyIOError, _>(format!(
+                    "The {} subsystem closed before {:?} arrived",
+                    self.name,
+                    String::from_utf8_lossy(&pattern)
+                ))
+            } else {
+                e
+            }
+        })?;
+        Ok(PyBytes::new(py, &output))
     }
 
-    /// Return a FileTailer instance given a remote file path
-    /// This is best used as a context manager, but can be used directly
-    /// ```python
-    /// with conn.tail("remote_file.log") as tailer:
-    ///     time.sleep(5)  # wait or perform other operations
-    ///     print(tailer.read())
-    ///     time.sleep(5)  # wait or perform other operations
-    /// print(tailer.contents)
-    /// ```
-    fn tail(&self, remote_file: String) -> FileTailer {
-        FileTailer::new(self, remote_file, None)
+    /// `True` once the subsystem has closed its end and everything it sent has been read.
+    #[getter]
+    fn eof(&self) -> bool {
+        self.pending.is_empty()
+            && self
+                .channel
+                .as_ref()
+                .is_none_or(|(channel, _)| channel.eof())
     }
 
-    /// Close the connection's session
-    fn close(&self) -> PyResult<()> {
-        self.session
-            .disconnect(None, "Bye from Hussh", None)
-            .unwrap();
-        Ok(())
+    /// Closes the channel. Closing is idempotent.
+    fn close(&mut self, py: Python<'_>) -> PyResult<()> {
+        self.closed.store(true, Ordering::Relaxed);
+        let Some((mut channel, _slot)) = self.channel.take() else {
+            return Ok(());
+        };
+        let lock = Arc::clone(&self.conn.borrow(py).lock);
+        lock.hold(py, || {
+            channel
+                .send_eof()
+                .and_then(|_| channel.close())
+                .and_then(|_| channel.wait_close())
+        })
+        .map_err(|e| PyErr::new::<PyIOError, _>(format!("Subsystem close error: {}", e)))
     }
 
-    /// Provide an enter for the context manager
     fn __enter__(slf: PyRef<Self>) -> PyRef<Self> {
         slf
     }
 
-    /// Provide an exit for the context manager
-    /// This will close the session
     #[pyo3(signature = (_exc_type=None, _exc_value=None, _traceback=None))]
     fn __exit__(
         &mut self,
+        py: Python<'_>,
         _exc_type: Option<&Bound<'_, PyAny>>,
         _exc_value: Option<&Bound<'_, PyAny>>,
         _traceback: Option<&Bound<'_, PyAny>>,
     ) -> PyResult<()> {
-        let _ = self.close();
-        Ok(())
+        let suppress = self.conn.borrow(py).suppress_cleanup_errors;
+        let closed = self.close(py);
+        let what = format!("the {} subsystem channel", self.name);
+        exit_context(py, &what, closed, _exc_value, suppress)
     }
+}
 
-    fn __repr__(&self) -> PyResult<String> {
-        Ok(format!(
-            "Connection(host={}, port={}, username={}, password=*****)",
-            self.host, self.port, self.username
-        ))
-    }
+/// A temporary remote directory or file, returned by `Connection.tempdir()` and `Connection.tempfile()`.
+/// Entering it gives its path, and leaving it, or calling `cleanup`, removes it.
+#[pyclass]
+pub struct RemoteTempPath {
+    conn: Py<Connection>,
+    #[pyo3(get)]
+    path: String,
+    is_dir: bool,
+    removed: bool,
+}
 
-    /// Creates an `InteractiveShell` instance.
-    /// If `pty` is `true`, a pseudo-terminal is requested for the shell.
-    /// Note: This is best used as a context manager
-    /// ```python
-    /// with conn.shell() as shell:
-    ///     shell.send("ls")
-    ///     shell.send("pwd")
-    /// print(shell.result.stdout)
-    /// ```
-    #[pyo3(signature = (pty=None))]
-    fn shell(&self, pty: Option<bool>) -> PyResult<InteractiveShell> {
-        let mut channel = self.session.channel_session().unwrap();
-        if let Some(pty) = pty {
-            if pty {
-                channel.request_pty("xterm", None, None).unwrap();
-            }
+impl RemoteTempPath {
+    fn create(
+        conn: &Bound<'_, Connection>,
+        prefix: &str,
+        dir: &str,
+        is_dir: bool,
+    ) -> PyResult<RemoteTempPath> {
+        if prefix.contains('/') {
+            return Err(PyErr::new::<PyValueError, _>(format!(
+                "The prefix can't contain a '/': {}",
+                prefix
+            )));
         }
-        channel.shell().unwrap();
-        Ok(InteractiveShell {
-            channel: ChannelWrapper { channel },
-            pty: pty.unwrap_or(false),
-            result: None,
+        let py = conn.py();
+        let conn_ref = conn.borrow();
+        let _guard = conn_ref.channel_guard(py, true)?;
+        let path = conn_ref.make_temp(prefix, dir, is_dir)?;
+        log(py, "sftp", DEBUG, || {
+            format!("Created temporary {} on {}", path, conn_ref.host)
+        });
+        Ok(RemoteTempPath {
+            conn: conn.clone().unbind(),
+            path,
+            is_dir,
+            removed: false,
         })
     }
 }
 
+#[pymethods]
+impl RemoteTempPath {
+    /// Removes the directory and everything in it, or the file. Removing is idempotent.
+    /// Returns whether it's gone; a failure is logged as a warning rather than raised.
+    fn cleanup(&mut self, py: Python<'_>) -> bool {
+        if self.removed {
+            return true;
+        }
+        let conn = self.conn.borrow(py);
+        let removed = conn
+            .channel_guard(py, true)
+            .and_then(|_guard| conn.remove_tree(&self.path, self.is_dir));
+        match removed {
+            Ok(()) => self.removed = true,
+            Err(e) => log(py, "sftp", WARNING, || {
+                format!("Couldn't remove {} on {}: {}", self.path, conn.host, e)
+            }),
+        }
+        self.removed
+    }
+
+    fn __enter__(&self) -> String {
+        self.path.clone()
+    }
+
+    #[pyo3(signature = (_exc_type=None, _exc_value=None, _traceback=None))]
+    fn __exit__(
+        &mut self,
+        py: Python<'_>,
+        _exc_type: Option<&Bound<'_, PyAny>>,
+        _exc_value: Option<&Bound<'_, PyAny>>,
+        _traceback: Option<&Bound<'_, PyAny>>,
+    ) {
+        self.cleanup(py);
+    }
+
+    fn __repr__(&self) -> String {
+        format!("RemoteTempPath({:?})", self.path)
+    }
+}
+
 #[pyclass]
 #[derive(Clone)]
 pub struct ChannelWrapper {
     channel: Channel,
 }
 
-#[pyclass]
+#[pyclass(weakref)]
 #[derive(Clone)]
 pub struct InteractiveShell {
     channel: ChannelWrapper,
     pty: bool,
     #[pyo3(get)]
     result: Option<SSHResult>,
+    // shared by clones, and released once the shell is read or closed
+    slot: Option<Arc<ChannelSlot>>,
+    // the session, for setting read timeouts
+    session: Option<Session>,
+    #[pyo3(get)]
+    prompt: Option<String>,
+    // what the shell printed before it was ready, if opened with suppress_noise
+    #[pyo3(get)]
+    preamble: Option<String>,
+    strip_ansi: bool,
+    // output read past the last prompt
+    pending: Vec<u8>,
+    // the connection's command policy, which what's sent to the shell is checked against
+    command_policy: Option<Arc<CommandPolicy>>,
+    // shared by clones and the connection, which sets it when it closes the shell
+    closed: Arc<AtomicBool>,
+    suppress_cleanup_errors: bool,
+    // the connection's lock, held for each read and write on the shell
+    lock: Arc<SessionLock>,
+}
+
+impl Release for InteractiveShell {
+    fn release(&mut self) {
+        self.slot = None;
+        let _ = self.channel.channel.close();
+    }
+}
+
+impl InteractiveShell {
+    // Tidy up output for returning, as the shell was asked to
+    fn clean(&self, output: &str) -> String {
+        if self.strip_ansi {
+            ansi::strip_ansi(output)
+        } else {
+            output.replace("\r\n", "\n")
+        }
+    }
+
+    fn check_open(&self) -> PyResult<()> {
+        if self.closed.load(Ordering::Relaxed) {
+            return Err(PyErr::new::<ChannelClosedError, _>("The shell is closed"));
+        }
+        Ok(())
+    }
+
+    // Have the shell print a unique line, and set aside everything before it as the preamble,
+    // so later reads start with the output of the caller's own commands
+    fn skip_noise(&mut self, py: Python<'_>) -> PyResult<()> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos());
+        let marker = format!("hussh-ready-{:x}_{:x}", std::process::id(), nanos);
+        // the quotes split the marker in the command a pty echoes, so only the printed line matches it
+        let command = format!("printf '%s\\n' '{}'", marker.replacen('-', "''-", 1));
+        self.write(py, &format!("{}\n", command))?;
+        let line_end = if self.pty { "\r\n" } else { "\n" };
+        let marker = format!("{}{}", marker, line_end);
+        let (channel, pending) = (&mut self.channel.channel, &mut self.pending);
+        let preamble = self.lock.hold(py, || {
+            read_until(channel, pending, marker.as_bytes(), "stdout")
+        })?;
+        let mut preamble = self.clean(&String::from_utf8_lossy(&preamble));
+        // a pty echoes the command after the prompt, and neither is part of what the shell printed
+        if let Some(pos) = preamble.rfind(&command) {
+            preamble.truncate(preamble[..pos].rfind('\n').map_or(0, |end| end + 1));
+        }
+        self.preamble = Some(preamble);
+        Ok(())
+    }
+
+    // Write to the shell as is, for what Hussh sends itself, which the command policy isn't applied to
+    fn write(&mut self, py: Python<'_>, data: &str) -> PyResult<()> {
+        self.check_open()?;
+        let channel = &mut self.channel.channel;
+        self.lock
+            .hold(py, || channel.write_all(data.as_bytes()))
+            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Shell write error: {}", e)))
+    }
+
+    fn known_prompt(&self) -> PyResult<String> {
+        self.prompt.clone().ok_or_else(|| {
+            PyErr::new::<PyValueError, _>(
+                "The shell has no known prompt, call set_prompt or open it with set_prompt=True",
+            )
+        })
+    }
 }
 
 #[pymethods]
@@ -633,24 +6048,124 @@ impl InteractiveShell {
             channel,
             pty,
             result: None,
+            slot: None,
+            session: None,
+            prompt: None,
+            preamble: None,
+            strip_ansi: false,
+            pending: Vec::new(),
+            command_policy: None,
+            closed: Arc::default(),
+            suppress_cleanup_errors: false,
+            lock: Arc::default(),
+        }
+    }
+
+    /// Replaces the shell's prompt with one unique to this shell, so `wait_for_prompt` and `send_and_read`
+    /// can tell exactly where a command's output ends. Also clears `PS2` and `PROMPT_COMMAND`,
+    /// and turns off bash's bracketed paste. Returns what the shell printed before the new prompt, like a MOTD.
+    /// This needs a pty, since shells don't prompt without one. Call it again after starting another shell.
+    #[pyo3(signature = (timeout=None))]
+    fn set_prompt(&mut self, py: Python<'_>, timeout: Option<u32>) -> PyResult<String> {
+        if !self.pty {
+            return Err(PyErr::new::<PyValueError, _>(
+                "Setting the prompt needs a shell with pty=True",
+            ));
         }
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos());
+        let marker = format!("{:x}_{:x}", std::process::id(), nanos);
+        // the quotes split the prompt in the echoed command, so only the real prompt matches it
+        self.write(
+            py,
+            &format!(
+            "PS1='<hussh''-{}> '; PS2=''; unset PROMPT_COMMAND; bind 'set enable-bracketed-paste off' 2>/dev/null\n",
+            marker
+        ),
+        )?;
+        self.prompt = Some(format!("<hussh-{}> ", marker));
+        self.wait_for_prompt(py, timeout)
+    }
+
+    /// Reads until the shell shows the prompt set by `set_prompt`, and returns the output before it.
+    /// `\r\n` line endings become `\n`, and escape sequences are removed if the shell has `strip_ansi` set.
+    /// If `timeout` (ms) is given, it replaces the session timeout while waiting, raising `TimeoutError` if it passes.
+    #[pyo3(signature = (timeout=None))]
+    fn wait_for_prompt(&mut self, py: Python<'_>, timeout: Option<u32>) -> PyResult<String> {
+        self.check_open()?;
+        let prompt = self.known_prompt()?;
+        let (session, channel, pending) =
+            (&self.session, &mut self.channel.channel, &mut self.pending);
+        let output = self.lock.hold(py, || {
+            let original_timeout = session.as_ref().map(Session::timeout);
+            if let (Some(session), Some(t)) = (session, timeout) {
+                session.set_timeout(t);
+            }
+            let output = read_until(channel, pending, prompt.as_bytes(), "stdout");
+            if let (Some(session), Some(t)) = (session, original_timeout) {
+                session.set_timeout(t);
+            }
+            output
+        });
+        Ok(self.clean(&String::from_utf8_lossy(&output?)))
+    }
+
+    /// Sends a command to the shell, then waits for the prompt and returns the command's output,
+    /// without the echoed command or the trailing prompt. The shell needs a prompt from `set_prompt`.
+    /// ```python
+    /// with conn.shell(pty=True, set_prompt=True) as shell:
+    ///     shell.send_and_read("cd /var/log")
+    ///     print(shell.send_and_read("ls"))
+    /// ```
+    #[pyo3(signature = (command, timeout=None))]
+    fn send_and_read(
+        &mut self,
+        py: Python<'_>,
+        command: String,
+        timeout: Option<u32>,
+    ) -> PyResult<String> {
+        // don't send a command we can't read the output of
+        self.known_prompt()?;
+        let echoed = command.lines().count().max(1);
+        self.send(py, command, None)?;
+        let output = self.wait_for_prompt(py, timeout)?;
+        Ok(output.split_inclusive('\n').skip(echoed).collect())
     }
 
     /// Reads the output from the shell and returns an `SSHResult`.
     /// Note: This sends an EOF to the shell, so you won't be able to send more commands after calling `read`.
-    fn read(&mut self) -> PyResult<SSHResult> {
-        self.channel
-            .channel
-            .flush()
-            .map_err(|e| PyErr::new::<PyTimeoutError, _>(format!("Channel flush error: {}", e)))?;
-        self.channel
-            .channel
-            .send_eof()
-            .map_err(|e| PyErr::new::<PyTimeoutError, _>(format!("Send EOF error: {}", e)))?;
-        match read_from_channel(&mut self.channel.channel) {
-            Ok(result) => Ok(result),
+    fn read(&mut self, py: Python<'_>) -> PyResult<SSHResult> {
+        self.check_open()?;
+        let _slot = self.slot.take();
+        let channel = &mut self.channel.channel;
+        let read = self.lock.hold(py, || {
+            channel.flush().map_err(|e| {
+                PyErr::new::<PyTimeoutError, _>(format!("Channel flush error: {}", e))
+            })?;
+            channel
+                .send_eof()
+                .map_err(|e| PyErr::new::<PyTimeoutError, _>(format!("Send EOF error: {}", e)))?;
+            read_output(channel, None, false, OutputOptions::default())
+        });
+        match read {
+            Ok(mut result) => {
+                if !self.pending.is_empty() {
+                    let pending = std::mem::take(&mut self.pending);
+                    result.stdout_bytes += pending.len() as u64;
+                    result
+                        .stdout
+                        .insert_str(0, &String::from_utf8_lossy(&pending));
+                }
+                if self.strip_ansi {
+                    result.stdout = ansi::strip_ansi(&result.stdout);
+                    result.stderr = ansi::strip_ansi(&result.stderr);
+                }
+                Ok(result)
+            }
             Err(e) => {
-                self.channel.channel.close().map_err(|e| {
+                let channel = &mut self.channel.channel;
+                self.lock.hold(py, || channel.close()).map_err(|e| {
                     PyErr::new::<PyTimeoutError, _>(format!("Channel close error: {}", e))
                 })?;
                 self.result = None;
@@ -661,22 +6176,32 @@ impl InteractiveShell {
 
     /// Sends a command to the shell.
     /// If you don't want to add a newline at the end of the command, set `add_newline` to `false`.
+    /// If the connection has a `command_policy`, a command it denies raises a `CommandPolicyError` instead.
     #[pyo3(signature = (data, add_newline=None))]
-    fn send(&mut self, data: String, add_newline: Option<bool>) -> PyResult<()> {
+    fn send(&mut self, py: Python<'_>, data: String, add_newline: Option<bool>) -> PyResult<()> {
+        self.check_open()?;
+        if let Some(policy) = &self.command_policy {
+            policy.check(py, data.trim_end_matches('\n'))?;
+        }
         let add_newline = add_newline.unwrap_or(true);
         let data = if add_newline && !data.ends_with('\n') {
             format!("{}\n", data)
         } else {
             data
         };
-        self.channel.channel.write_all(data.as_bytes()).unwrap();
-        Ok(())
+        self.write(py, &data)
     }
 
-    /// Closes the shell.
-    fn close(&mut self) -> PyResult<()> {
-        self.channel.channel.close().unwrap();
-        Ok(())
+    /// Closes the shell. Closing is idempotent.
+    fn close(&mut self, py: Python<'_>) -> PyResult<()> {
+        if self.closed.swap(true, Ordering::Relaxed) {
+            return Ok(());
+        }
+        self.slot = None;
+        let channel = &mut self.channel.channel;
+        self.lock
+            .hold(py, || channel.close())
+            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Shell close error: {}", e)))
     }
 
     fn __enter__(slf: PyRef<Self>) -> PyRef<Self> {
@@ -686,15 +6211,29 @@ impl InteractiveShell {
     #[pyo3(signature = (_exc_type=None, _exc_value=None, _traceback=None))]
     fn __exit__(
         &mut self,
+        py: Python<'_>,
         _exc_type: Option<&Bound<'_, PyAny>>,
         _exc_value: Option<&Bound<'_, PyAny>>,
         _traceback: Option<&Bound<'_, PyAny>>,
     ) -> PyResult<()> {
-        if self.pty {
-            self.send("exit\n".to_string(), Some(false)).unwrap();
+        // a shell closed along with its connection has nothing left to read
+        if self.closed.load(Ordering::Relaxed) {
+            return Ok(());
         }
-        self.result = Some(self.read()?);
-        Ok(())
+        let read = (|| {
+            if self.pty {
+                self.write(py, "exit\n")?;
+            }
+            self.result = Some(self.read(py)?);
+            Ok(())
+        })();
+        exit_context(
+            py,
+            "the shell",
+            read,
+            _exc_value,
+            self.suppress_cleanup_errors,
+        )
     }
 }
 
@@ -702,25 +6241,74 @@ impl InteractiveShell {
 ///
 /// It maintains an SFTP connection and the path to a remote file,
 /// and allows reading from a specified position in the file.
+/// Without SFTP, it runs `wc` and `tail` over exec channels instead.
 ///
 /// # Fields
 ///
-/// * `sftp_conn`: An SFTP connection from the ssh2 crate.
+/// * `source`: Whether the file is read over SFTP or with exec'd commands.
+/// * `session`: The session the tailer's channels are opened on.
 /// * `remote_file`: A string representing the path to the remote file.
 /// * `init_pos`: An optional initial position from where to start reading the file.
 /// * `last_pos`: The last position read from the file.
 /// * `contents`: The contents read from the file.
+/// * `started`: Whether the tailer has been started.
 ///
 /// # Methods
 ///
 /// * `new`: Constructs a new `FileTailer`.
 /// * `seek_end`: Seeks to the end of the remote file.
 /// * `read`: Reads the contents of the remote file from a given position.
+/// * `checkpoint`: Returns the file's path, inode and read position, to resume from later.
+/// * `start`: Starts tailing, from the initial position or the end of the file.
+/// * `stop`: Reads everything since the start into `contents`.
 /// * `__enter__`: Prepares the `FileTailer` for use in a `with` statement.
 /// * `__exit__`: Cleans up after the `FileTailer` is used in a `with` statement.
-#[pyclass]
+// Where a FileTailer reads the remote file from
+enum TailSource {
+    Sftp(ssh2::Sftp),
+    // commands run on a short-lived channel for each read
+    Exec,
+}
+
+impl TailSource {
+    // Run a command for the tailer, raising an error with its stderr if it fails
+    fn exec(session: &Session, command: &str, op: &str, remote_file: &str) -> PyResult<SSHResult> {
+        let mut channel = session.channel_session().map_err(|e| {
+            channel_open_error(&e).unwrap_or_else(|| {
+                PyErr::new::<PyIOError, _>(format!("Channel session error: {}", e))
+            })
+        })?;
+        channel
+            .exec(command)
+            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Exec error: {}", e)))?;
+        let result = read_from_channel(&mut channel)?;
+        if result.status != 0 {
+            return Err(PyErr::new::<PyIOError, _>(format!(
+                "{} error for {}: {}",
+                op,
+                remote_file,
+                result.stderr.trim()
+            )));
+        }
+        Ok(result)
+    }
+}
+
+// Where a tailer left off, as returned by `FileTailer.checkpoint()`
+#[derive(FromPyObject)]
+pub struct TailCheckpoint {
+    #[pyo3(item)]
+    path: String,
+    #[pyo3(item)]
+    pos: u64,
+    #[pyo3(item)]
+    inode: Option<u64>,
+}
+
+#[pyclass(weakref)]
 pub struct FileTailer {
-    sftp_conn: ssh2::Sftp,
+    source: TailSource,
+    session: Session,
     #[pyo3(get)]
     remote_file: String,
     init_pos: Option<u64>,
@@ -728,66 +6316,423 @@ pub struct FileTailer {
     last_pos: u64,
     #[pyo3(get)]
     contents: Option<String>,
+    #[pyo3(get)]
+    started: bool,
+    // an SFTP session holds one of the connection's channels open
+    _slot: Option<ChannelSlot>,
+    // shared with the connection, which sets it when it closes the tailer
+    closed: Arc<AtomicBool>,
+    suppress_cleanup_errors: bool,
+    // the connection's lock, held while the tailer reads the remote file
+    lock: Arc<SessionLock>,
+}
+
+impl Release for FileTailer {
+    fn release(&mut self) {
+        // dropping the SFTP session ends it while the connection is still up
+        self.source = TailSource::Exec;
+        self._slot = None;
+    }
+}
+
+impl FileTailer {
+    fn check_open(&self) -> PyResult<()> {
+        if self.closed.load(Ordering::Relaxed) {
+            return Err(PyErr::new::<ChannelClosedError, _>(format!(
+                "The tailer for {} is closed",
+                self.remote_file
+            )));
+        }
+        Ok(())
+    }
+
+    // Raised when the remote file doesn't exist, as a FileNotFoundError so it can be told apart
+    fn not_found(&self) -> PyErr {
+        PyFileNotFoundError::new_err((
+            libc::ENOENT,
+            "No such remote file",
+            self.remote_file.clone(),
+        ))
+    }
+
+    // An SFTP error for the remote file, with its status code so a missing file can be told apart
+    fn sftp_error(&self, op: &str, e: ssh2::Error) -> PyErr {
+        match e.code() {
+            ssh2::ErrorCode::SFTP(SSH_FX_NO_SUCH_FILE) => self.not_found(),
+            ssh2::ErrorCode::SFTP(status) => PyErr::new::<PyIOError, _>(format!(
+                "{} error for {} (SFTP status {}): {}",
+                op, self.remote_file, status, e
+            )),
+            _ => {
+                PyErr::new::<PyIOError, _>(format!("{} error for {}: {}", op, self.remote_file, e))
+            }
+        }
+    }
+
+    // Whether the remote file exists
+    fn exists(&self) -> PyResult<bool> {
+        match &self.source {
+            TailSource::Sftp(sftp) => match sftp.stat(Path::new(&self.remote_file)) {
+                Ok(_) => Ok(true),
+                Err(e) if e.code() == ssh2::ErrorCode::SFTP(SSH_FX_NO_SUCH_FILE) => Ok(false),
+                Err(e) => Err(self.sftp_error("Stat", e)),
+            },
+            TailSource::Exec => {
+                let command = format!(
+                    "test -e {} && echo 1 || echo 0",
+                    shell_quote(&self.remote_file)
+                );
+                let result = TailSource::exec(&self.session, &command, "Stat", &self.remote_file)?;
+                Ok(result.stdout.trim() == "1")
+            }
+        }
+    }
+
+    // Run a command on the remote file, raising a FileNotFoundError if it failed because the file is missing
+    fn exec_on_file(&self, command: &str, op: &str) -> PyResult<SSHResult> {
+        TailSource::exec(&self.session, command, op, &self.remote_file).map_err(|e| {
+            match self.exists() {
+                Ok(false) => self.not_found(),
+                _ => e,
+            }
+        })
+    }
+
+    // Wait up to `timeout` seconds for the remote file to exist
+    fn wait_for_file(&self, py: Python<'_>, timeout: f64) -> PyResult<()> {
+        let limit = Duration::try_from_secs_f64(timeout).map_err(|_| {
+            PyErr::new::<PyValueError, _>(format!("Invalid wait_for_file: {}", timeout))
+        })?;
+        let start = Instant::now();
+        while !self.lock.hold(py, || self.exists())? {
+            let remaining = limit.saturating_sub(start.elapsed());
+            if remaining.is_zero() {
+                return Err(PyErr::new::<PyTimeoutError, _>(format!(
+                    "{} didn't appear within {}s",
+                    self.remote_file, timeout
+                )));
+            }
+            py.allow_threads(|| thread::sleep(TAIL_WAIT_INTERVAL.min(remaining)));
+            py.check_signals()?;
+        }
+        Ok(())
+    }
+
+    // The size of the remote file
+    fn remote_size(&self) -> PyResult<Option<u64>> {
+        Ok(match &self.source {
+            TailSource::Sftp(sftp) => {
+                sftp.stat(Path::new(&self.remote_file))
+                    .map_err(|e| self.sftp_error("Stat", e))?
+                    .size
+            }
+            TailSource::Exec => {
+                let command = format!("wc -c < {}", shell_quote(&self.remote_file));
+                let result = self.exec_on_file(&command, "Stat")?;
+                result.stdout.trim().parse().ok()
+            }
+        })
+    }
+
+    // The remote file's inode, which SFTP doesn't report, so it's read from `ls -di`
+    fn inode(&self) -> PyResult<Option<u64>> {
+        let command = format!("ls -di -- {}", shell_quote(&self.remote_file));
+        let result = TailSource::exec(&self.session, &command, "Stat", &self.remote_file)?;
+        Ok(result
+            .stdout
+            .split_whitespace()
+            .next()
+            .and_then(|inode| inode.parse().ok()))
+    }
+
+    // Where to resume reading from a checkpoint. A new inode means the file was rotated,
+    // and a file shorter than the checkpoint was truncated, so both are read from the start.
+    fn resume_pos(&self, checkpoint: &TailCheckpoint) -> PyResult<u64> {
+        let rotated = match checkpoint.inode {
+            Some(inode) => self.inode()? != Some(inode),
+            None => false,
+        };
+        let truncated = self.remote_size()?.unwrap_or(0) < checkpoint.pos;
+        Ok(if rotated || truncated {
+            0
+        } else {
+            checkpoint.pos
+        })
+    }
+
+    // Read the remote file from `from_pos` to its end, moving `last_pos` past what was read
+    fn read_from(&mut self, from_pos: u64) -> PyResult<String> {
+        let io_err = |op: &str, e: &dyn std::fmt::Display| {
+            PyErr::new::<PyIOError, _>(format!("{} error for {}: {}", op, self.remote_file, e))
+        };
+        let sftp = match &self.source {
+            TailSource::Sftp(sftp) => sftp,
+            TailSource::Exec => {
+                let command = format!(
+                    "tail -c +{} {}",
+                    from_pos + 1,
+                    shell_quote(&self.remote_file)
+                );
+                let result = self.exec_on_file(&command, "Read")?;
+                self.last_pos = from_pos + result.stdout_bytes;
+                return Ok(result.stdout);
+            }
+        };
+        let mut remote_file = BufReader::new(
+            sftp.open(Path::new(&self.remote_file))
+                .map_err(|e| self.sftp_error("Remote open", e))?,
+        );
+        remote_file
+            .seek(std::io::SeekFrom::Start(from_pos))
+            .map_err(|e| io_err("Seek", &e))?;
+        let mut contents = String::new();
+        remote_file
+            .read_to_string(&mut contents)
+            .map_err(|e| io_err("Read", &e))?;
+        self.last_pos = remote_file
+            .stream_position()
+            .map_err(|e| io_err("Seek", &e))?;
+        Ok(contents)
+    }
 }
 
 #[pymethods]
 impl FileTailer {
     #[new]
     #[pyo3(signature = (conn, remote_file, init_pos=None))]
-    fn new(conn: &Connection, remote_file: String, init_pos: Option<u64>) -> FileTailer {
-        FileTailer {
-            sftp_conn: conn.session.sftp().unwrap(),
+    fn new(
+        py: Python<'_>,
+        conn: &Connection,
+        remote_file: RemotePath,
+        init_pos: Option<u64>,
+    ) -> PyResult<FileTailer> {
+        let _guard = conn.channel_guard(py, false)?;
+        let remote_file = conn.expand_remote_path(remote_file.0)?;
+        let session = conn.open_session()?;
+        let exec = (TailSource::Exec, None);
+        let (source, slot) = match conn.transfer_backend {
+            TransferBackend::Scp => exec,
+            TransferBackend::Auto if conn.sftp_unavailable.load(Ordering::Relaxed) => exec,
+            backend => {
+                let slot = conn.channels.take();
+                match session.sftp().map_err(|e| sftp_open_error(&e)) {
+                    Ok(sftp) => (TailSource::Sftp(sftp), Some(slot)),
+                    Err(e)
+                        if backend == TransferBackend::Auto
+                            && e.is_instance_of::<SFTPUnavailableError>(py) =>
+                    {
+                        conn.sftp_unavailable.store(true, Ordering::Relaxed);
+                        exec
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        };
+        Ok(FileTailer {
+            source,
+            session: session.clone(),
             remote_file,
             init_pos,
             last_pos: 0,
             contents: None,
-        }
+            started: false,
+            _slot: slot,
+            closed: Arc::default(),
+            suppress_cleanup_errors: conn.suppress_cleanup_errors,
+            lock: Arc::clone(&conn.lock),
+        })
     }
 
     // Determine the current end of the remote file
-    fn seek_end(&mut self) -> PyResult<Option<u64>> {
-        let metadata = self
-            .sftp_conn
-            .stat(Path::new(&self.remote_file))
-            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Stat error: {}", e)))?;
-        self.last_pos = metadata.size.unwrap_or(0);
+    fn seek_end(&mut self, py: Python<'_>) -> PyResult<Option<u64>> {
+        self.check_open()?;
+        let size = self.lock.hold(py, || self.remote_size())?;
+        self.last_pos = size.unwrap_or(0);
         if self.init_pos.is_none() {
-            self.init_pos = metadata.size;
+            self.init_pos = size;
         }
-        Ok(metadata.size)
+        Ok(size)
     }
 
     // Read the contents of the remote file from a given position
     #[pyo3(signature = (from_pos=None))]
-    fn read(&mut self, from_pos: Option<u64>) -> String {
+    fn read(&mut self, py: Python<'_>, from_pos: Option<u64>) -> PyResult<String> {
+        self.check_open()?;
         let from_pos = from_pos.unwrap_or(self.last_pos);
-        let mut remote_file = BufReader::new(
-            self.sftp_conn
-                .open(Path::new(&self.remote_file))
-                .expect("Opening remote file failed"),
-        );
-        remote_file
-            .seek(std::io::SeekFrom::Start(from_pos))
-            .unwrap();
-        let mut contents = String::new();
-        remote_file.read_to_string(&mut contents).unwrap();
-        self.last_pos = remote_file.stream_position().unwrap();
-        contents
+        let lock = Arc::clone(&self.lock);
+        lock.hold(py, || self.read_from(from_pos))
+    }
+
+    /// Return where the tailer has read up to, as a dict of `path`, `pos` and `inode`.
+    /// Pass it to `Connection.tail(checkpoint=...)`, even in another process, to carry on.
+    fn checkpoint<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        self.check_open()?;
+        let checkpoint = PyDict::new(py);
+        checkpoint.set_item("path", &self.remote_file)?;
+        checkpoint.set_item("pos", self.last_pos)?;
+        checkpoint.set_item("inode", self.lock.hold(py, || self.inode())?)?;
+        Ok(checkpoint)
+    }
+
+    /// Starts tailing from `init_pos`, or from the current end of the file if the tailer wasn't given one.
+    /// Entering the tailer's context does this, so call it directly when starting and stopping
+    /// don't happen in the same block, like a test's setup and teardown.
+    fn start(&mut self, py: Python<'_>) -> PyResult<()> {
+        // a tailer given a starting position reads on from there
+        match self.init_pos {
+            Some(pos) => {
+                self.check_open()?;
+                self.last_pos = pos;
+            }
+            None => {
+                self.seek_end(py)?;
+            }
+        }
+        self.started = true;
+        Ok(())
+    }
+
+    /// Stops tailing, reading everything written since the start into `contents`.
+    /// Leaving the tailer's context does this. A stopped tailer can still give a `checkpoint` to resume from.
+    fn stop(&mut self, py: Python<'_>) -> PyResult<()> {
+        self.contents = Some(self.read(py, self.init_pos)?);
+        self.started = false;
+        Ok(())
     }
 
     fn __enter__(mut slf: PyRefMut<Self>) -> PyResult<PyRefMut<Self>> {
-        slf.seek_end()?;
+        let py = slf.py();
+        slf.start(py)?;
         Ok(slf)
     }
 
     #[pyo3(signature = (_exc_type=None, _exc_value=None, _traceback=None))]
     fn __exit__(
         &mut self,
+        py: Python<'_>,
         _exc_type: Option<&Bound<'_, PyAny>>,
         _exc_value: Option<&Bound<'_, PyAny>>,
         _traceback: Option<&Bound<'_, PyAny>>,
     ) -> PyResult<()> {
-        self.contents = Some(self.read(self.init_pos));
-        Ok(())
+        // a tailer closed along with its connection can't read the file any more
+        if self.closed.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let stopped = self.stop(py);
+        let what = format!("the tailer for {}", self.remote_file);
+        exit_context(py, &what, stopped, _exc_value, self.suppress_cleanup_errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_file_name_handles_both_separators() {
+        assert_eq!(local_file_name("/home/me/file.txt"), Some("file.txt"));
+        assert_eq!(local_file_name("C:\\data\\file.txt"), Some("file.txt"));
+        assert_eq!(
+            local_file_name("C:\\data/mixed\\file.txt"),
+            Some("file.txt")
+        );
+        assert_eq!(local_file_name("file.txt"), Some("file.txt"));
+        assert_eq!(local_file_name("C:\\data\\"), None);
+        assert_eq!(local_file_name("/home/me/.."), None);
+    }
+
+    #[test]
+    fn default_remote_path_keeps_posix_paths() {
+        assert_eq!(default_remote_path("/tmp/file.txt"), "/tmp/file.txt");
+        assert_eq!(
+            default_remote_path("relative/file.txt"),
+            "relative/file.txt"
+        );
+    }
+
+    #[test]
+    fn default_remote_path_strips_windows_paths() {
+        assert_eq!(default_remote_path("C:\\data\\file.txt"), "file.txt");
+        assert_eq!(default_remote_path("c:/data/file.txt"), "file.txt");
+        assert_eq!(default_remote_path("data\\file.txt"), "file.txt");
+        assert_eq!(
+            default_remote_path("\\\\server\\share\\file.txt"),
+            "file.txt"
+        );
+    }
+
+    #[test]
+    fn parse_env_splits_on_nul() {
+        let output = "PATH=/usr/bin:/bin\0MULTI=one\ntwo=2\0EMPTY=\0";
+        assert_eq!(
+            parse_env(output),
+            vec![
+                ("PATH", "/usr/bin:/bin"),
+                ("MULTI", "one\ntwo=2"),
+                ("EMPTY", "")
+            ]
+        );
+        assert!(parse_env("").is_empty());
+    }
+
+    #[test]
+    fn parse_df_available_finds_column_before_capacity() {
+        let output = "Filesystem     1024-blocks    Used Available Capacity Mounted on\n\
+                      /dev/sda1         41152736 9861392  29177812      26% /mnt/my disk\n";
+        assert_eq!(parse_df_available(output), Some(29177812));
+        assert_eq!(parse_df_available(""), None);
+        assert_eq!(
+            parse_df_available("df: /nope: No such file or directory"),
+            None
+        );
+    }
+
+    #[test]
+    fn take_marked_waits_for_marker_and_status() {
+        let mut pending = b"out\n<m".to_vec();
+        let mut searched = 0;
+        assert_eq!(
+            take_marked(&mut pending, &mut searched, b"<m> ", true),
+            None
+        );
+        pending.extend_from_slice(b"> 3");
+        assert_eq!(
+            take_marked(&mut pending, &mut searched, b"<m> ", true),
+            None
+        );
+        pending.extend_from_slice(b"\nnext");
+        assert_eq!(
+            take_marked(&mut pending, &mut searched, b"<m> ", true),
+            Some((b"out\n".to_vec(), b"3\n".to_vec()))
+        );
+        assert_eq!(pending, b"next");
+        assert_eq!(
+            take_marked(&mut pending, &mut searched, b"ex", false),
+            Some((b"n".to_vec(), Vec::new()))
+        );
+    }
+
+    #[test]
+    fn read_stream_stops_at_limit() {
+        let read = |data: &[u8], limit| {
+            read_stream(
+                &mut std::io::Cursor::new(data),
+                "stdout",
+                false,
+                None,
+                limit,
+                None,
+            )
+            .unwrap()
+        };
+        assert_eq!(read(b"hello", None), ("hello".to_string(), 5, false));
+        assert_eq!(read(b"hello", Some(5)), ("hello".to_string(), 5, false));
+        assert_eq!(read(b"hello", Some(3)), ("hel".to_string(), 3, true));
+        assert_eq!(read(b"hello", Some(0)), (String::new(), 0, true));
+        // a cut through a multi-byte character drops the partial character
+        assert_eq!(
+            read("héllo".as_bytes(), Some(2)),
+            ("h".to_string(), 2, true)
+        );
     }
 }
@@ -0,0 +1,118 @@
+//! # defaults.rs
+//!
+//! This module provides `Defaults`, a bundle of settings given to any number of connections with `defaults=`,
+//! so timeouts, retries, encodings and buffer sizes are configured in one place.
+//!
+//! ```python
+//! defaults = Defaults(command_timeout=30_000, retries=2, buffer_size=1024 * 1024)
+//! conn = Connection("my.test.server", password="pass", defaults=defaults)
+//! conn.execute("flaky-thing")  # retried up to twice, with a 30 second timeout
+//! conn.execute("slow-thing", timeout=300_000, retries=0)  # arguments to a call still win
+//! ```
+//!
+//! Settings left as `None` fall back to each method's usual default.
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyTuple, PyType};
+
+use crate::connection::transfer_buffer_size;
+
+/// Settings shared by connections created with `defaults=`.
+///
+/// * `command_timeout`: The timeout(ms) for commands run with `execute`, `run`, `sudo`, `run_as` and batches.
+/// * `connect_timeout`: The timeout(ms) for opening the TCP connection, as in `Connection`.
+/// * `retries` / `retry_delay`: How many times `execute` retries a failed command, and how many seconds apart.
+/// * `encoding`: The codec command output is decoded with, unless the remote locale has been detected.
+/// * `buffer_size`: How much SCP and SFTP transfers read at a time, between 4 KiB and 16 MiB.
+#[pyclass(module = "hussh", frozen)]
+#[derive(Clone, Default)]
+pub struct Defaults {
+    #[pyo3(get)]
+    pub(crate) command_timeout: Option<u32>,
+    #[pyo3(get)]
+    pub(crate) connect_timeout: Option<u32>,
+    #[pyo3(get)]
+    pub(crate) retries: Option<u32>,
+    #[pyo3(get)]
+    pub(crate) retry_delay: Option<f64>,
+    #[pyo3(get)]
+    pub(crate) encoding: Option<String>,
+    #[pyo3(get)]
+    pub(crate) buffer_size: Option<usize>,
+}
+
+#[pymethods]
+impl Defaults {
+    #[new]
+    #[pyo3(signature = (command_timeout=None, connect_timeout=None, retries=None, retry_delay=None, encoding=None, buffer_size=None))]
+    fn new(
+        py: Python<'_>,
+        command_timeout: Option<u32>,
+        connect_timeout: Option<u32>,
+        retries: Option<u32>,
+        retry_delay: Option<f64>,
+        encoding: Option<String>,
+        buffer_size: Option<usize>,
+    ) -> PyResult<Self> {
+        if let Some(delay) = retry_delay.filter(|d| !d.is_finite() || *d < 0.0) {
+            return Err(PyErr::new::<PyValueError, _>(format!(
+                "Invalid retry_delay: {}",
+                delay
+            )));
+        }
+        if let Some(encoding) = &encoding {
+            py.import("codecs")?.call_method1("lookup", (encoding,))?;
+        }
+        if buffer_size.is_some() {
+            transfer_buffer_size(buffer_size)?;
+        }
+        Ok(Defaults {
+            command_timeout,
+            connect_timeout,
+            retries,
+            retry_delay,
+            encoding,
+            buffer_size,
+        })
+    }
+
+    fn __reduce__<'py>(
+        slf: &Bound<'py, Self>,
+    ) -> PyResult<(Bound<'py, PyType>, Bound<'py, PyTuple>)> {
+        let defaults = slf.get();
+        let args = (
+            defaults.command_timeout,
+            defaults.connect_timeout,
+            defaults.retries,
+            defaults.retry_delay,
+            &defaults.encoding,
+            defaults.buffer_size,
+        )
+            .into_pyobject(slf.py())?;
+        Ok((slf.get_type(), args))
+    }
+
+    fn __repr__(slf: &Bound<'_, Self>) -> PyResult<String> {
+        let set = slf.get().as_dict(slf.py())?;
+        let fields: Vec<String> = set
+            .iter()
+            .filter(|(_, value)| !value.is_none())
+            .map(|(name, value)| Ok(format!("{}={}", name, value.repr()?)))
+            .collect::<PyResult<_>>()?;
+        Ok(format!("Defaults({})", fields.join(", ")))
+    }
+}
+
+impl Defaults {
+    // Every setting by name, including those left as None
+    pub(crate) fn as_dict<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let dict = PyDict::new(py);
+        dict.set_item("command_timeout", self.command_timeout)?;
+        dict.set_item("connect_timeout", self.connect_timeout)?;
+        dict.set_item("retries", self.retries)?;
+        dict.set_item("retry_delay", self.retry_delay)?;
+        dict.set_item("encoding", &self.encoding)?;
+        dict.set_item("buffer_size", self.buffer_size)?;
+        Ok(dict)
+    }
+}
@@ -0,0 +1,331 @@
+//! # known_hosts.rs
+//!
+//! This module manages OpenSSH `known_hosts` files, and is exposed as `hussh.known_hosts`.
+//!
+//! ```python
+//! from hussh import known_hosts
+//!
+//! for key in known_hosts.scan("my.test.server"):
+//!     known_hosts.add("my.test.server", f"{key['type']} {key['key']}", hashed=True)
+//! assert known_hosts.verify("my.test.server", "SHA256:...")
+//! ```
+//!
+//! Every function takes the file as `path`, which defaults to `~/.ssh/known_hosts`.
+//! Hosts on a port other than 22 are written as `[host]:port`, as OpenSSH does.
+//! Entries are matched by plain or hashed (`|1|salt|hash`) hostname. Wildcard patterns and
+//! lines with a marker, like `@cert-authority`, are left alone.
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time::Duration;
+
+use openssl::base64::{decode_block, encode_block};
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use pyo3::exceptions::{PyIOError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use ssh2::{MethodType, Session};
+
+use crate::connection::{connect_tcp, expand_local_path, LocalPath};
+
+const DEFAULT_PATH: &str = "~/.ssh/known_hosts";
+
+// The host key algorithms `scan` asks for, one handshake each.
+// rsa-sha2-512 and ssh-rsa are the same key, so ssh-rsa is only a fallback for older servers.
+const HOST_KEY_ALGORITHMS: [&str; 6] = [
+    "ssh-ed25519",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp521",
+    "rsa-sha2-512",
+    "ssh-rsa",
+];
+
+// The name a host is stored under
+fn host_entry(host: &str, port: u16) -> String {
+    if port == 22 {
+        host.to_string()
+    } else {
+        format!("[{}]:{}", host, port)
+    }
+}
+
+// The key type named at the start of a key blob
+fn blob_type(blob: &[u8]) -> Option<&str> {
+    let len = u32::from_be_bytes(blob.get(..4)?.try_into().ok()?) as usize;
+    std::str::from_utf8(blob.get(4..4 + len)?).ok()
+}
+
+// OpenSSH's SHA256 fingerprint of a key blob
+pub(crate) fn fingerprint(blob: &[u8]) -> String {
+    let digest = encode_block(&openssl::sha::sha256(blob));
+    format!("SHA256:{}", digest.trim_end_matches('='))
+}
+
+fn hmac_sha1(salt: &[u8], name: &str) -> PyResult<Vec<u8>> {
+    let hmac_err = |e: openssl::error::ErrorStack| {
+        PyErr::new::<PyIOError, _>(format!("Error hashing a hostname: {}", e))
+    };
+    let key = PKey::hmac(salt).map_err(hmac_err)?;
+    let mut signer = Signer::new(MessageDigest::sha1(), &key).map_err(hmac_err)?;
+    signer.update(name.as_bytes()).map_err(hmac_err)?;
+    signer.sign_to_vec().map_err(hmac_err)
+}
+
+// Hash a hostname with a new salt, as `ssh-keygen -H` does
+fn hash_host(name: &str) -> PyResult<String> {
+    let mut salt = [0; 20];
+    openssl::rand::rand_bytes(&mut salt)
+        .map_err(|e| PyErr::new::<PyIOError, _>(format!("Error salting a hostname: {}", e)))?;
+    Ok(format!(
+        "|1|{}|{}",
+        encode_block(&salt),
+        encode_block(&hmac_sha1(&salt, name)?)
+    ))
+}
+
+// Whether a line's comma-separated host field names `name`, plainly or hashed
+fn hosts_match(hosts: &str, name: &str) -> bool {
+    hosts.split(',').any(|pattern| {
+        match pattern
+            .strip_prefix("|1|")
+            .and_then(|rest| rest.split_once('|'))
+        {
+            Some((salt, hash)) => match (decode_block(salt), decode_block(hash)) {
+                (Ok(salt), Ok(hash)) => hmac_sha1(&salt, name).is_ok_and(|h| h == hash),
+                _ => false,
+            },
+            None => pattern.eq_ignore_ascii_case(name),
+        }
+    })
+}
+
+// A known_hosts line's key blob, if it's an entry for `name`
+fn entry_for(line: &str, name: &str) -> Option<Vec<u8>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') || line.starts_with('@') {
+        return None;
+    }
+    let mut fields = line.split_whitespace();
+    let hosts = fields.next()?;
+    let key = fields.nth(1)?;
+    if !hosts_match(hosts, name) {
+        return None;
+    }
+    decode_block(key).ok()
+}
+
+// Read a known_hosts file, treating a missing one as empty
+fn read_file(path: &str) -> PyResult<String> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => Ok(contents),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(PyErr::new::<PyIOError, _>(format!(
+            "Error reading {}: {}",
+            path, e
+        ))),
+    }
+}
+
+fn known_hosts_path(path: Option<LocalPath>) -> PyResult<String> {
+    expand_local_path(path.as_deref().unwrap_or(DEFAULT_PATH))
+}
+
+/// Connects to `host` without authenticating and returns its host keys, like `ssh-keyscan`.
+/// Each key is a dict of its `type`, base64 `key` and SHA256 `fingerprint`.
+/// `timeout` is in seconds, and applies to each of the handshakes, one per key type.
+#[pyfunction]
+#[pyo3(signature = (host, port=22, timeout=5.0))]
+fn scan<'py>(
+    py: Python<'py>,
+    host: &str,
+    port: u16,
+    timeout: f64,
+) -> PyResult<Vec<Bound<'py, PyDict>>> {
+    let timeout_ms = Duration::try_from_secs_f64(timeout)
+        .map_err(|_| PyErr::new::<PyValueError, _>(format!("Invalid timeout: {}", timeout)))?
+        .as_millis()
+        .clamp(1, u32::MAX as u128) as u32;
+    let blobs = py.allow_threads(|| {
+        let mut blobs: Vec<Vec<u8>> = Vec::new();
+        let mut last_err = None;
+        for algorithm in HOST_KEY_ALGORITHMS {
+            let tcp = connect_tcp(host, port.into(), timeout_ms)?;
+            let key = Session::new().and_then(|mut session| {
+                session.set_timeout(timeout_ms);
+                session.method_pref(MethodType::HostKey, algorithm)?;
+                session.set_tcp_stream(tcp);
+                session.handshake()?;
+                let key = session.host_key().map(|(blob, _)| blob.to_vec());
+                let _ = session.disconnect(None, "", None);
+                Ok(key)
+            });
+            match key {
+                Ok(Some(blob)) if !blobs.contains(&blob) => blobs.push(blob),
+                Ok(_) => {}
+                // the server doesn't have a key of this type
+                Err(e) => last_err = Some(e),
+            }
+        }
+        match (blobs.is_empty(), last_err) {
+            (true, Some(e)) => Err(std::io::Error::other(e)),
+            _ => Ok(blobs),
+        }
+    });
+    let blobs = blobs.map_err(|e| {
+        PyErr::new::<PyIOError, _>(format!("Error scanning {}:{}: {}", host, port, e))
+    })?;
+    blobs
+        .iter()
+        .map(|blob| {
+            let key = PyDict::new(py);
+            key.set_item("type", blob_type(blob).unwrap_or("unknown"))?;
+            key.set_item("key", encode_block(blob))?;
+            key.set_item("fingerprint", fingerprint(blob))?;
+            Ok(key)
+        })
+        .collect()
+}
+
+/// Adds `key`, given as `"<type> <base64>"` like a `known_hosts` line, for `host`.
+/// With `hashed`, the hostname is written hashed, so the file doesn't list the hosts you connect to.
+/// Returns `False` if the host already has this key.
+#[pyfunction]
+#[pyo3(signature = (host, key, port=22, path=None, hashed=false))]
+fn add(host: &str, key: &str, port: u16, path: Option<LocalPath>, hashed: bool) -> PyResult<bool> {
+    let (key_type, encoded) = key
+        .split_once(char::is_whitespace)
+        .and_then(|(key_type, rest)| Some((key_type, rest.split_whitespace().next()?)))
+        .ok_or_else(|| {
+            PyErr::new::<PyValueError, _>(
+                "The key should be \"<type> <base64>\", like ssh-ed25519 AAAA...",
+            )
+        })?;
+    let blob = decode_block(encoded)
+        .map_err(|_| PyErr::new::<PyValueError, _>("The key isn't valid base64"))?;
+    if blob_type(&blob) != Some(key_type) {
+        return Err(PyErr::new::<PyValueError, _>(format!(
+            "The key's data isn't a {} key",
+            key_type
+        )));
+    }
+    let path = known_hosts_path(path)?;
+    let name = host_entry(host, port);
+    let contents = read_file(&path)?;
+    if contents
+        .lines()
+        .filter_map(|line| entry_for(line, &name))
+        .any(|existing| existing == blob)
+    {
+        return Ok(false);
+    }
+    let hosts = if hashed { hash_host(&name)? } else { name };
+    let separator = if contents.is_empty() || contents.ends_with('\n') {
+        ""
+    } else {
+        "\n"
+    };
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| {
+            writeln!(
+                file,
+                "{}{} {} {}",
+                separator,
+                hosts,
+                key_type,
+                encode_block(&blob)
+            )
+        })
+        .map_err(|e| PyErr::new::<PyIOError, _>(format!("Error writing {}: {}", path, e)))?;
+    Ok(true)
+}
+
+/// Removes every entry for `host`, like `ssh-keygen -R`, and returns how many were removed.
+#[pyfunction]
+#[pyo3(signature = (host, port=22, path=None))]
+fn remove(host: &str, port: u16, path: Option<LocalPath>) -> PyResult<usize> {
+    let path = known_hosts_path(path)?;
+    let name = host_entry(host, port);
+    let contents = read_file(&path)?;
+    let (removed, kept): (Vec<&str>, Vec<&str>) = contents
+        .lines()
+        .partition(|line| entry_for(line, &name).is_some());
+    if !removed.is_empty() {
+        let mut kept = kept.join("\n");
+        if !kept.is_empty() {
+            kept.push('\n');
+        }
+        std::fs::write(&path, kept)
+            .map_err(|e| PyErr::new::<PyIOError, _>(format!("Error writing {}: {}", path, e)))?;
+    }
+    Ok(removed.len())
+}
+
+/// Returns `True` if one of the keys known for `host` has `fingerprint`, like `SHA256:...`.
+#[pyfunction]
+#[pyo3(signature = (host, fingerprint, port=22, path=None))]
+fn verify(host: &str, fingerprint: &str, port: u16, path: Option<LocalPath>) -> PyResult<bool> {
+    if !fingerprint.starts_with("SHA256:") {
+        return Err(PyErr::new::<PyValueError, _>(
+            "Only SHA256 fingerprints, like SHA256:..., are supported",
+        ));
+    }
+    let fingerprint = fingerprint.trim_end_matches('=');
+    let path = known_hosts_path(path)?;
+    let name = host_entry(host, port);
+    Ok(read_file(&path)?
+        .lines()
+        .filter_map(|line| entry_for(line, &name))
+        .any(|blob| self::fingerprint(&blob) == fingerprint))
+}
+
+/// Build the `hussh.known_hosts` module, registering it in `sys.modules`
+/// so it can be imported directly, e.g. `from hussh import known_hosts`.
+pub fn register_known_hosts(parent: &Bound<'_, PyModule>) -> PyResult<()> {
+    let py = parent.py();
+    let known_hosts = PyModule::new(py, "known_hosts")?;
+    known_hosts.add_function(wrap_pyfunction!(scan, &known_hosts)?)?;
+    known_hosts.add_function(wrap_pyfunction!(add, &known_hosts)?)?;
+    known_hosts.add_function(wrap_pyfunction!(remove, &known_hosts)?)?;
+    known_hosts.add_function(wrap_pyfunction!(verify, &known_hosts)?)?;
+    parent.add_submodule(&known_hosts)?;
+    py.import("sys")?
+        .getattr("modules")?
+        .set_item("hussh.known_hosts", &known_hosts)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_plain_and_hashed_hosts() {
+        assert!(hosts_match("example.com,10.0.0.1", "10.0.0.1"));
+        assert!(hosts_match("Example.COM", "example.com"));
+        assert!(!hosts_match("[example.com]:2222", "example.com"));
+        // from `ssh-keygen -H`, for example.com
+        let hashed = "|1|TxlxXO9vVbjkjI2bN1MNeIyWxqc=|5AtlcY4tra0SP3IHFPwB9p/SYnk=";
+        assert!(hosts_match(hashed, "example.com"));
+        assert!(!hosts_match(hashed, "example.org"));
+        let fresh = hash_host("[example.com]:2222").unwrap();
+        assert!(hosts_match(&fresh, "[example.com]:2222"));
+    }
+
+    #[test]
+    fn fingerprints_match_openssh() {
+        // ssh-keygen -lf on this key prints SHA256:kmYcvdi2GkPeWxB6XLjrZB8JHsy2Hm8luHMFp9GMvqk
+        let blob =
+            decode_block("AAAAC3NzaC1lZDI1NTE5AAAAIAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA")
+                .unwrap();
+        assert_eq!(blob_type(&blob), Some("ssh-ed25519"));
+        assert_eq!(
+            fingerprint(&blob),
+            "SHA256:kmYcvdi2GkPeWxB6XLjrZB8JHsy2Hm8luHMFp9GMvqk"
+        );
+    }
+}
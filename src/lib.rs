@@ -1,7 +1,18 @@
-use connection::AuthenticationError;
+use connection::{
+    AuthenticationError, ChannelClosedError, ChannelOpenError, CommandError, CommandPolicyError,
+    ConnectionLostError, FileTooLargeError, HookError, SFTPUnavailableError,
+};
 use pyo3::prelude::*;
 
+mod agent;
+mod ansi;
+mod compat;
 mod connection;
+mod defaults;
+mod known_hosts;
+mod logging;
+mod pool;
+mod prompt;
 
 /// A Python module implemented in Rust.
 #[pymodule]
@@ -10,6 +21,32 @@ fn hussh(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<connection::SSHResult>()?;
     m.add_class::<connection::InteractiveShell>()?;
     m.add_class::<connection::FileTailer>()?;
+    m.add_class::<pool::ConnectionPool>()?;
+    m.add_class::<pool::PooledConnection>()?;
+    m.add_class::<connection::ConnectionLock>()?;
+    m.add_class::<connection::CommandBatch>()?;
+    m.add_class::<connection::SubsystemChannel>()?;
+    m.add_class::<connection::RemoteTempPath>()?;
+    m.add_class::<defaults::Defaults>()?;
+    m.add_function(wrap_pyfunction!(connection::shlex_quote, m)?)?;
+    m.add_function(wrap_pyfunction!(connection::wait_for_ssh, m)?)?;
+    m.add_function(wrap_pyfunction!(ansi::strip_ansi, m)?)?;
+    m.add_function(wrap_pyfunction!(logging::redact_enabled, m)?)?;
+    m.add_function(wrap_pyfunction!(prompt::set_credential_prompt, m)?)?;
     m.add("AuthenticationError", _py.get_type::<AuthenticationError>())?;
+    m.add("CommandError", _py.get_type::<CommandError>())?;
+    m.add("HookError", _py.get_type::<HookError>())?;
+    m.add("ChannelOpenError", _py.get_type::<ChannelOpenError>())?;
+    m.add(
+        "SFTPUnavailableError",
+        _py.get_type::<SFTPUnavailableError>(),
+    )?;
+    m.add("ConnectionLostError", _py.get_type::<ConnectionLostError>())?;
+    m.add("ChannelClosedError", _py.get_type::<ChannelClosedError>())?;
+    m.add("CommandPolicyError", _py.get_type::<CommandPolicyError>())?;
+    m.add("FileTooLargeError", _py.get_type::<FileTooLargeError>())?;
+    compat::register_compat(m)?;
+    known_hosts::register_known_hosts(m)?;
+    logging::register(_py)?;
     Ok(())
 }
@@ -0,0 +1,172 @@
+//! # logging.rs
+//!
+//! This module bridges Hussh's Rust code to Python's standard `logging` module.
+//! Records are emitted under the `hussh` logger hierarchy, e.g. `hussh.connection`, `hussh.scp` and `hussh.sftp`.
+//! Protocol traces from the `Tracer` go to `hussh.trace`, unless they're written to a trace file.
+//!
+//! Messages are built lazily, only after checking `isEnabledFor`, so logging costs next to nothing while disabled.
+//! The `hussh` logger gets a `NullHandler`, as libraries should, so Hussh stays quiet unless you configure logging.
+//!
+//! ```python
+//! import logging
+//! logging.basicConfig()
+//! logging.getLogger("hussh").setLevel(logging.DEBUG)
+//! ```
+//!
+//! Passwords and passphrases are replaced with `***` in traces, auth failures and other errors that could include them,
+//! and `Connection.password` is masked. `hussh.redact_enabled(False)` turns this off, e.g. while debugging auth locally.
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use pyo3::exceptions::PyIOError;
+use pyo3::prelude::*;
+use pyo3::sync::GILOnceCell;
+use pyo3::types::{PyDict, PyString, PyTuple};
+
+pub(crate) const DEBUG: u8 = 10;
+pub(crate) const INFO: u8 = 20;
+pub(crate) const WARNING: u8 = 30;
+pub(crate) const ERROR: u8 = 40;
+
+// What secrets are replaced with
+pub(crate) const REDACTED: &str = "***";
+
+static REDACT: AtomicBool = AtomicBool::new(true);
+
+// The `hussh.*` loggers, by name, so each is only looked up once
+static LOGGERS: GILOnceCell<Py<PyDict>> = GILOnceCell::new();
+
+/// Returns whether secrets are redacted from traces, errors and `Connection.password`, which they are by default.
+/// Pass `enabled` to turn redaction on or off for the whole process.
+#[pyfunction]
+#[pyo3(signature = (enabled=None))]
+pub(crate) fn redact_enabled(enabled: Option<bool>) -> bool {
+    if let Some(enabled) = enabled {
+        REDACT.store(enabled, Ordering::Relaxed);
+    }
+    REDACT.load(Ordering::Relaxed)
+}
+
+/// Replace each non-empty secret in `text` with `***`, unless redaction is turned off.
+pub(crate) fn redact(text: &str, secrets: &[&str]) -> String {
+    let mut text = text.to_string();
+    if REDACT.load(Ordering::Relaxed) {
+        for secret in secrets.iter().filter(|s| !s.is_empty()) {
+            text = text.replace(secret, REDACTED);
+        }
+    }
+    text
+}
+
+/// Redact secrets from an exception's string arguments, keeping its type and any other attributes.
+pub(crate) fn redact_err(py: Python<'_>, err: PyErr, secrets: &[&str]) -> PyErr {
+    let value = err.value(py);
+    let Ok(args) = value.getattr("args") else {
+        return err;
+    };
+    let redacted: PyResult<Vec<Bound<'_, PyAny>>> = args.try_iter().and_then(|args| {
+        args.map(|arg| {
+            let arg = arg?;
+            Ok(match arg.downcast::<PyString>() {
+                Ok(text) => PyString::new(py, &redact(text.to_str()?, secrets)).into_any(),
+                Err(_) => arg,
+            })
+        })
+        .collect()
+    });
+    if let Ok(redacted) = redacted.and_then(|args| PyTuple::new(py, args)) {
+        let _ = value.setattr("args", redacted);
+    }
+    err
+}
+
+/// Give the `hussh` logger a `NullHandler`, so warnings aren't printed by logging's last resort handler
+/// in applications that haven't configured logging.
+pub(crate) fn register(py: Python<'_>) -> PyResult<()> {
+    let logging = py.import("logging")?;
+    let handler = logging.getattr("NullHandler")?.call0()?;
+    logging
+        .call_method1("getLogger", ("hussh",))?
+        .call_method1("addHandler", (handler,))?;
+    Ok(())
+}
+
+/// Log a message to the `hussh.<logger>` Python logger.
+/// `message` is only called if the logger is enabled for `level`.
+/// Failures inside Python's logging machinery are ignored, so logging can never break an operation.
+pub(crate) fn log<F: FnOnce() -> String>(py: Python<'_>, logger: &str, level: u8, message: F) {
+    let _ = try_log(py, logger, level, message);
+}
+
+fn try_log<F: FnOnce() -> String>(
+    py: Python<'_>,
+    logger: &str,
+    level: u8,
+    message: F,
+) -> PyResult<()> {
+    let loggers = LOGGERS
+        .get_or_init(py, || PyDict::new(py).unbind())
+        .bind(py);
+    let logger = match loggers.get_item(logger)? {
+        Some(cached) => cached,
+        None => {
+            let cached = py
+                .import("logging")?
+                .call_method1("getLogger", (format!("hussh.{}", logger),))?;
+            loggers.set_item(logger, &cached)?;
+            cached
+        }
+    };
+    if logger.call_method1("isEnabledFor", (level,))?.is_truthy()? {
+        logger.call_method1("log", (level, message()))?;
+    }
+    Ok(())
+}
+
+/// An annotated protocol trace, enabled by `Connection(debug=True)` or `Connection(trace_file=...)`.
+/// Lines go to `trace_file` if one was given, otherwise to the `hussh.trace` logger at DEBUG.
+/// Any secret registered with the tracer is redacted before a line is written.
+pub(crate) struct Tracer {
+    file: Option<File>,
+    secrets: Vec<String>,
+}
+
+impl Tracer {
+    pub(crate) fn new(trace_file: Option<&str>, secrets: &[&str]) -> PyResult<Tracer> {
+        let file = match trace_file {
+            Some(path) => Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(shellexpand::tilde(path).as_ref())
+                    .map_err(|e| {
+                        PyErr::new::<PyIOError, _>(format!("Trace file open error: {}", e))
+                    })?,
+            ),
+            None => None,
+        };
+        Ok(Tracer {
+            file,
+            secrets: secrets
+                .iter()
+                .filter(|s| !s.is_empty())
+                .map(|s| s.to_string())
+                .collect(),
+        })
+    }
+
+    /// Write one trace line, annotated with the connection it belongs to.
+    pub(crate) fn trace(&self, py: Python<'_>, conn_str: &str, message: String) {
+        let secrets: Vec<&str> = self.secrets.iter().map(String::as_str).collect();
+        let line = redact(&format!("[{}] {}", conn_str, message), &secrets);
+        match &self.file {
+            // Trace output is best effort, like the rest of our logging
+            Some(file) => {
+                let mut file: &File = file;
+                let _ = writeln!(file, "{}", line);
+            }
+            None => log(py, "trace", DEBUG, || line),
+        }
+    }
+}
@@ -0,0 +1,297 @@
+//! # pool.rs
+//!
+//! This module provides `ConnectionPool`, which keeps connections open between uses, so code that connects
+//! to the same few hosts again and again pays for the handshake and auth once rather than every time.
+//!
+//! ```python
+//! pool = ConnectionPool(max_per_host=4, idle_timeout=60)
+//! with pool.acquire("my.test.server", username="user", password="pass") as conn:
+//!     conn.execute("uptime")
+//! ```
+//!
+//! Connections are pooled by host and the keyword arguments they were acquired with.
+//! Each is checked with `Connection.is_active()` before it's handed out again,
+//! and a background thread closes those left idle for longer than `idle_timeout` seconds.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+use pyo3::exceptions::{PyIOError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyTuple};
+
+use crate::connection::Connection;
+
+// The longest the reaper sleeps between looking for expired connections
+const MAX_REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Idle {
+    conn: Py<Connection>,
+    since: Instant,
+}
+
+struct PoolInner {
+    max_per_host: usize,
+    idle_timeout: Duration,
+    idle: Mutex<HashMap<String, Vec<Idle>>>,
+    closed: AtomicBool,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl PoolInner {
+    fn idle(&self) -> std::sync::MutexGuard<'_, HashMap<String, Vec<Idle>>> {
+        self.idle.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Take the connections that have been idle too long out of the pool
+    fn take_expired(&self) -> Vec<Py<Connection>> {
+        let mut expired = Vec::new();
+        self.idle().retain(|_, conns| {
+            let (old, fresh): (Vec<Idle>, Vec<Idle>) = std::mem::take(conns)
+                .into_iter()
+                .partition(|idle| idle.since.elapsed() >= self.idle_timeout);
+            expired.extend(old.into_iter().map(|idle| idle.conn));
+            *conns = fresh;
+            !conns.is_empty()
+        });
+        expired
+    }
+}
+
+// Close connections that are leaving the pool. Errors are ignored, since the connection is being discarded.
+fn close_all(py: Python<'_>, conns: Vec<Py<Connection>>) {
+    for conn in conns {
+        let _ = conn.bind(py).call_method0("close");
+    }
+}
+
+// Close idle connections as they expire, until the pool is closed or dropped
+fn reap(pool: Weak<PoolInner>, interval: Duration) {
+    loop {
+        thread::park_timeout(interval);
+        let Some(pool) = pool.upgrade() else {
+            return;
+        };
+        if pool.closed.load(Ordering::Relaxed) {
+            return;
+        }
+        let expired = pool.take_expired();
+        if !expired.is_empty() {
+            pool.evictions
+                .fetch_add(expired.len() as u64, Ordering::Relaxed);
+            Python::with_gil(|py| close_all(py, expired));
+        }
+    }
+}
+
+/// A thread-safe pool of open connections, handed out by `acquire` and returned when its context exits.
+///
+/// At most `max_per_host` idle connections are kept for each host. Acquiring more than that at once
+/// opens extra connections, which are closed rather than kept when they're returned.
+/// Idle connections are closed once they've gone unused for `idle_timeout` seconds.
+#[pyclass]
+pub struct ConnectionPool {
+    inner: Arc<PoolInner>,
+    reaper: Thread,
+}
+
+#[pymethods]
+impl ConnectionPool {
+    #[new]
+    #[pyo3(signature = (max_per_host=4, idle_timeout=60.0))]
+    fn new(max_per_host: usize, idle_timeout: f64) -> PyResult<Self> {
+        let idle_timeout = Duration::try_from_secs_f64(idle_timeout)
+            .ok()
+            .filter(|t| !t.is_zero())
+            .ok_or_else(|| {
+                PyErr::new::<PyValueError, _>("idle_timeout must be a positive number of seconds")
+            })?;
+        let inner = Arc::new(PoolInner {
+            max_per_host,
+            idle_timeout,
+            idle: Mutex::default(),
+            closed: AtomicBool::new(false),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        });
+        let pool = Arc::downgrade(&inner);
+        let interval = idle_timeout.min(MAX_REAP_INTERVAL);
+        let reaper = thread::Builder::new()
+            .name("hussh-pool-reaper".to_string())
+            .spawn(move || reap(pool, interval))
+            .map_err(|e| {
+                PyErr::new::<PyIOError, _>(format!("Couldn't start the reaper thread: {}", e))
+            })?
+            .thread()
+            .clone();
+        Ok(ConnectionPool { inner, reaper })
+    }
+
+    /// Checks a connection to `host` out of the pool, or opens a new one if none is idle.
+    /// Keyword arguments are passed to `Connection`, and only connections acquired with the same ones are shared.
+    /// Use the result as a context manager, which returns the connection to the pool on exit.
+    ///
+    /// ```python
+    /// with pool.acquire("my.test.server", username="user", private_key="~/.ssh/id_ed25519") as conn:
+    ///     conn.execute("uptime")
+    /// ```
+    #[pyo3(signature = (host, **kwargs))]
+    fn acquire(
+        &self,
+        py: Python<'_>,
+        host: &str,
+        kwargs: Option<&Bound<'_, PyDict>>,
+    ) -> PyResult<PooledConnection> {
+        if self.inner.closed.load(Ordering::Relaxed) {
+            return Err(PyErr::new::<PyValueError, _>(
+                "The connection pool is closed",
+            ));
+        }
+        let mut params = Vec::new();
+        for (name, value) in kwargs.into_iter().flatten() {
+            params.push((name.extract::<String>()?, value.repr()?.to_string()));
+        }
+        params.sort();
+        let key = format!("{}\0{:?}", host, params);
+        loop {
+            // the most recently returned connection is the likeliest to still be alive
+            let idle = self
+                .inner
+                .idle()
+                .get_mut(&key)
+                .and_then(|conns| conns.pop());
+            let Some(idle) = idle else {
+                break;
+            };
+            if idle.conn.borrow(py).is_active(py) {
+                self.inner.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(PooledConnection {
+                    pool: Arc::clone(&self.inner),
+                    key,
+                    conn: Some(idle.conn),
+                });
+            }
+            self.inner.evictions.fetch_add(1, Ordering::Relaxed);
+            close_all(py, vec![idle.conn]);
+        }
+        self.inner.misses.fetch_add(1, Ordering::Relaxed);
+        let conn = py
+            .get_type::<Connection>()
+            .call(PyTuple::new(py, [host])?, kwargs)?
+            .downcast_into::<Connection>()?
+            .unbind();
+        Ok(PooledConnection {
+            pool: Arc::clone(&self.inner),
+            key,
+            conn: Some(conn),
+        })
+    }
+
+    /// Returns the pool's counters as a dict: `hits` and `misses` on acquire,
+    /// `evictions` of idle connections that expired, failed their health check or didn't fit,
+    /// and the number of connections currently `idle`.
+    fn stats<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let stats = PyDict::new(py);
+        stats.set_item("hits", self.inner.hits.load(Ordering::Relaxed))?;
+        stats.set_item("misses", self.inner.misses.load(Ordering::Relaxed))?;
+        stats.set_item("evictions", self.inner.evictions.load(Ordering::Relaxed))?;
+        let idle: usize = self.inner.idle().values().map(Vec::len).sum();
+        stats.set_item("idle", idle)?;
+        Ok(stats)
+    }
+
+    /// Closes every idle connection and stops the reaper. Connections still checked out are closed when returned.
+    /// Closing is idempotent.
+    fn close(&self, py: Python<'_>) {
+        self.inner.closed.store(true, Ordering::Relaxed);
+        self.reaper.unpark();
+        let idle: Vec<Py<Connection>> = self
+            .inner
+            .idle()
+            .drain()
+            .flat_map(|(_, conns)| conns)
+            .map(|idle| idle.conn)
+            .collect();
+        close_all(py, idle);
+    }
+
+    fn __enter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    #[pyo3(signature = (_exc_type=None, _exc_value=None, _traceback=None))]
+    fn __exit__(
+        &self,
+        py: Python<'_>,
+        _exc_type: Option<&Bound<'_, PyAny>>,
+        _exc_value: Option<&Bound<'_, PyAny>>,
+        _traceback: Option<&Bound<'_, PyAny>>,
+    ) {
+        self.close(py);
+    }
+}
+
+impl Drop for ConnectionPool {
+    fn drop(&mut self) {
+        self.inner.closed.store(true, Ordering::Relaxed);
+        self.reaper.unpark();
+    }
+}
+
+/// A connection checked out of a `ConnectionPool`. Entering its context gives the `Connection`,
+/// and exiting returns the connection to the pool.
+#[pyclass]
+pub struct PooledConnection {
+    pool: Arc<PoolInner>,
+    key: String,
+    conn: Option<Py<Connection>>,
+}
+
+#[pymethods]
+impl PooledConnection {
+    /// Returns the connection to its pool. Connections that were closed, or that don't fit, are closed instead.
+    /// Releasing is idempotent.
+    fn release(&mut self, py: Python<'_>) {
+        let Some(conn) = self.conn.take() else {
+            return;
+        };
+        if !self.pool.closed.load(Ordering::Relaxed)
+            && !conn.borrow(py).closed.load(Ordering::Relaxed)
+        {
+            let mut idle = self.pool.idle();
+            let conns = idle.entry(self.key.clone()).or_default();
+            if conns.len() < self.pool.max_per_host {
+                conns.push(Idle {
+                    conn,
+                    since: Instant::now(),
+                });
+                return;
+            }
+            self.pool.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        close_all(py, vec![conn]);
+    }
+
+    fn __enter__(&self, py: Python<'_>) -> PyResult<Py<Connection>> {
+        self.conn
+            .as_ref()
+            .map(|conn| conn.clone_ref(py))
+            .ok_or_else(|| PyErr::new::<PyValueError, _>("The connection was already released"))
+    }
+
+    #[pyo3(signature = (_exc_type=None, _exc_value=None, _traceback=None))]
+    fn __exit__(
+        &mut self,
+        py: Python<'_>,
+        _exc_type: Option<&Bound<'_, PyAny>>,
+        _exc_value: Option<&Bound<'_, PyAny>>,
+        _traceback: Option<&Bound<'_, PyAny>>,
+    ) {
+        self.release(py);
+    }
+}
@@ -70,6 +70,52 @@ def run_second_server(ensure_test_server_image):
     client.close()
 
 
+@pytest.fixture(scope="session")
+def run_limited_server(ensure_test_server_image):
+    """Run a test server that allows one session channel per connection."""
+    client = docker.from_env()
+    try:  # check to see if the container is already running
+        container = client.containers.get("hussh-test-server-limited")
+    except docker.errors.NotFound:  # if not, start it
+        container = client.containers.run(
+            TEST_SERVER_IMAGE,
+            command=["/usr/sbin/sshd", "-D", "-o", "MaxSessions=1"],
+            detach=True,
+            ports={"22/tcp": 8024},
+            name="hussh-test-server-limited",
+        )
+        time.sleep(5)  # give the server time to start
+    yield container
+    container.stop()
+    container.remove()
+    client.close()
+
+
+@pytest.fixture(scope="session")
+def run_no_sftp_server(ensure_test_server_image):
+    """Run a test server with the SFTP subsystem turned off."""
+    client = docker.from_env()
+    try:  # check to see if the container is already running
+        container = client.containers.get("hussh-test-server-no-sftp")
+    except docker.errors.NotFound:  # if not, start it
+        container = client.containers.run(
+            TEST_SERVER_IMAGE,
+            command=[
+                "sh",
+                "-c",
+                "sed -i '/^Subsystem/d' /etc/ssh/sshd_config && exec /usr/sbin/sshd -D",
+            ],
+            detach=True,
+            ports={"22/tcp": 8025},
+            name="hussh-test-server-no-sftp",
+        )
+        time.sleep(5)  # give the server time to start
+    yield container
+    container.stop()
+    container.remove()
+    client.close()
+
+
 @pytest.fixture(scope="session")
 def setup_agent_auth():
     # Define the key paths
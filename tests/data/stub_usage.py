@@ -0,0 +1,31 @@
+"""Usage sample type checked against hussh.pyi by tests/test_stubs.py."""
+
+from hussh import CommandError, Connection, FileTailer, SSHResult
+
+
+def sample(conn: Connection) -> list[str]:
+    """Exercise the stubbed API the way a typical caller would."""
+    result: SSHResult = conn.execute("ls", timeout=1000)
+    lines = result.stdout.splitlines()
+    try:
+        conn.run("false", hide=True)
+    except CommandError as err:
+        lines.append(str(err.result.status))
+    conn.sftp_write_data("data", "/tmp/data.txt")
+    contents: str = conn.sftp_read("/tmp/data.txt")
+    lines.append(contents)
+    data: bytes = conn.sftp_read("/tmp/data.txt", binary=True, max_bytes=1024)
+    lines.append(data.decode())
+    tailer: FileTailer = conn.tail("/tmp/data.txt")
+    with tailer as tf:
+        lines.append(tf.read(0))
+    with conn.shell(pty=True) as shell:
+        shell.send("ls")
+    if shell.result is not None:
+        lines.append(shell.result.stdout)
+    return lines
+
+
+if __name__ == "__main__":
+    with Connection("localhost", port=8022, password="toor") as conn:
+        print(sample(conn))
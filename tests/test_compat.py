@@ -0,0 +1,99 @@
+"""Tests for hussh.compat.paramiko module."""
+
+from pathlib import Path, PurePosixPath
+
+import pytest
+
+from hussh.compat import paramiko
+
+TEXT_FILE = Path("tests/data/hp.txt").resolve()
+
+
+@pytest.fixture
+def client():
+    """Return a connected paramiko-style SSHClient."""
+    client = paramiko.SSHClient()
+    client.set_missing_host_key_policy(paramiko.AutoAddPolicy())
+    client.connect("localhost", port=8022, username="root", password="toor")
+    yield client
+    client.close()
+
+
+def test_key_connect():
+    """Test that we can connect with a key file, paramiko style."""
+    with paramiko.SSHClient() as client:
+        client.connect("localhost", port=8022, key_filename="tests/data/test_key")
+        assert client.get_transport().is_active()
+
+
+def test_bad_password():
+    """Test that failed auth raises paramiko's AuthenticationException."""
+    client = paramiko.SSHClient()
+    with pytest.raises(paramiko.AuthenticationException):
+        client.connect("localhost", port=8022, password="wrong")
+
+
+def test_exec_command(client):
+    """Test that exec_command returns paramiko-style file-likes."""
+    stdin, stdout, stderr = client.exec_command("echo hello; echo oops >&2; exit 3")
+    assert stdout.read() == b"hello\n"
+    assert stderr.readlines() == ["oops\n"]
+    assert stdout.channel.recv_exit_status() == 3
+
+
+def test_exec_command_stdin(client):
+    """Test that we can write to a command's stdin."""
+    stdin, stdout, _ = client.exec_command("cat")
+    stdin.write("piped data\n")
+    stdin.close()
+    assert list(stdout) == ["piped data\n"]
+
+
+def test_exec_command_timeout(client):
+    """Test that an exec_command timeout applies to its own channel, not later commands."""
+    _, stdout, _ = client.exec_command("sleep 2; echo late", timeout=0.5)
+    with pytest.raises(OSError):
+        stdout.read()
+    _, stdout, _ = client.exec_command("sleep 1; echo done")
+    assert stdout.read() == b"done\n"
+
+
+def test_sftp_put_get_listdir(client):
+    """Test the put/get/listdir/stat subset of SFTPClient, with paths as str or Path objects."""
+    sftp = client.open_sftp()
+    attrs = sftp.put(TEXT_FILE, "/root/compat_hp.txt")
+    assert attrs.st_size == TEXT_FILE.stat().st_size
+    assert "compat_hp.txt" in sftp.listdir("/root")
+    local_copy = Path("compat_hp.txt")
+    sftp.get(PurePosixPath("/root/compat_hp.txt"), local_copy)
+    assert local_copy.read_text() == TEXT_FILE.read_text()
+    local_copy.unlink()
+    sftp.remove("/root/compat_hp.txt")
+    assert "compat_hp.txt" not in sftp.listdir("/root")
+    sftp.close()
+
+
+def test_invoke_shell(client):
+    """Test that we can drive an interactive shell over a paramiko-style Channel."""
+    chan = client.invoke_shell()
+    chan.send("echo compat shell\nexit\n")
+    output = b""
+    while data := chan.recv(1024):
+        output += data
+    assert b"compat shell" in output
+
+
+def test_transport_inactive_after_close(client):
+    """Test that closing the client leaves no active transport."""
+    assert client.get_transport().is_active()
+    client.close()
+    assert client.get_transport() is None
+
+
+def test_host_key_policies():
+    """Test that policies Hussh can't enforce raise instead of being ignored."""
+    client = paramiko.SSHClient()
+    client.set_missing_host_key_policy(paramiko.AutoAddPolicy)
+    for policy in (paramiko.RejectPolicy(), paramiko.WarningPolicy, object()):
+        with pytest.raises(NotImplementedError, match="doesn't verify host keys"):
+            client.set_missing_host_key_policy(policy)
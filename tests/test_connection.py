@@ -1,10 +1,42 @@
 """Tests for hussh.connection module."""
 
-from pathlib import Path
+import copy
+import gc
+import hashlib
+import logging
+import os
+from pathlib import Path, PurePosixPath, PureWindowsPath
+import pickle
+import re
+import socket
+import struct
+import subprocess
+import sys
+import threading
+import time
 
 import pytest
 
-from hussh import Connection, SSHResult
+from hussh import (
+    AuthenticationError,
+    ChannelClosedError,
+    ChannelOpenError,
+    CommandError,
+    CommandPolicyError,
+    Connection,
+    ConnectionLostError,
+    Defaults,
+    FileTailer,
+    FileTooLargeError,
+    HookError,
+    SFTPUnavailableError,
+    SSHResult,
+    redact_enabled,
+    set_credential_prompt,
+    shlex_quote,
+    strip_ansi,
+    wait_for_ssh,
+)
 
 TEXT_FILE = Path("tests/data/hp.txt").resolve()
 IMG_FILE = Path("tests/data/puppy.jpeg").resolve()
@@ -21,6 +53,27 @@ def test_password_auth():
     assert Connection(host="localhost", port=8022, password="toor")
 
 
+def test_auth_attempts():
+    """Test that each auth attempt is recorded with its outcome."""
+    conn = Connection(host="localhost", port=8022, password="toor")
+    assert conn.auth_attempts == [("password", "success")]
+
+
+def test_allowed_auth_never_sends_password():
+    """Test that a password isn't sent when allowed_auth excludes password methods."""
+    conn = Connection(
+        host="localhost", port=8022, password="toor", allowed_auth=("publickey",), connect=False
+    )
+    with pytest.raises(AuthenticationError, match="No allowed auth method"):
+        conn.connect()
+    assert not conn.connected
+    assert {method for method, _ in conn.auth_attempts}.isdisjoint(
+        {"password", "keyboard-interactive"}
+    )
+    with pytest.raises(ValueError, match="Unknown auth method"):
+        Connection(host="localhost", port=8022, allowed_auth=("hostbased",), connect=False)
+
+
 def test_key_auth():
     """Test that we can establish a connection with key-based authentication."""
     assert Connection(host="localhost", port=8022, private_key="tests/data/test_key")
@@ -39,6 +92,15 @@ def test_key_in_user_home():
         new_path.rename(key_path)
 
 
+def test_key_path_env_vars(monkeypatch):
+    """Test that environment variables in private_key are expanded, and unset ones are reported."""
+    monkeypatch.setenv("HUSSH_KEY_DIR", str(Path("tests/data").absolute()))
+    assert Connection(host="localhost", port=8022, private_key="${HUSSH_KEY_DIR}/test_key")
+    monkeypatch.delenv("HUSSH_KEY_DIR")
+    with pytest.raises(ValueError, match="HUSSH_KEY_DIR is not set"):
+        Connection(host="localhost", port=8022, private_key="$HUSSH_KEY_DIR/test_key")
+
+
 def test_key_with_password_auth():
     """Test that we can establish a connection with key-based authentication and a password."""
     assert Connection(
@@ -49,6 +111,40 @@ def test_key_with_password_auth():
     )
 
 
+def test_credential_prompt():
+    """Test that a registered prompt supplies a missing passphrase or password, and is cached."""
+    requests = []
+
+    def prompt(request):
+        requests.append(request)
+        if request["kind"] == "passphrase":
+            return "wrong" if request["attempt"] == 1 else "husshpuppy"
+        return "toor"
+
+    set_credential_prompt(prompt)
+    try:
+        assert Connection(host="localhost", port=8022, private_key="tests/data/auth_test_key")
+        assert [(r["kind"], r["attempt"]) for r in requests] == [
+            ("passphrase", 1),
+            ("passphrase", 2),
+        ]
+        assert requests[0]["key_path"] == "tests/data/auth_test_key"
+        # the passphrase that worked is reused without asking again
+        assert Connection(host="localhost", port=8022, private_key="tests/data/auth_test_key")
+        assert len(requests) == 2
+        conn = Connection(host="localhost", port=8022, allowed_auth=("password",))
+        assert conn.auth_attempts == [("password", "success")]
+        assert requests[-1]["kind"] == "password"
+        assert requests[-1]["key_path"] is None
+        set_credential_prompt(lambda request: None, cache=False)
+        with pytest.raises(AuthenticationError):
+            Connection(host="localhost", port=8022, allowed_auth=("password",))
+    finally:
+        set_credential_prompt(None)
+    with pytest.raises(TypeError, match="callable"):
+        set_credential_prompt("password")
+
+
 @pytest.mark.skip("fixture-based setup for agent-based auth currently not working")
 def test_agent_auth(setup_agent_auth):
     """Test that we can establish a connection with agent-based authentication."""
@@ -70,6 +166,285 @@ def test_bad_command(conn):
     assert "command not found" in result.stderr
 
 
+def test_run(conn, capsys):
+    """Test that run echoes output and records the command on the result."""
+    result = conn.run("echo hello; echo oops >&2")
+    assert result.ok
+    assert result.command == "echo hello; echo oops >&2"
+    captured = capsys.readouterr()
+    assert captured.out == "hello\n"
+    assert captured.err == "oops\n"
+
+
+def test_run_hide_and_warn(conn, capsys):
+    """Test that run can hide output and raise or warn on failure."""
+    with pytest.raises(CommandError) as exc_info:
+        conn.run("exit 3", hide=True)
+    assert exc_info.value.result.status == 3
+    result = conn.run("echo quiet; exit 3", hide=True, warn=True)
+    assert not result.ok
+    assert result.stdout == "quiet\n"
+    assert capsys.readouterr().out == ""
+
+
+def test_secrets_redacted():
+    """Test that a planted password doesn't show up in the password getter or in errors."""
+    secret = "planted-Secret-42"
+    conn = Connection(host="localhost", port=8022, password=secret, connect=False)
+    assert conn.password == "***"
+    assert secret not in repr(conn)
+    with pytest.raises(AuthenticationError) as error:
+        conn.connect()
+    assert secret not in str(error.value)
+    assert all(secret not in outcome for _, outcome in conn.auth_attempts)
+    root = Connection(host="localhost", port=8022, password="toor")
+    with pytest.raises(CommandError) as error:
+        root.sudo(f"echo {secret} >&2; exit 3", password=secret, hide=True)
+    assert secret not in str(error.value)
+    assert "***" in str(error.value)
+    assert error.value.result.status == 3
+    try:
+        assert redact_enabled(False) is False
+        assert conn.password == secret
+    finally:
+        redact_enabled(True)
+    assert redact_enabled()
+
+
+def test_sudo(conn):
+    """Test that we can run a command through sudo."""
+    result = conn.sudo("whoami", hide=True)
+    assert result.stdout == "root\n"
+    assert result.command == "whoami"
+    assert result.user == "root"
+
+
+def test_run_as(conn):
+    """Test that run_as quotes commands through sudo and su, and answers su's password prompt."""
+    conn.execute("id hussh_app || useradd -m hussh_app; echo 'hussh_app:app' | chpasswd")
+    tricky = """printf '%s|' "it's" 'say "hi"' '$HOME' `whoami` \\back; echo"""
+    expected = """it's|say "hi"|$HOME|hussh_app|\\back|\n"""
+    for via in ("sudo", "su"):
+        result = conn.run_as("hussh_app", tricky, via=via, hide=True)
+        assert result.stdout == expected
+        assert result.user == "hussh_app"
+        assert result.command == tricky
+    argv = conn.run_as("hussh_app", ["echo", "a b", "$c"], via="su", hide=True)
+    assert argv.stdout == "a b $c\n"
+    assert conn.run_as("hussh_app", "pwd", via="su", hide=True).stdout == "/home/hussh_app\n"
+    # a non-root user has to give su a password, which goes over a pty
+    app = Connection(host="localhost", port=8022, username="hussh_app", password="app")
+    result = app.run_as("root", tricky.replace("hussh_app", "root"), via="su", password="toor")
+    assert result.stdout == expected.replace("hussh_app", "root")
+    failed = app.run_as("root", "true", via="su", password="wrong", hide=True, warn=True)
+    assert failed.status != 0
+    assert "toor" not in failed.stdout
+    with pytest.raises(ValueError, match="Unknown run_as method"):
+        conn.run_as("hussh_app", "true", via="doas")
+
+
+def test_put_get(conn):
+    """Test that put and get round-trip a file."""
+    conn.put(str(TEXT_FILE), "/root/put_hp.txt")
+    conn.get("/root/put_hp.txt")
+    local_copy = Path("put_hp.txt")
+    assert local_copy.read_text() == TEXT_FILE.read_text()
+    local_copy.unlink()
+
+
+def test_upload_download(conn, tmp_path):
+    """Test that upload and download pick a backend and report what they copied."""
+    size = TEXT_FILE.stat().st_size
+    result = conn.upload(TEXT_FILE, "/root/upload_hp.txt", mode=0o600)
+    assert result["backend"] == "sftp"
+    assert result["bytes"] == size
+    assert result["duration"] >= 0
+    assert conn.execute("stat -c %a /root/upload_hp.txt").stdout.strip() == "600"
+    result = conn.download("/root/upload_hp.txt", tmp_path / "upload_hp.txt")
+    assert result == {"backend": "sftp", "bytes": size, "duration": result["duration"]}
+    assert (tmp_path / "upload_hp.txt").read_text() == TEXT_FILE.read_text()
+    with pytest.raises(OSError, match="is a directory"):
+        conn.upload(tmp_path)
+
+
+def test_upload_counts_own_bytes(conn):
+    """Test that upload reports its own bytes while other transfers and stats resets happen."""
+    size = TEXT_FILE.stat().st_size
+    others = threading.Thread(
+        target=lambda: [conn.sftp_write_data("x" * 100000, "/root/other.txt") for _ in range(5)]
+    )
+    others.start()
+    for _ in range(5):
+        conn.reset_stats()
+        assert conn.upload(TEXT_FILE, "/root/upload_own.txt")["bytes"] == size
+    others.join()
+
+
+def test_path_objects(conn, tmp_path):
+    """Test that local paths can be os.PathLike and remote paths PurePath, not only str."""
+    assert Connection(host="localhost", port=8022, private_key=Path("tests/data/test_key"))
+    remote = PurePosixPath("/root") / "paths_hp.txt"
+    conn.sftp_write(TEXT_FILE, remote)
+    conn.sftp_read(remote, tmp_path / "sftp.txt")
+    conn.scp_write(TEXT_FILE, PureWindowsPath("\\root\\scp_hp.txt"))
+    conn.scp_read("/root/scp_hp.txt", os.fsencode(tmp_path / "scp.txt"))
+    conn.get(remote, tmp_path / "get.txt")
+    for name in ("sftp.txt", "scp.txt", "get.txt"):
+        assert (tmp_path / name).read_text() == TEXT_FILE.read_text()
+    conn.execute("cat", stdin_file=TEXT_FILE, stdout_file=tmp_path / "stdout.txt")
+    assert (tmp_path / "stdout.txt").read_text() == TEXT_FILE.read_text()
+    with conn.tail(remote, from_pos=0) as tailer:
+        assert tailer.remote_file == "/root/paths_hp.txt"
+    with conn.tempdir(dir=PurePosixPath("/root")) as scratch:
+        assert scratch.startswith("/root/hussh-")
+    with pytest.raises(TypeError, match="expected str or pathlib.PurePath"):
+        conn.sftp_read(42)
+    with pytest.raises(TypeError, match="os.PathLike"):
+        conn.sftp_write(42, "/root/paths_hp.txt")
+
+
+def test_local_path_env_vars(conn, tmp_path, monkeypatch):
+    """Test that environment variables in local paths are expanded for SFTP and SCP."""
+    monkeypatch.setenv("HUSSH_LOCAL_DIR", str(tmp_path))
+    conn.sftp_write(str(TEXT_FILE), "/root/env_hp.txt")
+    conn.sftp_read("/root/env_hp.txt", "$HUSSH_LOCAL_DIR/sftp.txt")
+    conn.scp_read("/root/env_hp.txt", "${HUSSH_LOCAL_DIR}/scp.txt")
+    assert (tmp_path / "sftp.txt").read_text() == TEXT_FILE.read_text()
+    assert (tmp_path / "scp.txt").read_text() == TEXT_FILE.read_text()
+
+
+def test_logging(caplog):
+    """Test that connections and commands are logged under the hussh logger, without secrets."""
+    caplog.set_level(logging.DEBUG, logger="hussh")
+    conn = Connection(host="localhost", port=8022, password="toor")
+    conn.execute("echo hello")
+    conn.sftp_write_data("hello", "/root/logged.txt")
+    loggers = {record.name for record in caplog.records}
+    assert {"hussh.connection", "hussh.sftp"} <= loggers
+    assert any("exited with status 0" in record.getMessage() for record in caplog.records)
+    assert all("toor" not in record.getMessage() for record in caplog.records)
+
+
+def test_trace_file(tmp_path):
+    """Test that a protocol trace is written to the trace file, with the password redacted."""
+    trace_file = tmp_path / "trace.log"
+    conn = Connection(host="localhost", port=8022, password="toor", trace_file=str(trace_file))
+    conn.execute("echo hello")
+    trace = trace_file.read_text()
+    assert "Negotiated kex" in trace
+    assert "password auth succeeded" in trace
+    assert "Opened session channel" in trace
+    assert "toor" not in trace
+
+
+def test_stats(conn):
+    """Test that commands and transfers are counted, and that the counters can be reset."""
+    conn.execute("echo hello")
+    conn.sftp_write_data("hello", "/root/stats.txt")
+    assert conn.sftp_read("/root/stats.txt") == "hello"
+    stats = conn.stats()
+    assert stats["commands"] == 1
+    assert stats["command_time"] > 0
+    assert stats["bytes_uploaded"] == 5
+    assert stats["bytes_downloaded"] == 5
+    conn.reset_stats()
+    assert conn.stats() == {
+        "commands": 0,
+        "command_time": 0.0,
+        "bytes_uploaded": 0,
+        "bytes_downloaded": 0,
+        "reconnects": 0,
+    }
+
+
+def test_transport_stats(conn):
+    """Test that transport_stats reports the negotiated algorithms and the socket's counts."""
+    stats = conn.transport_stats()
+    assert stats["cipher"]
+    assert stats["compression"] == "none"
+    if sys.platform != "linux":
+        assert stats["bytes_sent"] is None
+        return
+    conn.sftp_write_data("x" * 100_000, "/root/transport.txt")
+    after = conn.transport_stats()
+    assert after["bytes_sent"] - stats["bytes_sent"] >= 100_000
+    assert after["packets_sent"] > stats["packets_sent"]
+    assert after["bytes_received"] > stats["bytes_received"]
+    assert after["retransmits"] >= 0
+
+
+def test_hooks():
+    """Test that hooks see connects, commands and closes, and that before_execute can veto."""
+    calls = []
+    conn = Connection(
+        host="localhost",
+        port=8022,
+        password="toor",
+        hooks={"on_connect": lambda host: calls.append(("connect", host))},
+    )
+    conn.add_hook("after_execute", lambda command, result: calls.append((command, result.status)))
+    conn.add_hook("on_close", lambda host: calls.append(("close", host)))
+    conn.execute("echo hello")
+    conn.close()
+    assert calls == [("connect", "localhost"), ("echo hello", 0), ("close", "localhost")]
+
+
+def test_hook_veto_and_errors(conn):
+    """Test that a raising before hook vetoes, and after hook errors keep the result."""
+
+    def veto(command):
+        if "rm" in command:
+            raise PermissionError(command)
+
+    def broken(command, result):
+        raise RuntimeError("audit log unavailable")
+
+    conn.add_hook("before_execute", veto)
+    with pytest.raises(PermissionError):
+        conn.execute("rm -rf /tmp/nothing")
+    conn.add_hook("after_execute", broken)
+    with pytest.raises(HookError) as err:
+        conn.execute("echo hello")
+    assert err.value.result.stdout == "hello\n"
+    assert isinstance(err.value.errors[0], RuntimeError)
+    with pytest.raises(ValueError, match="Unknown hook event"):
+        conn.add_hook("before_everything", veto)
+
+
+def test_command_policy():
+    """Test that a command policy denies commands wherever they're sent, before they run."""
+    conn = Connection(
+        host="localhost", port=8022, password="toor", command_policy=[r"\brm\b", "mkfs"]
+    )
+    conn.execute("touch /root/policy.txt")
+    with pytest.raises(CommandPolicyError, match="denied by the pattern") as err:
+        conn.execute("rm /root/policy.txt")
+    assert err.value.command == "rm /root/policy.txt"
+    assert isinstance(err.value, PermissionError)
+    # the check is on the command as sent, wrapper and all
+    with pytest.raises(CommandPolicyError, match="sudo"):
+        conn.sudo("rm /root/policy.txt")
+    with conn.batch() as batch, pytest.raises(CommandPolicyError):
+        batch.execute(["rm", "/root/policy.txt"])
+    with conn.shell(pty=True, set_prompt=True) as sh:
+        assert sh.send_and_read("echo kept") == "kept\n"
+        with pytest.raises(CommandPolicyError):
+            sh.send_and_read("mkfs.ext4 /dev/nope")
+    assert conn.execute("ls /root/policy.txt").status == 0
+    assert pickle.loads(pickle.dumps(conn)).command_policy == conn.command_policy
+    allowed = Connection(
+        host="localhost",
+        port=8022,
+        password="toor",
+        command_policy=lambda command: command.startswith("echo"),
+    )
+    assert allowed.execute("echo allowed").stdout == "allowed\n"
+    with pytest.raises(CommandPolicyError, match="denied by the command policy"):
+        allowed.execute("ls")
+    with pytest.raises(ValueError, match="command_policy"):
+        Connection(host="localhost", port=8022, command_policy=42, connect=False)
+
+
 def test_conn_context():
     """Test that the Connection class' context manager works."""
     with Connection(host="localhost", port=8022, password="toor") as conn:
@@ -78,6 +453,297 @@ def test_conn_context():
     assert result.stdout == "hello\n"
 
 
+def test_conn_context_cleanup_errors(caplog):
+    """Test that a close error is raised after a clean block, but never hides the block's error."""
+
+    def closing(**kwargs):
+        conn = Connection(host="localhost", port=8022, password="toor", **kwargs)
+        conn.add_hook("on_close", lambda host: 1 / 0)
+        return conn
+
+    with pytest.raises(HookError):
+        with closing():
+            pass
+    with pytest.raises(KeyError, match="from the block"):
+        with closing():
+            raise KeyError("from the block")
+    assert "Error closing the connection while handling KeyError" in caplog.text
+    with closing(suppress_cleanup_errors=True) as conn:
+        assert conn.suppress_cleanup_errors
+    assert conn.closed
+
+
+def test_shell_and_tail_cleanup_errors(caplog):
+    """Test the cleanup error rules for shells and tailers, and their suppress_cleanup_errors."""
+    conn = Connection(host="localhost", port=8022, password="toor", timeout=1000)
+    # the shell's result can't be read while sleep holds it open past the timeout
+    with pytest.raises(TimeoutError):
+        with conn.shell(pty=True) as shell:
+            shell.send("sleep 5")
+    with pytest.raises(KeyError):
+        with conn.shell(pty=True) as shell:
+            shell.send("sleep 5")
+            raise KeyError("from the block")
+    assert "Error closing the shell while handling KeyError" in caplog.text
+    with conn.shell(pty=True, suppress_cleanup_errors=True) as shell:
+        shell.send("sleep 5")
+    assert shell.result is None
+    # the tailer can't read the file on exit once it's been removed
+    for raised in (None, KeyError):
+        conn.execute("echo line > /root/cleanup.log")
+        with pytest.raises(raised or IOError, match="cleanup.log|from the block"):
+            with conn.tail("/root/cleanup.log"):
+                conn.execute("rm /root/cleanup.log")
+                if raised:
+                    raise raised("from the block")
+    assert "Error closing the tailer for /root/cleanup.log while handling KeyError" in caplog.text
+    conn.execute("echo line > /root/cleanup.log")
+    with conn.tail("/root/cleanup.log", suppress_cleanup_errors=True) as tailer:
+        conn.execute("rm /root/cleanup.log")
+    assert tailer.contents is None
+
+
+def test_double_close(conn):
+    """Test that closing a connection more than once is harmless."""
+    conn.close()
+    conn.close()
+    assert conn.closed
+
+
+def test_use_after_close(conn):
+    """Test that operations on a closed connection raise a clear error."""
+    conn.close()
+    with pytest.raises(IOError, match="is closed"):
+        conn.execute("echo hello")
+    with pytest.raises(IOError, match="is closed"):
+        conn.sftp_read("/root/.bashrc")
+
+
+def test_gc_without_close():
+    """Test that connections garbage collected without a close are cleaned up."""
+    for _ in range(20):
+        conn = Connection(host="localhost", port=8022, password="toor")
+        conn.execute("echo hello")
+        del conn
+    gc.collect()
+    assert Connection(host="localhost", port=8022, password="toor").execute("echo hi").status == 0
+
+
+def test_lazy_connect():
+    """Test that connect=False defers connecting until the connection is used."""
+    conn = Connection(host="localhost", port=8022, password="toor", connect=False)
+    assert not conn.connected
+    assert conn.execute("echo hello").stdout == "hello\n"
+    assert conn.connected
+
+
+def test_wait_for_ssh():
+    """Test that wait_for_ssh returns once a server sends its banner, and times out otherwise."""
+    assert 0 <= wait_for_ssh("localhost", 8022, timeout=5) < 5
+    conn = Connection(host="localhost", port=8022, connect=False)
+    assert conn.wait_until_ready(timeout=5) < 5
+    assert not conn.connected
+    # a listener that never sends a banner isn't an SSH server
+    with socket.create_server(("127.0.0.1", 0)) as silent:
+        port = silent.getsockname()[1]
+        start = time.monotonic()
+        with pytest.raises(TimeoutError, match="wasn't ready after"):
+            wait_for_ssh("127.0.0.1", port, timeout=1, interval=0.2)
+        assert time.monotonic() - start < 3
+    with pytest.raises(TimeoutError):
+        wait_for_ssh("127.0.0.1", port, timeout=0.5, interval=0.1)
+    with pytest.raises(ValueError, match="Invalid interval"):
+        wait_for_ssh("localhost", 8022, interval=-1)
+
+
+def test_reboot_without_going_down(conn):
+    """Test that reboot flags a host that never goes down, and reconnects after wait=False."""
+    with pytest.raises(CommandError):
+        conn.reboot(command="exit 3")
+    result = conn.reboot(command="true", timeout=3)
+    assert result["rebooted"] is False
+    assert conn.connected
+    assert conn.reboot(wait=False, command="true") is None
+    assert not conn.connected
+    assert conn.execute("echo back").stdout == "back\n"
+    # both reboots re-established the session
+    assert conn.stats()["reconnects"] == 2
+
+
+def test_reboot_denied_by_policy():
+    """Test that a reboot command the policy denies raises, instead of waiting for a reboot."""
+    conn = Connection(host="localhost", port=8022, password="toor", command_policy=["reboot"])
+    start = time.monotonic()
+    with pytest.raises(CommandPolicyError):
+        conn.reboot(timeout=30)
+    assert time.monotonic() - start < 10
+    assert conn.connected
+
+
+def test_check_publickey():
+    """Test that check_publickey reports whether a key would be accepted, without connecting."""
+    conn = Connection(host="localhost", port=8022, password="toor", connect=False)
+    accepted = conn.check_publickey("tests/data/test_key")
+    assert accepted["result"] == "accepted"
+    assert accepted["key_type"] == "ssh-rsa"
+    assert accepted["fingerprint"].startswith("SHA256:")
+    assert conn.check_publickey(Path("tests/data/auth_test_key.pub").read_text())["result"] == (
+        "accepted"
+    )
+    # an all-zero ed25519 key, which nobody has authorized
+    unknown_key = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAI" + "A" * 43
+    assert conn.check_publickey(unknown_key)["result"] == "rejected"
+    assert not conn.connected
+    with pytest.raises(ValueError, match="No public key found"):
+        conn.check_publickey("tests/data/hp.txt")
+
+
+def test_as_openssh_command():
+    """Test that as_openssh_command gives an ssh command line matching the settings."""
+    conn = Connection(host="localhost", port=8022, password="toor", connect=False)
+    assert conn.as_openssh_command(include_options=False) == (
+        "ssh -p 8022 root@localhost  # the password isn't included, ssh will ask for it"
+    )
+    assert "-o ConnectTimeout=30 -o StrictHostKeyChecking=no" in conn.as_openssh_command()
+    conn = Connection(
+        host="localhost",
+        private_key="tests/data/test_key",
+        keepalive_interval=30,
+        dead_peer_timeout=100,
+        allowed_auth=("agent", "publickey"),
+        connect=False,
+    )
+    command = conn.as_openssh_command()
+    assert command.startswith("ssh -i tests/data/test_key -o ConnectTimeout=30")
+    assert command.endswith(
+        "-o ServerAliveInterval=30 -o ServerAliveCountMax=4"
+        " -o PreferredAuthentications=publickey root@localhost"
+    )
+    assert "#" not in command
+
+
+def test_keepalive_and_dead_peer():
+    """Test that quiet commands send keepalives, and a silent server raises ConnectionLostError."""
+    dropping = threading.Event()
+    client_sends = []
+
+    def forward(src, dst, from_client):
+        try:
+            while data := src.recv(65536):
+                if dropping.is_set():
+                    continue  # like an iptables DROP rule: nothing gets through or is refused
+                if from_client:
+                    client_sends.append(time.monotonic())
+                dst.sendall(data)
+        except OSError:
+            pass
+
+    def relay(listener):
+        client, _ = listener.accept()
+        server = socket.create_connection(("localhost", 8022))
+        for args in ((client, server, True), (server, client, False)):
+            threading.Thread(target=forward, args=args, daemon=True).start()
+
+    with socket.create_server(("127.0.0.1", 0)) as listener:
+        threading.Thread(target=relay, args=(listener,), daemon=True).start()
+        conn = Connection(
+            host="127.0.0.1",
+            port=listener.getsockname()[1],
+            password="toor",
+            keepalive_interval=1,
+            dead_peer_timeout=3,
+        )
+        start = time.monotonic()
+        assert conn.execute("sleep 4; echo done").stdout == "done\n"
+        assert any(start + 1.5 < sent < start + 3.5 for sent in client_sends)
+        threading.Timer(1, dropping.set).start()
+        start = time.monotonic()
+        with pytest.raises(ConnectionLostError, match="hasn't answered"):
+            conn.execute("sleep 60")
+        assert time.monotonic() - start < 10
+    clone = pickle.loads(pickle.dumps(conn))
+    assert (clone.keepalive_interval, clone.dead_peer_timeout) == (1, 3.0)
+    with pytest.raises(ValueError, match="needs a keepalive_interval"):
+        Connection(host="localhost", port=8022, connect=False, dead_peer_timeout=3)
+    with pytest.raises(ValueError, match="Invalid dead_peer_timeout"):
+        Connection(
+            host="localhost", port=8022, connect=False, keepalive_interval=5, dead_peer_timeout=5
+        )
+
+
+def test_pickle_and_deepcopy(conn):
+    """Test that pickled and copied connections are independent and connect on first use."""
+    for clone in (pickle.loads(pickle.dumps(conn)), copy.deepcopy(conn)):
+        assert not clone.connected
+        assert (clone.host, clone.port, clone.password) == (conn.host, conn.port, conn.password)
+        assert clone.execute("echo hello").status == 0
+        clone.close()
+    assert conn.execute("echo still here").status == 0
+
+
+def test_threaded_use(conn):
+    """Test that threads sharing one connection are serialized, not corrupting the session."""
+    deadline = time.monotonic() + 3
+    errors = []
+
+    def hammer(worker):
+        try:
+            while time.monotonic() < deadline:
+                assert conn.execute(f"echo {worker}").stdout == f"{worker}\n"
+                conn.sftp_write_data(str(worker), f"/root/thread{worker}.txt")
+                assert conn.sftp_read(f"/root/thread{worker}.txt") == str(worker)
+        except Exception as err:
+            errors.append(err)
+
+    threads = [threading.Thread(target=hammer, args=(worker,)) for worker in range(8)]
+    for thread in threads:
+        thread.start()
+    for thread in threads:
+        thread.join()
+    assert not errors
+
+
+def test_gil_released_during_command(conn):
+    """Test that other threads keep running while a command waits on the server."""
+    other = Connection(host="localhost", port=8022, password="toor")
+    slow = threading.Thread(target=conn.execute, args=("sleep 2",))
+    start = time.monotonic()
+    slow.start()
+    assert conn.connected
+    assert other.execute("echo other").stdout == "other\n"
+    assert time.monotonic() - start < 2
+    slow.join()
+    other.close()
+
+
+def test_shell_alongside_execute(conn):
+    """Test that a shell on one thread and commands on another take turns on the connection."""
+    results = []
+
+    def run_commands():
+        for i in range(5):
+            results.append(conn.execute(f"sleep 0.2; echo out{i}; echo err{i} >&2"))
+
+    with conn.shell(pty=True, set_prompt=True) as sh:
+        runner = threading.Thread(target=run_commands)
+        runner.start()
+        for i in range(5):
+            assert sh.send_and_read(f"echo shell{i}") == f"shell{i}\n"
+        runner.join()
+    assert [(r.stdout, r.stderr) for r in results] == [
+        (f"out{i}\n", f"err{i}\n") for i in range(5)
+    ]
+
+
+def test_lock(conn):
+    """Test that lock() groups operations and can be re-entered by its holder."""
+    with conn.lock():
+        conn.sftp_write_data("#!/bin/sh\necho locked", "/root/locked.sh")
+        with conn.lock():
+            conn.execute("chmod +x /root/locked.sh")
+        assert conn.execute("/root/locked.sh").stdout == "locked\n"
+
+
 def test_text_scp(conn):
     """Test that we can copy a file to the server and read it back."""
     # copy a local file to the server
@@ -94,6 +760,16 @@ def test_text_scp(conn):
     assert scp_hp_text == hp_text
 
 
+def test_scp_write_to_directory(conn):
+    """Test that scp_write into an existing directory keeps the local file name."""
+    conn.scp_write(str(TEXT_FILE), "/tmp")
+    assert conn.scp_read("/tmp/hp.txt") == TEXT_FILE.read_text()
+    limited = Connection(host="localhost", port=8022, password="toor", max_channels=1)
+    limited.scp_write(str(TEXT_FILE), "/root")
+    assert limited.scp_read("/root/hp.txt") == TEXT_FILE.read_text()
+    limited.close()
+
+
 def test_scp_write_data(conn):
     """Test that we can write a string to a file on the server."""
     conn.scp_write_data("hello", "/root/hello.txt")
@@ -102,6 +778,38 @@ def test_scp_write_data(conn):
     assert read_text == "hello"
 
 
+def test_file_mode(conn, tmp_path):
+    """Test that mode and default_file_mode set permissions from creation onward."""
+
+    def mode(path):
+        return conn.execute(f"stat -c %a {path}").stdout.strip()
+
+    conn.execute("rm -f /root/mode_*")
+    conn.scp_write_data("secret", "/root/mode_scp.txt", mode=0o600)
+    conn.sftp_write_data("secret", "/root/mode_sftp.txt", mode=0o600)
+    assert (mode("/root/mode_scp.txt"), mode("/root/mode_sftp.txt")) == ("600", "600")
+    # an existing file is narrowed before it's written over SFTP
+    conn.sftp_write_data("public", "/root/mode_open.txt")
+    assert mode("/root/mode_open.txt") == "644"
+    conn.sftp_write_data("secret", "/root/mode_open.txt", mode=0o600)
+    assert mode("/root/mode_open.txt") == "600"
+    # watch the file's mode from another channel while a large file is written
+    big_file = tmp_path / "big.bin"
+    big_file.write_bytes(os.urandom(32 * 1024 * 1024))
+    conn.execute(
+        "(for i in $(seq 500); do stat -c %a /root/mode_big.bin 2>/dev/null; sleep 0.01; done)"
+        " > /root/mode_watch.log 2>&1 &"
+    )
+    strict = Connection(host="localhost", port=8022, password="toor", default_file_mode=0o600)
+    assert strict.default_file_mode == 0o600
+    strict.sftp_write(str(big_file), "/root/mode_big.bin")
+    time.sleep(5)
+    seen = set(conn.execute("cat /root/mode_watch.log").stdout.split())
+    assert seen == {"600"}
+    with pytest.raises(ValueError, match="permission mode"):
+        conn.sftp_write_data("data", "/root/mode_bad.txt", mode=0o100644)
+
+
 @pytest.mark.skip("non-text files are not supported by scp")
 def test_non_utf8_scp(conn):
     """Test that we can copy a non-text file to the server and read it back."""
@@ -109,9 +817,14 @@ def test_non_utf8_scp(conn):
     conn.scp_write(str(IMG_FILE), "/root/puppy.jpeg")
     assert "puppy.jpeg" in conn.execute("ls /root").stdout
     # read the file back from the server
-    read_img = conn.scp_read("/root/puppy.jpeg")
+    read_img = conn.scp_read("/root/puppy.jpeg", binary=True)
     img_data = Path(str(IMG_FILE)).read_bytes()
     assert read_img == img_data
+    with pytest.raises(OSError, match="binary=True"):
+        conn.scp_read("/root/puppy.jpeg")
+    with pytest.raises(FileTooLargeError):
+        conn.scp_read("/root/puppy.jpeg", binary=True, max_bytes=len(img_data) - 1)
+    assert conn.scp_read("/root/puppy.jpeg", binary=True, max_bytes=len(img_data)) == img_data
     # copy the file from the server to a local file
     conn.scp_read("/root/puppy.jpeg", "scp_puppy.jpeg")
     scp_img_data = Path("scp_puppy.jpeg").read_bytes()
@@ -135,6 +848,25 @@ def test_text_sftp(conn):
     assert sftp_hp_text == hp_text
 
 
+def test_sftp_write_from_pipe(conn, tmp_path):
+    """Test that sftp_write copies from a named pipe, and scp_write refuses one."""
+    fifo = tmp_path / "fifo"
+    os.mkfifo(fifo)
+    data = "piped data\n" * 10000
+
+    def feed():
+        with fifo.open("w") as writer:
+            writer.write(data)
+
+    feeder = threading.Thread(target=feed)
+    feeder.start()
+    conn.sftp_write(str(fifo), "/root/piped.txt")
+    feeder.join()
+    assert conn.sftp_read("/root/piped.txt") == data
+    with pytest.raises(IOError, match="Use sftp_write instead"):
+        conn.scp_write(str(fifo), "/root/piped.txt")
+
+
 def test_sftp_write_data(conn):
     """Test that we can write a string to a file on the server."""
     conn.sftp_write_data("hello", "/root/hello.txt")
@@ -144,15 +876,34 @@ def test_sftp_write_data(conn):
 
 
 @pytest.mark.skip("non-text files are not supported by sftp")
+def test_remote_tilde(conn):
+    """Test that a leading ~ in remote paths expands to the remote user's home directory."""
+    conn.sftp_write_data("tilde", "~/tilde.txt")
+    assert conn.sftp_read("/root/tilde.txt") == "tilde"
+    assert conn.scp_read("~/tilde.txt") == "tilde"
+    with conn.tail("~/tilde.txt") as tf:
+        conn.execute("echo more >> /root/tilde.txt")
+    assert tf.contents == "more\n"
+    with pytest.raises(ValueError, match="another user's home"):
+        conn.sftp_read("~nobody/tilde.txt")
+
+
 def test_non_utf8_sftp(conn):
     """Test that we can copy a non-text file to the server and read it back."""
     # copy an image file to the server
     conn.sftp_write(str(IMG_FILE), "/root/puppy.jpeg")
     assert "puppy.jpeg" in conn.execute("ls /root").stdout
     # read the file back from the server
-    read_img = conn.sftp_read("/root/puppy.jpeg")
+    read_img = conn.sftp_read("/root/puppy.jpeg", binary=True)
     img_data = Path(str(IMG_FILE)).read_bytes()
     assert read_img == img_data
+    with pytest.raises(OSError, match="binary=True"):
+        conn.sftp_read("/root/puppy.jpeg")
+    with pytest.raises(FileTooLargeError):
+        conn.sftp_read("/root/puppy.jpeg", binary=True, max_bytes=len(img_data) - 1)
+    # /dev/zero reports no size, so the limit stops the read instead
+    with pytest.raises(FileTooLargeError, match="at least 11 bytes"):
+        conn.sftp_read("/dev/zero", binary=True, max_bytes=10)
     # copy the file from the server to a local file
     conn.sftp_read("/root/puppy.jpeg", "sftp_puppy.jpeg")
     sftp_img_data = Path("sftp_puppy.jpeg").read_bytes()
@@ -160,6 +911,96 @@ def test_non_utf8_sftp(conn):
     assert sftp_img_data == img_data
 
 
+def test_empty_file_transfers(conn, tmp_path):
+    """Test that empty files round-trip through all four transfer methods."""
+    empty = tmp_path / "empty.txt"
+    empty.touch()
+    conn.scp_write(str(empty), "/root/empty_scp.txt")
+    conn.sftp_write(str(empty), "/root/empty_sftp.txt")
+    for read, remote in (
+        (conn.scp_read, "/root/empty_scp.txt"),
+        (conn.sftp_read, "/root/empty_sftp.txt"),
+    ):
+        assert read(remote) == ""
+        local = tmp_path / f"copy_{Path(remote).name}"
+        read(remote, str(local))
+        assert local.read_bytes() == b""
+
+
+def _sha256(path):
+    digest = hashlib.sha256()
+    with path.open("rb") as f:
+        for chunk in iter(lambda: f.read(1024 * 1024), b""):
+            digest.update(chunk)
+    return digest.hexdigest()
+
+
+@pytest.mark.slow
+def test_large_file_sftp(conn, tmp_path):
+    """Test that a sparse file larger than 4 GB round-trips over SFTP intact."""
+    size = 5 * 1024**3
+    big = tmp_path / "big.bin"
+    with big.open("wb") as f:
+        f.seek(size - 5)
+        f.write(b"hussh")
+    conn.sftp_write(str(big), "/root/big.bin")
+    copy = tmp_path / "big_copy.bin"
+    try:
+        conn.sftp_read("/root/big.bin", str(copy))
+    finally:
+        conn.execute("rm -f /root/big.bin")
+    assert copy.stat().st_size == size
+    assert _sha256(copy) == _sha256(big)
+    assert conn.stats()["bytes_uploaded"] == size
+
+
+def test_transfer_buffer_size(conn, tmp_path):
+    """Test that transfers work with a custom buffer_size, and reject unreasonable ones."""
+    for size in (4096, 1024 * 1024):
+        conn.sftp_write(str(IMG_FILE), "/root/puppy_buf.jpeg", buffer_size=size)
+        conn.scp_write(str(IMG_FILE), "/root/puppy_buf_scp.jpeg", buffer_size=size)
+        for read, remote in (
+            (conn.sftp_read, "/root/puppy_buf.jpeg"),
+            (conn.scp_read, "/root/puppy_buf_scp.jpeg"),
+        ):
+            local = tmp_path / f"{size}_{Path(remote).name}"
+            read(remote, str(local), buffer_size=size)
+            assert local.read_bytes() == IMG_FILE.read_bytes()
+    with pytest.raises(ValueError, match="buffer_size must be between"):
+        conn.sftp_read("/root/puppy_buf.jpeg", buffer_size=0)
+
+
+BENCH_READ = """
+import resource, sys, time
+from hussh import Connection
+conn = Connection(host="localhost", port=8022, password="toor")
+start = time.perf_counter()
+local = sys.argv[2] if sys.argv[2] != "-" else None
+conn.sftp_read("/root/bench.bin", local, buffer_size=int(sys.argv[1]))
+print(time.perf_counter() - start, resource.getrusage(resource.RUSAGE_SELF).ru_maxrss)
+"""
+
+
+@pytest.mark.slow
+def test_large_read_benchmark(conn, tmp_path):
+    """Report time and peak RSS for a 200 MB read. Informational, nothing is asserted."""
+    conn.execute("head -c 200000000 /dev/zero | tr '\\0' x > /root/bench.bin")
+    try:
+        cases = ((65536, "-"), (1024 * 1024, "-"), (65536, str(tmp_path / "bench")))
+        for size, local in cases:
+            result = subprocess.run(
+                [sys.executable, "-c", BENCH_READ, str(size), local],
+                capture_output=True,
+                text=True,
+                check=True,
+            )
+            seconds, peak_kb = result.stdout.split()
+            target = "memory" if local == "-" else "file"
+            print(f"sftp_read to {target}, {size} byte buffer: {seconds}s, {peak_kb} KB peak RSS")
+    finally:
+        conn.execute("rm -f /root/bench.bin")
+
+
 def test_shell_context(conn):
     """Test that we can run multiple commands in a shell context."""
     with conn.shell() as sh:
@@ -180,6 +1021,55 @@ def test_pty_shell_context(conn):
     assert sh.result.status != 0
 
 
+def test_shell_suppress_noise(conn):
+    """Test that suppress_noise keeps a profile's output out of the result, in preamble instead."""
+    conn.execute("id hussh_app || useradd -m hussh_app; echo 'hussh_app:app' | chpasswd")
+    app = Connection(host="localhost", port=8022, username="hussh_app", password="app")
+    app.execute("echo 'echo profile noise' > ~/.bash_profile")
+    try:
+        with app.shell() as sh:
+            sh.send("echo clean")
+        assert sh.result.stdout == "profile noise\nclean\n"
+        assert sh.preamble is None
+        with app.shell(suppress_noise=True) as sh:
+            sh.send("echo clean")
+        assert sh.result.stdout == "clean\n"
+        assert sh.preamble == "profile noise\n"
+        with app.shell(pty=True, set_prompt=True, suppress_noise=True) as sh:
+            assert "profile noise" in sh.preamble
+            assert "printf" not in sh.preamble
+            assert sh.send_and_read("echo clean") == "clean\n"
+    finally:
+        app.execute("rm -f ~/.bash_profile")
+
+
+def test_shell_prompt(conn):
+    """Test that send_and_read returns exactly each command's output, in bash and then sh."""
+    with conn.shell(pty=True, set_prompt=True) as sh:
+        assert sh.prompt.startswith("<hussh-")
+        assert sh.send_and_read("echo one; echo two") == "one\ntwo\n"
+        assert sh.send_and_read("printf 'no newline'") == "no newline"
+        assert sh.send_and_read("cd /tmp") == ""
+        assert sh.send_and_read("for i in 1 2\ndo echo $i\ndone") == "1\n2\n"
+        sh.send("exec sh")
+        sh.set_prompt()
+        assert sh.send_and_read("pwd") == "/tmp\n"
+    with conn.shell(pty=True) as sh:
+        with pytest.raises(ValueError, match="no known prompt"):
+            sh.send_and_read("true")
+    with pytest.raises(ValueError, match="pty=True"):
+        conn.shell(set_prompt=True)
+
+
+def test_shell_strip_ansi(conn):
+    """Test that strip_ansi removes color codes from pty shell output."""
+    with conn.shell(pty=True, set_prompt=True, strip_ansi=True) as sh:
+        output = sh.send_and_read(r"printf '\033[1;31mred\033[0m\n'")
+        assert output == "red\n"
+        with pytest.raises(TimeoutError):
+            sh.send_and_read("sleep 5", timeout=500)
+
+
 @pytest.mark.skip("not yet implemented")
 def test_hangup_shell_context(conn):
     """Test that we can hang up a running shell while a previous command is still running."""
@@ -197,6 +1087,93 @@ def test_remote_copy(conn, run_second_server):
     dest_conn = Connection(host="localhost", port=8023, password="toor")
     conn.remote_copy("/root/hp.txt", dest_conn)
     assert "hp.txt" in dest_conn.execute("ls /root").stdout
+    # the copy is created with the destination connection's default file mode
+    strict = Connection(host="localhost", port=8023, password="toor", default_file_mode=0o600)
+    conn.remote_copy("/root/hp.txt", strict, "/root/hp_strict.txt")
+    assert strict.execute("stat -c %a /root/hp_strict.txt").stdout.strip() == "600"
+
+
+def test_sftp_session_recovers(conn):
+    """Test that a cached SFTP session whose channel died is reopened instead of failing."""
+    conn.sftp_write_data("before", "/root/sftp_recover.txt")
+    # stands in for a server reaping the idle channel
+    conn.execute("pkill -x sftp-server")
+    time.sleep(0.5)
+    assert conn.sftp_read("/root/sftp_recover.txt") == "before"
+    conn.sftp_write_data("after", "/root/sftp_recover.txt")
+    assert conn.sftp_read("/root/sftp_recover.txt") == "after"
+
+
+def test_sftp_max_idle():
+    """Test that an SFTP session idle past sftp_max_idle is replaced, and that it pickles."""
+    conn = Connection(host="localhost", port=8022, password="toor", sftp_max_idle=0.5)
+    assert conn.sftp_max_idle == 0.5
+    conn.sftp_write_data("idle", "/root/sftp_idle.txt")
+    pid = conn.execute("pgrep -x sftp-server").stdout
+    time.sleep(1)
+    assert conn.sftp_read("/root/sftp_idle.txt") == "idle"
+    assert conn.execute("pgrep -x sftp-server").stdout != pid
+    assert pickle.loads(pickle.dumps(conn)).sftp_max_idle == 0.5
+    with pytest.raises(ValueError, match="Invalid sftp_max_idle"):
+        Connection(host="localhost", port=8022, connect=False, sftp_max_idle=-1)
+
+
+def test_tempdir_and_tempfile(conn, caplog):
+    """Test that temporary paths are unique, private, and removed even when the block raises."""
+    with conn.tempdir() as scratch:
+        assert scratch.startswith("/tmp/hussh-")
+        conn.execute(f"mkdir -p {scratch}/a/b && touch {scratch}/a/b/c && ln -s / {scratch}/root")
+        assert conn.execute(f"stat -c %a {scratch}").stdout == "700\n"
+    assert conn.execute(f"test -e {scratch}").status != 0
+    assert conn.execute("test -d /etc").status == 0
+    with pytest.raises(RuntimeError), conn.tempfile(prefix="t-", dir="/root") as path:
+        assert path.startswith("/root/t-")
+        assert conn.execute(f"stat -c %a:%s {path}").stdout == "600:0\n"
+        raise RuntimeError("the body failed")
+    assert conn.execute(f"test -e {path}").status != 0
+    temps = [conn.tempdir() for _ in range(20)]
+    assert len({temp.path for temp in temps}) == 20
+    assert all(temp.cleanup() for temp in temps)
+    # a failed cleanup is logged, not raised
+    temp = conn.tempdir()
+    conn.execute(f"rmdir {temp.path}")
+    with caplog.at_level(logging.WARNING, logger="hussh"):
+        assert not temp.cleanup()
+    assert "Couldn't remove" in caplog.text
+    with pytest.raises(ValueError, match="prefix"):
+        conn.tempdir(prefix="a/b")
+    # a server failure that isn't a name collision is raised straight away
+    with pytest.raises(OSError, match="Error creating a temporary path in /dev/null"):
+        conn.tempdir(dir="/dev/null")
+
+
+def test_sftp_du(conn):
+    """Test that sftp_du totals a tree, skipping unreadable directories instead of failing."""
+    conn.execute("id hussh_app || useradd -m hussh_app; echo 'hussh_app:app' | chpasswd")
+    # root can read any directory, so walk the tree as someone else
+    app = Connection(host="localhost", port=8022, username="hussh_app", password="app")
+    app.execute(
+        "rm -rf ~/du && mkdir -p ~/du/a/b ~/du/locked && printf 12345 > ~/du/one"
+        " && printf 123 > ~/du/a/b/two && ln -s one ~/du/link && chmod 000 ~/du/locked"
+    )
+    # the symlink is 3 bytes, the length of its target's name
+    assert app.sftp_du("~/du") == {"files": 3, "dirs": 3, "bytes": 5 + 3 + 3, "skipped": 1}
+    with pytest.raises(IOError, match="readdir"):
+        app.sftp_du("~/du/locked")
+
+
+def test_sftp_statvfs(conn):
+    """Test that sftp_statvfs and disk_free agree with df on the free space."""
+    usage = conn.sftp_statvfs("/root")
+    assert usage["bytes_total"] == usage["blocks"] * usage["block_size"]
+    assert usage["bytes_free"] == usage["blocks_available"] * usage["block_size"]
+    assert usage["blocks_available"] <= usage["blocks_free"] <= usage["blocks"]
+    assert conn.sftp_statvfs("/root/.ssh/authorized_keys")["blocks"] == usage["blocks"]
+    df_kib = int(conn.execute("df -Pk /root | tail -n 1").stdout.split()[3])
+    # other processes may be writing, so allow some drift
+    assert abs(conn.disk_free("/root") - df_kib * 1024) < 64 * 1024 * 1024
+    with pytest.raises(IOError, match="statvfs"):
+        conn.sftp_statvfs("/root/nope")
 
 
 def test_tail(conn):
@@ -210,6 +1187,102 @@ def test_tail(conn):
     assert tf.contents == "goodbye\n"
 
 
+def test_tail_start_stop(conn):
+    """Test that start and stop tail a file outside of a with block."""
+    conn.scp_write_data("before\n", "/root/start_stop.log")
+    tailer = conn.tail("/root/start_stop.log")
+    assert not tailer.started
+    tailer.start()
+    assert tailer.started
+    assert tailer.last_pos == len("before\n")
+    conn.execute("echo after >> /root/start_stop.log")
+    tailer.stop()
+    assert not tailer.started
+    assert tailer.contents == "after\n"
+    assert tailer.checkpoint()["pos"] == len("before\nafter\n")
+
+
+def test_tail_checkpoint(conn):
+    """Test that a tailer resumes from a checkpoint, and starts over after rotation."""
+    conn.scp_write_data("one\n", "/root/rotate.log")
+    with conn.tail("/root/rotate.log", from_pos=0) as tf:
+        assert tf.read() == "one\n"
+        conn.execute("echo two >> /root/rotate.log")
+    assert tf.contents == "one\ntwo\n"
+    saved = tf.checkpoint()
+    assert saved["path"] == "/root/rotate.log"
+    assert saved["pos"] == 8
+    conn.execute("echo three >> /root/rotate.log")
+    with conn.tail(checkpoint=saved) as tf:
+        assert tf.read() == "three\n"
+    saved = tf.checkpoint()
+    conn.execute("mv /root/rotate.log /root/rotate.log.1 && echo four > /root/rotate.log")
+    with conn.tail(checkpoint=saved) as tf:
+        pass
+    assert tf.contents == "four\n"
+    with pytest.raises(ValueError, match="not both"):
+        conn.tail(checkpoint=saved, from_pos=0)
+    with pytest.raises(ValueError, match="checkpoint is for"):
+        conn.tail("/root/other.log", checkpoint=saved)
+
+
+def test_tail_wait_for_file(conn):
+    """Test that tail waits for a missing file with wait_for_file, and raises without it."""
+    conn.execute("rm -f /root/later.log")
+    with pytest.raises(FileNotFoundError, match="later.log"):
+        conn.tail("/root/later.log").start()
+    with pytest.raises(TimeoutError, match="didn't appear"):
+        conn.tail("/root/later.log", wait_for_file=0.5)
+    conn.execute("(sleep 1; echo started > /root/later.log) > /dev/null 2>&1 &")
+    with conn.tail("/root/later.log", wait_for_file=10) as tf:
+        conn.execute("echo more >> /root/later.log")
+    assert tf.contents == "more\n"
+    scp = Connection(host="localhost", port=8022, password="toor", transfer_backend="scp")
+    with pytest.raises(FileNotFoundError, match="missing.log"):
+        scp.tail("/root/missing.log").start()
+
+
+def test_scp_transfer_backend(tmp_path):
+    """Test that transfer_backend="scp" sends sftp_* and tail over SCP and exec instead."""
+    conn = Connection(host="localhost", port=8022, password="toor", transfer_backend="scp")
+    assert conn.transfer_backend == "scp"
+    conn.sftp_write_data("hello\nworld\n", "/root/backend.txt")
+    assert conn.sftp_read("/root/backend.txt") == "hello\nworld\n"
+    with conn.tail("/root/backend.txt") as tf:
+        assert tf.read(6) == "world\n"
+        conn.execute("echo goodbye >> /root/backend.txt")
+    assert tf.contents == "goodbye\n"
+    assert tf.last_pos == 20
+    with pytest.raises(ValueError, match="Unknown transfer backend"):
+        Connection(host="localhost", port=8022, password="toor", transfer_backend="ftp")
+
+
+def test_capabilities(conn):
+    """Test that capabilities probes the stock server once, and again on refresh."""
+    capabilities = conn.capabilities()
+    assert capabilities == {"sftp": True, "setenv": False, "shell": True}
+    assert conn.capabilities() == capabilities
+    assert conn.capabilities(refresh=True) == capabilities
+
+
+def test_sftp_unavailable(run_no_sftp_server, tmp_path):
+    """Test that servers without SFTP raise SFTPUnavailableError, or fall back to SCP with auto."""
+    strict = Connection(host="localhost", port=8025, password="toor", transfer_backend="sftp")
+    with pytest.raises(SFTPUnavailableError):
+        strict.sftp_write_data("data", "/root/no_sftp.txt")
+    conn = Connection(host="localhost", port=8025, password="toor")
+    conn.sftp_write_data("fallback\n", "/root/no_sftp.txt")
+    assert conn.sftp_read("/root/no_sftp.txt") == "fallback\n"
+    conn.put(str(TEXT_FILE), "/root/no_sftp_hp.txt")
+    conn.get("/root/no_sftp_hp.txt", str(tmp_path / "hp.txt"))
+    assert (tmp_path / "hp.txt").read_text() == TEXT_FILE.read_text()
+    with conn.tail("/root/no_sftp.txt") as tf:
+        conn.execute("echo more >> /root/no_sftp.txt")
+    assert tf.contents == "more\n"
+    assert conn.capabilities() == {"sftp": False, "setenv": False, "shell": True}
+    assert conn.disk_free("/root") > 0
+
+
 # ------------- Negative Tests -------------
 
 
@@ -219,6 +1292,405 @@ def test_session_timeout():
         Connection(host="localhost", port=8022, password="toor", timeout=10)
 
 
+def test_empty_username():
+    """Test that an empty username is refused up front, while leaving it out means root."""
+    with pytest.raises(ValueError, match="username can't be empty"):
+        Connection(host="localhost", port=8022, username="", password="toor")
+    conn = Connection(host="localhost", port=8022, password="toor", connect=False)
+    assert conn.username == "root"
+
+
+def test_connect_timeout():
+    """Test that an unreachable host fails within connect_timeout rather than the OS default."""
+    start = time.monotonic()
+    with pytest.raises(TimeoutError):
+        Connection(host="10.255.255.1", connect_timeout=500)
+    assert time.monotonic() - start < 5
+    conn = Connection(host="localhost", port=8022, password="toor", timeout=1000)
+    assert conn.connect_timeout == 1000
+
+
+NASTY_STRINGS = [
+    "",
+    "plain",
+    "with space",
+    "it's",
+    '"double"',
+    "$(echo pwned)",
+    "`echo pwned`",
+    "$HOME",
+    "*",
+    "a\nb",
+    "tab\there",
+    "back\\slash",
+    "semi;colon && more",
+    "-n",
+    "ünïcödé",
+]
+
+
+@pytest.mark.parametrize("value", NASTY_STRINGS)
+def test_argv_round_trip(conn, value):
+    """Test that list arguments and shlex_quote reach the remote command literally."""
+    assert conn.execute(["printf", "%s", value]).stdout == value
+    assert conn.execute(f"printf %s {shlex_quote(value)}").stdout == value
+
+
+def test_argv_run_and_sudo(conn):
+    """Test that run and sudo accept argument lists and record the quoted command."""
+    result = conn.run(["echo", "a b", "$HOME"], hide=True)
+    assert result.stdout == "a b $HOME\n"
+    assert result.command == "echo 'a b' '$HOME'"
+    assert conn.sudo(["printf", "%s", "it's"], hide=True).stdout == "it's"
+    with pytest.raises(ValueError, match="argument list is empty"):
+        conn.execute([])
+
+
+def test_max_output_bytes(conn):
+    """Test that output past max_output_bytes is cut off instead of buffered."""
+    result = conn.execute("yes | head -c 1000000", max_output_bytes=1000)
+    assert result.truncated
+    assert result.status == -1
+    assert result.stdout == "y\n" * 500
+    assert result.stdout_bytes == 1000
+    result = conn.execute("echo out; echo err >&2", max_output_bytes=6)
+    assert (result.stdout, result.stderr, result.truncated) == ("out\n", "er", True)
+    result = conn.execute("echo fits", max_output_bytes=5)
+    assert not result.truncated
+    assert result.status == 0
+
+
+def test_output_to_files(conn, tmp_path):
+    """Test that stdout_file and stderr_file receive output in place of the result."""
+    stdout_file, stderr_file = tmp_path / "out.txt", tmp_path / "err.txt"
+    result = conn.execute(
+        "seq 100000; echo oops >&2", stdout_file=str(stdout_file), stderr_file=str(stderr_file)
+    )
+    assert (result.stdout, result.stderr) == ("", "")
+    expected = "".join(f"{i}\n" for i in range(1, 100001))
+    assert stdout_file.read_text() == expected
+    assert result.stdout_bytes == len(expected)
+    assert stderr_file.read_text() == "oops\n"
+    assert result.stderr_bytes == 5
+
+
+def test_execute_encoding(conn):
+    """Test that stdout and stderr can be decoded with different encodings."""
+    command = r"printf 'caf\303\251'; printf 'caf\351' >&2"
+    result = conn.execute(command, encoding=("utf-8", "latin-1"))
+    assert (result.stdout, result.stderr) == ("café", "café")
+    result = conn.execute(command, encoding={"stderr": "latin-1"})
+    assert (result.stdout, result.stderr) == ("café", "café")
+    with pytest.raises(UnicodeDecodeError):
+        conn.execute(command, encoding="ascii")
+    with pytest.raises(ValueError, match="Unknown stream"):
+        conn.execute(command, encoding={"stdin": "utf-8"})
+
+
+def test_detect_remote_locale():
+    """Test that the remote charset is detected, cached, and used as the default encoding."""
+    conn = Connection(host="localhost", port=8022, password="toor")
+    charmap = conn.execute("locale charmap").stdout.strip()
+    assert conn.detect_remote_locale() == charmap
+    assert conn.detect_remote_locale() == charmap
+    command = r"printf 'caf\303\251'"
+    assert conn.execute(command, encoding="utf-8").stdout == "café"
+    # without an encoding, the output is decoded with the remote charset
+    try:
+        expected = "café".encode().decode(charmap)
+    except UnicodeDecodeError:
+        with pytest.raises(UnicodeDecodeError):
+            conn.execute(command)
+    else:
+        assert conn.execute(command).stdout == expected
+
+
+def test_execute_retries(conn):
+    """Test that execute retries failures it's told to, and records each attempt."""
+    flaky = "n=$(cat /root/flaky 2>/dev/null || echo 0); echo $((n+1)) > /root/flaky; [ $n -ge 2 ]"
+    conn.execute("rm -f /root/flaky")
+    result = conn.execute(flaky, retries=3, retry_delay=0.1)
+    assert result.status == 0
+    assert len(result.attempts) == 3
+    conn.execute("rm -f /root/flaky")
+    result = conn.execute(flaky, retries=1, retry_delay=0)
+    assert result.status == 1
+    assert len(result.attempts) == 2
+    # only stderr matching the pattern is retried
+    noisy = "echo 'mirror: 503' >&2; exit 1"
+    pattern = re.compile("503")
+    assert len(conn.execute(noisy, retries=2, retry_delay=0, retry_on=pattern).attempts) == 3
+    quiet = conn.execute("exit 1", retries=2, retry_delay=0, retry_on=[pattern, "timeout"])
+    assert len(quiet.attempts) == 1
+    assert len(conn.execute("true").attempts) == 1
+    with pytest.raises(ValueError, match="Unknown retry condition"):
+        conn.execute("true", retries=1, retry_on="stderr")
+
+
+def test_defaults_validation():
+    """Test that Defaults rejects bad settings, and pickles with the connection it's given to."""
+    with pytest.raises(ValueError, match="Invalid retry_delay"):
+        Defaults(retry_delay=-1)
+    with pytest.raises(LookupError):
+        Defaults(encoding="no-such-codec")
+    with pytest.raises(ValueError, match="buffer_size must be between"):
+        Defaults(buffer_size=1)
+    defaults = Defaults(command_timeout=5000, retries=2, encoding="latin-1")
+    assert repr(defaults) == "Defaults(command_timeout=5000, retries=2, encoding='latin-1')"
+    conn = Connection(host="localhost", port=8022, connect=False, defaults=defaults)
+    clone = pickle.loads(pickle.dumps(conn))
+    assert repr(clone.defaults) == repr(defaults)
+    assert clone.effective_defaults() == {
+        "command_timeout": 5000,
+        "connect_timeout": 30000,
+        "retries": 2,
+        "retry_delay": 1.0,
+        "encoding": "latin-1",
+        "buffer_size": conn.effective_defaults()["buffer_size"],
+    }
+
+
+def test_defaults_precedence(tmp_path):
+    """Test that a connection's defaults apply unless a call gives its own settings."""
+    defaults = Defaults(command_timeout=1000, retries=2, retry_delay=0, buffer_size=4096)
+    conn = Connection(host="localhost", port=8022, password="toor", defaults=defaults)
+    with pytest.raises(TimeoutError):
+        conn.execute("sleep 5", retries=0)
+    assert conn.execute("sleep 2", timeout=5000).status == 0
+    assert len(conn.execute("exit 1").attempts) == 3
+    assert len(conn.execute("exit 1", retries=0).attempts) == 1
+    conn.sftp_write(str(IMG_FILE), "/root/puppy_defaults.jpeg")
+    local = tmp_path / "puppy.jpeg"
+    conn.sftp_read("/root/puppy_defaults.jpeg", str(local))
+    assert local.read_bytes() == IMG_FILE.read_bytes()
+    with pytest.raises(ValueError, match="buffer_size must be between"):
+        conn.sftp_read("/root/puppy_defaults.jpeg", str(local), buffer_size=0)
+    latin = Connection(
+        host="localhost", port=8022, password="toor", defaults=Defaults(encoding="latin-1")
+    )
+    assert latin.execute(r"printf '\xe9'").stdout == "\xe9"
+    assert latin.execute(r"printf '\xc3\xa9'", encoding="utf-8").stdout == "\xe9"
+
+
+def test_remote_env(conn):
+    """Test that remote_env parses env -0, and that login_shell sources the login profile."""
+    profile = "/etc/profile.d/hussh_test.sh"
+    # values can hold newlines and equals signs, which NUL-delimited parsing keeps intact
+    conn.execute(f"""printf '%s\\n' 'export PATH=$PATH:/opt/hussh/bin' \
+        'export HUSSH_MULTI="one' 'two=2"' > {profile}""")
+    try:
+        env, login_env = conn.remote_env(), conn.remote_env(login_shell=True)
+        assert env["HOME"] == "/root"
+        assert "/opt/hussh/bin" not in env["PATH"]
+        assert "/opt/hussh/bin" in login_env["PATH"].split(":")
+        assert login_env["HUSSH_MULTI"] == "one\ntwo=2"
+        result = conn.execute("echo $PATH", login_shell=True)
+        assert "/opt/hussh/bin" in result.stdout.strip().split(":")
+        assert result.command == "echo $PATH"
+    finally:
+        conn.execute(f"rm -f {profile}")
+
+
+def test_execute_large_stderr(conn):
+    """Test that a command filling the channel with stderr doesn't stall reading its stdout."""
+    command = r"head -c 8388608 /dev/zero | tr '\0' x >&2; printf y"
+    result = conn.execute(command, timeout=60_000)
+    assert (result.stdout, result.stderr_bytes, result.status) == ("y", 8388608, 0)
+    assert result.stderr == "x" * 8388608
+
+
+def test_execute_combine_streams(conn):
+    """Test that combine_streams keeps the order stdout and stderr arrived in."""
+    command = "echo one; sleep 0.3; echo two >&2; sleep 0.3; echo three"
+    result = conn.execute(command, combine_streams=True)
+    assert result.segments == [("stdout", "one\n"), ("stderr", "two\n"), ("stdout", "three\n")]
+    assert result.combined == "one\ntwo\nthree\n"
+    assert (result.stdout, result.stderr, result.status) == ("one\nthree\n", "two\n", 0)
+    # a character split between two runs goes to the later one
+    command = r"printf '\xc3'; sleep 0.3; echo two >&2; sleep 0.3; printf '\xa9'"
+    result = conn.execute(command, combine_streams=True)
+    assert result.segments == [("stderr", "two\n"), ("stdout", "\xe9")]
+    assert conn.execute("echo plain").segments is None
+    with pytest.raises(ValueError, match="combine_streams"):
+        conn.execute("true", combine_streams=True, max_output_bytes=10)
+
+
+def test_execute_strip_ansi(conn):
+    """Test that strip_ansi cleans escape sequences out of a command's output."""
+    command = (
+        r"printf '\033[1;32mgreen\033[0m\r\n\033]0;title\007done\n';"
+        r" printf '\033[31merr\033[0m' >&2"
+    )
+    result = conn.execute(command, strip_ansi=True)
+    assert (result.stdout, result.stderr) == ("green\ndone\n", "err")
+    assert result.stdout_bytes > len(result.stdout)
+    assert "\x1b[1;32m" in conn.execute(command).stdout
+
+
+def test_strip_ansi_helper():
+    """Test that the standalone strip_ansi removes sequences without touching other text."""
+    output = "\x1b[?2004h\x1b]0;root@host\x07# ls\r\n\x1b[01;34mdir\x1b[0m\r\n"
+    assert strip_ansi(output) == "# ls\ndir\n"
+    text = "[0m and ] aren't sequences, and neither is a lone \r or é\n"
+    assert strip_ansi(text) == text
+
+
+def test_stdin_file(conn, tmp_path):
+    """Test that stdin_file is streamed to the command without deadlocking on its output."""
+    data = os.urandom(4 * 1024 * 1024)
+    stdin_file = tmp_path / "in.bin"
+    stdin_file.write_bytes(data)
+    result = conn.execute("sha256sum", stdin_file=str(stdin_file))
+    assert result.stdout.split()[0] == hashlib.sha256(data).hexdigest()
+    # echoing everything back fills the channel window while we're still writing
+    stdin_file.write_text("line\n" * 500000)
+    result = conn.execute("cat; echo done >&2", stdin_file=str(stdin_file))
+    assert result.stdout_bytes == 2500000
+    assert result.stderr == "done\n"
+    with pytest.raises(IOError, match="Local file open error"):
+        conn.execute("cat", stdin_file=str(tmp_path / "missing"))
+
+
+def test_channel_open_refused(run_limited_server):
+    """Test that a channel the server refuses raises ChannelOpenError with its reason."""
+    conn = Connection(host="localhost", port=8024, password="toor")
+    with conn.shell():
+        with pytest.raises(ChannelOpenError) as excinfo:
+            conn.execute("true")
+    assert excinfo.value.reason == "administratively prohibited"
+    assert isinstance(excinfo.value, IOError)
+
+
+def test_max_channels_waits(run_limited_server):
+    """Test that max_channels queues channel opens instead of exceeding the server's limit."""
+    conn = Connection(host="localhost", port=8024, password="toor", max_channels=1)
+    conn.sftp_write_data("data", "/root/limited.txt")  # leaves the sftp channel cached
+    assert conn.execute("cat /root/limited.txt").stdout == "data"
+    assert conn.sftp_read("/root/limited.txt") == "data"
+    shell = conn.shell()
+    closer = threading.Timer(1, shell.close)
+    closer.start()
+    start = time.monotonic()
+    assert conn.execute("echo waited").stdout == "waited\n"
+    assert time.monotonic() - start >= 0.9
+    closer.join()
+
+
+def test_max_channels_timeout(run_limited_server):
+    """Test that waiting for a free channel gives up after the connection's timeout."""
+    conn = Connection(host="localhost", port=8024, password="toor", timeout=500, max_channels=1)
+    assert conn.max_channels == 1
+    with conn.shell():
+        with pytest.raises(ChannelOpenError, match="No channel"):
+            conn.execute("true")
+    with pytest.raises(ValueError, match="at least 1"):
+        Connection(host="localhost", port=8024, password="toor", max_channels=0)
+
+def test_banner_info(conn):
+    """Test that get_banner_info reports the banners and MOTD without leaving a channel open."""
+    conn.execute("echo 'Authorized use only' > /etc/motd")
+    try:
+        limited = Connection(host="localhost", port=8022, password="toor", max_channels=1)
+        info = limited.get_banner_info()
+        assert info["server_banner"].startswith("SSH-2.0-OpenSSH")
+        assert info["auth_instructions"] is None  # password auth has no instructions
+        assert "Authorized use only\n" in info["motd"]
+        assert limited.execute("echo free").stdout == "free\n"
+        assert limited.get_banner_info(motd=False)["motd"] is None
+    finally:
+        conn.execute(": > /etc/motd")
+
+def test_batch_shares_shell_state(conn):
+    """Test that batched commands share a working directory and environment."""
+    with conn.batch() as batch:
+        batch.execute("cd /tmp")
+        batch.execute("export HUSSH_BATCH_VAR=shared")
+        result = batch.execute("pwd; echo $HUSSH_BATCH_VAR")
+        assert result.stdout == "/tmp\nshared\n"
+        assert result.command == "pwd; echo $HUSSH_BATCH_VAR"
+        isolated = batch.execute("pwd; echo $HUSSH_BATCH_VAR", isolated=True)
+        assert isolated.stdout == "/root\n\n"
+
+
+def test_batch_status_and_stderr(conn):
+    """Test that each batched command gets its own status, stdout and stderr."""
+    with conn.batch() as batch:
+        result = batch.execute("echo out; echo err >&2; (exit 3)")
+        assert (result.stdout, result.stderr, result.status) == ("out\n", "err\n", 3)
+        result = batch.execute(["printf", "%s", "no newline"])
+        assert (result.stdout, result.stderr, result.status) == ("no newline", "", 0)
+        assert batch.execute("cat").stdout == ""  # stdin is /dev/null
+    with pytest.raises(IOError, match="closed"):
+        batch.execute("true")
+
+
+def test_batch_large_stderr(conn):
+    """Test that a batched command writing more than the channel window to stderr finishes."""
+    with conn.batch() as batch:
+        result = batch.execute("head -c 4000000 /dev/zero | tr '\\0' e >&2; echo done")
+        assert result.stdout == "done\n"
+        assert result.stderr == "e" * 4000000
+        assert batch.execute("echo next").stdout == "next\n"
+
+
+def test_batch_uses_connection_encoding():
+    """Test that batched and isolated commands decode output with the connection's encoding."""
+    latin = Connection(
+        host="localhost", port=8022, password="toor", defaults=Defaults(encoding="latin-1")
+    )
+    with latin.batch() as batch:
+        assert batch.execute(r"printf '\xe9'").stdout == "\xe9"
+        assert batch.execute(r"printf '\xe9'", isolated=True).stdout == "\xe9"
+    latin.close()
+
+
+def test_batch_ended_by_exit(conn):
+    """Test that a command exiting the batch's shell raises, and the batch can't be reused."""
+    with conn.batch() as batch:
+        with pytest.raises(IOError, match="exited"):
+            batch.execute("exit 1")
+        with pytest.raises(IOError, match="closed"):
+            batch.execute("true")
+
+
+def test_batch_wall_time(conn):
+    """Report the time 100 commands take, batched and not. Informational, nothing is asserted."""
+    start = time.perf_counter()
+    for _ in range(100):
+        conn.execute("true")
+    separate = time.perf_counter() - start
+    with conn.batch() as batch:
+        start = time.perf_counter()
+        for _ in range(100):
+            batch.execute("true")
+        batched = time.perf_counter() - start
+    print(f"100 commands: {separate:.3f}s with execute, {batched:.3f}s batched")
+
+
+def test_subsystem(conn):
+    """Test that a raw sftp subsystem channel passes SFTP packets through untouched."""
+    with conn.open_subsystem("sftp") as sftp:
+        assert sftp.name == "sftp"
+        sftp.send(struct.pack(">IBI", 5, 1, 3))  # SSH_FXP_INIT, version 3
+        reply = b""
+        deadline = time.monotonic() + 5
+        while len(reply) < 4 or len(reply) < 4 + struct.unpack(">I", reply[:4])[0]:
+            assert time.monotonic() < deadline
+            reply += sftp.read_nonblocking()
+        assert reply[4] == 2  # SSH_FXP_VERSION
+        assert struct.unpack(">I", reply[5:9])[0] == 3
+        sftp.send(struct.pack(">IBII", 10, 16, 7, 1) + b".")  # SSH_FXP_REALPATH of "."
+        before = sftp.expect(b"/root", timeout=5000)
+        assert before[4] == 104  # SSH_FXP_NAME
+        assert struct.unpack(">I", before[5:9])[0] == 7
+        assert not sftp.eof
+    with pytest.raises(IOError, match="closed"):
+        sftp.send(b"")
+    # the connection's own SFTP is unaffected
+    conn.sftp_write_data("still fine", "/root/subsystem.txt")
+    assert conn.sftp_read("/root/subsystem.txt") == "still fine"
+
+
 def test_command_timeout(conn):
     """Test that we can trigger a timeout on command execution."""
     with pytest.raises(TimeoutError):
@@ -241,3 +1713,50 @@ def test_scp_read_directory_as_file(conn):
     """Test that IOError is raised if scp_read tries to read a directory as a file."""
     with pytest.raises(IOError):  # noqa: PT011
         conn.scp_read("/root")
+
+
+def test_tail_missing_file(conn):
+    """Test that reading a tailer for a missing file raises IOError instead of panicking."""
+    tailer = FileTailer(conn, "/root/missing.txt")
+    with pytest.raises(IOError, match="missing.txt"):
+        tailer.read(0)
+
+
+def test_shell_after_close(conn):
+    """Test that opening a shell on a closed connection raises instead of panicking."""
+    conn.close()
+    with pytest.raises(IOError, match="is closed"):
+        conn.shell()
+
+
+def test_close_closes_children(conn):
+    """Test that closing a connection closes its shells, batches, subsystems and tailers first."""
+    conn.execute("echo line > /root/children.log")
+    shell = conn.shell()
+    batch = conn.batch()
+    subsystem = conn.open_subsystem("sftp")
+    tailer = conn.tail("/root/children.log", from_pos=0)
+    finished = conn.shell()
+    finished.close()
+    del finished
+    assert conn.children() == [shell, batch, subsystem, tailer]
+    conn.close()
+    assert conn.children() == []
+    with pytest.raises(ChannelClosedError, match="shell is closed"):
+        shell.send("ls")
+    with pytest.raises(ChannelClosedError, match="batch is closed"):
+        batch.execute("true")
+    with pytest.raises(ChannelClosedError, match="sftp subsystem channel is closed"):
+        subsystem.send(b"data")
+    with pytest.raises(ChannelClosedError, match="children.log is closed"):
+        tailer.read()
+    # leaving their contexts after the connection closed is harmless
+    for child in (shell, batch, subsystem, tailer):
+        child.__exit__(None, None, None)
+    assert shell.result is None
+
+
+def test_scp_write_missing_local_file(conn):
+    """Test that IOError is raised if scp_write is given a local file that doesn't exist."""
+    with pytest.raises(IOError, match="Local file open error"):
+        conn.scp_write("/nonexistent/file.txt", "/root/")
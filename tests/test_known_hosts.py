@@ -0,0 +1,69 @@
+"""Tests for hussh.known_hosts module."""
+
+import subprocess
+
+import pytest
+
+from hussh import known_hosts
+
+KEY = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA"
+FINGERPRINT = "SHA256:kmYcvdi2GkPeWxB6XLjrZB8JHsy2Hm8luHMFp9GMvqk"
+
+
+def test_scan():
+    """Test that scanning returns the server's host keys without authenticating."""
+    keys = known_hosts.scan("localhost", port=8022)
+    assert keys
+    for key in keys:
+        assert key["fingerprint"].startswith("SHA256:")
+        assert key["type"] in {"ssh-ed25519", "ssh-rsa"} or key["type"].startswith("ecdsa-")
+    assert len({key["key"] for key in keys}) == len(keys)
+    with pytest.raises(OSError, match="Error scanning"):
+        known_hosts.scan("localhost", port=1)
+
+
+def test_scan_and_verify(tmp_path):
+    """Test that scanned keys can be added and then verified by fingerprint."""
+    path = str(tmp_path / "known_hosts")
+    for key in known_hosts.scan("localhost", port=8022):
+        assert known_hosts.add("localhost", f"{key['type']} {key['key']}", port=8022, path=path)
+        assert known_hosts.verify("localhost", key["fingerprint"], port=8022, path=path)
+    assert not known_hosts.verify("localhost", FINGERPRINT, port=8022, path=path)
+
+
+def test_add_remove(tmp_path):
+    """Test that entries are added once, plain or hashed, and removed by host, given a Path."""
+    path = tmp_path / "known_hosts"
+    path.write_text("# kept\nother.com " + KEY)
+    assert known_hosts.add("example.com", KEY + " a comment", path=path, hashed=True)
+    assert not known_hosts.add("example.com", KEY, path=path)
+    assert known_hosts.add("example.com", KEY, port=2222, path=path)
+    lines = path.read_text().splitlines()
+    assert lines[2].startswith("|1|")
+    assert lines[3].startswith("[example.com]:2222 ")
+    assert "example.com " not in path.read_text()
+    assert known_hosts.verify("example.com", FINGERPRINT, path=path)
+    assert not known_hosts.verify("example.org", FINGERPRINT, path=path)
+    # ssh-keygen finds the hashed entry too, if it's installed
+    try:
+        found = subprocess.run(
+            ["ssh-keygen", "-F", "example.com", "-f", str(path)], capture_output=True, text=True
+        )
+    except FileNotFoundError:
+        pass
+    else:
+        assert "found: line 3" in found.stdout
+    assert known_hosts.remove("example.com", path=path) == 1
+    assert path.read_text() == "# kept\nother.com " + KEY + "\n[example.com]:2222 " + KEY + "\n"
+    assert known_hosts.remove("example.com", path=path) == 0
+
+
+def test_bad_keys(tmp_path):
+    """Test that malformed keys and fingerprints raise ValueError."""
+    path = str(tmp_path / "known_hosts")
+    with pytest.raises(ValueError, match="should be"):
+        known_hosts.add("example.com", "AAAA", path=path)
+    with pytest.raises(ValueError, match="isn't a ssh-rsa key"):
+        known_hosts.add("example.com", KEY.replace("ssh-ed25519", "ssh-rsa"), path=path)
+    with pytest.raises(ValueError, match="Only SHA256"):
+        known_hosts.verify("example.com", "MD5:00:11", path=path)
@@ -0,0 +1,113 @@
+"""Tests for hussh.pool module."""
+
+import threading
+import time
+
+import pytest
+
+from hussh import Connection, ConnectionPool
+
+AUTH = {"port": 8022, "password": "toor"}
+
+
+@pytest.fixture
+def pool():
+    """Return a pool that's closed after the test."""
+    with ConnectionPool(max_per_host=2, idle_timeout=60) as pool:
+        yield pool
+
+
+def test_acquire_reuses_connection(pool):
+    """Test that a returned connection is handed out again instead of reconnecting."""
+    with pool.acquire("localhost", **AUTH) as conn:
+        assert isinstance(conn, Connection)
+        assert conn.execute("echo hello").stdout == "hello\n"
+    with pool.acquire("localhost", **AUTH) as again:
+        assert again is conn
+    assert pool.stats() == {"hits": 1, "misses": 1, "evictions": 0, "idle": 1}
+
+
+def test_pooled_by_arguments(pool):
+    """Test that connections acquired with different arguments aren't shared."""
+    with pool.acquire("localhost", **AUTH) as conn:
+        pass
+    with pool.acquire("localhost", username="root", **AUTH) as other:
+        assert other is not conn
+    assert pool.stats()["misses"] == 2
+
+
+def test_closed_connection_not_reused(pool):
+    """Test that a connection closed while checked out isn't returned to the pool."""
+    with pool.acquire("localhost", **AUTH) as conn:
+        conn.close()
+    with pool.acquire("localhost", **AUTH) as again:
+        assert again is not conn
+        assert not again.closed
+    assert pool.stats()["hits"] == 0
+
+
+def test_dead_connection_evicted(pool):
+    """Test that an idle connection whose session died fails its health check on checkout."""
+    with pool.acquire("localhost", **AUTH) as conn:
+        # end the connection's sshd process once the command has returned
+        conn.execute("(sleep 0.5; kill $PPID) >/dev/null 2>&1 &")
+    time.sleep(1.5)
+    with pool.acquire("localhost", **AUTH) as again:
+        assert again is not conn
+        assert again.execute("echo alive").stdout == "alive\n"
+    assert pool.stats()["evictions"] == 1
+
+
+def test_max_per_host(pool):
+    """Test that connections beyond max_per_host are closed when returned."""
+    checked_out = [pool.acquire("localhost", **AUTH) for _ in range(3)]
+    conns = [pooled.__enter__() for pooled in checked_out]
+    for pooled in checked_out:
+        pooled.release()
+    assert pool.stats()["idle"] == 2
+    assert pool.stats()["evictions"] == 1
+    assert conns[2].closed
+
+
+def test_idle_timeout():
+    """Test that the reaper closes connections left idle past the timeout."""
+    with ConnectionPool(idle_timeout=0.5) as pool:
+        with pool.acquire("localhost", **AUTH) as conn:
+            pass
+        time.sleep(1.5)
+        assert conn.closed
+        assert pool.stats() == {"hits": 0, "misses": 1, "evictions": 1, "idle": 0}
+
+
+def test_pool_threads(pool):
+    """Test that threads can share a pool, each checking out its own connection."""
+    errors = []
+
+    def worker():
+        try:
+            for _ in range(5):
+                with pool.acquire("localhost", **AUTH) as conn:
+                    assert conn.execute("true").status == 0
+        except Exception as err:
+            errors.append(err)
+
+    threads = [threading.Thread(target=worker) for _ in range(4)]
+    for thread in threads:
+        thread.start()
+    for thread in threads:
+        thread.join()
+    assert not errors
+    stats = pool.stats()
+    assert stats["hits"] + stats["misses"] == 20
+    assert stats["idle"] <= 2
+
+
+def test_closed_pool():
+    """Test that a closed pool closes its idle connections and refuses to hand out more."""
+    pool = ConnectionPool()
+    with pool.acquire("localhost", **AUTH) as conn:
+        pass
+    pool.close()
+    assert conn.closed
+    with pytest.raises(ValueError, match="closed"):
+        pool.acquire("localhost", **AUTH)
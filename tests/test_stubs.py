@@ -0,0 +1,142 @@
+"""Tests that the hussh.pyi type stubs stay in sync with the compiled module."""
+
+import ast
+import inspect
+from pathlib import Path
+import shutil
+import subprocess
+
+import pytest
+
+import hussh
+
+STUB_FILE = Path(__file__).parent.parent / "hussh.pyi"
+USAGE_SAMPLE = Path(__file__).parent / "data" / "stub_usage.py"
+STUB_CLASSES = {
+    node.name: node
+    for node in ast.parse(STUB_FILE.read_text()).body
+    if isinstance(node, ast.ClassDef)
+}
+STUB_FUNCTIONS = {
+    node.name: node
+    for node in ast.parse(STUB_FILE.read_text()).body
+    if isinstance(node, ast.FunctionDef)
+}
+
+
+def _stub_params(func, drop_self=True):
+    """Return (name, default) pairs for a stub function or method."""
+    args = func.args.posonlyargs + func.args.args
+    defaults = [inspect.Parameter.empty] * (len(args) - len(func.args.defaults))
+    defaults += [ast.literal_eval(default) for default in func.args.defaults]
+    params = [(arg.arg, default) for arg, default in zip(args, defaults)]
+    if func.args.kwarg:
+        params.append((func.args.kwarg.arg, inspect.Parameter.empty))
+    return params[1:] if drop_self else params
+
+
+def _runtime_params(obj, drop_self=True):
+    """Return (name, default) pairs for a compiled function or class constructor."""
+    params = list(inspect.signature(obj).parameters.values())
+    if drop_self:
+        params = params[1:]
+    return [(param.name, param.default) for param in params]
+
+
+def _stub_members(cls_node):
+    """Return the methods and attributes a stub class declares."""
+    members = {}
+    for node in cls_node.body:
+        if isinstance(node, ast.FunctionDef):
+            members[node.name] = node
+        elif isinstance(node, ast.AnnAssign):
+            members[node.target.id] = node
+    return members
+
+
+@pytest.mark.parametrize(
+    "name",
+    [
+        "Connection",
+        "SSHResult",
+        "ConnectionLock",
+        "CommandBatch",
+        "SubsystemChannel",
+        "RemoteTempPath",
+        "Defaults",
+        "InteractiveShell",
+        "FileTailer",
+        "ConnectionPool",
+        "PooledConnection",
+    ],
+)
+def test_stub_covers_class(name):
+    """Test that each stubbed class declares exactly the public members the compiled class has."""
+    runtime_cls = getattr(hussh, name)
+    runtime_members = {
+        member
+        for member in vars(runtime_cls)
+        if not member.startswith("_") or member in ("__enter__", "__exit__")
+    }
+    stub_members = set(_stub_members(STUB_CLASSES[name])) - {"__init__"}
+    assert stub_members == runtime_members
+
+
+@pytest.mark.parametrize(
+    "name",
+    [
+        "Connection",
+        "ConnectionLock",
+        "CommandBatch",
+        "SubsystemChannel",
+        "RemoteTempPath",
+        "Defaults",
+        "InteractiveShell",
+        "FileTailer",
+        "ConnectionPool",
+        "PooledConnection",
+    ],
+)
+def test_stub_signatures_match(name):
+    """Test that stubbed method parameters and defaults match the compiled signatures."""
+    runtime_cls = getattr(hussh, name)
+    for member, node in _stub_members(STUB_CLASSES[name]).items():
+        if not isinstance(node, ast.FunctionDef) or node.decorator_list:
+            continue
+        if member == "__init__":
+            assert _stub_params(node) == _runtime_params(runtime_cls, drop_self=False)
+        else:
+            assert _stub_params(node) == _runtime_params(getattr(runtime_cls, member)), member
+
+
+def test_stub_covers_functions():
+    """Test that every module-level function is stubbed with matching parameters."""
+    functions = {
+        name for name, obj in vars(hussh).items() if inspect.isbuiltin(obj) and name[0] != "_"
+    }
+    assert functions == set(STUB_FUNCTIONS)
+    for name, node in STUB_FUNCTIONS.items():
+        runtime_params = _runtime_params(getattr(hussh, name), drop_self=False)
+        assert _stub_params(node, drop_self=False) == runtime_params, name
+
+
+def test_stub_covers_exceptions():
+    """Test that every exception the module exports is stubbed."""
+    exceptions = {
+        name
+        for name, obj in vars(hussh).items()
+        if isinstance(obj, type) and issubclass(obj, BaseException)
+    }
+    assert exceptions <= set(STUB_CLASSES)
+
+
+@pytest.mark.skipif(shutil.which("mypy") is None, reason="mypy is not installed")
+def test_stub_usage_type_checks():
+    """Test that a small usage sample passes mypy --strict against the stubs."""
+    result = subprocess.run(
+        ["mypy", "--strict", str(USAGE_SAMPLE)],
+        capture_output=True,
+        text=True,
+        check=False,
+    )
+    assert result.returncode == 0, result.stdout